@@ -0,0 +1,256 @@
+//! LAN discovery of the other sites through UDP multicast
+//!
+//! When no peer is given with `--cli-peers`, a starting site sends a beacon to a multicast
+//! group, carrying its site id, the address of its peer port and the protocol version.
+//! Every running site listens on that group and answers with its own beacon, sent back to
+//! the socket the beacon came from. The starting site then connects to the sites that
+//! answered through the usual `Discovery` handshake.
+
+#[cfg(feature = "server")]
+/// Version of the peer-to-peer protocol, sites only answer beacons of the same version
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Default multicast group and port used for the discovery beacons
+pub const DEFAULT_DISCOVERY_GROUP: &str = "239.255.42.99:7645";
+
+#[cfg(feature = "server")]
+/// Time during which a starting site collects the answers to its beacon
+pub const DISCOVERY_WINDOW: std::time::Duration = std::time::Duration::from_millis(500);
+
+#[cfg(feature = "server")]
+/// Maximum size of an encoded beacon
+const BEACON_MAX_SIZE: usize = 1024;
+
+#[cfg(feature = "server")]
+/// Presence announcement of a site on the LAN
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct Beacon {
+    /// Version of the protocol spoken by the site
+    pub protocol_version: u16,
+    /// ID of the site
+    pub site_id: String,
+    /// Address on which the site accepts peer connections
+    pub peer_addr: std::net::SocketAddr,
+}
+
+#[cfg(feature = "server")]
+impl Beacon {
+    /// Creates the beacon of a site
+    pub fn new(site_id: String, peer_addr: std::net::SocketAddr) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            site_id,
+            peer_addr,
+        }
+    }
+
+    /// Serializes the beacon
+    fn encode(&self) -> std::io::Result<Vec<u8>> {
+        rmp_serde::encode::to_vec(self)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+
+    /// Deserializes a beacon, returns None if the datagram is not a beacon
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        rmp_serde::decode::from_slice(bytes).ok()
+    }
+}
+
+#[cfg(feature = "server")]
+/// Returns the interface used for multicast for a site listening on `ip`
+///
+/// A site bound to a specific IPv4 address uses the matching interface, otherwise
+/// the system picks one
+pub fn interface_for(ip: std::net::IpAddr) -> std::net::Ipv4Addr {
+    match ip {
+        std::net::IpAddr::V4(ip) => ip,
+        std::net::IpAddr::V6(_) => std::net::Ipv4Addr::UNSPECIFIED,
+    }
+}
+
+#[cfg(feature = "server")]
+/// Creates a UDP socket for multicast on `interface`, bound to `port`
+///
+/// The address is reusable, so that several sites of the same host can listen on the group
+fn multicast_socket(
+    interface: std::net::Ipv4Addr,
+    port: u16,
+) -> std::io::Result<tokio::net::UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.set_multicast_loop_v4(true)?;
+    if !interface.is_unspecified() {
+        socket.set_multicast_if_v4(&interface)?;
+    }
+    let bind_addr = std::net::SocketAddrV4::new(std::net::Ipv4Addr::UNSPECIFIED, port);
+    socket.bind(&bind_addr.into())?;
+    tokio::net::UdpSocket::from_std(socket.into())
+}
+
+#[cfg(feature = "server")]
+/// Creates the socket on which a site receives the beacons sent to `group`
+pub fn bind_group(
+    group: std::net::SocketAddrV4,
+    interface: std::net::Ipv4Addr,
+) -> std::io::Result<tokio::net::UdpSocket> {
+    let socket = multicast_socket(interface, group.port())?;
+    socket.join_multicast_v4(*group.ip(), interface)?;
+    Ok(socket)
+}
+
+#[cfg(feature = "server")]
+/// Answers every beacon received on `socket` with the beacon of this site
+pub async fn run_beacon_listener(socket: tokio::net::UdpSocket, own: Beacon) {
+    let reply = match own.encode() {
+        Ok(reply) => reply,
+        Err(e) => {
+            log::error!("Unable to encode the beacon of this site: {}", e);
+            return;
+        }
+    };
+
+    let mut buf = [0u8; BEACON_MAX_SIZE];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                log::error!("Beacon listener stopped: {}", e);
+                return;
+            }
+        };
+        let Some(beacon) = Beacon::decode(&buf[..len]) else {
+            log::debug!("Ignoring a datagram that is not a beacon from {}", from);
+            continue;
+        };
+        if beacon.site_id == own.site_id {
+            continue;
+        }
+        if beacon.protocol_version != PROTOCOL_VERSION {
+            log::warn!(
+                "Ignoring beacon of site {} speaking protocol version {}",
+                beacon.site_id,
+                beacon.protocol_version
+            );
+            continue;
+        }
+
+        log::info!(
+            "Site {} at {} is looking for peers, answering",
+            beacon.site_id,
+            beacon.peer_addr
+        );
+        if let Err(e) = socket.send_to(&reply, from).await {
+            log::error!("Unable to answer the beacon of {}: {}", beacon.site_id, e);
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+/// Spawns the task answering the beacons of the starting sites
+pub fn spawn_beacon_listener(
+    group: std::net::SocketAddrV4,
+    interface: std::net::Ipv4Addr,
+    own: Beacon,
+) {
+    match bind_group(group, interface) {
+        Ok(socket) => {
+            log::debug!("Listening for beacons on {}", group);
+            tokio::spawn(run_beacon_listener(socket, own));
+        }
+        Err(e) => log::warn!("LAN discovery disabled, unable to join {}: {}", group, e),
+    }
+}
+
+#[cfg(feature = "server")]
+/// Sends the beacon of this site to `group` and collects the answers for `window`
+///
+/// Returns one beacon per answering site
+pub async fn discover(
+    group: std::net::SocketAddrV4,
+    interface: std::net::Ipv4Addr,
+    own: &Beacon,
+    window: std::time::Duration,
+) -> std::io::Result<Vec<Beacon>> {
+    let socket = multicast_socket(interface, 0)?;
+    socket.send_to(&own.encode()?, group).await?;
+
+    let deadline = tokio::time::Instant::now() + window;
+    let mut found: Vec<Beacon> = Vec::new();
+    let mut buf = [0u8; BEACON_MAX_SIZE];
+    while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (len, from) = received?;
+        match Beacon::decode(&buf[..len]) {
+            Some(beacon)
+                if beacon.protocol_version == PROTOCOL_VERSION
+                    && beacon.site_id != own.site_id
+                    && !found.iter().any(|b| b.site_id == beacon.site_id) =>
+            {
+                log::debug!("Site {} answered at {}", beacon.site_id, beacon.peer_addr);
+                found.push(beacon);
+            }
+            _ => log::debug!("Ignoring an answer from {}", from),
+        }
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+#[cfg(feature = "server")]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    const LOOPBACK: Ipv4Addr = Ipv4Addr::LOCALHOST;
+
+    fn group(port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(239, 255, 42, 99), port)
+    }
+
+    fn mk_beacon(site_id: &str, port: u16) -> Beacon {
+        Beacon::new(
+            site_id.to_string(),
+            format!("127.0.0.1:{}", port).parse().unwrap(),
+        )
+    }
+
+    #[test]
+    fn beacon_round_trip() {
+        let beacon = mk_beacon("A", 10000);
+        let bytes = beacon.encode().unwrap();
+        assert_eq!(Beacon::decode(&bytes), Some(beacon));
+        assert_eq!(Beacon::decode(b"ping"), None);
+    }
+
+    #[tokio::test]
+    async fn running_sites_answer_over_loopback_multicast() {
+        let group = group(17645);
+        for (site_id, port) in [("A", 10000), ("B", 10001), ("C", 10002)] {
+            let socket = bind_group(group, LOOPBACK).unwrap();
+            tokio::spawn(run_beacon_listener(socket, mk_beacon(site_id, port)));
+        }
+
+        // Site A also receives its own beacon, but does not answer it
+        let mut found = discover(group, LOOPBACK, &mk_beacon("A", 10000), DISCOVERY_WINDOW)
+            .await
+            .unwrap();
+        found.sort_by(|a, b| a.site_id.cmp(&b.site_id));
+        assert_eq!(found, vec![mk_beacon("B", 10001), mk_beacon("C", 10002)]);
+    }
+
+    #[tokio::test]
+    async fn beacon_of_another_protocol_version_is_ignored() {
+        let group = group(17646);
+        let socket = bind_group(group, LOOPBACK).unwrap();
+        tokio::spawn(run_beacon_listener(socket, mk_beacon("B", 10001)));
+
+        let mut newer = mk_beacon("A", 10000);
+        newer.protocol_version = PROTOCOL_VERSION + 1;
+        let found = discover(group, LOOPBACK, &newer, DISCOVERY_WINDOW)
+            .await
+            .unwrap();
+        assert!(found.is_empty());
+    }
+}
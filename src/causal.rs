@@ -0,0 +1,319 @@
+//! Causal delivery of the replicated transactions
+//!
+//! Every transaction carries the number of transactions of each site its initiator had
+//! applied when it sent it, its own included. A site holds an incoming transaction back
+//! until it applied all of them, so that a transfer never runs on a replica before the
+//! deposit that funded it, whatever the order the messages arrive in.
+
+#[cfg(feature = "server")]
+/// Transactions held back until their causal predecessors are applied
+#[derive(Debug, Default)]
+pub struct CausalBuffer {
+    /// Number of transactions of each site applied locally
+    delivered: std::collections::HashMap<String, i64>,
+    /// Transactions received too early, in arrival order
+    held: Vec<crate::message::Message>,
+}
+
+#[cfg(feature = "server")]
+impl CausalBuffer {
+    /// Counts a transaction initiated and applied by this site
+    ///
+    /// Returns the causal dependencies to send with the transaction
+    pub fn stamp(&mut self, site_id: &str) -> std::collections::HashMap<String, i64> {
+        *self.delivered.entry(site_id.to_string()).or_insert(0) += 1;
+        self.delivered.clone()
+    }
+
+    /// Returns the number of transactions of each site applied locally
+    pub fn get_delivered(&self) -> &std::collections::HashMap<String, i64> {
+        &self.delivered
+    }
+
+    /// Returns true if no transaction was applied since the site started
+    pub fn is_fresh(&self) -> bool {
+        self.delivered.is_empty()
+    }
+
+    /// Takes the counters of a site we are about to synchronize with
+    ///
+    /// The transactions it applied will come with the synchronization, the held
+    /// transactions they include are dropped
+    ///
+    /// Returns the transactions that can be applied now
+    pub fn adopt(
+        &mut self,
+        delivered: &std::collections::HashMap<String, i64>,
+    ) -> Vec<crate::message::Message> {
+        for (site_id, count) in delivered {
+            let local = self.delivered.entry(site_id.clone()).or_insert(0);
+            *local = (*local).max(*count);
+        }
+        self.release()
+    }
+
+    /// Takes an incoming transaction
+    ///
+    /// Returns the transactions that can be applied now in causal order, they are counted
+    /// as applied. The others are held until their predecessors are applied.
+    pub fn receive(&mut self, msg: crate::message::Message) -> Vec<crate::message::Message> {
+        if msg.causal_deps.is_none() {
+            log::warn!(
+                "Transaction from {} without causal dependencies, applied at once",
+                msg.message_initiator_id
+            );
+            return vec![msg];
+        }
+        if self.is_applied(&msg) || self.held.iter().any(|held| same_transaction(held, &msg)) {
+            log::debug!(
+                "Transaction from {} already received, skipping",
+                msg.message_initiator_id
+            );
+            return Vec::new();
+        }
+        self.held.push(msg);
+        self.release()
+    }
+
+    /// Returns the transactions held back, in arrival order
+    pub fn get_held(&self) -> &[crate::message::Message] {
+        &self.held
+    }
+
+    /// Returns the transactions still missing before `msg` can be applied
+    ///
+    /// Each entry gives a site and the number of its transactions to apply, sorted by site
+    pub fn waiting_for(&self, msg: &crate::message::Message) -> Vec<(String, i64)> {
+        let Some(deps) = &msg.causal_deps else {
+            return Vec::new();
+        };
+        let mut missing: Vec<(String, i64)> = deps
+            .iter()
+            .map(|(site_id, count)| {
+                // The transaction itself is the last one of its initiator
+                if site_id == &msg.message_initiator_id {
+                    (site_id.clone(), count - 1)
+                } else {
+                    (site_id.clone(), *count)
+                }
+            })
+            .filter(|(site_id, count)| self.applied_of(site_id) < *count)
+            .collect();
+        missing.sort();
+        missing
+    }
+
+    fn applied_of(&self, site_id: &str) -> i64 {
+        self.delivered.get(site_id).copied().unwrap_or(0)
+    }
+
+    fn is_applied(&self, msg: &crate::message::Message) -> bool {
+        self.applied_of(&msg.message_initiator_id) >= rank(msg)
+    }
+
+    /// Removes the held transactions that can be applied, until none is left
+    fn release(&mut self) -> Vec<crate::message::Message> {
+        let mut ready = Vec::new();
+        loop {
+            // Transactions applied meanwhile, through a synchronization
+            let delivered = &self.delivered;
+            self.held.retain(|msg| {
+                delivered
+                    .get(&msg.message_initiator_id)
+                    .copied()
+                    .unwrap_or(0)
+                    < rank(msg)
+            });
+            let Some(i) = self
+                .held
+                .iter()
+                .position(|msg| self.waiting_for(msg).is_empty())
+            else {
+                return ready;
+            };
+            let msg = self.held.remove(i);
+            self.delivered
+                .insert(msg.message_initiator_id.clone(), rank(&msg));
+            ready.push(msg);
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+/// Applications of the transactions released by the causal buffer of the current node
+pub static DELIVERY: crate::node::NodeLocal<tokio::sync::Mutex<()>> =
+    crate::node::NodeLocal::new(|node| &node.delivery);
+
+#[cfg(feature = "server")]
+/// Runs `release` on the causal buffer, then applies the transactions it released
+///
+/// The releases are applied one after the other in the order of the buffer, without the
+/// app state being locked while they are written
+pub async fn deliver(
+    store: &std::sync::Arc<dyn crate::store::LedgerStore>,
+    release: impl FnOnce(&mut CausalBuffer) -> Vec<crate::message::Message>,
+) {
+    let _delivery = DELIVERY.lock().await;
+    let ready = {
+        let mut state = crate::state::LOCAL_APP_STATE.lock().await;
+        release(&mut state.causal)
+    };
+    apply(store, ready).await;
+}
+
+#[cfg(feature = "server")]
+/// Applies the transactions released by a `CausalBuffer`, in order
+///
+/// A transaction that fails is counted as applied all the same: the ledger now misses
+/// what its initiator recorded, so the site synchronizes with the network to get it
+async fn apply(
+    store: &std::sync::Arc<dyn crate::store::LedgerStore>,
+    ready: Vec<crate::message::Message>,
+) {
+    let mut diverged = false;
+    for msg in ready {
+        let initiator = msg.message_initiator_id.clone();
+        if let Err(e) =
+            crate::control::process_network_command(store, msg.info, msg.clock, &initiator).await
+        {
+            log::error!("Unable to apply a transaction from {}: {}", initiator, e);
+            diverged = true;
+        }
+    }
+    if diverged {
+        log::warn!("The ledger diverged from the network, starting synchronization");
+        let store = store.clone();
+        crate::node::spawn(async move {
+            // A run of failures asks for a single synchronization
+            if let Err(e) = crate::control::enqueue_sync(&store).await {
+                log::error!("Unable to start the synchronization: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(feature = "server")]
+/// Rank of a transaction among the transactions of its initiator
+fn rank(msg: &crate::message::Message) -> i64 {
+    msg.causal_deps
+        .as_ref()
+        .and_then(|deps| deps.get(&msg.message_initiator_id))
+        .copied()
+        .unwrap_or(0)
+}
+
+#[cfg(feature = "server")]
+fn same_transaction(a: &crate::message::Message, b: &crate::message::Message) -> bool {
+    a.message_initiator_id == b.message_initiator_id && rank(a) == rank(b)
+}
+
+#[cfg(test)]
+#[cfg(feature = "server")]
+mod tests {
+    use super::*;
+    use crate::message::{Deposit, Message, MessageInfo, NetworkMessageCode};
+
+    fn transaction(initiator: &str, deps: &[(&str, i64)]) -> Message {
+        Message {
+            sender_id: initiator.to_string(),
+            sender_addr: "127.0.0.1:8080".parse().unwrap(),
+            message_initiator_id: initiator.to_string(),
+            message_initiator_addr: "127.0.0.1:8080".parse().unwrap(),
+            clock: crate::clock::Clock::new(),
+            command: Some(crate::control::Command::Deposit),
+            info: MessageInfo::Deposit(Deposit::new(
+                "alice".to_string(),
+                crate::money::Money::from_euros(10),
+            )),
+            code: NetworkMessageCode::Transaction,
+            signature: None,
+            wave_id: None,
+            causal_deps: Some(
+                deps.iter()
+                    .map(|(site_id, count)| (site_id.to_string(), *count))
+                    .collect(),
+            ),
+        }
+    }
+
+    fn origins(msgs: &[Message]) -> Vec<(String, i64)> {
+        msgs.iter()
+            .map(|msg| (msg.message_initiator_id.clone(), rank(msg)))
+            .collect()
+    }
+
+    #[test]
+    fn transaction_waits_for_its_predecessors() {
+        let mut buffer = CausalBuffer::default();
+        let deposit = transaction("A", &[("A", 1)]);
+        // B applied the deposit of A before sending its transfer
+        let transfer = transaction("B", &[("A", 1), ("B", 1)]);
+
+        assert!(buffer.receive(transfer.clone()).is_empty());
+        assert_eq!(buffer.waiting_for(&transfer), vec![("A".to_string(), 1)]);
+        assert_eq!(buffer.get_held().len(), 1);
+
+        let ready = buffer.receive(deposit);
+        assert_eq!(
+            origins(&ready),
+            vec![("A".to_string(), 1), ("B".to_string(), 1)]
+        );
+        assert!(buffer.get_held().is_empty());
+    }
+
+    #[test]
+    fn transactions_of_a_site_are_applied_in_order() {
+        let mut buffer = CausalBuffer::default();
+        let second = transaction("A", &[("A", 2)]);
+
+        assert!(buffer.receive(second.clone()).is_empty());
+        assert_eq!(buffer.waiting_for(&second), vec![("A".to_string(), 1)]);
+        assert_eq!(
+            origins(&buffer.receive(transaction("A", &[("A", 1)]))),
+            vec![("A".to_string(), 1), ("A".to_string(), 2)]
+        );
+    }
+
+    #[test]
+    fn concurrent_transactions_are_applied_at_once() {
+        let mut buffer = CausalBuffer::default();
+        assert_eq!(buffer.receive(transaction("A", &[("A", 1)])).len(), 1);
+        assert_eq!(buffer.receive(transaction("B", &[("B", 1)])).len(), 1);
+    }
+
+    #[test]
+    fn duplicates_are_dropped() {
+        let mut buffer = CausalBuffer::default();
+        let early = transaction("A", &[("A", 2)]);
+        assert!(buffer.receive(early.clone()).is_empty());
+        assert!(buffer.receive(early).is_empty());
+        assert_eq!(buffer.get_held().len(), 1);
+
+        assert_eq!(buffer.receive(transaction("A", &[("A", 1)])).len(), 2);
+        assert!(buffer.receive(transaction("A", &[("A", 1)])).is_empty());
+    }
+
+    #[test]
+    fn own_transactions_are_counted() {
+        let mut buffer = CausalBuffer::default();
+        assert!(buffer.is_fresh());
+        buffer.receive(transaction("A", &[("A", 1)]));
+
+        let deps = buffer.stamp("B");
+        assert_eq!(deps.get("A"), Some(&1));
+        assert_eq!(deps.get("B"), Some(&1));
+        assert!(!buffer.is_fresh());
+    }
+
+    #[test]
+    fn adopted_counters_release_the_held_transactions() {
+        let mut buffer = CausalBuffer::default();
+        // Sent by A after transactions we will get from the synchronization
+        let late = transaction("A", &[("A", 4), ("B", 2)]);
+        assert!(buffer.receive(late).is_empty());
+
+        let ready = buffer.adopt(&[("A".to_string(), 3), ("B".to_string(), 2)].into());
+        assert_eq!(origins(&ready), vec![("A".to_string(), 4)]);
+        assert_eq!(buffer.get_delivered().get("A"), Some(&4));
+    }
+}
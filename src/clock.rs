@@ -1,7 +1,99 @@
 //! Logical clock implementation for distributed synchronization
 //!
 //! This module provides both Lamport and Vector clock implementations for
-//! maintaining causal ordering of events in the distributed system.
+//! maintaining causal ordering of events in the distributed system, and a
+//! hybrid logical clock to date the transactions.
+
+/// Timestamp of a hybrid logical clock
+///
+/// The wall time is the largest physical time known when the event happened, the logical
+/// counter orders the events sharing a wall time. Timestamps follow causality even when the
+/// wall clocks of the sites drift, and stay close to the physical time.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+pub struct HybridTimestamp {
+    /// Milliseconds since the Unix epoch
+    pub wall_ms: i64,
+    /// Counter of the events known at this wall time
+    pub logical: i64,
+}
+
+impl std::fmt::Display for HybridTimestamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match chrono::DateTime::from_timestamp_millis(self.wall_ms) {
+            Some(date) => write!(f, "{}", date.format("%Y-%m-%d %H:%M:%S%.3f"))?,
+            None => write!(f, "{}ms", self.wall_ms)?,
+        }
+        if self.logical > 0 {
+            write!(f, " +{}", self.logical)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "server")]
+impl HybridTimestamp {
+    /// Timestamp of a local or send event happening at physical time `now_ms`
+    fn tick(self, now_ms: i64) -> Self {
+        if now_ms > self.wall_ms {
+            Self {
+                wall_ms: now_ms,
+                logical: 0,
+            }
+        } else {
+            Self {
+                wall_ms: self.wall_ms,
+                logical: self.logical + 1,
+            }
+        }
+    }
+
+    /// Timestamp of the receipt of an event stamped `received` at physical time `now_ms`
+    fn merge(self, received: Self, now_ms: i64) -> Self {
+        let wall_ms = self.wall_ms.max(received.wall_ms).max(now_ms);
+        let logical = if wall_ms == self.wall_ms && wall_ms == received.wall_ms {
+            self.logical.max(received.logical) + 1
+        } else if wall_ms == self.wall_ms {
+            self.logical + 1
+        } else if wall_ms == received.wall_ms {
+            received.logical + 1
+        } else {
+            0
+        };
+        Self { wall_ms, logical }
+    }
+}
+
+#[cfg(feature = "server")]
+/// Physical time of the site, in milliseconds since the Unix epoch
+fn physical_time_ms() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+#[cfg(feature = "server")]
+/// Causal relation between the events stamped by two vector clocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Causality {
+    /// The first event happened before the second one
+    Before,
+    /// The first event happened after the second one
+    After,
+    /// Both clocks stamp the same event
+    Equal,
+    /// Neither event knows about the other
+    Concurrent,
+}
 
 #[cfg(feature = "server")]
 /// Implements logical clocks for distributed synchronization
@@ -15,7 +107,30 @@ pub struct Clock {
     /// Vector clock mapping site IDs to their clock values
     ///
     /// Site_id -> clock value
+    #[serde(serialize_with = "serialize_sorted")]
     vector_clock: std::collections::HashMap<String, i64>,
+    /// Hybrid logical clock, dating the events
+    hlc: HybridTimestamp,
+    /// Last value of each retired site, the entries at or below it are not merged again
+    ///
+    /// Kept on this site only, see `crate::membership`
+    #[serde(skip)]
+    retired: std::collections::HashMap<String, i64>,
+}
+
+#[cfg(feature = "server")]
+/// Serializes a vector clock sorted by site
+///
+/// The clocks of the batched transactions are signed with their message, every site must
+/// compute the same bytes for them
+fn serialize_sorted<S: serde::Serializer>(
+    vector_clock: &std::collections::HashMap<String, i64>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    use serde::Serialize;
+
+    let sorted: std::collections::BTreeMap<_, _> = vector_clock.iter().collect();
+    sorted.serialize(serializer)
 }
 
 #[cfg(feature = "server")]
@@ -25,16 +140,21 @@ impl Clock {
         Clock {
             lamport_clock: 0,
             vector_clock: std::collections::HashMap::new(),
+            hlc: HybridTimestamp::default(),
+            retired: std::collections::HashMap::new(),
         }
     }
 
     pub fn from_parts(
         lamport_clock: i64,
         vector_clock: std::collections::HashMap<String, i64>,
+        hlc: HybridTimestamp,
     ) -> Self {
         Clock {
             lamport_clock,
             vector_clock,
+            hlc,
+            retired: std::collections::HashMap::new(),
         }
     }
 
@@ -44,6 +164,8 @@ impl Clock {
         Clock {
             lamport_clock: lamport,
             vector_clock: vector,
+            hlc: HybridTimestamp::default(),
+            retired: std::collections::HashMap::new(),
         }
     }
 
@@ -63,25 +185,68 @@ impl Clock {
         &self.lamport_clock
     }
 
+    /// Returns the hybrid logical clock timestamp
+    pub fn get_hlc(&self) -> HybridTimestamp {
+        self.hlc
+    }
+
     /// Returns a reference to the vector clock
     pub fn get_vector_clock_map(&self) -> &std::collections::HashMap<String, i64> {
         &self.vector_clock
     }
 
-    /// Returns the vector clock as a list of values
-    pub fn get_vector_clock_values(&self) -> Vec<i64> {
-        let mut vc: Vec<i64> = vec![0; self.vector_clock.len()];
-        for (i, clock_value) in self.vector_clock.values().enumerate() {
-            vc[i] = *clock_value;
+    /// Returns the vector clock entries ordered by site ID
+    pub fn get_vector_clock_entries(&self) -> Vec<(String, i64)> {
+        let mut entries: Vec<(String, i64)> = self
+            .vector_clock
+            .iter()
+            .map(|(site_id, value)| (site_id.clone(), *value))
+            .collect();
+        entries.sort();
+        entries
+    }
+
+    /// Compares the vector clocks, a missing entry counts as 0
+    ///
+    /// The entries of the sites retired by either clock are ignored, so that both clocks
+    /// give the same answer whichever is compared to the other
+    pub fn compare(&self, other: &Self) -> Causality {
+        let sites = self
+            .vector_clock
+            .keys()
+            .chain(other.vector_clock.keys())
+            .filter(|site_id| {
+                !self.retired.contains_key(*site_id) && !other.retired.contains_key(*site_id)
+            });
+        let (mut less, mut greater) = (false, false);
+        for site_id in sites {
+            let mine = self.vector_clock.get(site_id).copied().unwrap_or(0);
+            let theirs = other.vector_clock.get(site_id).copied().unwrap_or(0);
+            less |= mine < theirs;
+            greater |= mine > theirs;
+        }
+        match (less, greater) {
+            (false, false) => Causality::Equal,
+            (true, false) => Causality::Before,
+            (false, true) => Causality::After,
+            (true, true) => Causality::Concurrent,
         }
-        vc
     }
 
     /// Updates the vector clock with received values, taking the maximum of local and received values
+    ///
+    /// The entry of a retired site is only taken back if it moved past its last value, the
+    /// site came back then
     fn update_vector(&mut self, received_vc: &std::collections::HashMap<String, i64>) {
         for (site_id, clock_value) in received_vc {
+            if let Some(last) = self.retired.get(site_id) {
+                if clock_value <= last {
+                    continue;
+                }
+                self.retired.remove(site_id);
+            }
             let current_value = self.vector_clock.entry(site_id.clone()).or_insert(0);
-            *current_value = (*current_value).max(*clock_value) + 1;
+            *current_value = (*current_value).max(*clock_value);
         }
     }
 
@@ -90,21 +255,75 @@ impl Clock {
         self.lamport_clock = (self.lamport_clock).max(*received_lc) + 1;
     }
 
+    /// Drops the entry of a site that left the network
+    ///
+    /// `last` is the last value of the entry known on the network, the entries received
+    /// later are merged again only if they are newer
+    pub fn retire(&mut self, site_id: &str, last: i64) {
+        let value = self.vector_clock.remove(site_id).unwrap_or(0);
+        let retired = self.retired.entry(site_id.to_string()).or_insert(0);
+        *retired = (*retired).max(value).max(last);
+    }
+
+    /// Returns the value of the entry of a site, 0 if it has none
+    pub fn get_entry(&self, site_id: &str) -> i64 {
+        self.vector_clock.get(site_id).copied().unwrap_or(0)
+    }
+
+    /// Takes back a retired site which joined the network again
+    pub fn readmit(&mut self, site_id: &str) {
+        self.retired.remove(site_id);
+    }
+
+    /// Returns the retired sites ordered by site ID
+    pub fn get_retired(&self) -> Vec<String> {
+        let mut retired: Vec<String> = self.retired.keys().cloned().collect();
+        retired.sort();
+        retired
+    }
+
     /// Update the current clock value with an optional clock
     ///
-    /// Local lamport clock is incremented
+    /// The received clocks, if any, are merged by taking the maximum of each entry
     ///
-    /// Element of local vector clock is incremented
+    /// Then the local lamport clock and the local element of the vector clock are incremented
     ///
-    /// Then we call update methods to take the maximum of the received clocks if any
+    /// The hybrid logical clock moves past both the received timestamp and the physical time
     pub fn update_clock(&mut self, local_site_id: &str, received_clock: Option<&Self>) {
+        self.update_clock_at(local_site_id, received_clock, physical_time_ms());
+    }
+
+    fn update_clock_at(&mut self, local_site_id: &str, received_clock: Option<&Self>, now_ms: i64) {
         if let Some(rc) = received_clock {
             self.update_vector(rc.get_vector_clock_map());
             self.update_lamport(rc.get_lamport());
+            self.hlc = self.hlc.merge(rc.hlc, now_ms);
         } else {
-            // If the received clock is None, we increment the local lamport clock and the local vector clock
             self.increment_lamport();
-            self.increment_vector(local_site_id);
+            self.hlc = self.hlc.tick(now_ms);
+        }
+        self.increment_vector(local_site_id);
+    }
+}
+
+#[cfg(feature = "server")]
+/// Clocks are equal when `compare` finds they stamp the same event, the Lamport clock and
+/// the hybrid logical clock are ignored
+impl PartialEq for Clock {
+    fn eq(&self, other: &Self) -> bool {
+        self.compare(other) == Causality::Equal
+    }
+}
+
+#[cfg(feature = "server")]
+/// Orders the clocks by happened-before as `compare` does, concurrent clocks are not comparable
+impl PartialOrd for Clock {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        match self.compare(other) {
+            Causality::Before => Some(std::cmp::Ordering::Less),
+            Causality::After => Some(std::cmp::Ordering::Greater),
+            Causality::Equal => Some(std::cmp::Ordering::Equal),
+            Causality::Concurrent => None,
         }
     }
 }
@@ -143,15 +362,21 @@ mod tests {
     }
 
     #[test]
-    fn test_get_vector_clock_values() {
+    fn test_get_vector_clock_entries() {
         let mut clock = Clock::new();
+        clock.increment_vector("C");
         clock.increment_vector("A");
         clock.increment_vector("B");
         clock.increment_vector("B");
 
-        let mut values = clock.get_vector_clock_values();
-        values.sort(); // Order not guaranteed by HashMap
-        assert_eq!(values, vec![1, 2]);
+        assert_eq!(
+            clock.get_vector_clock_entries(),
+            vec![
+                ("A".to_string(), 1),
+                ("B".to_string(), 2),
+                ("C".to_string(), 1)
+            ]
+        );
     }
 
     #[test]
@@ -164,11 +389,11 @@ mod tests {
         incoming.increment_vector("A"); // A:2
         incoming.increment_vector("B"); // B:1
 
-        local.update_vector(&incoming.get_vector_clock_map());
+        local.update_vector(incoming.get_vector_clock_map());
 
         let local_vc = local.get_vector_clock_map();
-        assert_eq!(local_vc.get("A"), Some(&3));
-        assert_eq!(local_vc.get("B"), Some(&2));
+        assert_eq!(local_vc.get("A"), Some(&2));
+        assert_eq!(local_vc.get("B"), Some(&1));
     }
 
     #[test]
@@ -194,16 +419,148 @@ mod tests {
         received.increment_lamport(); // lamport: 2
 
         // Here local is A:2 before
-        // Incrementing local to A:3
-        // Then updated with received (A:2)
-        // So should be A:3 after
-        // Lamport was 1 before, so should be 2 after
+        // Merged with received (A:1, B:1), so A:2, B:1
+        // Then incrementing local to A:3
         local.update_clock("A", Some(&received));
 
         // Lamport clock should be max(received, local) + 1
         assert_eq!(*local.get_lamport(), 3);
         let vc = local.get_vector_clock_map();
-        assert_eq!(vc.get("A"), Some(&3)); // Incremented locally + merged max
-        assert_eq!(vc.get("B"), Some(&2));
+        assert_eq!(vc.get("A"), Some(&3)); // Merged max + incremented locally
+        assert_eq!(vc.get("B"), Some(&1)); // Only merged
+    }
+
+    #[test]
+    fn test_hlc_follows_physical_time() {
+        let mut clock = Clock::new();
+        clock.update_clock_at("A", None, 1000);
+        assert_eq!(
+            clock.get_hlc(),
+            HybridTimestamp {
+                wall_ms: 1000,
+                logical: 0
+            }
+        );
+
+        // Events in the same millisecond are told apart by the counter
+        clock.update_clock_at("A", None, 1000);
+        assert_eq!(clock.get_hlc().logical, 1);
+
+        clock.update_clock_at("A", None, 1500);
+        assert_eq!(
+            clock.get_hlc(),
+            HybridTimestamp {
+                wall_ms: 1500,
+                logical: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_hlc_keeps_causality_despite_drift() {
+        // The wall clock of A is ahead of the one of B
+        let mut a = Clock::new();
+        a.update_clock_at("A", None, 5000);
+        let sent = a.clone();
+
+        let mut b = Clock::new();
+        b.update_clock_at("B", None, 1000);
+        b.update_clock_at("B", Some(&sent), 1001);
+        assert!(sent.get_hlc() < b.get_hlc());
+        assert_eq!(b.get_hlc().wall_ms, 5000);
+
+        // The next events of B stay after the receipt until its wall clock catches up
+        let received = b.get_hlc();
+        b.update_clock_at("B", None, 1002);
+        assert!(received < b.get_hlc());
+        b.update_clock_at("B", None, 6000);
+        assert_eq!(
+            b.get_hlc(),
+            HybridTimestamp {
+                wall_ms: 6000,
+                logical: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_hlc_display() {
+        let ts = HybridTimestamp {
+            wall_ms: 1_700_000_000_123,
+            logical: 2,
+        };
+        assert_eq!(ts.to_string(), "2023-11-14 22:13:20.123 +2");
+    }
+
+    fn clock(entries: &[(&str, i64)]) -> Clock {
+        Clock::new_with_values(
+            0,
+            entries
+                .iter()
+                .map(|(site_id, value)| (site_id.to_string(), *value))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_compare_causality() {
+        let a = clock(&[("A", 1)]);
+        let ab = clock(&[("A", 1), ("B", 1)]);
+        let b = clock(&[("B", 1)]);
+
+        assert_eq!(a.compare(&ab), Causality::Before);
+        assert_eq!(ab.compare(&a), Causality::After);
+        assert_eq!(a.compare(&b), Causality::Concurrent);
+        // A missing entry counts as 0
+        assert_eq!(a.compare(&clock(&[("A", 1), ("B", 0)])), Causality::Equal);
+
+        assert!(a < ab);
+        assert!(ab > a);
+        assert_eq!(a.partial_cmp(&b), None);
+    }
+
+    #[test]
+    fn test_message_exchange_orders_events() {
+        let mut sender = Clock::new();
+        sender.update_clock("A", None);
+        let sent = sender.clone();
+
+        let mut receiver = Clock::new();
+        receiver.update_clock("B", None);
+        let before_receipt = receiver.clone();
+        receiver.update_clock("B", Some(&sent));
+
+        assert!(sent < receiver);
+        assert!(before_receipt < receiver);
+        assert_eq!(sent.compare(&before_receipt), Causality::Concurrent);
+        assert_eq!(
+            receiver.get_vector_clock_entries(),
+            vec![("A".to_string(), 1), ("B".to_string(), 2)]
+        );
+    }
+
+    #[test]
+    fn test_retired_entries_are_not_merged_again() {
+        let mut local = clock(&[("A", 3), ("B", 4)]);
+        // Another site got a later value of B before it left
+        local.retire("B", 5);
+        assert_eq!(local.get_vector_clock_entries(), vec![("A".to_string(), 3)]);
+        assert_eq!(local.get_retired(), vec!["B".to_string()]);
+
+        // A message sent before the departure still carries the entry
+        let stale = clock(&[("A", 3), ("B", 5)]);
+        local.update_vector(stale.get_vector_clock_map());
+        assert_eq!(local.get_vector_clock_entries(), vec![("A".to_string(), 3)]);
+        assert_eq!(local.compare(&stale), Causality::Equal);
+        // Whichever clock retired the site, both agree
+        assert_eq!(stale.compare(&local), Causality::Equal);
+        assert_eq!(stale, local);
+        assert_eq!(local, stale);
+        assert_eq!(stale.partial_cmp(&local), Some(std::cmp::Ordering::Equal));
+
+        // The site came back and moved on
+        local.update_vector(clock(&[("B", 6)]).get_vector_clock_map());
+        assert_eq!(local.get_entry("B"), 6);
+        assert!(local.get_retired().is_empty());
     }
 }
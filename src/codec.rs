@@ -0,0 +1,327 @@
+//! Framing of the peer-to-peer wire protocol
+//!
+//! Every `Message` exchanged between two sites is serialized with MessagePack and
+//! sent as a single frame: a 4-byte big-endian length header followed by the payload.
+//! This lets the reader rebuild messages that were split across several TCP segments
+//! or merged into a single one, and reject frames that are too large or corrupted.
+
+#[cfg(feature = "server")]
+/// Size in bytes of the length header placed before each frame
+pub const FRAME_HEADER_SIZE: usize = 4;
+
+/// Default maximum size of a frame payload (8 MiB)
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 8 * 1024 * 1024;
+
+#[cfg(feature = "server")]
+/// Errors raised while encoding or decoding a frame
+#[derive(Debug)]
+pub enum FrameError {
+    /// The payload is bigger than the configured maximum frame size
+    TooLarge { size: usize, max: usize },
+    /// The connection was closed in the middle of a frame
+    Truncated { expected: usize, received: usize },
+    /// The payload could not be serialized
+    Encode(rmp_serde::encode::Error),
+    /// The payload is not a valid message
+    Decode(rmp_serde::decode::Error),
+    /// Underlying IO error
+    Io(std::io::Error),
+}
+
+#[cfg(feature = "server")]
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::TooLarge { size, max } => {
+                write!(
+                    f,
+                    "frame of {} bytes exceeds the maximum of {} bytes",
+                    size, max
+                )
+            }
+            FrameError::Truncated { expected, received } => write!(
+                f,
+                "connection closed after {} of {} bytes of a frame",
+                received, expected
+            ),
+            FrameError::Encode(e) => write!(f, "unable to encode frame: {}", e),
+            FrameError::Decode(e) => write!(f, "corrupt frame: {}", e),
+            FrameError::Io(e) => write!(f, "io error on frame: {}", e),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl std::error::Error for FrameError {}
+
+#[cfg(feature = "server")]
+impl From<std::io::Error> for FrameError {
+    fn from(e: std::io::Error) -> Self {
+        FrameError::Io(e)
+    }
+}
+
+#[cfg(feature = "server")]
+/// Length-prefixed codec used on every peer connection
+#[derive(Debug, Clone, Copy)]
+pub struct FrameCodec {
+    /// Maximum accepted size of a frame payload, in bytes
+    max_frame_size: usize,
+}
+
+#[cfg(feature = "server")]
+impl Default for FrameCodec {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_FRAME_SIZE)
+    }
+}
+
+#[cfg(feature = "server")]
+impl FrameCodec {
+    /// Creates a codec accepting payloads up to `max_frame_size` bytes
+    pub fn new(max_frame_size: usize) -> Self {
+        Self {
+            max_frame_size: max_frame_size.min(u32::MAX as usize),
+        }
+    }
+
+    /// Checks that a payload of `size` bytes fits in a frame
+    fn check_size(&self, size: usize) -> Result<(), FrameError> {
+        if size > self.max_frame_size {
+            return Err(FrameError::TooLarge {
+                size,
+                max: self.max_frame_size,
+            });
+        }
+        Ok(())
+    }
+
+    /// Serializes a message into a frame payload
+    pub fn encode(&self, msg: &crate::message::Message) -> Result<Vec<u8>, FrameError> {
+        let payload = rmp_serde::encode::to_vec(msg).map_err(FrameError::Encode)?;
+        self.check_size(payload.len())?;
+        Ok(payload)
+    }
+
+    /// Deserializes a frame payload into a message
+    pub fn decode(&self, payload: &[u8]) -> Result<crate::message::Message, FrameError> {
+        rmp_serde::decode::from_slice(payload).map_err(FrameError::Decode)
+    }
+
+    /// Writes a payload as a single frame
+    pub async fn write_frame<W>(&self, writer: &mut W, payload: &[u8]) -> Result<(), FrameError>
+    where
+        W: tokio::io::AsyncWrite + Unpin,
+    {
+        use tokio::io::AsyncWriteExt;
+
+        self.check_size(payload.len())?;
+        let header = (payload.len() as u32).to_be_bytes();
+        writer.write_all(&header).await?;
+        writer.write_all(payload).await?;
+        writer.flush().await?;
+        Ok(())
+    }
+
+    /// Reads the next frame payload
+    ///
+    /// Returns `Ok(None)` if the connection was closed cleanly between two frames
+    pub async fn read_frame<R>(&self, reader: &mut R) -> Result<Option<Vec<u8>>, FrameError>
+    where
+        R: tokio::io::AsyncRead + Unpin,
+    {
+        let mut header = [0u8; FRAME_HEADER_SIZE];
+        let received = read_full(reader, &mut header).await?;
+        if received == 0 {
+            return Ok(None);
+        }
+        if received < FRAME_HEADER_SIZE {
+            return Err(FrameError::Truncated {
+                expected: FRAME_HEADER_SIZE,
+                received,
+            });
+        }
+
+        let size = u32::from_be_bytes(header) as usize;
+        self.check_size(size)?;
+
+        let mut payload = vec![0u8; size];
+        let received = read_full(reader, &mut payload).await?;
+        if received < size {
+            return Err(FrameError::Truncated {
+                expected: size,
+                received,
+            });
+        }
+        Ok(Some(payload))
+    }
+}
+
+#[cfg(feature = "server")]
+/// Fills `buf` from the reader, stopping early only at the end of the stream
+///
+/// Returns the number of bytes actually read
+async fn read_full<R>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    use tokio::io::AsyncReadExt;
+
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = reader.read(&mut buf[filled..]).await?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+#[cfg(feature = "server")]
+mod tests {
+    use super::*;
+    use crate::message::{Message, MessageInfo, NetworkMessageCode};
+
+    fn mk_message(code: NetworkMessageCode) -> Message {
+        Message {
+            sender_id: "A".to_string(),
+            sender_addr: "127.0.0.1:8080".parse().unwrap(),
+            message_initiator_id: "A".to_string(),
+            message_initiator_addr: "127.0.0.1:8080".parse().unwrap(),
+            clock: crate::clock::Clock::new(),
+            command: None,
+            info: MessageInfo::None,
+            code,
+            signature: None,
+            wave_id: None,
+            causal_deps: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn frame_round_trip() {
+        let codec = FrameCodec::default();
+        let payload = codec
+            .encode(&mk_message(NetworkMessageCode::Discovery))
+            .unwrap();
+
+        let mut wire = Vec::new();
+        codec.write_frame(&mut wire, &payload).await.unwrap();
+        assert_eq!(wire.len(), FRAME_HEADER_SIZE + payload.len());
+
+        let mut reader = wire.as_slice();
+        let frame = codec.read_frame(&mut reader).await.unwrap().unwrap();
+        let msg = codec.decode(&frame).unwrap();
+        assert_eq!(msg.code, NetworkMessageCode::Discovery);
+        assert!(codec.read_frame(&mut reader).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn two_frames_in_one_segment() {
+        let codec = FrameCodec::default();
+        let mut wire = Vec::new();
+        for code in [
+            NetworkMessageCode::Discovery,
+            NetworkMessageCode::Disconnect,
+        ] {
+            let payload = codec.encode(&mk_message(code)).unwrap();
+            codec.write_frame(&mut wire, &payload).await.unwrap();
+        }
+
+        let mut reader = wire.as_slice();
+        let first = codec.read_frame(&mut reader).await.unwrap().unwrap();
+        let second = codec.read_frame(&mut reader).await.unwrap().unwrap();
+        assert_eq!(
+            codec.decode(&first).unwrap().code,
+            NetworkMessageCode::Discovery
+        );
+        assert_eq!(
+            codec.decode(&second).unwrap().code,
+            NetworkMessageCode::Disconnect
+        );
+    }
+
+    #[tokio::test]
+    async fn large_message_is_not_cut() {
+        let codec = FrameCodec::default();
+        let mut msg = mk_message(NetworkMessageCode::SnapshotResponse);
+        let tx_log = (0..200)
+            .map(|i| crate::snapshot::TxSummary {
+                lamport_time: i,
+                source_node: "A".into(),
+                from_user: "user1".into(),
+                to_user: "user2".into(),
+                amount: crate::money::Money::from_cents(100),
+                hlc: Default::default(),
+            })
+            .collect();
+        msg.info = MessageInfo::SnapshotResponse(vec![crate::message::SnapshotResponse {
+            site_id: "A".into(),
+            clock: crate::clock::Clock::new(),
+            tx_log,
+            signature: None,
+        }]);
+
+        let payload = codec.encode(&msg).unwrap();
+        assert!(payload.len() > 1024);
+
+        let mut wire = Vec::new();
+        codec.write_frame(&mut wire, &payload).await.unwrap();
+        let frame = codec
+            .read_frame(&mut wire.as_slice())
+            .await
+            .unwrap()
+            .unwrap();
+        match codec.decode(&frame).unwrap().info {
+            MessageInfo::SnapshotResponse(resp) => assert_eq!(resp[0].tx_log.len(), 200),
+            other => panic!("unexpected payload {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_is_rejected() {
+        let codec = FrameCodec::new(16);
+        assert!(matches!(
+            codec.encode(&mk_message(NetworkMessageCode::Discovery)),
+            Err(FrameError::TooLarge { max: 16, .. })
+        ));
+
+        let mut wire = (1024u32).to_be_bytes().to_vec();
+        wire.extend(vec![0u8; 1024]);
+        assert!(matches!(
+            codec.read_frame(&mut wire.as_slice()).await,
+            Err(FrameError::TooLarge {
+                size: 1024,
+                max: 16
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn truncated_and_corrupt_frames_are_rejected() {
+        let codec = FrameCodec::default();
+
+        let mut wire = (10u32).to_be_bytes().to_vec();
+        wire.extend([1, 2, 3]);
+        assert!(matches!(
+            codec.read_frame(&mut wire.as_slice()).await,
+            Err(FrameError::Truncated {
+                expected: 10,
+                received: 3
+            })
+        ));
+
+        let wire = [0u8, 0];
+        assert!(matches!(
+            codec.read_frame(&mut wire.as_slice()).await,
+            Err(FrameError::Truncated { .. })
+        ));
+
+        assert!(matches!(
+            codec.decode(&[0xc1, 0xff]),
+            Err(FrameError::Decode(_))
+        ));
+    }
+}
@@ -4,9 +4,9 @@
 //! for the Peillute application, including both local and network command processing.
 
 #![cfg(feature = "server")]
-/// Worker that handles critical commands
-pub fn control_worker() {
-    tokio::spawn(async {
+/// Worker that handles critical commands, it writes their transactions to `store`
+pub fn control_worker(store: std::sync::Arc<dyn crate::store::LedgerStore>) {
+    crate::node::spawn(async move {
         use crate::state::LOCAL_APP_STATE;
 
         loop {
@@ -21,34 +21,77 @@ pub fn control_worker() {
 
             // Vider la file de tsx en attente
             {
-                let (in_st, waiting, nb_pending) = {
+                let (in_st, waiting, nb_pending, site_id) = {
                     let st = LOCAL_APP_STATE.lock().await;
-                    (st.in_sc, st.waiting_sc, st.pending_commands.len())
+                    (
+                        st.in_sc,
+                        st.waiting_sc,
+                        st.pending_commands.len(),
+                        st.get_site_id(),
+                    )
                 };
 
                 if !waiting && nb_pending > 0 && !in_st {
                     let mut st = LOCAL_APP_STATE.lock().await;
-                    let _ = st.acquire_mutex().await;
+                    let scope = pending_scope(&store, &st.pending_commands).await;
+                    let _ = st.acquire_mutex(scope).await;
                     continue;
                 }
 
                 if in_st && nb_pending > 0 {
                     log::info!("Début de la section critique");
+                    // Transactions of the section, replicated together when it ends
+                    let mut batch = Batch::new(site_id);
                     loop {
                         let cmd_opt = {
                             let mut st = LOCAL_APP_STATE.lock().await;
-                            st.pending_commands.pop_front()
+                            let scope = st.sc_scope.clone();
+                            if st.leases.lease_lost(tokio::time::Instant::now()) {
+                                // The other sites may have given the accounts to another site
+                                log::warn!("Le bail de la section critique a expiré");
+                                None
+                            } else {
+                                next_command(&store, &mut st.pending_commands, &scope).await
+                            }
                         };
-                        if let Some(cmd) = cmd_opt {
+                        if let Some((PendingCommand { cmd, receipt }, scope)) = cmd_opt {
                             log::info!("Execute critical command");
-                            if let Err(e) = crate::control::execute_critical(cmd).await {
-                                log::error!("Erreur exécution commande critique : {}", e);
+                            wait_for_held_transactions(&store, &scope).await;
+                            if matches!(
+                                cmd,
+                                CriticalCommands::FileSnapshot | CriticalCommands::SyncSnapshot
+                            ) {
+                                // Snapshots include the earlier transactions of the section
+                                batch.flush().await;
+                            }
+                            match crate::control::execute_critical(&store, cmd).await {
+                                Ok(Some(transaction)) => batch.push(transaction, receipt),
+                                Ok(None) => {
+                                    let _ = receipt.send(Ok(None));
+                                }
+                                Err(e) => {
+                                    log::error!("Erreur exécution commande critique : {}", e);
+                                    let _ = receipt.send(Err(CommandError::Rejected(e)));
+                                }
                             }
                         } else {
                             break;
                         }
                     }
+                    batch.flush().await;
                     log::info!("Fin de la section critique");
+                    // Every wave of the section is complete, the other sites can have the mutex
+                    let mut st = LOCAL_APP_STATE.lock().await;
+                    if let Err(e) = st.release_mutex().await {
+                        log::error!("Erreur lors du relachement du mutex : {}", e);
+                    }
+                    // Commands on other accounts, queued during the section
+                    if !st.pending_commands.is_empty() {
+                        let scope = pending_scope(&store, &st.pending_commands).await;
+                        if let Err(e) = st.acquire_mutex(scope).await {
+                            log::error!("Erreur lors de l'acquisition du mutex : {}", e);
+                        }
+                    }
                 }
             }
         }
@@ -60,24 +103,21 @@ pub fn control_worker() {
 pub fn parse_command(line: Result<Option<String>, std::io::Error>) -> Command {
     use log;
     match line {
-        Ok(Some(cmd)) => {
-            let command = match cmd.trim() {
-                "/create_user" => Command::CreateUser,
-                "/user_accounts" => Command::UserAccounts,
-                "/print_user_tsx" => Command::PrintUserTransactions,
-                "/print_tsx" => Command::PrintTransactions,
-                "/deposit" => Command::Deposit,
-                "/withdraw" => Command::Withdraw,
-                "/transfer" => Command::Transfer,
-                "/pay" => Command::Pay,
-                "/refund" => Command::Refund,
-                "/help" => Command::Help,
-                "/info" => Command::Info,
-                "/start_snapshot" => Command::Snapshot,
-                other => Command::Unknown(other.to_string()),
-            };
-            command
-        }
+        Ok(Some(cmd)) => match cmd.trim() {
+            "/create_user" => Command::CreateUser,
+            "/user_accounts" => Command::UserAccounts,
+            "/print_user_tsx" => Command::PrintUserTransactions,
+            "/print_tsx" => Command::PrintTransactions,
+            "/deposit" => Command::Deposit,
+            "/withdraw" => Command::Withdraw,
+            "/transfer" => Command::Transfer,
+            "/pay" => Command::Pay,
+            "/refund" => Command::Refund,
+            "/help" => Command::Help,
+            "/info" => Command::Info,
+            "/start_snapshot" => Command::Snapshot,
+            other => Command::Unknown(other.to_string()),
+        },
         Ok(None) => {
             println!("Aucun input");
             Command::Unknown("Aucun input".to_string())
@@ -130,17 +170,26 @@ pub enum CriticalCommands {
     /// Create a new user account
     CreateUser { name: String },
     /// Deposit money into an account
-    Deposit { name: String, amount: f64 },
+    Deposit {
+        name: String,
+        amount: crate::money::Money,
+    },
     /// Withdraw money from an account
-    Withdraw { name: String, amount: f64 },
+    Withdraw {
+        name: String,
+        amount: crate::money::Money,
+    },
     /// Transfer money between accounts
     Transfer {
         from: String,
         to: String,
-        amount: f64,
+        amount: crate::money::Money,
     },
     /// Make a payment
-    Pay { name: String, amount: f64 },
+    Pay {
+        name: String,
+        amount: crate::money::Money,
+    },
     /// Process a refund
     Refund {
         name: String,
@@ -153,13 +202,270 @@ pub enum CriticalCommands {
     SyncSnapshot,
 }
 
+#[cfg(feature = "server")]
+impl CriticalCommands {
+    /// Returns the accounts the command has to lock
+    ///
+    /// The snapshots read the whole ledger and lock every account
+    pub fn lock_scope(&self, store: &dyn crate::store::LedgerStore) -> crate::mutex::LockScope {
+        use crate::mutex::LockScope;
+
+        match self {
+            CriticalCommands::CreateUser { name }
+            | CriticalCommands::Deposit { name, .. }
+            | CriticalCommands::Withdraw { name, .. }
+            | CriticalCommands::Pay { name, .. } => LockScope::accounts([name.as_str()]),
+            CriticalCommands::Transfer { from, to, .. } => {
+                LockScope::accounts([from.as_str(), to.as_str()])
+            }
+            CriticalCommands::Refund { lamport, node, .. } => refund_scope(store, *lamport, node),
+            CriticalCommands::FileSnapshot | CriticalCommands::SyncSnapshot => LockScope::All,
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+/// Reason why a critical command did not complete
+#[derive(Debug)]
+pub enum CommandError {
+    /// The command failed on our site and was not diffused, e.g. for insufficient funds
+    Rejected(crate::error::PeillutError),
+    /// The command was applied on our site but its diffusion failed
+    NotReplicated(String),
+    /// The site stopped before running the command
+    Dropped,
+}
+
+#[cfg(feature = "server")]
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandError::Rejected(e) => write!(f, "{}", e),
+            CommandError::NotReplicated(reason) => {
+                write!(
+                    f,
+                    "Command applied on this site but not diffused: {}",
+                    reason
+                )
+            }
+            CommandError::Dropped => write!(f, "Command dropped before it ran"),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl std::error::Error for CommandError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CommandError::Rejected(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+/// Final result of a critical command, with the key of the transaction it made if any
+pub type CommandOutcome = Result<Option<crate::db::TransactionKey>, CommandError>;
+
+#[cfg(feature = "server")]
+/// Handle on a queued critical command
+pub struct Receipt(tokio::sync::oneshot::Receiver<CommandOutcome>);
+
+#[cfg(feature = "server")]
+impl Receipt {
+    /// Waits until the command is applied on our site and diffused to the others
+    pub async fn outcome(self) -> CommandOutcome {
+        self.0.await.unwrap_or(Err(CommandError::Dropped))
+    }
+}
+
+#[cfg(feature = "server")]
+/// Critical command waiting for the mutex
+pub struct PendingCommand {
+    /// Command to run
+    pub cmd: CriticalCommands,
+    /// Resolves the receipt of the command
+    pub receipt: tokio::sync::oneshot::Sender<CommandOutcome>,
+}
+
+#[cfg(feature = "server")]
+/// Returns the accounts of the parties of the refunded transaction
+///
+/// Every account is locked if the transaction is unknown
+fn refund_scope(
+    store: &dyn crate::store::LedgerStore,
+    lamport: i64,
+    node: &str,
+) -> crate::mutex::LockScope {
+    use crate::mutex::LockScope;
+
+    match store.transaction(lamport, node) {
+        Ok(Some(tx)) => LockScope::accounts(
+            [tx.from_user, tx.to_user]
+                .into_iter()
+                .filter(|name| name != super::db::NULL),
+        ),
+        _ => LockScope::All,
+    }
+}
+
+#[cfg(feature = "server")]
+/// Returns the accounts touched by a replicated transaction, None for the other messages
+fn transaction_scope(
+    store: &dyn crate::store::LedgerStore,
+    info: &crate::message::MessageInfo,
+) -> Option<crate::mutex::LockScope> {
+    use crate::message::MessageInfo;
+    use crate::mutex::LockScope;
+
+    match info {
+        MessageInfo::CreateUser(create) => Some(LockScope::accounts([create.name.as_str()])),
+        MessageInfo::Deposit(deposit) => Some(LockScope::accounts([deposit.name.as_str()])),
+        MessageInfo::Withdraw(withdraw) => Some(LockScope::accounts([withdraw.name.as_str()])),
+        MessageInfo::Pay(pay) => Some(LockScope::accounts([pay.name.as_str()])),
+        MessageInfo::Transfer(transfer) => Some(LockScope::accounts([
+            transfer.name.as_str(),
+            transfer.beneficiary.as_str(),
+        ])),
+        MessageInfo::Refund(refund) => Some(refund_scope(
+            store,
+            refund.transac_time,
+            &refund.transac_node,
+        )),
+        MessageInfo::Batch(batch) => batch
+            .iter()
+            .filter_map(|tx| transaction_scope(store, &tx.info))
+            .reduce(LockScope::union),
+        _ => None,
+    }
+}
+
+#[cfg(feature = "server")]
+/// Returns the accounts each pending command has to lock, in order
+///
+/// The refunds read their transaction from the ledger, on the blocking thread pool
+async fn command_scopes(
+    store: &std::sync::Arc<dyn crate::store::LedgerStore>,
+    pending: &std::collections::VecDeque<PendingCommand>,
+) -> Vec<crate::mutex::LockScope> {
+    let cmds: Vec<CriticalCommands> = pending.iter().map(|pending| pending.cmd.clone()).collect();
+    crate::store::blocking(store, move |store| {
+        cmds.iter().map(|cmd| cmd.lock_scope(store)).collect()
+    })
+    .await
+}
+
+#[cfg(feature = "server")]
+/// Returns the accounts locked by the pending commands together
+async fn pending_scope(
+    store: &std::sync::Arc<dyn crate::store::LedgerStore>,
+    pending: &std::collections::VecDeque<PendingCommand>,
+) -> crate::mutex::LockScope {
+    command_scopes(store, pending).await.into_iter().fold(
+        crate::mutex::LockScope::default(),
+        crate::mutex::LockScope::union,
+    )
+}
+
+#[cfg(feature = "server")]
+/// Takes the first pending command the critical section is granted for, with its scope
+///
+/// A command never overtakes an older pending command sharing one of its accounts
+async fn next_command(
+    store: &std::sync::Arc<dyn crate::store::LedgerStore>,
+    pending: &mut std::collections::VecDeque<PendingCommand>,
+    granted: &crate::mutex::LockScope,
+) -> Option<(PendingCommand, crate::mutex::LockScope)> {
+    let mut skipped = crate::mutex::LockScope::default();
+    for (i, scope) in command_scopes(store, pending).await.into_iter().enumerate() {
+        if granted.covers(&scope) && !skipped.conflicts_with(&scope) {
+            return pending.remove(i).map(|cmd| (cmd, scope));
+        }
+        skipped = skipped.union(scope);
+    }
+    None
+}
+
+#[cfg(feature = "server")]
+/// Waits until the causal buffer holds back no transaction on the accounts of `scope`
+///
+/// Such a transaction was made in an earlier critical section on the same accounts, the
+/// balance of the command must include it. Critical sections on other accounts run
+/// meanwhile, so it may wait for one of their transactions.
+async fn wait_for_held_transactions(
+    store: &std::sync::Arc<dyn crate::store::LedgerStore>,
+    scope: &crate::mutex::LockScope,
+) {
+    loop {
+        let held: Vec<crate::message::MessageInfo> = {
+            let state = crate::state::LOCAL_APP_STATE.lock().await;
+            state
+                .causal
+                .get_held()
+                .iter()
+                .map(|msg| msg.info.clone())
+                .collect()
+        };
+        if held.is_empty() {
+            return;
+        }
+        let scope = scope.clone();
+        let conflicting = crate::store::blocking(store, move |store| {
+            held.iter().any(|info| {
+                transaction_scope(store, info).is_some_and(|held| held.conflicts_with(&scope))
+            })
+        })
+        .await;
+        if !conflicting {
+            return;
+        }
+        log::debug!("Transaction on the same accounts held back, waiting");
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+}
+
 #[cfg(feature = "server")]
 /// Enqueue a critical command
-pub async fn enqueue_critical(cmd: CriticalCommands) -> Result<(), Box<dyn std::error::Error>> {
-    use crate::state::LOCAL_APP_STATE;
-    let mut st = LOCAL_APP_STATE.lock().await;
+///
+/// Returns the receipt of the command, resolved once it is applied and diffused
+pub async fn enqueue_critical(
+    store: &std::sync::Arc<dyn crate::store::LedgerStore>,
+    cmd: CriticalCommands,
+) -> Result<Receipt, Box<dyn std::error::Error>> {
+    let mut st = crate::state::LOCAL_APP_STATE.lock().await;
+    push_critical(&mut st, store, cmd).await
+}
+
+#[cfg(feature = "server")]
+/// Enqueue a synchronization with the network, unless one is already pending
+///
+/// Returns None if a synchronization was already pending
+pub async fn enqueue_sync(
+    store: &std::sync::Arc<dyn crate::store::LedgerStore>,
+) -> Result<Option<Receipt>, Box<dyn std::error::Error>> {
+    let mut st = crate::state::LOCAL_APP_STATE.lock().await;
+    if st
+        .pending_commands
+        .iter()
+        .any(|pending| pending.cmd == CriticalCommands::SyncSnapshot)
+    {
+        return Ok(None);
+    }
+    push_critical(&mut st, store, CriticalCommands::SyncSnapshot)
+        .await
+        .map(Some)
+}
 
-    st.pending_commands.push_back(cmd);
+#[cfg(feature = "server")]
+/// Queues `cmd` and asks for the critical section if the site does not wait for it yet
+async fn push_critical(
+    st: &mut crate::state::AppState,
+    store: &std::sync::Arc<dyn crate::store::LedgerStore>,
+    cmd: CriticalCommands,
+) -> Result<Receipt, Box<dyn std::error::Error>> {
+    let (receipt, done) = tokio::sync::oneshot::channel();
+    st.pending_commands
+        .push_back(PendingCommand { cmd, receipt });
 
     // si on n’est ni en SC ni déjà en attente → on déclenche la vague
 
@@ -167,136 +473,122 @@ pub async fn enqueue_critical(cmd: CriticalCommands) -> Result<(), Box<dyn std::
     log::debug!("is waiting {}", !st.waiting_sc);
 
     if !st.in_sc && !st.waiting_sc {
-        st.acquire_mutex().await?;
+        let scope = pending_scope(store, &st.pending_commands).await;
+        st.acquire_mutex(scope).await?;
     }
-    Ok(())
+    Ok(Receipt(done))
 }
 
 #[cfg(feature = "server")]
 /// Execute a critical command on our site
 ///
-/// Called by the control worker only when the Mutex is acquired
-pub async fn execute_critical(cmd: CriticalCommands) -> Result<(), Box<dyn std::error::Error>> {
-    use crate::message::{Message, MessageInfo, NetworkMessageCode};
-    use crate::network::diffuse_message;
+/// Called by the control worker only when the Mutex is acquired. Returns the transaction
+/// to replicate with the other transactions of the critical section, None for the
+/// snapshots which run their own wave.
+pub async fn execute_critical(
+    store: &std::sync::Arc<dyn crate::store::LedgerStore>,
+    cmd: CriticalCommands,
+) -> Result<Option<crate::message::BatchedTransaction>, crate::error::PeillutError> {
+    use crate::message::BatchedTransaction;
+    use crate::snapshot::SnapshotMode;
     use crate::state::LOCAL_APP_STATE;
 
-    let (clock, site_addr, site_id) = {
+    // The clock is taken under the lock, the ledger is written without it
+    let (site_id, clock) = {
         let mut state = LOCAL_APP_STATE.lock().await;
-        let local_addr = state.get_site_addr();
-        let node = state.get_site_id();
-        let _ = state.update_clock(None);
-        let clock = state.get_clock();
-        (clock, local_addr, node)
+        state.update_clock(None).await;
+        (state.get_site_id(), state.get_clock())
     };
 
-    let msg;
+    let mode = match cmd {
+        CriticalCommands::FileSnapshot => SnapshotMode::FileMode,
+        CriticalCommands::SyncSnapshot => SnapshotMode::SyncMode,
+        cmd => {
+            let stamp = clock.clone();
+            let info = crate::store::blocking(store, move |store| {
+                apply_command(store, cmd, &site_id, &stamp)
+            })
+            .await?;
+            return Ok(Some(BatchedTransaction { info, clock }));
+        }
+    };
+    snapshot(store, mode, clock)
+        .await
+        .map_err(|e| crate::error::PeillutError::Snapshot(e.to_string()))?;
+    Ok(None)
+}
 
-    match cmd {
+#[cfg(feature = "server")]
+/// Applies a command of our site dated by `clock` to the ledger, returning what to replicate
+fn apply_command(
+    store: &dyn crate::store::LedgerStore,
+    cmd: CriticalCommands,
+    site_id: &str,
+    clock: &crate::clock::Clock,
+) -> Result<crate::message::MessageInfo, crate::error::PeillutError> {
+    use crate::message::MessageInfo;
+
+    let info = match cmd {
         CriticalCommands::CreateUser { name } => {
             use crate::message::CreateUser;
             if name.is_empty() {
-                log::warn!("Skipping CreateUser command with empty username");
-                return Ok(());
+                return Err(crate::error::PeillutError::EmptyUserName);
             }
-            super::db::create_user(&name)?;
-            msg = Message {
-                command: Some(Command::CreateUser),
-                info: MessageInfo::CreateUser(CreateUser::new(name)),
-                code: NetworkMessageCode::Transaction,
-                clock: clock,
-                sender_addr: site_addr,
-                sender_id: site_id.to_string(),
-                message_initiator_id: site_id.to_string(),
-                message_initiator_addr: site_addr,
-            };
+            store.create_user(&name)?;
+            MessageInfo::CreateUser(CreateUser::new(name))
         }
         CriticalCommands::Deposit { name, amount } => {
             use crate::message::Deposit;
 
-            super::db::deposit(
+            store.deposit(
                 &name,
                 amount,
                 clock.get_lamport(),
-                site_id.as_str(),
+                &clock.get_hlc(),
+                site_id,
                 clock.get_vector_clock_map(),
             )?;
-
-            msg = Message {
-                command: Some(Command::Deposit),
-                info: MessageInfo::Deposit(Deposit::new(name, amount)),
-                code: NetworkMessageCode::Transaction,
-                clock: clock,
-                sender_addr: site_addr,
-                sender_id: site_id.to_string(),
-                message_initiator_id: site_id.to_string(),
-                message_initiator_addr: site_addr,
-            };
+            MessageInfo::Deposit(Deposit::new(name, amount))
         }
         CriticalCommands::Withdraw { name, amount } => {
             use crate::message::Withdraw;
-            super::db::withdraw(
+            store.withdraw(
                 &name,
                 amount,
                 clock.get_lamport(),
-                site_id.as_str(),
+                &clock.get_hlc(),
+                site_id,
                 clock.get_vector_clock_map(),
             )?;
-
-            msg = Message {
-                command: Some(Command::Withdraw),
-                info: MessageInfo::Withdraw(Withdraw::new(name, amount)),
-                code: NetworkMessageCode::Transaction,
-                clock: clock,
-                sender_addr: site_addr,
-                sender_id: site_id.to_string(),
-                message_initiator_id: site_id.to_string(),
-                message_initiator_addr: site_addr,
-            };
+            MessageInfo::Withdraw(Withdraw::new(name, amount))
         }
         CriticalCommands::Transfer { from, to, amount } => {
             use crate::message::Transfer;
-            super::db::create_transaction(
+            store.create_transaction(
                 &from,
                 &to,
                 amount,
                 clock.get_lamport(),
-                site_id.as_str(),
+                &clock.get_hlc(),
+                site_id,
                 "",
                 clock.get_vector_clock_map(),
             )?;
-            msg = Message {
-                command: Some(Command::Transfer),
-                info: MessageInfo::Transfer(Transfer::new(from.clone(), to.clone(), amount)),
-                code: NetworkMessageCode::Transaction,
-                clock: clock,
-                sender_addr: site_addr,
-                sender_id: site_id.to_string(),
-                message_initiator_id: site_id.to_string(),
-                message_initiator_addr: site_addr,
-            };
+            MessageInfo::Transfer(Transfer::new(from.clone(), to.clone(), amount))
         }
         CriticalCommands::Pay { name, amount } => {
             use crate::message::Pay;
-            super::db::create_transaction(
+            store.create_transaction(
                 &name,
                 "NULL",
                 amount,
                 clock.get_lamport(),
-                site_id.as_str(),
+                &clock.get_hlc(),
+                site_id,
                 "",
                 clock.get_vector_clock_map(),
             )?;
-            msg = Message {
-                command: Some(Command::Pay),
-                info: MessageInfo::Pay(Pay::new(name, amount)),
-                code: NetworkMessageCode::Transaction,
-                clock: clock,
-                sender_addr: site_addr,
-                sender_id: site_id.to_string(),
-                message_initiator_id: site_id.to_string(),
-                message_initiator_addr: site_addr,
-            };
+            MessageInfo::Pay(Pay::new(name, amount))
         }
         CriticalCommands::Refund {
             name,
@@ -304,82 +596,203 @@ pub async fn execute_critical(cmd: CriticalCommands) -> Result<(), Box<dyn std::
             node,
         } => {
             use crate::message::Refund;
-            super::db::refund_transaction(
+            store.refund_transaction(
                 lamport,
-                &node.as_str(),
+                node.as_str(),
                 clock.get_lamport(),
-                site_id.as_str(),
+                &clock.get_hlc(),
+                site_id,
                 clock.get_vector_clock_map(),
             )?;
-            msg = Message {
-                command: Some(Command::Refund),
-                info: MessageInfo::Refund(Refund::new(name, lamport, node)),
-                code: NetworkMessageCode::Transaction,
-                clock: clock,
-                sender_addr: site_addr,
-                sender_id: site_id.to_string(),
-                message_initiator_id: site_id.to_string(),
-                message_initiator_addr: site_addr,
-            };
+            MessageInfo::Refund(Refund::new(name, lamport, node))
         }
-        CriticalCommands::FileSnapshot => {
-            use crate::snapshot;
-            snapshot::start_snapshot(snapshot::SnapshotMode::FileMode).await?;
-
-            msg = Message {
-                command: None,
-                code: NetworkMessageCode::SnapshotRequest,
-                info: MessageInfo::None,
-                sender_addr: site_addr,
-                sender_id: site_id.to_string(),
-                message_initiator_id: site_id.to_string(),
-                message_initiator_addr: site_addr,
-                clock: clock.clone(),
-            };
+        CriticalCommands::FileSnapshot | CriticalCommands::SyncSnapshot => {
+            unreachable!("the snapshots run their own wave")
         }
-        CriticalCommands::SyncSnapshot => {
-            use crate::snapshot;
-            snapshot::start_snapshot(snapshot::SnapshotMode::SyncMode).await?;
-
-            msg = Message {
-                command: None,
-                code: NetworkMessageCode::SnapshotRequest,
-                info: MessageInfo::None,
-                sender_addr: site_addr,
-                sender_id: site_id.to_string(),
-                message_initiator_id: site_id.to_string(),
-                message_initiator_addr: site_addr,
-                clock: clock.clone(),
-            };
+    };
+    Ok(info)
+}
+
+#[cfg(feature = "server")]
+/// Collects the local snapshot of every site
+async fn snapshot(
+    store: &std::sync::Arc<dyn crate::store::LedgerStore>,
+    mode: crate::snapshot::SnapshotMode,
+    clock: crate::clock::Clock,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use crate::message::{Message, MessageInfo, NetworkMessageCode};
+
+    let own = crate::snapshot::local_snapshot(store).await?;
+    let msg = {
+        let mut state = crate::state::LOCAL_APP_STATE.lock().await;
+        let site_addr = state.get_site_addr();
+        Message {
+            command: None,
+            code: NetworkMessageCode::SnapshotRequest,
+            info: MessageInfo::None,
+            sender_addr: site_addr,
+            sender_id: state.get_site_id(),
+            message_initiator_id: state.get_site_id(),
+            message_initiator_addr: site_addr,
+            clock,
+            signature: None,
+            wave_id: Some(state.next_wave_id()),
+            causal_deps: None,
         }
+    };
+
+    // The snapshot is complete once every site echoed
+    let done = crate::network::wave::start(msg).await?;
+    let echo = done.await?;
+    println!("\x1b[1;31mDiffusion terminée et réussie !\x1b[0m");
+
+    let mut snapshots = vec![own];
+    if let MessageInfo::SnapshotResponse(others) = echo {
+        snapshots.extend(others);
     }
+    crate::snapshot::complete_snapshot(store, mode, snapshots).await
+}
 
-    let should_diffuse = {
-        // initialisation des paramètres avant la diffusion d'un message
-        let mut state = LOCAL_APP_STATE.lock().await;
-        let nb_neigh = state.get_nb_connected_neighbours();
-        state.set_parent_addr(site_id.to_string(), site_addr);
-        state.set_nb_nei_for_wave(site_id.to_string(), nb_neigh);
-        nb_neigh > 0
-    };
+#[cfg(feature = "server")]
+/// Diffuses the transactions of a critical section to every site in one wave
+async fn replicate(
+    batch: Vec<crate::message::BatchedTransaction>,
+) -> Result<(), crate::error::PeillutError> {
+    use crate::error::PeillutError;
+    use crate::message::{Message, MessageInfo, NetworkMessageCode};
 
-    if should_diffuse {
-        diffuse_message(&msg).await?;
-    } else {
-        // pas release depuis le réseau si on est tout seul
-        // on doit relacher le mutex directement
-        let mut state = LOCAL_APP_STATE.lock().await;
-        let _ = state.release_mutex().await;
+    let Some(last) = batch.last() else {
+        return Ok(());
+    };
+    let clock = last.clock.clone();
+
+    let msg = {
+        let mut state = crate::state::LOCAL_APP_STATE.lock().await;
+        let site_addr = state.get_site_addr();
+        let site_id = state.get_site_id();
+        Message {
+            command: None,
+            info: MessageInfo::Batch(batch),
+            code: NetworkMessageCode::Transaction,
+            clock,
+            sender_addr: site_addr,
+            sender_id: site_id.clone(),
+            message_initiator_id: site_id.clone(),
+            message_initiator_addr: site_addr,
+            signature: None,
+            wave_id: Some(state.next_wave_id()),
+            causal_deps: Some(state.causal.stamp(&site_id)),
+        }
     };
+
+    // The transactions are replicated once every site echoed
+    let done = crate::network::wave::start(msg).await?;
+    done.await
+        .map_err(|_| PeillutError::Network("the wave was abandoned".to_string()))?;
+    println!("\x1b[1;31mDiffusion terminée et réussie !\x1b[0m");
     Ok(())
 }
 
+#[cfg(feature = "server")]
+/// Transactions made by our site in a critical section, replicated together
+struct Batch {
+    /// ID of our site
+    site_id: String,
+    transactions: Vec<crate::message::BatchedTransaction>,
+    /// Receipts of the commands of the transactions, with the key of their transaction
+    receipts: Vec<(
+        tokio::sync::oneshot::Sender<CommandOutcome>,
+        Option<crate::db::TransactionKey>,
+    )>,
+}
+
+#[cfg(feature = "server")]
+impl Batch {
+    fn new(site_id: String) -> Self {
+        Self {
+            site_id,
+            transactions: Vec::new(),
+            receipts: Vec::new(),
+        }
+    }
+
+    /// Adds a transaction applied on our site
+    fn push(
+        &mut self,
+        transaction: crate::message::BatchedTransaction,
+        receipt: tokio::sync::oneshot::Sender<CommandOutcome>,
+    ) {
+        // Creating a user records no transaction
+        let key = match transaction.info {
+            crate::message::MessageInfo::CreateUser(_) => None,
+            _ => Some(crate::db::TransactionKey {
+                lamport_time: *transaction.clock.get_lamport(),
+                source_node: self.site_id.clone(),
+            }),
+        };
+        self.transactions.push(transaction);
+        self.receipts.push((receipt, key));
+    }
+
+    /// Replicates the transactions collected so far, then resolves their receipts
+    async fn flush(&mut self) {
+        let failure = replicate(std::mem::take(&mut self.transactions))
+            .await
+            .err()
+            .map(|e| e.to_string());
+        if let Some(reason) = &failure {
+            log::error!("Erreur lors de la diffusion des transactions : {}", reason);
+        }
+        for (receipt, key) in self.receipts.drain(..) {
+            let _ = receipt.send(match &failure {
+                None => Ok(key),
+                Some(reason) => Err(CommandError::NotReplicated(reason.clone())),
+            });
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+/// Wave applying a transaction on every site
+pub struct TransactionWave;
+
+#[cfg(feature = "server")]
+impl crate::network::wave::WaveProtocol for TransactionWave {
+    fn code(&self) -> crate::message::NetworkMessageCode {
+        crate::message::NetworkMessageCode::Transaction
+    }
+
+    fn echo_code(&self) -> crate::message::NetworkMessageCode {
+        crate::message::NetworkMessageCode::TransactionAcknowledgement
+    }
+
+    fn visit(
+        &self,
+        store: std::sync::Arc<dyn crate::store::LedgerStore>,
+        message: crate::message::Message,
+    ) -> crate::network::wave::VisitFuture {
+        Box::pin(async move {
+            // A batch carries the operations of its transactions instead of a command
+            if message.command.is_none()
+                && !matches!(message.info, crate::message::MessageInfo::Batch(_))
+            {
+                return Err("Command is None for Transaction message".into());
+            }
+            crate::causal::deliver(&store, |causal| causal.receive(message)).await;
+            Ok(crate::message::MessageInfo::None)
+        })
+    }
+}
+
 #[cfg(feature = "server")]
 /// Execute a command from the CLI
 /// Update the clock of the site
 /// Interact with the database
 /// Implement our wave diffusion protocol
-pub async fn process_cli_command(cmd: Command) -> Result<(), Box<dyn std::error::Error>> {
+pub async fn process_cli_command(
+    store: &std::sync::Arc<dyn crate::store::LedgerStore>,
+    cmd: Command,
+) -> Result<(), Box<dyn std::error::Error>> {
     use crate::state::LOCAL_APP_STATE;
 
     match cmd {
@@ -389,86 +802,111 @@ pub async fn process_cli_command(cmd: Command) -> Result<(), Box<dyn std::error:
                 println!("❌ Username cannot be empty");
                 return Ok(());
             }
-            enqueue_critical(CriticalCommands::CreateUser { name }).await?;
+            print_outcome(enqueue_critical(store, CriticalCommands::CreateUser { name }).await?)
+                .await;
         }
 
         Command::UserAccounts => {
-            super::db::print_users()?;
+            crate::store::blocking(store, |store| super::db::print_users(store)).await?;
         }
 
         Command::PrintUserTransactions => {
             let name = prompt("Username");
-            super::db::print_transaction_for_user(&name)?;
+            crate::store::blocking(store, move |store| {
+                super::db::print_transaction_for_user(store, &name)
+            })
+            .await?;
         }
 
         Command::PrintTransactions => {
-            super::db::print_transactions()?;
+            crate::store::blocking(store, |store| super::db::print_transactions(store)).await?;
         }
 
         Command::Deposit => {
             let name = prompt("Username");
-            let amount = prompt_parse::<f64>("Deposit amount");
-            enqueue_critical(CriticalCommands::Deposit {
-                name: name,
-                amount: amount,
-            })
-            .await?;
+            let amount = prompt_parse::<crate::money::Money>("Deposit amount");
+            print_outcome(
+                enqueue_critical(store, CriticalCommands::Deposit { name, amount }).await?,
+            )
+            .await;
         }
 
         Command::Withdraw => {
             let name = prompt("Username");
-            let amount = prompt_parse::<f64>("Withdraw amount");
-            if amount < 0.0 {}
-            enqueue_critical(CriticalCommands::Withdraw {
-                name: name,
-                amount: amount,
-            })
-            .await?;
+            let amount = prompt_parse::<crate::money::Money>("Withdraw amount");
+
+            print_outcome(
+                enqueue_critical(store, CriticalCommands::Withdraw { name, amount }).await?,
+            )
+            .await;
         }
 
         Command::Transfer => {
             let name = prompt("Username");
 
-            let amount = prompt_parse::<f64>("Transfer amount");
-            let _ = super::db::print_users();
+            let amount = prompt_parse::<crate::money::Money>("Transfer amount");
+            let _ = crate::store::blocking(store, |store| super::db::print_users(store)).await;
             let beneficiary = prompt("Beneficiary");
 
-            enqueue_critical(CriticalCommands::Transfer {
-                from: name.clone(),
-                to: beneficiary.clone(),
-                amount,
-            })
-            .await?;
+            print_outcome(
+                enqueue_critical(
+                    store,
+                    CriticalCommands::Transfer {
+                        from: name.clone(),
+                        to: beneficiary.clone(),
+                        amount,
+                    },
+                )
+                .await?,
+            )
+            .await;
         }
 
         Command::Pay => {
             let name = prompt("Username");
-            let amount = prompt_parse::<f64>("Payment amount");
+            let amount = prompt_parse::<crate::money::Money>("Payment amount");
 
-            if amount <= 0.0 {
+            if amount <= crate::money::Money::ZERO {
                 println!("❌ Amount must be positive");
                 return Ok(());
             }
-            enqueue_critical(CriticalCommands::Pay {
-                name: name.clone(),
-                amount,
-            })
-            .await?;
+            print_outcome(
+                enqueue_critical(
+                    store,
+                    CriticalCommands::Pay {
+                        name: name.clone(),
+                        amount,
+                    },
+                )
+                .await?,
+            )
+            .await;
         }
 
         Command::Refund => {
             let name = prompt("Username");
-            super::db::print_transaction_for_user(&name).unwrap();
+            let user = name.clone();
+            crate::store::blocking(store, move |store| {
+                super::db::print_transaction_for_user(store, &user)
+            })
+            .await
+            .unwrap();
 
             let transac_time = prompt_parse::<i64>("Lamport time");
             let transac_node = prompt("Node");
 
-            enqueue_critical(CriticalCommands::Refund {
-                name: name.clone(),
-                lamport: transac_time,
-                node: transac_node.clone(),
-            })
-            .await?;
+            print_outcome(
+                enqueue_critical(
+                    store,
+                    CriticalCommands::Refund {
+                        name: name.clone(),
+                        lamport: transac_time,
+                        node: transac_node.clone(),
+                    },
+                )
+                .await?,
+            )
+            .await;
         }
 
         Command::Help => {
@@ -491,7 +929,7 @@ pub async fn process_cli_command(cmd: Command) -> Result<(), Box<dyn std::error:
 
         Command::Snapshot => {
             println!("📸 Starting snapshot...");
-            enqueue_critical(CriticalCommands::FileSnapshot).await?;
+            print_outcome(enqueue_critical(store, CriticalCommands::FileSnapshot).await?).await;
         }
 
         Command::Info => {
@@ -519,10 +957,9 @@ pub async fn process_cli_command(cmd: Command) -> Result<(), Box<dyn std::error:
             };
 
             let db_path = {
-                let conn = crate::db::DB_CONN.lock().unwrap();
-                let path = conn.path().unwrap();
+                let path = store.location();
                 // keep only the name of the file (after the last "/")
-                path.to_string().split("/").last().unwrap().to_string()
+                path.split("/").last().unwrap().to_string()
             };
 
             println!("📊 System Information:");
@@ -537,7 +974,8 @@ pub async fn process_cli_command(cmd: Command) -> Result<(), Box<dyn std::error:
                 "Number of connected neighbors: {:?}",
                 connected_neighbours_addrs
             );
-            println!("Vector Clock: {:?}", clock.get_vector_clock_map());
+            println!("Vector Clock: {:?}", clock.get_vector_clock_entries());
+            println!("Retired sites: {:?}", clock.get_retired());
             println!("Lamport Clock: {}", clock.get_lamport());
             println!("--------- Wave diffusion info ------------");
             println!(
@@ -567,18 +1005,42 @@ pub async fn process_cli_command(cmd: Command) -> Result<(), Box<dyn std::error:
 /// Process commands received from the network
 /// Update the clock of the site
 /// Interact with the database
+///
+/// The transactions of a batch are applied atomically, none of them is kept if one fails
 pub async fn process_network_command(
+    store: &std::sync::Arc<dyn crate::store::LedgerStore>,
     msg: crate::message::MessageInfo,
     received_clock: crate::clock::Clock,
     sender_id: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<(), crate::error::PeillutError> {
+    let sender_id = sender_id.to_string();
+    crate::store::blocking(store, move |store| match msg {
+        crate::message::MessageInfo::Batch(batch) => store.atomically(|| {
+            batch
+                .into_iter()
+                .try_for_each(|tx| apply_transaction(store, tx.info, tx.clock, &sender_id))
+        }),
+        msg => apply_transaction(store, msg, received_clock, &sender_id),
+    })
+    .await
+}
+
+#[cfg(feature = "server")]
+/// Applies a transaction of `sender_id` dated by `received_clock` to the ledger
+fn apply_transaction(
+    store: &dyn crate::store::LedgerStore,
+    msg: crate::message::MessageInfo,
+    received_clock: crate::clock::Clock,
+    sender_id: &str,
+) -> Result<(), crate::error::PeillutError> {
     use crate::message::MessageInfo;
     use log;
 
     let message_lamport_time = received_clock.get_lamport();
+    let message_hlc = received_clock.get_hlc();
     let message_vc_clock = received_clock.get_vector_clock_map();
 
-    if crate::db::transaction_exists(*message_lamport_time, sender_id)? {
+    if store.transaction_exists(*message_lamport_time, sender_id)? {
         log::info!("Transaction allready exists, skipping");
         return Ok(());
     }
@@ -589,63 +1051,68 @@ pub async fn process_network_command(
                 log::warn!("Received CreateUser message with empty username, skipping");
                 return Ok(());
             }
-            if crate::db::user_exists(&create_user.name)? {
+            if store.user_exists(&create_user.name)? {
                 log::info!("User already exists, skipping");
                 return Ok(());
             }
-            super::db::create_user(&create_user.name)?;
+            store.create_user(&create_user.name)?;
         }
         crate::message::MessageInfo::Deposit(deposit) => {
-            super::db::deposit(
+            store.deposit(
                 &deposit.name,
                 deposit.amount,
-                &message_lamport_time,
+                message_lamport_time,
+                &message_hlc,
                 sender_id,
-                &message_vc_clock,
+                message_vc_clock,
             )?;
         }
 
         MessageInfo::Withdraw(withdraw) => {
-            super::db::withdraw(
+            store.withdraw(
                 &withdraw.name,
                 withdraw.amount,
-                &message_lamport_time,
+                message_lamport_time,
+                &message_hlc,
                 sender_id,
-                &message_vc_clock,
+                message_vc_clock,
             )?;
         }
 
         MessageInfo::Transfer(transfer) => {
-            super::db::create_transaction(
+            store.create_transaction(
                 &transfer.name,
                 &transfer.beneficiary,
                 transfer.amount,
-                &message_lamport_time,
+                message_lamport_time,
+                &message_hlc,
                 sender_id,
                 "",
-                &message_vc_clock,
+                message_vc_clock,
             )?;
         }
 
         MessageInfo::Pay(pay) => {
-            super::db::create_transaction(
+            store.create_transaction(
                 &pay.name,
                 "NULL",
                 pay.amount,
-                &message_lamport_time,
+                message_lamport_time,
+                &message_hlc,
                 sender_id,
                 "",
-                &message_vc_clock,
+                message_vc_clock,
             )?;
         }
 
         MessageInfo::Refund(refund) => {
-            super::db::refund_transaction(
+            store.refund_transaction(
                 refund.transac_time,
                 &refund.transac_node,
-                &message_lamport_time,
+                message_lamport_time,
+                &message_hlc,
                 sender_id,
-                &message_vc_clock,
+                message_vc_clock,
             )?;
         }
         crate::message::MessageInfo::SnapshotResponse(_) => {
@@ -666,11 +1133,33 @@ pub async fn process_network_command(
         crate::message::MessageInfo::Acknowledge(_) => {
             log::error!("Should not process Acknowledge message");
         }
+        crate::message::MessageInfo::Error(_) => {
+            log::error!("Should not process Error message");
+        }
+        crate::message::MessageInfo::RetireSite(_)
+        | crate::message::MessageInfo::TokenRequest(_)
+        | crate::message::MessageInfo::TokenStatus(_)
+        | crate::message::MessageInfo::Token(_)
+        | crate::message::MessageInfo::RenewLease(_)
+        | crate::message::MessageInfo::EvictRequest(_)
+        | crate::message::MessageInfo::Batch(_) => {
+            log::error!("Should not process {:?} message", msg);
+        }
     }
 
     Ok(())
 }
 
+#[cfg(feature = "server")]
+/// Waits for a command queued from the CLI and prints its outcome
+async fn print_outcome(receipt: Receipt) {
+    match receipt.outcome().await {
+        Ok(Some(key)) => println!("✅ Transaction {} recorded", key),
+        Ok(None) => println!("✅ Done"),
+        Err(e) => println!("❌ {}", e),
+    }
+}
+
 #[cfg(feature = "server")]
 /// Prompts the user for input with a label
 fn prompt(label: &str) -> String {
@@ -704,113 +1193,265 @@ where
 #[cfg(feature = "server")]
 #[tokio::test]
 async fn test_mutex_critical_section_high_load() {
-    use crate::state::{AppState, MutexStamp, MutexTag};
-    use std::net::SocketAddr;
-
-    let local_addr: SocketAddr = "127.0.0.1:9000".parse().unwrap();
-    let mut state = AppState::new(
-        "A".to_string(),
-        vec![
-            "127.0.0.1:9001".parse().unwrap(),
-            "127.0.0.1:9002".parse().unwrap(),
-        ],
-        local_addr,
-    );
-
-    // Set manually the number of connected neighbours
-    state.set_nb_connected_neighbours(2);
-
-    // Simulate remote requests in FIFO before our own
-    state.global_mutex_fifo.insert(
-        "B".to_string(),
-        MutexStamp {
-            tag: MutexTag::Request,
-            date: 1,
-        },
-    );
-
-    state.global_mutex_fifo.insert(
-        "C".to_string(),
-        MutexStamp {
-            tag: MutexTag::Request,
-            date: 2,
-        },
-    );
+    // The mutex waves are sent through the network of a node
+    crate::node::Node::in_memory()
+        .run(async {
+            use crate::mutex::LockScope;
+            use crate::state::{AppState, MutexStamp, MutexTag};
+            use std::net::SocketAddr;
+
+            let local_addr: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+            let mut state = AppState::new(
+                "A".to_string(),
+                vec![
+                    "127.0.0.1:9001".parse().unwrap(),
+                    "127.0.0.1:9002".parse().unwrap(),
+                ],
+                local_addr,
+            );
 
-    // Now request our own access with a higher Lamport (should wait)
-    for _ in 0..3 {
-        state.update_clock(None).await;
-    }
-    let _ = state.acquire_mutex().await;
+            // Set manually the number of connected neighbours
+            state.set_nb_connected_neighbours(2);
+
+            // Simulate remote requests in FIFO before our own
+            state.global_mutex_fifo.insert(
+                "B".to_string(),
+                MutexStamp {
+                    tag: MutexTag::Request,
+                    date: 1,
+                    scope: LockScope::All,
+                },
+            );
 
-    // Our site should not be in SC yet
-    assert_eq!(state.in_sc, false);
+            state.global_mutex_fifo.insert(
+                "C".to_string(),
+                MutexStamp {
+                    tag: MutexTag::Request,
+                    date: 2,
+                    scope: LockScope::All,
+                },
+            );
 
-    // Insert ACKs from all peers with lower Lamport (simulate reception)
-    state.global_mutex_fifo.insert(
-        "B".to_string(),
-        MutexStamp {
-            tag: MutexTag::Ack,
-            date: 1,
-        },
-    );
-    state.global_mutex_fifo.insert(
-        "C".to_string(),
-        MutexStamp {
-            tag: MutexTag::Ack,
-            date: 2,
-        },
-    );
+            // Now request our own access with a higher Lamport (should wait)
+            for _ in 0..3 {
+                state.update_clock(None).await;
+            }
+            let _ = state.acquire_mutex(LockScope::All).await;
+
+            // Our site should not be in SC yet
+            assert!(!state.in_sc);
+
+            // Insert ACKs from all peers with lower Lamport (simulate reception)
+            state.global_mutex_fifo.insert(
+                "B".to_string(),
+                MutexStamp {
+                    tag: MutexTag::Ack,
+                    date: 1,
+                    scope: LockScope::All,
+                },
+            );
+            state.global_mutex_fifo.insert(
+                "C".to_string(),
+                MutexStamp {
+                    tag: MutexTag::Ack,
+                    date: 2,
+                    scope: LockScope::All,
+                },
+            );
 
-    // Manually call try_enter_sc() to simulate triggering by incoming ack
-    state.try_enter_sc();
+            // Manually call try_enter_sc() to simulate triggering by incoming ack
+            state.try_enter_sc();
+
+            // Now we should be in the section critique
+            assert!(state.in_sc);
+
+            // Simulate some work and then release
+            let _ = state.release_mutex().await;
+
+            // After release, should no longer be in critical section
+            assert!(!state.in_sc);
+            assert!(!state.waiting_sc);
+
+            // All entries should be cleaned up
+            assert!(!state.global_mutex_fifo.contains_key("A"));
+
+            // Simulate again to check order with large number of requests
+            for i in 0..100 {
+                let site = format!("S{}", i);
+                state.global_mutex_fifo.insert(
+                    site.clone(),
+                    MutexStamp {
+                        tag: MutexTag::Request,
+                        date: i,
+                        scope: LockScope::All,
+                    },
+                );
+            }
 
-    // Now we should be in the section critique
-    assert_eq!(state.in_sc, true);
+            // Now site A requests with date = 50 (should wait since lower stamps exist)
+            for _ in 0..50 {
+                state.update_clock(None).await;
+            }
+            let _ = state.acquire_mutex(LockScope::All).await;
+            state.try_enter_sc();
+            assert!(!state.in_sc); // can't enter yet
+
+            // Now convert all others to ACK
+            for i in 0..100 {
+                let site = format!("S{}", i);
+                state.global_mutex_fifo.insert(
+                    site.clone(),
+                    MutexStamp {
+                        tag: MutexTag::Ack,
+                        date: i,
+                        scope: LockScope::All,
+                    },
+                );
+            }
 
-    // Simulate some work and then release
-    let _ = state.release_mutex().await;
+            // Try entering again
+            state.try_enter_sc();
+            assert!(state.in_sc); // should succeed now
+        })
+        .await
+}
 
-    // After release, should no longer be in critical section
-    assert_eq!(state.in_sc, false);
-    assert_eq!(state.waiting_sc, false);
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_failed_batch_transaction_is_not_applied() {
+    use crate::clock::{Clock, HybridTimestamp};
+    use crate::message::{BatchedTransaction, Deposit, MessageInfo, Withdraw};
+    use crate::money::Money;
+
+    let store: std::sync::Arc<dyn crate::store::LedgerStore> =
+        std::sync::Arc::new(crate::store::MemoryStore::default());
+    store.create_user("alice").unwrap();
+    let at = |lamport| Clock::from_parts(lamport, Default::default(), HybridTimestamp::default());
+    let batch = vec![
+        BatchedTransaction {
+            info: MessageInfo::Deposit(Deposit::new("alice".to_string(), Money::from_euros(10))),
+            clock: at(1),
+        },
+        BatchedTransaction {
+            info: MessageInfo::Withdraw(Withdraw::new("alice".to_string(), Money::from_euros(50))),
+            clock: at(2),
+        },
+        BatchedTransaction {
+            info: MessageInfo::Deposit(Deposit::new("alice".to_string(), Money::from_euros(5))),
+            clock: at(3),
+        },
+    ];
+
+    let result = process_network_command(&store, MessageInfo::Batch(batch), at(3), "B").await;
+    assert!(matches!(
+        result,
+        Err(crate::error::PeillutError::InsufficientFunds { .. })
+    ));
+    assert_eq!(store.balance("alice").unwrap(), Money::from_euros(0));
+    assert!(!store.transaction_exists(1, "B").unwrap());
+    assert!(!store.transaction_exists(3, "B").unwrap());
+}
 
-    // All entries should be cleaned up
-    assert!(!state.global_mutex_fifo.contains_key("A"));
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_pending_sync_is_not_queued_again() {
+    let node = crate::node::Node::in_memory();
+    node.run(async {
+        assert!(enqueue_sync(&node.store).await.unwrap().is_some());
+        assert!(enqueue_sync(&node.store).await.unwrap().is_none());
+        let st = crate::state::LOCAL_APP_STATE.lock().await;
+        assert_eq!(st.pending_commands.len(), 1);
+    })
+    .await;
+}
 
-    // Simulate again to check order with large number of requests
-    for i in 0..100 {
-        let site = format!("S{}", i);
-        state.global_mutex_fifo.insert(
-            site.clone(),
-            MutexStamp {
-                tag: MutexTag::Request,
-                date: i,
+#[cfg(feature = "server")]
+#[tokio::test(start_paused = true)]
+async fn test_commands_of_a_section_are_replicated_in_one_wave() {
+    use crate::money::Money;
+    use crate::network::sim::{SimConfig, SimNetwork};
+    use crate::node::testing::{balance, create_user, enqueue, start_triangle, wait_until};
+    use std::time::Duration;
+
+    let sim = SimNetwork::new(SimConfig {
+        seed: 17,
+        ..SimConfig::default()
+    });
+    let nodes = start_triangle(&sim, crate::mutex::MutexAlgorithm::Fifo).await;
+    create_user(&nodes, &nodes[0], "alice").await;
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    // Queued while A waits for the mutex, they all run in the same section
+    let before = sim.trace().len();
+    for _ in 0..5 {
+        enqueue(
+            &nodes[0],
+            CriticalCommands::Deposit {
+                name: "alice".to_string(),
+                amount: Money::from_euros(10),
             },
-        );
+        )
+        .await;
     }
+    wait_until(&nodes, "the deposits", || {
+        balance("alice") == Some(Money::from_euros(50))
+    })
+    .await;
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    // Acquire, one transaction wave, release
+    assert_eq!(sim.trace().len() - before, 3 * 6);
+}
 
-    // Now site A requests with date = 50 (should wait since lower stamps exist)
-    for _ in 0..50 {
-        state.update_clock(None).await;
-    }
-    let _ = state.acquire_mutex().await;
-    state.try_enter_sc();
-    assert_eq!(state.in_sc, false); // can't enter yet
-
-    // Now convert all others to ACK
-    for i in 0..100 {
-        let site = format!("S{}", i);
-        state.global_mutex_fifo.insert(
-            site.clone(),
-            MutexStamp {
-                tag: MutexTag::Ack,
-                date: i,
-            },
-        );
+#[cfg(feature = "server")]
+#[tokio::test(start_paused = true)]
+async fn test_receipts_report_the_outcome_of_commands() {
+    use crate::error::PeillutError;
+    use crate::money::Money;
+    use crate::network::sim::{SimConfig, SimNetwork};
+
+    let sim = SimNetwork::new(SimConfig {
+        seed: 19,
+        ..SimConfig::default()
+    });
+    let nodes =
+        crate::node::testing::start_triangle(&sim, crate::mutex::MutexAlgorithm::Fifo).await;
+    let run = |cmd| {
+        let store = nodes[0].store.clone();
+        nodes[0].run(async move { enqueue_critical(&store, cmd).await.unwrap().outcome().await })
+    };
+
+    let created = run(CriticalCommands::CreateUser {
+        name: "alice".to_string(),
+    })
+    .await;
+    assert!(matches!(created, Ok(None)));
+
+    let deposited = run(CriticalCommands::Deposit {
+        name: "alice".to_string(),
+        amount: Money::from_euros(10),
+    })
+    .await
+    .unwrap()
+    .expect("a deposit records a transaction");
+    // Resolved once the deposit reached every site
+    for node in &nodes {
+        let recorded = node.run(async {
+            crate::node::current()
+                .store
+                .transaction(deposited.lamport_time, &deposited.source_node)
+        });
+        assert!(recorded.await.unwrap().is_some());
     }
 
-    // Try entering again
-    state.try_enter_sc();
-    assert_eq!(state.in_sc, true); // should succeed now
+    let overdraft = run(CriticalCommands::Pay {
+        name: "alice".to_string(),
+        amount: Money::from_euros(50),
+    })
+    .await;
+    assert!(matches!(
+        overdraft,
+        Err(CommandError::Rejected(PeillutError::InsufficientFunds { balance, .. }))
+            if balance == Money::from_euros(10)
+    ));
 }
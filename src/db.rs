@@ -1,8 +1,7 @@
 //! Database management for the Peillute application
 //!
-//! This module handles all database operations, including user management,
-//! transaction processing, and state synchronization. It uses SQLite as the
-//! underlying database engine.
+//! This module keeps the ledger in a SQLite database, see [`SqliteStore`], and prints
+//! it for the CLI. The rules of the ledger are the ones of [`crate::store::LedgerStore`].
 
 /// Represents a transaction in the system
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -12,7 +11,7 @@ pub struct Transaction {
     /// Destination user of the transaction
     pub to_user: String,
     /// Transaction amount
-    pub amount: f64,
+    pub amount: crate::money::Money,
     /// Lamport timestamp of the transaction
     pub lamport_time: i64,
     /// ID of the node that created the transaction
@@ -21,849 +20,620 @@ pub struct Transaction {
     pub optional_msg: Option<String>,
     /// Vector clock state at the time of the transaction
     pub vector_clock: std::collections::HashMap<String, i64>,
+    /// Hybrid logical clock timestamp, dating the transaction
+    pub hlc: crate::clock::HybridTimestamp,
 }
 
-#[allow(unused_imports)]
-use clap::Parser;
-#[cfg(feature = "server")]
-lazy_static::lazy_static! {
-    pub static ref DB_CONN: std::sync::Mutex<rusqlite::Connection> =
-        std::sync::Mutex::new(rusqlite::Connection::open(format!("peillute_{}.db", super::Args::parse().cli_db_id)).unwrap());
+/// Identifies a transaction on every site
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct TransactionKey {
+    /// Lamport timestamp of the transaction
+    pub lamport_time: i64,
+    /// ID of the node that created the transaction
+    pub source_node: String,
+}
+
+impl std::fmt::Display for TransactionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.source_node, self.lamport_time)
+    }
 }
 
 #[cfg(feature = "server")]
 /// Special value representing a null user
-const NULL: &str = "NULL";
+pub const NULL: &str = "NULL";
 
 #[cfg(feature = "server")]
-/// Initializes the database schema
-pub fn init_db() -> rusqlite::Result<()> {
-    {
-        let conn = DB_CONN.lock().unwrap();
-
-        // Create VectorClock table for storing vector clock states
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS VectorClock (
-            id INTEGER PRIMARY KEY AUTOINCREMENT
-        );",
-            [],
-        )?;
-
-        // Create VectorClockEntry table for storing individual vector clock entries
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS VectorClockEntry (
-                vector_clock_id INTEGER,
-                site_id TEXT,
-                value INTEGER NOT NULL,
-                PRIMARY KEY(vector_clock_id, site_id),
-                FOREIGN KEY(vector_clock_id) REFERENCES VectorClock(id) ON DELETE CASCADE
-            );
-            ",
-            [],
-        )?;
-
-        // Create User table for storing user accounts
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS User (
-            unique_name TEXT PRIMARY KEY,
-            solde FLOAT NOT NULL
-        )",
-            [],
-        )?;
+/// Columns of a transaction read by [`SqliteStore::read_transactions`]
+const TRANSACTION_COLUMNS: &str = "from_user, to_user, amount, lamport_time, source_node, optional_msg, vector_clock_id, hlc_wall, hlc_logical";
 
-        // Create Transactions table for storing transaction history
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS Transactions (
-                from_user TEXT,
-                to_user TEXT NOT NULL,
-                amount FLOAT NOT NULL,
-                lamport_time INTEGER NOT NULL,
-                vector_clock_id INTEGER NOT NULL,
-                source_node TEXT NOT NULL,
-                optional_msg TEXT,
-                FOREIGN KEY(from_user) REFERENCES User(unique_name),
-                FOREIGN KEY(to_user) REFERENCES User(unique_name),
-                FOREIGN KEY(vector_clock_id) REFERENCES VectorClock(id),
-                PRIMARY KEY(lamport_time, source_node)
-            );",
-            [],
-        )?;
-
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS LocalState (
-            site_id TEXT PRIMARY KEY,
-            lamport_time INTEGER NOT NULL,
-            vector_clock_id INTEGER NOT NULL,
-            FOREIGN KEY(vector_clock_id) REFERENCES VectorClock(id)
-        );",
-            [],
-        )?;
-    }
-
-    log::debug!("Database initialized successfully.");
-    Ok(())
-}
+#[cfg(feature = "server")]
+/// Balance of the user `?1`, computed from their transactions
+const BALANCE: &str = "SELECT
+    IFNULL((SELECT SUM(amount) FROM Transactions WHERE to_user = ?1), 0) -
+    IFNULL((SELECT SUM(amount) FROM Transactions WHERE from_user = ?1), 0)
+AS balance";
 
 #[cfg(feature = "server")]
-/// Update the local state of the site
-pub fn update_local_state(site_id: &str, clock: crate::clock::Clock) -> rusqlite::Result<()> {
-    use rusqlite::params;
-
-    let lamport_time = clock.get_lamport();
-    let vc_clock = clock.get_vector_clock_map();
-
-    let conn = DB_CONN.lock().unwrap();
-    conn.execute("INSERT INTO VectorClock DEFAULT VALUES", [])?;
-    let vector_clock_id = conn.last_insert_rowid();
-
-    let mut stmt = conn.prepare(
-        "INSERT INTO VectorClockEntry (vector_clock_id, site_id, value) VALUES (?1, ?2, ?3)",
-    )?;
-    for (site, value) in vc_clock.iter() {
-        stmt.execute(params![vector_clock_id, site, value])?;
-    }
-    conn.execute(
-        "INSERT OR REPLACE INTO LocalState (site_id, lamport_time, vector_clock_id)
-        VALUES (?1, ?2, ?3)",
-        params![site_id, lamport_time, vector_clock_id],
-    )?;
-    Ok(())
-}
+/// Read-only connections of a database file
+const READERS: usize = 4;
 
 #[cfg(feature = "server")]
-/// Update the database with a snapshot
-pub fn update_db_with_snapshot(
-    snapshot: &crate::snapshot::GlobalSnapshot,
-    vector_clock: &std::collections::HashMap<String, i64>,
-) {
-    log::info!("Applying snapshot to database");
-
-    if snapshot.missing.is_empty() {
-        log::info!("No missing transactions, nothing to do");
-        return;
-    }
-
-    // sort tsx actions by lamport time
-    let mut sorted_txs: Vec<_> = snapshot
-        .missing
-        .values()
-        .flat_map(|txs| txs.iter())
-        .collect();
-    sorted_txs.sort_by_key(|tx| tx.lamport_time);
-
-    for tx in sorted_txs {
-        let optional_msg = "";
-
-        let _ = crate::db::create_transaction(
-            &tx.from_user,
-            &tx.to_user,
-            (tx.amount_in_cent as f64) / 100.0,
-            &tx.lamport_time,
-            &tx.source_node,
-            &optional_msg,
-            vector_clock,
-        );
-    }
+/// Ledger kept in a SQLite database
+///
+/// A database file is in WAL mode: its read-only connections read the last committed
+/// ledger while the writing connection replicates transactions, so that the history
+/// pages do not wait for the replication.
+pub struct SqliteStore {
+    /// Connection writing the ledger, shared by every task of the node, see
+    /// `LedgerStore::atomically`
+    writer: std::sync::Mutex<rusqlite::Connection>,
+    /// Read-only connections, none in memory
+    readers: Vec<std::sync::Mutex<rusqlite::Connection>>,
+    /// Reader used by the next read
+    next_reader: std::sync::atomic::AtomicUsize,
+    /// Thread writing the open groups, it reads through the writer to see its writes
+    gate: crate::store::WriteGate,
+    /// Database file, empty in memory
+    path: String,
 }
 
 #[cfg(feature = "server")]
-/// Get the local state of the site
-pub fn get_local_state() -> rusqlite::Result<(String, crate::clock::Clock)> {
-    use rusqlite::params;
-    let conn = DB_CONN.lock().unwrap();
-    let mut stmt =
-        conn.prepare("SELECT site_id, lamport_time, vector_clock_id FROM LocalState LIMIT 1")?;
-
-    let row = stmt.query_row([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)));
-
-    let (site_id, lamport_time, vector_clock_id): (String, i64, i64) = match row {
-        Ok(data) => data,
-        Err(rusqlite::Error::QueryReturnedNoRows) => {
-            return Err(rusqlite::Error::QueryReturnedNoRows);
+impl SqliteStore {
+    /// Opens the database file, applying the migrations it is missing
+    pub fn open(path: &str) -> Result<Self, crate::error::PeillutError> {
+        use rusqlite::OpenFlags;
+
+        let writer = rusqlite::Connection::open(path)?;
+        writer
+            .pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))?;
+        crate::migration::migrate(&writer)?;
+
+        let mut readers = Vec::with_capacity(READERS);
+        for _ in 0..READERS {
+            let reader = rusqlite::Connection::open_with_flags(
+                path,
+                OpenFlags::SQLITE_OPEN_READ_ONLY
+                    | OpenFlags::SQLITE_OPEN_NO_MUTEX
+                    | OpenFlags::SQLITE_OPEN_URI,
+            )?;
+            readers.push(std::sync::Mutex::new(reader));
         }
-        Err(e) => return Err(e),
-    };
+        Ok(Self::new(writer, readers))
+    }
+
+    /// Opens a database in memory, lost when the store is dropped
+    #[cfg(test)]
+    pub fn in_memory() -> Result<Self, crate::error::PeillutError> {
+        let writer = rusqlite::Connection::open_in_memory()?;
+        crate::migration::migrate(&writer)?;
+        Ok(Self::new(writer, Vec::new()))
+    }
+
+    fn new(
+        writer: rusqlite::Connection,
+        readers: Vec<std::sync::Mutex<rusqlite::Connection>>,
+    ) -> Self {
+        log::debug!("Database initialized successfully.");
+        SqliteStore {
+            path: writer.path().unwrap_or_default().to_string(),
+            writer: std::sync::Mutex::new(writer),
+            readers,
+            next_reader: std::sync::atomic::AtomicUsize::new(0),
+            gate: crate::store::WriteGate::default(),
+        }
+    }
 
-    let mut clock_map = std::collections::HashMap::new();
-    let mut vc_stmt =
-        conn.prepare("SELECT site_id, value FROM VectorClockEntry WHERE vector_clock_id = ?1")?;
-    let mut rows = vc_stmt.query(params![vector_clock_id])?;
-    while let Some(vc_row) = rows.next()? {
-        let site: String = vc_row.get(0)?;
-        let value: i64 = vc_row.get(1)?;
-        clock_map.insert(site, value);
+    /// Connection to write the ledger with, once the groups of the other threads are
+    /// closed
+    fn writer(&self) -> std::sync::MutexGuard<'_, rusqlite::Connection> {
+        let _gate = self.gate.wait();
+        self.writer.lock().unwrap()
     }
 
-    let c = crate::clock::Clock::from_parts(lamport_time, clock_map);
+    /// Connection to read the ledger with
+    ///
+    /// Without readers, the other threads wait for the open groups to be closed so that
+    /// they do not see their writes
+    fn reader(&self) -> std::sync::MutexGuard<'_, rusqlite::Connection> {
+        if self.gate.is_mine() {
+            return self.writer.lock().unwrap();
+        }
+        if self.readers.is_empty() {
+            return self.writer();
+        }
+        let i = self
+            .next_reader
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+            % self.readers.len();
+        self.readers[i].lock().unwrap()
+    }
+
+    /// Saves a vector clock, returning its ID
+    fn insert_vector_clock(
+        conn: &rusqlite::Connection,
+        vector_clock: &std::collections::HashMap<String, i64>,
+    ) -> rusqlite::Result<i64> {
+        use rusqlite::params;
 
-    Ok((site_id, c))
-}
+        conn.execute("INSERT INTO VectorClock DEFAULT VALUES", [])?;
+        let vector_clock_id = conn.last_insert_rowid();
 
-#[cfg(feature = "server")]
-/// Checks if the database has been initialized
-pub fn is_database_initialized() -> rusqlite::Result<bool> {
-    {
-        let conn = DB_CONN.lock().unwrap();
         let mut stmt = conn.prepare(
-            "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'Transactions')",
+            "INSERT INTO VectorClockEntry (vector_clock_id, site_id, value) VALUES (?1, ?2, ?3)",
         )?;
-        let exists: bool = stmt.query_row([], |row| row.get(0))?;
-        Ok(exists)
+        for (site_id, value) in vector_clock.iter() {
+            stmt.execute(params![vector_clock_id, site_id, value])?;
+        }
+        Ok(vector_clock_id)
+    }
+
+    /// Reads a vector clock saved by `insert_vector_clock`
+    fn read_vector_clock(
+        conn: &rusqlite::Connection,
+        vector_clock_id: i64,
+    ) -> rusqlite::Result<std::collections::HashMap<String, i64>> {
+        let mut clock_map = std::collections::HashMap::new();
+        let mut vc_stmt =
+            conn.prepare("SELECT site_id, value FROM VectorClockEntry WHERE vector_clock_id = ?1")?;
+        let mut rows = vc_stmt.query(rusqlite::params![vector_clock_id])?;
+        while let Some(vc_row) = rows.next()? {
+            let site_id: String = vc_row.get(0)?;
+            let value: i64 = vc_row.get(1)?;
+            clock_map.insert(site_id, value);
+        }
+        Ok(clock_map)
+    }
+
+    /// Reads the transactions selected by `filter`, a SQL clause following their columns
+    fn read_transactions(
+        &self,
+        filter: &str,
+        params: impl rusqlite::Params,
+    ) -> Result<Vec<Transaction>, crate::error::PeillutError> {
+        let conn = self.reader();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM Transactions {}",
+            TRANSACTION_COLUMNS, filter
+        ))?;
+        let txs = stmt.query_map(params, |row| {
+            Ok((
+                Transaction {
+                    from_user: row.get(0)?,
+                    to_user: row.get(1)?,
+                    amount: row.get(2)?,
+                    lamport_time: row.get(3)?,
+                    source_node: row.get(4)?,
+                    optional_msg: row.get(5)?,
+                    vector_clock: std::collections::HashMap::new(),
+                    hlc: crate::clock::HybridTimestamp {
+                        wall_ms: row.get(7)?,
+                        logical: row.get(8)?,
+                    },
+                },
+                row.get::<_, i64>(6)?,
+            ))
+        })?;
+
+        let mut txs_vec = Vec::new();
+        for tx in txs {
+            let (mut tx, vector_clock_id) = tx?;
+            tx.vector_clock = Self::read_vector_clock(&conn, vector_clock_id)?;
+            txs_vec.push(tx);
+        }
+        Ok(txs_vec)
     }
-}
 
-#[cfg(feature = "server")]
-/// Check if a transaction exists in the database
-pub fn transaction_exists(lamport_time: i64, source_node: &str) -> rusqlite::Result<bool> {
-    use rusqlite::params;
-    {
-        let conn = DB_CONN.lock().unwrap();
-        let mut stmt = conn.prepare(
-            "SELECT EXISTS(SELECT 1 FROM Transactions WHERE lamport_time = ?1 AND source_node = ?2)",
+    /// Updates the stored balance for a user from their transactions
+    fn update_solde(conn: &rusqlite::Connection, name: &str) -> rusqlite::Result<()> {
+        let solde: crate::money::Money =
+            conn.query_row(BALANCE, rusqlite::params![name], |row| row.get(0))?;
+        conn.execute(
+            "UPDATE User SET solde = ?1 WHERE unique_name = ?2",
+            rusqlite::params![solde, name],
         )?;
-        let exists: bool = stmt.query_row(params![lamport_time, source_node], |row| row.get(0))?;
-        Ok(exists)
+        log::debug!("Updated solde for {} to {}", name, solde);
+        Ok(())
     }
 }
 
 #[cfg(feature = "server")]
-/// Checks if a user exists in the database
-pub fn user_exists(name: &str) -> rusqlite::Result<bool> {
-    {
-        use rusqlite::params;
-        let conn = DB_CONN.lock().unwrap();
-        let mut stmt = conn.prepare("SELECT EXISTS(SELECT 1 FROM User WHERE unique_name = ?1)")?;
-        let exists: bool = stmt.query_row(params![name], |row| row.get(0))?;
-        Ok(exists)
+impl crate::store::LedgerStore for SqliteStore {
+    fn location(&self) -> String {
+        self.path.clone()
     }
-}
 
-#[cfg(feature = "server")]
-/// Creates a new user with zero balance
-pub fn create_user(unique_name: &str) -> rusqlite::Result<()> {
-    use rusqlite::params;
-    if user_exists(unique_name)? {
-        log::warn!("User '{}' already exists.", unique_name);
-        return Ok(());
+    fn begin(&self) -> Result<(), crate::error::PeillutError> {
+        self.gate.open(|| {
+            let conn = self.writer.lock().unwrap();
+            conn.execute_batch("SAVEPOINT atomically")?;
+            Ok(())
+        })
     }
 
-    {
-        log::debug!("Ajout de l'utilisateur {}", unique_name);
-        let conn = DB_CONN.lock().unwrap();
-        conn.execute(
-            "INSERT INTO User (unique_name, solde) VALUES (?1, 0)",
-            params![unique_name],
-        )?;
-        Ok(())
+    fn commit(&self) -> Result<(), crate::error::PeillutError> {
+        self.gate.close(|| {
+            let conn = self.writer.lock().unwrap();
+            conn.execute_batch("RELEASE atomically")?;
+            Ok(())
+        })
     }
-}
 
-#[cfg(feature = "server")]
-/// Deletes a user from the database
-pub fn delete_user(name: &str) -> rusqlite::Result<()> {
-    use rusqlite::params;
-    if !user_exists(name)? {
-        let err = rusqlite::Error::SqliteFailure(
-            rusqlite::ffi::Error::new(rusqlite::ffi::ErrorCode::Unknown as i32),
-            Some(format!("User '{}' does not exist.", name).into()),
-        );
-
-        log::error!("User '{}' does not exist.", name);
-        return Err(err);
+    fn rollback(&self) -> Result<(), crate::error::PeillutError> {
+        self.gate.close(|| {
+            let conn = self.writer.lock().unwrap();
+            conn.execute_batch("ROLLBACK TO atomically; RELEASE atomically")?;
+            Ok(())
+        })
     }
-    {
-        let conn = DB_CONN.lock().unwrap();
-        conn.execute("DELETE FROM User WHERE unique_name = ?1", params![name])?;
-        Ok(())
+
+    fn user_exists(&self, name: &str) -> Result<bool, crate::error::PeillutError> {
+        let conn = self.reader();
+        let mut stmt = conn.prepare("SELECT EXISTS(SELECT 1 FROM User WHERE unique_name = ?1)")?;
+        let exists: bool = stmt.query_row(rusqlite::params![name], |row| row.get(0))?;
+        Ok(exists)
     }
-}
 
-#[cfg(feature = "server")]
-/// Calculates the current balance for a user
-pub fn calculate_solde(name: &str) -> rusqlite::Result<f64> {
-    {
-        use rusqlite::params;
-        let conn = DB_CONN.lock().unwrap();
-        let mut stmt = conn.prepare(
-            "SELECT
-            IFNULL((SELECT SUM(amount) FROM Transactions WHERE to_user = ?1), 0) -
-            IFNULL((SELECT SUM(amount) FROM Transactions WHERE from_user = ?1), 0)
-        AS balance",
+    fn insert_user(&self, name: &str) -> Result<(), crate::error::PeillutError> {
+        let conn = self.writer();
+        conn.execute(
+            "INSERT INTO User (unique_name, solde) VALUES (?1, 0)",
+            rusqlite::params![name],
         )?;
-        stmt.query_row(params![name], |row| row.get(0))
+        Ok(())
     }
-}
 
-#[cfg(feature = "server")]
-/// Updates the stored balance for a user
-pub fn update_solde(name: &str) -> rusqlite::Result<()> {
-    use rusqlite::params;
-
-    if !user_exists(name)? {
-        let err = rusqlite::Error::SqliteFailure(
-            rusqlite::ffi::Error::new(rusqlite::ffi::ErrorCode::Unknown as i32),
-            Some(format!("User '{}' does not exist.", name).into()),
-        );
-
-        return Err(err);
-    }
-    let solde = calculate_solde(name)?;
-    {
-        let conn = DB_CONN.lock().unwrap();
+    fn remove_user(&self, name: &str) -> Result<(), crate::error::PeillutError> {
+        let conn = self.writer();
         conn.execute(
-            "UPDATE User SET solde = ?1 WHERE unique_name = ?2",
-            params![solde, name],
+            "DELETE FROM User WHERE unique_name = ?1",
+            rusqlite::params![name],
         )?;
-        log::debug!("Updated solde for {} to {}", name, solde);
         Ok(())
     }
-}
 
-#[cfg(feature = "server")]
-/// Ensures a user exists, creating it if necessary
-pub fn ensure_user(name: &str) -> rusqlite::Result<()> {
-    if name != NULL && !user_exists(name)? {
-        create_user(name)?;
+    fn users(&self) -> Result<Vec<String>, crate::error::PeillutError> {
+        let conn = self.reader();
+        let mut stmt = conn.prepare("SELECT unique_name FROM User")?;
+        let users = stmt.query_map([], |row| row.get::<_, String>(0))?;
+        let mut users_vec = Vec::new();
+        for user in users {
+            users_vec.push(user?);
+        }
+        Ok(users_vec)
     }
-    Ok(())
-}
 
-#[cfg(feature = "server")]
-/// Creates a new transaction between users
-pub fn create_transaction(
-    from_user: &str,
-    to_user: &str,
-    amount: f64,
-    lamport_time: &i64,
-    source_node: &str,
-    optional_msg: &str,
-    vector_clock: &std::collections::HashMap<String, i64>,
-) -> rusqlite::Result<()> {
-    use rusqlite::params;
-    if from_user != NULL && calculate_solde(from_user)? < amount {
-        let err = rusqlite::Error::SqliteFailure(
-            rusqlite::ffi::Error::new(rusqlite::ffi::ErrorCode::Unknown as i32),
-            Some(
-                format!(
-                    "Insufficient funds: '{}' has less than {}.",
-                    from_user, amount
-                )
-                .into(),
-            ),
-        );
-
-        log::error!(
-            "Insufficient funds: '{}' has less than {}.",
-            from_user,
-            amount
-        );
-        return Err(err);
+    fn balance(&self, name: &str) -> Result<crate::money::Money, crate::error::PeillutError> {
+        let conn = self.reader();
+        Ok(conn.query_row(BALANCE, rusqlite::params![name], |row| row.get(0))?)
     }
 
-    ensure_user(from_user)?;
-    ensure_user(to_user)?;
-
-    log::debug!(
-        "Creating transaction from {} to {} with amount {}",
-        from_user,
-        to_user,
-        amount
-    );
-
-    {
-        let conn = DB_CONN.lock().unwrap();
-        conn.execute("INSERT INTO VectorClock DEFAULT VALUES", [])?;
-        let vector_clock_id = conn.last_insert_rowid();
+    fn insert_transaction(&self, tx: &Transaction) -> Result<(), crate::error::PeillutError> {
+        use rusqlite::params;
 
-        let mut stmt = conn.prepare(
-            "INSERT INTO VectorClockEntry (vector_clock_id, site_id, value) VALUES (?1, ?2, ?3)",
+        // The row, its clock and the balances are written together
+        let mut conn = self.writer();
+        let sp = conn.savepoint()?;
+        let vector_clock_id = Self::insert_vector_clock(&sp, &tx.vector_clock)?;
+        sp.execute(
+            &format!(
+                "INSERT INTO Transactions ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                TRANSACTION_COLUMNS
+            ),
+            params![
+                tx.from_user,
+                tx.to_user,
+                tx.amount,
+                tx.lamport_time,
+                tx.source_node,
+                tx.optional_msg,
+                vector_clock_id,
+                tx.hlc.wall_ms,
+                tx.hlc.logical
+            ],
         )?;
-        for (site_id, value) in vector_clock.iter() {
-            stmt.execute(params![vector_clock_id, site_id, value])?;
+        for user in [&tx.from_user, &tx.to_user] {
+            if user != NULL {
+                Self::update_solde(&sp, user)?;
+            }
         }
-
-        conn.execute(
-        "INSERT INTO Transactions (from_user, to_user, amount, lamport_time, vector_clock_id, source_node, optional_msg)
-        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
-        params![
-            from_user,
-            to_user,
-            amount,
-            lamport_time,
-            vector_clock_id,
-            source_node,
-            optional_msg
-        ],
-    )?;
-    }
-
-    if from_user != NULL {
-        update_solde(from_user)?;
-    }
-    if to_user != NULL {
-        update_solde(to_user)?;
+        sp.commit()?;
+        Ok(())
     }
 
-    Ok(())
-}
-
-#[cfg(feature = "server")]
-pub fn deposit(
-    user: &str,
-    amount: f64,
-    lamport_time: &i64,
-    source_node: &str,
-    vector_clock: &std::collections::HashMap<String, i64>,
-) -> rusqlite::Result<()> {
-    if !user_exists(user)? {
-        let err = rusqlite::Error::SqliteFailure(
-            rusqlite::ffi::Error::new(rusqlite::ffi::ErrorCode::Unknown as i32),
-            Some(format!("Unknown User: {}", user).into()),
-        );
-
-        log::error!("Unknown User: {}", user);
-        return Err(err);
+    fn transaction(
+        &self,
+        lamport_time: i64,
+        source_node: &str,
+    ) -> Result<Option<Transaction>, crate::error::PeillutError> {
+        Ok(self
+            .read_transactions(
+                "WHERE lamport_time = ?1 AND source_node = ?2",
+                rusqlite::params![lamport_time, source_node],
+            )?
+            .pop())
     }
 
-    if amount < 0.0 {
-        let err = rusqlite::Error::SqliteFailure(
-            rusqlite::ffi::Error::new(rusqlite::ffi::ErrorCode::Unknown as i32),
-            Some(format!("Negative deposit amount: {}", amount).into()),
-        );
-
-        log::error!("Negative deposit amount: {}", amount);
-        return Err(err);
+    fn transactions(&self) -> Result<Vec<Transaction>, crate::error::PeillutError> {
+        self.read_transactions("ORDER BY hlc_wall, hlc_logical", [])
     }
 
-    log::debug!("Depositing {} to {}", amount, user);
-
-    create_transaction(
-        NULL,
-        user,
-        amount,
-        lamport_time,
-        source_node,
-        "Deposit",
-        vector_clock,
-    )
-}
-
-#[cfg(feature = "server")]
-pub fn withdraw(
-    user: &str,
-    amount: f64,
-    lamport_time: &i64,
-    source_node: &str,
-    vector_clock: &std::collections::HashMap<String, i64>,
-) -> rusqlite::Result<()> {
-    if amount < 0.0 {
-        let err = rusqlite::Error::SqliteFailure(
-            rusqlite::ffi::Error::new(rusqlite::ffi::ErrorCode::Unknown as i32),
-            Some(format!("Negative withdrawal amount: {}", amount).into()),
-        );
-        log::error!("Negative withdrawal amount: {}", amount);
-        return Err(err);
-    }
-    if !user_exists(user)? {
-        let err = rusqlite::Error::SqliteFailure(
-            rusqlite::ffi::Error::new(rusqlite::ffi::ErrorCode::Unknown as i32),
-            Some(format!("Unknown user: {}", user).into()),
-        );
-        log::error!("Unknown user: {}", user);
-        return Err(err);
-    }
-    if calculate_solde(user)? < amount {
-        let err = rusqlite::Error::SqliteFailure(
-            rusqlite::ffi::Error::new(rusqlite::ffi::ErrorCode::Unknown as i32),
-            Some(format!("User {} not enough money", user).into()),
-        );
-        log::error!("User {} not enough money", user);
-        return Err(err);
+    fn transactions_for_user(
+        &self,
+        name: &str,
+    ) -> Result<Vec<Transaction>, crate::error::PeillutError> {
+        self.read_transactions(
+            "WHERE from_user = ?1 OR to_user = ?1 ORDER BY hlc_wall, hlc_logical",
+            rusqlite::params![name],
+        )
     }
 
-    log::debug!("Withdrawing {} from {}", amount, user);
-
-    create_transaction(
-        user,
-        NULL,
-        amount,
-        lamport_time,
-        source_node,
-        "Withdraw",
-        vector_clock,
-    )
-}
-
-#[cfg(feature = "server")]
-pub fn has_been_refunded(transac_time: i64, node: &str) -> rusqlite::Result<bool> {
-    use rusqlite::params;
-    {
-        let conn = DB_CONN.lock().unwrap();
+    fn is_refunded(
+        &self,
+        lamport_time: i64,
+        source_node: &str,
+    ) -> Result<bool, crate::error::PeillutError> {
+        let conn = self.reader();
         let mut stmt =
             conn.prepare("SELECT EXISTS(SELECT 1 FROM Transactions WHERE optional_msg = ?1)")?;
 
-        let optional_msg = format!("Refund transaction {}-{}", node, transac_time);
-        let exists: bool = stmt.query_row(params![optional_msg], |row| row.get(0))?;
-
+        let optional_msg = format!("Refund transaction {}-{}", source_node, lamport_time);
+        let exists: bool = stmt.query_row(rusqlite::params![optional_msg], |row| row.get(0))?;
         Ok(exists)
     }
-}
-
-#[cfg(feature = "server")]
-pub fn refund_transaction(
-    transac_time: i64,
-    node: &str,
-    lamport_time: &i64,
-    source_node: &str,
-    vector_clock: &std::collections::HashMap<String, i64>,
-) -> rusqlite::Result<()> {
-    if let Some(tx) = get_transaction(transac_time, node)? {
-        if calculate_solde(&tx.to_user)? < tx.amount {
-            let err = rusqlite::Error::SqliteFailure(
-                rusqlite::ffi::Error::new(rusqlite::ffi::ErrorCode::Unknown as i32),
-                Some(format!("User {} has not enough money to give back", &tx.to_user).into()),
-            );
-            log::error!("User {} has not enough money to give back", &tx.to_user);
-            return Err(err);
-        }
 
-        if tx.optional_msg.is_some() && tx.optional_msg.unwrap().starts_with("Refund transaction") {
-            let err = rusqlite::Error::SqliteFailure(
-                rusqlite::ffi::Error::new(rusqlite::ffi::ErrorCode::Unknown as i32),
-                Some(
-                    format!(
-                        "Transaction {}-{} is a refund transaction",
-                        node, transac_time
-                    )
-                    .into(),
-                ),
-            );
-            log::error!(
-                "Transaction {}-{} is a refund transaction",
-                node,
-                transac_time
-            );
-            return Err(err);
-        }
+    fn local_state(
+        &self,
+    ) -> Result<Option<(String, crate::clock::Clock)>, crate::error::PeillutError> {
+        use rusqlite::OptionalExtension;
+
+        let conn = self.reader();
+        let row = conn
+            .query_row(
+                "SELECT site_id, lamport_time, vector_clock_id, hlc_wall, hlc_logical FROM LocalState LIMIT 1",
+                [],
+                |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, i64>(1)?,
+                        row.get::<_, i64>(2)?,
+                        crate::clock::HybridTimestamp {
+                            wall_ms: row.get(3)?,
+                            logical: row.get(4)?,
+                        },
+                    ))
+                },
+            )
+            .optional()?;
+
+        let Some((site_id, lamport_time, vector_clock_id, hlc)) = row else {
+            return Ok(None);
+        };
+        let clock_map = Self::read_vector_clock(&conn, vector_clock_id)?;
+        let c = crate::clock::Clock::from_parts(lamport_time, clock_map, hlc);
+        Ok(Some((site_id, c)))
+    }
+
+    fn save_local_state(
+        &self,
+        site_id: &str,
+        clock: &crate::clock::Clock,
+    ) -> Result<(), crate::error::PeillutError> {
+        use rusqlite::{OptionalExtension, params};
+
+        let lamport_time = clock.get_lamport();
+        let hlc = clock.get_hlc();
+
+        let mut conn = self.writer();
+        let conn = conn.savepoint()?;
+        let previous_clock_id: Option<i64> = conn
+            .query_row(
+                "SELECT vector_clock_id FROM LocalState WHERE site_id = ?1",
+                params![site_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        let vector_clock_id = Self::insert_vector_clock(&conn, clock.get_vector_clock_map())?;
+        conn.execute(
+            "INSERT OR REPLACE INTO LocalState (site_id, lamport_time, vector_clock_id, hlc_wall, hlc_logical)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![site_id, lamport_time, vector_clock_id, hlc.wall_ms, hlc.logical],
+        )?;
 
-        if has_been_refunded(transac_time, node)? {
-            let err = rusqlite::Error::SqliteFailure(
-                rusqlite::ffi::Error::new(rusqlite::ffi::ErrorCode::Unknown as i32),
-                Some(format!("Transaction {}-{} already refunded", node, transac_time).into()),
-            );
-            log::error!("Transaction {}-{} already refunded", node, transac_time);
-            return Err(err);
+        // The clock saved before is referenced by nothing else
+        if let Some(previous_clock_id) = previous_clock_id {
+            conn.execute(
+                "DELETE FROM VectorClockEntry WHERE vector_clock_id = ?1",
+                params![previous_clock_id],
+            )?;
+            conn.execute(
+                "DELETE FROM VectorClock WHERE id = ?1",
+                params![previous_clock_id],
+            )?;
         }
+        conn.commit()?;
+        Ok(())
+    }
 
-        create_transaction(
-            &tx.to_user,
-            &tx.from_user,
-            tx.amount,
-            lamport_time,
-            source_node,
-            &format!("Refund transaction {}-{}", node, transac_time),
-            vector_clock,
-        )?;
-    } else {
-        let err = rusqlite::Error::SqliteFailure(
-            rusqlite::ffi::Error::new(rusqlite::ffi::ErrorCode::Unknown as i32),
-            Some(
-                format!(
-                    "No transaction found at time {} from node {}",
-                    transac_time, node
-                )
-                .into(),
-            ),
-        );
+    fn site_key(&self, site_id: &str) -> Result<Option<Vec<u8>>, crate::error::PeillutError> {
+        use rusqlite::OptionalExtension;
 
-        log::error!(
-            "No transaction found at time {} from node {}",
-            transac_time,
-            node
-        );
-        return Err(err);
+        let conn = self.reader();
+        Ok(conn
+            .query_row(
+                "SELECT signing_key FROM SiteIdentity WHERE site_id = ?1",
+                rusqlite::params![site_id],
+                |row| row.get(0),
+            )
+            .optional()?)
     }
-    Ok(())
-}
 
-#[cfg(feature = "server")]
-pub fn get_transaction(transac_time: i64, node: &str) -> rusqlite::Result<Option<Transaction>> {
-    use rusqlite::params;
-    {
-        let conn = DB_CONN.lock().unwrap();
-        let mut stmt = conn.prepare(
-            "SELECT from_user, to_user, amount, lamport_time, source_node, optional_msg, vector_clock_id
-        FROM Transactions WHERE lamport_time = ?1 AND source_node = ?2",
+    fn save_site_key(
+        &self,
+        site_id: &str,
+        signing_key: &[u8],
+    ) -> Result<(), crate::error::PeillutError> {
+        let conn = self.writer();
+        conn.execute(
+            "INSERT OR REPLACE INTO SiteIdentity (site_id, signing_key) VALUES (?1, ?2)",
+            rusqlite::params![site_id, signing_key],
         )?;
-
-        match stmt.query_row(params![transac_time, node], |row| {
-            let from_user: String = row.get(0)?;
-            let to_user: String = row.get(1)?;
-            let amount: f64 = row.get(2)?;
-            let lamport_time: i64 = row.get(3)?;
-            let source_node: String = row.get(4)?;
-            let optional_msg: Option<String> = row.get(5)?;
-            let vector_clock_id: i64 = row.get(6)?;
-
-            let mut clock_map = std::collections::HashMap::new();
-            let mut vc_stmt = conn.prepare(
-                "SELECT site_id, value FROM VectorClockEntry WHERE vector_clock_id = ?1",
-            )?;
-            let mut rows = vc_stmt.query(params![vector_clock_id])?;
-            while let Some(vc_row) = rows.next()? {
-                let site_id: String = vc_row.get(0)?;
-                let value: i64 = vc_row.get(1)?;
-                clock_map.insert(site_id, value);
-            }
-
-            Ok(Transaction {
-                from_user,
-                to_user,
-                amount,
-                lamport_time,
-                source_node,
-                optional_msg,
-                vector_clock: clock_map,
-            })
-        }) {
-            Ok(tx) => Ok(Some(tx)),
-            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
-            Err(e) => Err(e),
-        }
+        Ok(())
     }
-}
 
-#[cfg(feature = "server")]
-pub fn print_users() -> rusqlite::Result<()> {
-    {
-        let conn = DB_CONN.lock().unwrap();
-        let mut stmt = conn.prepare("SELECT unique_name, solde FROM User")?;
-        let users = stmt.query_map([], |row| {
-            Ok((row.get::<_, String>(0)?, row.get::<_, f64>(1)?))
-        })?;
+    fn site_public_keys(
+        &self,
+    ) -> Result<std::collections::HashMap<String, Vec<u8>>, crate::error::PeillutError> {
+        let conn = self.reader();
+        let mut stmt = conn.prepare("SELECT site_id, public_key FROM SitePublicKey")?;
+        let keys = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(keys.collect::<Result<_, _>>()?)
+    }
 
-        println!("-- Users --");
-        for user in users {
-            let (name, solde) = user?;
-            println!("{}: {:.2}", name, solde);
-        }
+    fn save_site_public_key(
+        &self,
+        site_id: &str,
+        public_key: &[u8],
+    ) -> Result<(), crate::error::PeillutError> {
+        let conn = self.writer();
+        conn.execute(
+            "INSERT OR REPLACE INTO SitePublicKey (site_id, public_key) VALUES (?1, ?2)",
+            rusqlite::params![site_id, public_key],
+        )?;
         Ok(())
     }
 }
 
 #[cfg(feature = "server")]
-pub fn get_users() -> rusqlite::Result<Vec<String>> {
-    {
-        let conn = DB_CONN.lock().unwrap();
-        let mut stmt = conn.prepare("SELECT unique_name FROM User")?;
-        let users = stmt.query_map([], |row| Ok(row.get::<_, String>(0)?))?;
-        let mut users_vec = Vec::new();
-        for user in users {
-            users_vec.push(user?);
-        }
-        Ok(users_vec)
+pub fn print_users(
+    store: &dyn crate::store::LedgerStore,
+) -> Result<(), crate::error::PeillutError> {
+    println!("-- Users --");
+    for name in store.users()? {
+        println!("{}: {}", name, store.balance(&name)?);
     }
+    Ok(())
 }
 
 #[cfg(feature = "server")]
-pub fn print_transactions() -> rusqlite::Result<()> {
-    {
-        let conn = DB_CONN.lock().unwrap();
-        let mut stmt = conn.prepare(
-            "SELECT from_user, to_user, amount, lamport_time, source_node, optional_msg, vector_clock_id FROM Transactions",
-        )?;
-        let txs = stmt.query_map([], |row| {
-            Ok((
-                row.get::<_, String>(0)?,
-                row.get::<_, String>(1)?,
-                row.get::<_, f64>(2)?,
-                row.get::<_, i64>(3)?,
-                row.get::<_, String>(4)?,
-                row.get::<_, Option<String>>(5)?,
-                row.get::<_, i64>(6)?,
-            ))
-        })?;
-
-        println!("📜 -- Transactions --");
-        println!(
-            "┌─────────────────┬─────────────────┬────────────┬────────────┬─────────────────┬──────────────────────┬──────────────────────┐"
-        );
-        println!(
-            "│ {:<15} │ {:<15} │ {:<10} │ {:<10} │ {:<15} │ {:<20} │ {:<20} │",
-            "From", "To", "Amount", "Time", "Node", "Message", "Vector Clock"
-        );
-        println!(
-            "├─────────────────┼─────────────────┼────────────┼────────────┼─────────────────┼──────────────────────┼──────────────────────┤"
-        );
-
-        for tx in txs {
-            let (from, to, amount, time, node, msg, vector_clock_id) = tx?;
-            let mut clock_map = std::collections::HashMap::new();
-            let mut vc_stmt = conn.prepare(
-                "SELECT site_id, value FROM VectorClockEntry WHERE vector_clock_id = ?1",
-            )?;
-            let mut rows = vc_stmt.query(rusqlite::params![vector_clock_id])?;
-            while let Some(vc_row) = rows.next()? {
-                let site_id: String = vc_row.get(0)?;
-                let value: i64 = vc_row.get(1)?;
-                clock_map.insert(site_id, value);
-            }
-
-            println!(
-                "│ {:<15} │ {:<15} │ {:<10.2} │ {:<10} │ {:<15} │ {:<20} │ {:<20?} │",
-                from,
-                to,
-                amount,
-                time,
-                node,
-                msg.unwrap_or_default(),
-                clock_map
-            );
-        }
-
-        println!(
-            "└─────────────────┴─────────────────┴────────────┴────────────┴─────────────────┴──────────────────────┴──────────────────────┘"
-        );
-        Ok(())
-    }
+pub fn print_transactions(
+    store: &dyn crate::store::LedgerStore,
+) -> Result<(), crate::error::PeillutError> {
+    println!("📜 -- Transactions --");
+    print_transaction_table(store.transactions()?);
+    Ok(())
 }
 
 #[cfg(feature = "server")]
-pub fn print_transaction_for_user(name: &str) -> rusqlite::Result<()> {
-    use rusqlite::params;
-    {
-        let conn = DB_CONN.lock().unwrap();
-        let mut stmt = conn.prepare(
-            "SELECT from_user, to_user, amount, lamport_time, source_node, optional_msg, vector_clock_id
-        FROM Transactions WHERE from_user = ?1 OR to_user = ?1",
-        )?;
-
-        let txs = stmt.query_map(params![name], |row| {
-            Ok((
-                row.get::<_, String>(0)?,
-                row.get::<_, String>(1)?,
-                row.get::<_, f64>(2)?,
-                row.get::<_, i64>(3)?,
-                row.get::<_, String>(4)?,
-                row.get::<_, Option<String>>(5)?,
-                row.get::<_, i64>(6)?,
-            ))
-        })?;
-
-        println!("📜 -- Transactions for user {} --", name);
-        println!(
-            "┌─────────────────┬─────────────────┬────────────┬────────────┬─────────────────┬──────────────────────┬──────────────────────┐"
-        );
-        println!(
-            "│ {:<15} │ {:<15} │ {:<10} │ {:<10} │ {:<15} │ {:<20} │ {:<20} │",
-            "From", "To", "Amount", "Time", "Node", "Message", "Vector Clock"
-        );
-        println!(
-            "├─────────────────┼─────────────────┼────────────┼────────────┼─────────────────┼──────────────────────┼──────────────────────┤"
-        );
+pub fn print_transaction_for_user(
+    store: &dyn crate::store::LedgerStore,
+    name: &str,
+) -> Result<(), crate::error::PeillutError> {
+    println!("📜 -- Transactions for user {} --", name);
+    print_transaction_table(store.transactions_for_user(name)?);
+    Ok(())
+}
 
-        for tx in txs {
-            let (from, to, amount, time, node, msg, vector_clock_id) = tx?;
-            let mut clock_map = std::collections::HashMap::new();
-            let mut vc_stmt = conn.prepare(
-                "SELECT site_id, value FROM VectorClockEntry WHERE vector_clock_id = ?1",
-            )?;
-            let mut rows = vc_stmt.query(rusqlite::params![vector_clock_id])?;
-            while let Some(vc_row) = rows.next()? {
-                let site_id: String = vc_row.get(0)?;
-                let value: i64 = vc_row.get(1)?;
-                clock_map.insert(site_id, value);
-            }
-            println!(
-                "│ {:<15} │ {:<15} │ {:<10.2} │ {:<10} │ {:<15} │ {:<20} │ {:<20?} │",
-                from,
-                to,
-                amount,
-                time,
-                node,
-                msg.unwrap_or_default(),
-                clock_map
-            );
-        }
+#[cfg(feature = "server")]
+fn print_transaction_table(txs: Vec<Transaction>) {
+    println!(
+        "┌─────────────────┬─────────────────┬────────────┬────────────┬────────────────────────────┬─────────────────┬──────────────────────┬──────────────────────┐"
+    );
+    println!(
+        "│ {:<15} │ {:<15} │ {:<10} │ {:<10} │ {:<26} │ {:<15} │ {:<20} │ {:<20} │",
+        "From", "To", "Amount", "Time", "Date", "Node", "Message", "Vector Clock"
+    );
+    println!(
+        "├─────────────────┼─────────────────┼────────────┼────────────┼────────────────────────────┼─────────────────┼──────────────────────┼──────────────────────┤"
+    );
 
+    for tx in txs {
         println!(
-            "└─────────────────┴─────────────────┴────────────┴────────────┴─────────────────┴──────────────────────┴──────────────────────┘"
+            "│ {:<15} │ {:<15} │ {:<10} │ {:<10} │ {:<26} │ {:<15} │ {:<20} │ {:<20?} │",
+            tx.from_user,
+            tx.to_user,
+            tx.amount,
+            tx.lamport_time,
+            tx.hlc.to_string(),
+            tx.source_node,
+            tx.optional_msg.unwrap_or_default(),
+            tx.vector_clock
         );
-        Ok(())
     }
-}
-
-#[cfg(feature = "server")]
-pub fn get_transactions_for_user(name: &str) -> rusqlite::Result<Vec<Transaction>> {
-    use rusqlite::params;
-    {
-        let conn = DB_CONN.lock().unwrap();
-        let mut stmt = conn.prepare(
-            "SELECT from_user, to_user, amount, lamport_time, source_node, optional_msg, vector_clock_id
-        FROM Transactions WHERE from_user = ?1 OR to_user = ?1",
-        )?;
 
-        let txs = stmt.query_map(params![name], |row| {
-            Ok((
-                row.get::<_, String>(0)?,
-                row.get::<_, String>(1)?,
-                row.get::<_, f64>(2)?,
-                row.get::<_, i64>(3)?,
-                row.get::<_, String>(4)?,
-                row.get::<_, Option<String>>(5)?,
-                row.get::<_, i64>(6)?,
-            ))
-        })?;
-
-        let mut txs_vec = Vec::new();
-        for tx in txs {
-            let (from, to, amount, time, node, msg, vector_clock_id) = tx?;
-            let mut clock_map = std::collections::HashMap::new();
-            let mut vc_stmt = conn.prepare(
-                "SELECT site_id, value FROM VectorClockEntry WHERE vector_clock_id = ?1",
-            )?;
-            let mut rows = vc_stmt.query(rusqlite::params![vector_clock_id])?;
-            while let Some(vc_row) = rows.next()? {
-                let site_id: String = vc_row.get(0)?;
-                let value: i64 = vc_row.get(1)?;
-                clock_map.insert(site_id, value);
-            }
-
-            txs_vec.push(Transaction {
-                from_user: from,
-                to_user: to,
-                amount: amount,
-                lamport_time: time,
-                source_node: node,
-                optional_msg: msg,
-                vector_clock: clock_map,
-            });
-        }
-        Ok(txs_vec)
-    }
+    println!(
+        "└─────────────────┴─────────────────┴────────────┴────────────┴────────────────────────────┴─────────────────┴──────────────────────┴──────────────────────┘"
+    );
 }
 
+#[cfg(test)]
 #[cfg(feature = "server")]
-pub fn get_local_transaction_log() -> rusqlite::Result<Vec<Transaction>> {
-    let conn = DB_CONN.lock().unwrap();
-    let mut stmt = conn.prepare(
-    "SELECT from_user, to_user, amount, lamport_time, source_node, optional_msg, vector_clock_id
-        FROM Transactions")?;
-    let rows = stmt.query_map([], |row| {
-        Ok(Transaction {
-            from_user: row.get(0)?,
-            to_user: row.get(1)?,
-            amount: row.get(2)?,
-            lamport_time: row.get(3)?,
-            source_node: row.get(4)?,
-            optional_msg: row.get(5)?,
-            vector_clock: std::collections::HashMap::new(),
-        })
-    })?;
+mod tests {
+    use super::*;
+    use crate::store::LedgerStore;
+
+    #[test]
+    fn only_the_last_local_clock_is_kept() {
+        let store = SqliteStore::in_memory().unwrap();
+        let mut clock = crate::clock::Clock::new();
+        for _ in 0..3 {
+            clock.update_clock("A", None);
+            store.save_local_state("A", &clock).unwrap();
+        }
 
-    let mut out = Vec::new();
-    for r in rows {
-        out.push(r?);
+        let conn = store.writer.lock().unwrap();
+        let clocks: i64 = conn
+            .query_row("SELECT COUNT(*) FROM VectorClock", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(clocks, 1);
+    }
+
+    #[test]
+    fn readers_see_the_committed_ledger() {
+        let path = std::env::temp_dir().join(format!("peillute_readers_{}.db", std::process::id()));
+        let store = SqliteStore::open(path.to_str().unwrap()).unwrap();
+        let journal_mode: String = store
+            .writer
+            .lock()
+            .unwrap()
+            .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(journal_mode, "wal");
+
+        // Another thread reads the ledger as it was before the open group
+        let reader_sees_bob = || -> bool {
+            std::thread::scope(|scope| {
+                scope
+                    .spawn(|| store.user_exists("bob").unwrap())
+                    .join()
+                    .unwrap()
+            })
+        };
+        let money = crate::money::Money::from_euros(10);
+        let hlc = crate::clock::HybridTimestamp::default();
+        store.create_user("alice").unwrap();
+        store.begin().unwrap();
+        store.create_user("bob").unwrap();
+        store
+            .deposit(
+                "bob",
+                money,
+                &1,
+                &hlc,
+                "A",
+                &std::collections::HashMap::new(),
+            )
+            .unwrap();
+        assert_eq!(store.balance("bob").unwrap(), money);
+        assert!(!reader_sees_bob());
+        store.commit().unwrap();
+        assert!(reader_sees_bob());
+        assert_eq!(store.users().unwrap().len(), 2);
+
+        drop(store);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
     }
-    Ok(out)
 }
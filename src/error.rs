@@ -0,0 +1,111 @@
+//! Errors of the Peillute application
+//!
+//! The ledger rejects a command with a business error carrying what went wrong, so that
+//! the CLI and the web interface can tell the user. Failures of the database and of the
+//! network are wrapped so that they flow through the same type.
+
+#[cfg(feature = "server")]
+/// Error of a ledger operation
+#[derive(Debug)]
+pub enum PeillutError {
+    /// The account has less money than the transaction takes
+    InsufficientFunds {
+        user: String,
+        balance: crate::money::Money,
+        amount: crate::money::Money,
+    },
+    /// No account has this name
+    UnknownUser { user: String },
+    /// Accounts need a name
+    EmptyUserName,
+    /// Amounts must be positive
+    InvalidAmount { amount: crate::money::Money },
+    /// The amount does not fit in an account
+    AmountOverflow,
+    /// No transaction has this key
+    UnknownTransaction {
+        transaction: crate::db::TransactionKey,
+    },
+    /// The transaction was refunded already
+    AlreadyRefunded {
+        transaction: crate::db::TransactionKey,
+    },
+    /// The transaction is a refund, which cannot be refunded
+    NotRefundable {
+        transaction: crate::db::TransactionKey,
+    },
+    /// A transaction with this key is recorded already
+    DuplicateTransaction {
+        transaction: crate::db::TransactionKey,
+    },
+    /// The database failed
+    Storage(rusqlite::Error),
+    /// A message could not reach the other sites
+    Network(String),
+    /// The snapshot could not be completed
+    Snapshot(String),
+    /// The database was migrated by a newer build, see [`crate::migration`]
+    UnsupportedSchema { version: i64, supported: i64 },
+}
+
+#[cfg(feature = "server")]
+impl std::fmt::Display for PeillutError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeillutError::InsufficientFunds {
+                user,
+                balance,
+                amount,
+            } => write!(
+                f,
+                "Insufficient funds: {} has {}, {} needed",
+                user, balance, amount
+            ),
+            PeillutError::UnknownUser { user } => write!(f, "Unknown user: {}", user),
+            PeillutError::EmptyUserName => write!(f, "Username cannot be empty"),
+            PeillutError::InvalidAmount { amount } => {
+                write!(f, "Invalid amount {}, it must be positive", amount)
+            }
+            PeillutError::AmountOverflow => write!(f, "Amount too large"),
+            PeillutError::UnknownTransaction { transaction } => {
+                write!(f, "No transaction {}", transaction)
+            }
+            PeillutError::AlreadyRefunded { transaction } => {
+                write!(f, "Transaction {} was already refunded", transaction)
+            }
+            PeillutError::NotRefundable { transaction } => write!(
+                f,
+                "Transaction {} is a refund, it cannot be refunded",
+                transaction
+            ),
+            PeillutError::DuplicateTransaction { transaction } => {
+                write!(f, "Transaction {} is recorded already", transaction)
+            }
+            PeillutError::Storage(e) => write!(f, "Database error: {}", e),
+            PeillutError::Network(reason) => write!(f, "Network error: {}", reason),
+            PeillutError::Snapshot(reason) => write!(f, "Snapshot failed: {}", reason),
+            PeillutError::UnsupportedSchema { version, supported } => write!(
+                f,
+                "Database schema version {} is newer than the version {} this build supports",
+                version, supported
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl std::error::Error for PeillutError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PeillutError::Storage(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl From<rusqlite::Error> for PeillutError {
+    fn from(e: rusqlite::Error) -> Self {
+        PeillutError::Storage(e)
+    }
+}
@@ -0,0 +1,280 @@
+//! Heartbeat failure detector for neighbours
+//!
+//! Every site periodically sends a heartbeat to its neighbours, and any message received
+//! from a neighbour counts as a sign of life. Following the phi-accrual failure detector,
+//! the inter-arrival times of these messages are used to compute a suspicion level `phi`
+//! for the current silence of each neighbour, instead of relying on a fixed timeout.
+//! A neighbour whose `phi` crosses the threshold is suspected and removed from the network.
+
+/// Default interval between two heartbeats sent to a neighbour, in milliseconds
+pub const DEFAULT_HEARTBEAT_INTERVAL_MS: u64 = 1000;
+
+/// Default suspicion threshold, a neighbour is suspected when its `phi` exceeds it
+///
+/// With `phi = 8`, the probability that a live neighbour is wrongly suspected is about 10^-8
+pub const DEFAULT_PHI_THRESHOLD: f64 = 8.0;
+
+#[cfg(feature = "server")]
+/// Number of inter-arrival times kept per neighbour
+const MAX_SAMPLES: usize = 100;
+
+/// Suspicion state of a neighbour, as shown on the Info page
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct PeerHealth {
+    /// Address of the neighbour
+    pub addr: String,
+    /// Current suspicion level
+    pub phi: f64,
+    /// Whether the neighbour has been suspected and removed
+    pub suspected: bool,
+}
+
+#[cfg(feature = "server")]
+/// Arrival times of the messages received from a neighbour
+struct ArrivalWindow {
+    /// Arrival time of the last message
+    last: tokio::time::Instant,
+    /// Last inter-arrival times, in milliseconds
+    intervals: std::collections::VecDeque<f64>,
+}
+
+#[cfg(feature = "server")]
+impl ArrivalWindow {
+    /// Returns the mean and the standard deviation of the inter-arrival times
+    fn stats(&self) -> (f64, f64) {
+        let n = self.intervals.len() as f64;
+        let mean = self.intervals.iter().sum::<f64>() / n;
+        let variance = self
+            .intervals
+            .iter()
+            .map(|x| (x - mean) * (x - mean))
+            .sum::<f64>()
+            / n;
+        (mean, variance.sqrt())
+    }
+}
+
+#[cfg(feature = "server")]
+/// Phi-accrual failure detector over the neighbours of the site
+pub struct FailureDetector {
+    /// Expected interval between two heartbeats
+    heartbeat_interval: std::time::Duration,
+    /// Suspicion threshold
+    threshold: f64,
+    /// Arrival history of each monitored neighbour
+    arrivals: std::collections::HashMap<std::net::SocketAddr, ArrivalWindow>,
+    /// Neighbours suspected to have failed
+    suspected: std::collections::HashSet<std::net::SocketAddr>,
+}
+
+#[cfg(feature = "server")]
+impl Default for FailureDetector {
+    fn default() -> Self {
+        Self::new(
+            std::time::Duration::from_millis(DEFAULT_HEARTBEAT_INTERVAL_MS),
+            DEFAULT_PHI_THRESHOLD,
+        )
+    }
+}
+
+#[cfg(feature = "server")]
+impl FailureDetector {
+    /// Creates a detector for heartbeats sent every `heartbeat_interval`
+    pub fn new(heartbeat_interval: std::time::Duration, threshold: f64) -> Self {
+        Self {
+            heartbeat_interval,
+            threshold,
+            arrivals: std::collections::HashMap::new(),
+            suspected: std::collections::HashSet::new(),
+        }
+    }
+
+    /// Returns the expected interval between two heartbeats
+    pub fn get_heartbeat_interval(&self) -> std::time::Duration {
+        self.heartbeat_interval
+    }
+
+    /// Records a message received from a neighbour at `now`
+    ///
+    /// Returns true if the neighbour was suspected until now
+    pub fn heartbeat(&mut self, addr: std::net::SocketAddr, now: tokio::time::Instant) -> bool {
+        match self.arrivals.get_mut(&addr) {
+            Some(window) => {
+                let interval = now.saturating_duration_since(window.last);
+                window.last = now;
+                if window.intervals.len() == MAX_SAMPLES {
+                    window.intervals.pop_front();
+                }
+                window.intervals.push_back(interval.as_secs_f64() * 1000.0);
+            }
+            None => {
+                // The history starts with the expected interval, so that a neighbour
+                // that never sends a second message is still suspected
+                let expected = self.heartbeat_interval.as_secs_f64() * 1000.0;
+                self.arrivals.insert(
+                    addr,
+                    ArrivalWindow {
+                        last: now,
+                        intervals: std::collections::VecDeque::from([expected]),
+                    },
+                );
+            }
+        }
+        self.suspected.remove(&addr)
+    }
+
+    /// Returns the suspicion level of a neighbour at `now`
+    ///
+    /// Uses the logistic approximation of the normal distribution of the inter-arrival times
+    pub fn phi(&self, addr: &std::net::SocketAddr, now: tokio::time::Instant) -> Option<f64> {
+        let window = self.arrivals.get(addr)?;
+        let (mean, std_dev) = window.stats();
+        // Short bursts of messages must not make the detector oversensitive
+        let std_dev = std_dev.max(self.heartbeat_interval.as_secs_f64() * 1000.0 / 4.0);
+
+        let elapsed = now.saturating_duration_since(window.last).as_secs_f64() * 1000.0;
+        let y = (elapsed - mean) / std_dev;
+        let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+        let p_later = if elapsed > mean {
+            e / (1.0 + e)
+        } else {
+            1.0 - 1.0 / (1.0 + e)
+        };
+        Some(-p_later.max(f64::MIN_POSITIVE).log10())
+    }
+
+    /// Returns the neighbours that crossed the threshold since the last call
+    pub fn take_suspects(&mut self, now: tokio::time::Instant) -> Vec<std::net::SocketAddr> {
+        let suspects: Vec<_> = self
+            .arrivals
+            .keys()
+            .filter(|addr| !self.suspected.contains(addr))
+            .filter(|addr| self.phi(addr, now).unwrap_or(0.0) > self.threshold)
+            .copied()
+            .collect();
+        self.suspected.extend(suspects.iter().copied());
+        suspects
+    }
+
+    /// Stops monitoring a neighbour that left the network
+    ///
+    /// A suspected neighbour stays marked as such until it sends a message again
+    pub fn forget(&mut self, addr: &std::net::SocketAddr) {
+        self.arrivals.remove(addr);
+    }
+
+    /// Returns the suspicion state of the monitored and suspected neighbours
+    pub fn get_health(&self, now: tokio::time::Instant) -> Vec<PeerHealth> {
+        self.arrivals
+            .keys()
+            .chain(self.suspected.iter())
+            .collect::<std::collections::BTreeSet<_>>()
+            .into_iter()
+            .map(|addr| PeerHealth {
+                addr: addr.to_string(),
+                // A neighbour that is not monitored anymore gets the highest suspicion level
+                phi: self.phi(addr, now).unwrap_or(-f64::MIN_POSITIVE.log10()),
+                suspected: self.suspected.contains(addr),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "server")]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    fn addr(port: u16) -> std::net::SocketAddr {
+        format!("127.0.0.1:{}", port).parse().unwrap()
+    }
+
+    #[test]
+    fn regular_neighbour_is_not_suspected() {
+        let mut detector = FailureDetector::default();
+        let start = Instant::now();
+        for i in 0..20 {
+            detector.heartbeat(addr(1), start + Duration::from_millis(1000 * i));
+        }
+        let last = start + Duration::from_millis(19_000);
+
+        assert!(detector.phi(&addr(1), last).unwrap() < 1.0);
+        assert!(
+            detector
+                .take_suspects(last + Duration::from_millis(1500))
+                .is_empty()
+        );
+        assert!(detector.phi(&addr(2), last).is_none());
+    }
+
+    #[test]
+    fn silent_neighbour_is_suspected_once() {
+        let mut detector = FailureDetector::default();
+        let start = Instant::now();
+        for i in 0..10 {
+            detector.heartbeat(addr(1), start + Duration::from_millis(1000 * i));
+            detector.heartbeat(addr(2), start + Duration::from_millis(1000 * i));
+        }
+        // Only the second neighbour keeps sending heartbeats
+        for i in 10..15 {
+            detector.heartbeat(addr(2), start + Duration::from_millis(1000 * i));
+        }
+        let now = start + Duration::from_millis(14_000);
+
+        let phi = detector.phi(&addr(1), now).unwrap();
+        assert!(phi > DEFAULT_PHI_THRESHOLD);
+        assert_eq!(detector.take_suspects(now), vec![addr(1)]);
+        assert!(detector.take_suspects(now).is_empty());
+
+        detector.forget(&addr(1));
+        let health = detector.get_health(now);
+        assert_eq!(health.len(), 2);
+        assert!(health[0].suspected && !health[1].suspected);
+    }
+
+    #[test]
+    fn neighbour_that_never_speaks_again_is_suspected() {
+        let mut detector = FailureDetector::default();
+        let start = Instant::now();
+        detector.heartbeat(addr(1), start);
+
+        assert!(
+            detector
+                .take_suspects(start + Duration::from_millis(1200))
+                .is_empty()
+        );
+        assert_eq!(
+            detector.take_suspects(start + Duration::from_millis(5000)),
+            vec![addr(1)]
+        );
+    }
+
+    #[test]
+    fn suspicion_is_cleared_by_a_new_message() {
+        let mut detector = FailureDetector::default();
+        let start = Instant::now();
+        detector.heartbeat(addr(1), start);
+        let later = start + Duration::from_secs(10);
+        assert_eq!(detector.take_suspects(later), vec![addr(1)]);
+
+        assert!(detector.heartbeat(addr(1), later));
+        assert!(!detector.heartbeat(addr(1), later));
+        assert!(!detector.get_health(later)[0].suspected);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silence_is_measured_on_the_paused_clock() {
+        let mut detector = FailureDetector::default();
+        for _ in 0..10 {
+            detector.heartbeat(addr(1), Instant::now());
+            tokio::time::sleep(Duration::from_millis(1000)).await;
+        }
+        assert!(detector.take_suspects(Instant::now()).is_empty());
+
+        // Only the simulated time passes
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert_eq!(detector.take_suspects(Instant::now()), vec![addr(1)]);
+    }
+}
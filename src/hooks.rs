@@ -32,18 +32,38 @@ where
     F: Fn() -> Fut + 'static,
     Fut: Future<Output = ()> + 'static,
 {
-    use_effect(move || {
-        let mut interval = tokio::time::interval(tokio::time::Duration::from_millis(interval_ms));
+    let fetcher = std::rc::Rc::new(fetcher);
 
-        let task = spawn(async move {
+    use_future(move || {
+        let fetcher = fetcher.clone();
+        async move {
             loop {
-                interval.tick().await;
                 fetcher().await;
+                sleep(interval_ms).await;
             }
-        });
+        }
+    });
+}
 
-        move || drop(task)
+/// Waits for `ms` milliseconds
+#[cfg(feature = "server")]
+async fn sleep(ms: u64) {
+    tokio::time::sleep(std::time::Duration::from_millis(ms)).await;
+}
+
+/// Waits for `ms` milliseconds
+///
+/// The browser has no tokio timer, the promise of a `setTimeout` is awaited instead
+#[cfg(not(feature = "server"))]
+async fn sleep(ms: u64) {
+    let global = js_sys::global();
+    let promise = js_sys::Promise::new(&mut |resolve, _| {
+        if let Ok(set_timeout) = js_sys::Reflect::get(&global, &"setTimeout".into()) {
+            let set_timeout = js_sys::Function::from(set_timeout);
+            let _ = set_timeout.call2(&global, &resolve, &(ms as f64).into());
+        }
     });
+    let _ = wasm_bindgen_futures::JsFuture::from(promise).await;
 }
 
 /// Hook to automatically refresh resource data
@@ -57,29 +77,29 @@ where
 ///
 /// # Returns
 /// * A trigger function that can be called to manually refresh
-pub fn use_auto_refresh_resource<T>(
-    interval_ms: u64,
-    key: T,
-) -> impl Fn()
+#[allow(dead_code)]
+pub fn use_auto_refresh_resource<T>(interval_ms: u64, key: T) -> impl Fn()
 where
     T: Clone + PartialEq + 'static,
 {
     let refresh_counter = use_signal(|| 0);
 
     use_auto_refresh(interval_ms, {
-        let refresh_counter = refresh_counter;
         let key = key.clone();
         move || {
-            let refresh_counter = refresh_counter;
-            let key = key.clone();
+            let mut refresh_counter = refresh_counter;
+            let _key = key.clone();
             async move {
                 // Trigger refresh by incrementing counter
-                refresh_counter.set(refresh_counter.read() + 1);
+                let next = *refresh_counter.read() + 1;
+                refresh_counter.set(next);
             }
         }
     });
 
     move || {
-        refresh_counter.set(refresh_counter.read() + 1);
+        let mut refresh_counter = refresh_counter;
+        let next = *refresh_counter.read() + 1;
+        refresh_counter.set(next);
     }
 }
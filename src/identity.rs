@@ -0,0 +1,375 @@
+//! Site identities and message signatures
+//!
+//! Every site owns a persistent Ed25519 key pair, stored in its database next to the
+//! local state. The messages changing the state of the sites they reach are signed by the
+//! site that initiated them, so a relay can check that a wave really comes from the site
+//! it claims and that it was not modified on the way before applying or forwarding it.
+//! The local snapshot of a site is signed by it too, as relays gather the snapshots of
+//! their children in their echo.
+//!
+//! The first public key seen for a site is pinned and saved in the database, any other
+//! key is refused afterwards, even once the site restarted.
+
+#[cfg(feature = "server")]
+/// Errors raised while signing or verifying a message
+#[derive(Debug)]
+pub enum SignatureError {
+    /// The message carries no signature
+    Missing,
+    /// The signature does not match the message content
+    Forged,
+    /// The initiator is known under another public key
+    KeyMismatch { site_id: String },
+    /// The key pair could not be generated or loaded
+    Key(String),
+    /// The signed fields could not be serialized
+    Encode(rmp_serde::encode::Error),
+}
+
+#[cfg(feature = "server")]
+impl std::fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SignatureError::Missing => write!(f, "message is not signed"),
+            SignatureError::Forged => write!(f, "signature does not match the message"),
+            SignatureError::KeyMismatch { site_id } => {
+                write!(f, "site {} is known under another key", site_id)
+            }
+            SignatureError::Key(e) => write!(f, "invalid site key: {}", e),
+            SignatureError::Encode(e) => write!(f, "unable to encode signed fields: {}", e),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl std::error::Error for SignatureError {}
+
+#[cfg(feature = "server")]
+/// Ed25519 key pair identifying a site
+pub struct SiteIdentity {
+    key_pair: ring::signature::Ed25519KeyPair,
+    /// PKCS#8 document of the key pair, as stored in the database
+    pkcs8: Vec<u8>,
+}
+
+#[cfg(feature = "server")]
+impl SiteIdentity {
+    /// Generates a new random key pair
+    pub fn generate() -> Result<Self, SignatureError> {
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8 = ring::signature::Ed25519KeyPair::generate_pkcs8(&rng)
+            .map_err(|e| SignatureError::Key(e.to_string()))?;
+        Self::from_pkcs8(pkcs8.as_ref())
+    }
+
+    /// Loads a key pair from its PKCS#8 document
+    pub fn from_pkcs8(pkcs8: &[u8]) -> Result<Self, SignatureError> {
+        let key_pair = ring::signature::Ed25519KeyPair::from_pkcs8(pkcs8)
+            .map_err(|e| SignatureError::Key(e.to_string()))?;
+        Ok(Self {
+            key_pair,
+            pkcs8: pkcs8.to_vec(),
+        })
+    }
+
+    /// Returns the PKCS#8 document of the key pair
+    pub fn to_pkcs8(&self) -> &[u8] {
+        &self.pkcs8
+    }
+
+    /// Returns the public key of the site
+    pub fn public_key(&self) -> Vec<u8> {
+        use ring::signature::KeyPair;
+        self.key_pair.public_key().as_ref().to_vec()
+    }
+
+    /// Signs a message initiated by this site
+    pub fn sign(&self, msg: &mut crate::message::Message) -> Result<(), SignatureError> {
+        msg.signature = Some(self.signature(&signed_bytes(msg)?));
+        Ok(())
+    }
+
+    /// Signs the local snapshot of this site
+    pub fn sign_snapshot(
+        &self,
+        snapshot: &mut crate::message::SnapshotResponse,
+    ) -> Result<(), SignatureError> {
+        snapshot.signature = Some(self.signature(&snapshot_bytes(snapshot)?));
+        Ok(())
+    }
+
+    fn signature(&self, bytes: &[u8]) -> crate::message::MessageSignature {
+        crate::message::MessageSignature {
+            public_key: self.public_key(),
+            signature: self.key_pair.sign(bytes).as_ref().to_vec(),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+/// Signing key of the site run by the current node
+pub fn site_identity() -> Result<std::sync::Arc<SiteIdentity>, SignatureError> {
+    crate::node::current()
+        .identity
+        .get()
+        .cloned()
+        .ok_or_else(|| SignatureError::Key("the site has not started".to_string()))
+}
+
+#[cfg(feature = "server")]
+/// Serializes the fields of a message covered by the signature
+///
+/// The sender fields are left out since relays rewrite them, the vector clock
+/// and the causal dependencies are sorted so that every site computes the same bytes
+fn signed_bytes(msg: &crate::message::Message) -> Result<Vec<u8>, SignatureError> {
+    let vector_clock: std::collections::BTreeMap<_, _> =
+        msg.clock.get_vector_clock_map().iter().collect();
+    let causal_deps: Option<std::collections::BTreeMap<_, _>> =
+        msg.causal_deps.as_ref().map(|deps| deps.iter().collect());
+    rmp_serde::encode::to_vec(&(
+        &msg.message_initiator_id,
+        &msg.message_initiator_addr,
+        msg.clock.get_lamport(),
+        &vector_clock,
+        msg.clock.get_hlc(),
+        &msg.command,
+        &msg.info,
+        &msg.code,
+        &msg.wave_id,
+        &causal_deps,
+    ))
+    .map_err(SignatureError::Encode)
+}
+
+#[cfg(feature = "server")]
+/// Serializes the fields of a local snapshot covered by the signature
+fn snapshot_bytes(snapshot: &crate::message::SnapshotResponse) -> Result<Vec<u8>, SignatureError> {
+    let vector_clock: std::collections::BTreeMap<_, _> =
+        snapshot.clock.get_vector_clock_map().iter().collect();
+    rmp_serde::encode::to_vec(&(
+        &snapshot.site_id,
+        snapshot.clock.get_lamport(),
+        &vector_clock,
+        snapshot.clock.get_hlc(),
+        &snapshot.tx_log,
+    ))
+    .map_err(SignatureError::Encode)
+}
+
+#[cfg(feature = "server")]
+/// Checks that a message was signed with the public key it carries
+///
+/// Returns that public key, the caller is responsible for checking that it
+/// belongs to the initiator of the message, see [`check_site_key`]
+pub fn verify(msg: &crate::message::Message) -> Result<&[u8], SignatureError> {
+    check_signature(msg.signature.as_ref(), &signed_bytes(msg)?)
+}
+
+#[cfg(feature = "server")]
+/// Checks that a local snapshot was signed with the public key it carries
+///
+/// Returns that public key, which must belong to the site of the snapshot
+pub fn verify_snapshot(
+    snapshot: &crate::message::SnapshotResponse,
+) -> Result<&[u8], SignatureError> {
+    check_signature(snapshot.signature.as_ref(), &snapshot_bytes(snapshot)?)
+}
+
+#[cfg(feature = "server")]
+fn check_signature<'a>(
+    signature: Option<&'a crate::message::MessageSignature>,
+    bytes: &[u8],
+) -> Result<&'a [u8], SignatureError> {
+    let signature = signature.ok_or(SignatureError::Missing)?;
+    ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, &signature.public_key)
+        .verify(bytes, &signature.signature)
+        .map_err(|_| SignatureError::Forged)?;
+    Ok(&signature.public_key)
+}
+
+#[cfg(feature = "server")]
+/// Checks that `public_key` belongs to `site_id`, see `AppState::check_site_key`
+///
+/// A key pinned for the first time is saved in `store`, the ledger of the site. It stays
+/// pinned for this run if it cannot be saved
+pub async fn check_site_key(
+    store: &std::sync::Arc<dyn crate::store::LedgerStore>,
+    site_id: &str,
+    public_key: &[u8],
+) -> Result<(), SignatureError> {
+    let pinned = crate::state::LOCAL_APP_STATE
+        .lock()
+        .await
+        .check_site_key(site_id, public_key)?;
+    if pinned {
+        let (site, key) = (site_id.to_string(), public_key.to_vec());
+        if let Err(e) =
+            crate::store::blocking(store, move |store| store.save_site_public_key(&site, &key))
+                .await
+        {
+            log::error!("Unable to save the public key of site {}: {}", site_id, e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[cfg(feature = "server")]
+mod tests {
+    use super::*;
+    use crate::message::{Deposit, Message, MessageInfo, NetworkMessageCode};
+
+    fn mk_deposit(amount: crate::money::Money) -> Message {
+        Message {
+            sender_id: "A".to_string(),
+            sender_addr: "127.0.0.1:8080".parse().unwrap(),
+            message_initiator_id: "A".to_string(),
+            message_initiator_addr: "127.0.0.1:8080".parse().unwrap(),
+            clock: crate::clock::Clock::new_with_values(
+                3,
+                [("A".to_string(), 2), ("B".to_string(), 1)].into(),
+            ),
+            command: Some(crate::control::Command::Deposit),
+            info: MessageInfo::Deposit(Deposit::new("alice".to_string(), amount)),
+            code: NetworkMessageCode::Transaction,
+            signature: None,
+            wave_id: Some(crate::message::WaveId {
+                initiator: "A".to_string(),
+                seq: 1,
+            }),
+            causal_deps: None,
+        }
+    }
+
+    #[test]
+    fn signed_message_is_verified_after_relay() {
+        let identity = SiteIdentity::generate().unwrap();
+        let mut msg = mk_deposit(crate::money::Money::from_euros(10));
+        identity.sign(&mut msg).unwrap();
+
+        // A relay rewrites the sender fields and the message goes through the wire
+        msg.sender_id = "B".to_string();
+        msg.sender_addr = "127.0.0.1:8081".parse().unwrap();
+        let payload = rmp_serde::encode::to_vec(&msg).unwrap();
+        let msg: Message = rmp_serde::decode::from_slice(&payload).unwrap();
+
+        assert_eq!(verify(&msg).unwrap(), identity.public_key().as_slice());
+    }
+
+    #[test]
+    fn modified_or_unsigned_message_is_rejected() {
+        let identity = SiteIdentity::generate().unwrap();
+        let mut msg = mk_deposit(crate::money::Money::from_euros(10));
+        assert!(matches!(verify(&msg), Err(SignatureError::Missing)));
+
+        identity.sign(&mut msg).unwrap();
+        msg.info = MessageInfo::Deposit(Deposit::new(
+            "alice".to_string(),
+            crate::money::Money::from_euros(10000),
+        ));
+        assert!(matches!(verify(&msg), Err(SignatureError::Forged)));
+
+        let mut msg = mk_deposit(crate::money::Money::from_euros(10));
+        identity.sign(&mut msg).unwrap();
+        msg.message_initiator_id = "C".to_string();
+        assert!(matches!(verify(&msg), Err(SignatureError::Forged)));
+
+        // The wave id is covered too, a relay can not move the transaction to another wave
+        let mut msg = mk_deposit(crate::money::Money::from_euros(10));
+        identity.sign(&mut msg).unwrap();
+        msg.wave_id.as_mut().unwrap().seq = 2;
+        assert!(matches!(verify(&msg), Err(SignatureError::Forged)));
+    }
+
+    #[test]
+    fn acknowledgment_state_can_not_be_forged() {
+        let identity = SiteIdentity::generate().unwrap();
+        let mut msg = mk_deposit(crate::money::Money::from_euros(10));
+        msg.code = NetworkMessageCode::Acknowledgment;
+        msg.command = None;
+        msg.wave_id = None;
+        msg.info = MessageInfo::Acknowledge(crate::message::AcknowledgePayload {
+            global_fifo: Default::default(),
+            delivered: [("A".to_string(), 1)].into(),
+        });
+        assert!(msg.code.is_signed());
+        identity.sign(&mut msg).unwrap();
+
+        // A relay can not make the site skip transactions it never delivered
+        if let MessageInfo::Acknowledge(payload) = &mut msg.info {
+            payload.delivered.insert("A".to_string(), 9);
+        }
+        assert!(matches!(verify(&msg), Err(SignatureError::Forged)));
+    }
+
+    #[test]
+    fn gathered_snapshot_is_checked_against_its_site() {
+        let identity = SiteIdentity::generate().unwrap();
+        let mut snapshot = crate::message::SnapshotResponse {
+            site_id: "B".to_string(),
+            clock: crate::clock::Clock::new_with_values(
+                2,
+                [("A".to_string(), 1), ("B".to_string(), 1)].into(),
+            ),
+            tx_log: Vec::new(),
+            signature: None,
+        };
+        assert!(matches!(
+            verify_snapshot(&snapshot),
+            Err(SignatureError::Missing)
+        ));
+
+        identity.sign_snapshot(&mut snapshot).unwrap();
+        assert_eq!(
+            verify_snapshot(&snapshot).unwrap(),
+            identity.public_key().as_slice()
+        );
+
+        // A relay adding a transaction to the snapshot of its child is caught
+        snapshot.tx_log.push(crate::snapshot::TxSummary {
+            lamport_time: 1,
+            source_node: "B".to_string(),
+            from_user: crate::db::NULL.to_string(),
+            to_user: "mallory".to_string(),
+            amount: crate::money::Money::from_euros(1000),
+            hlc: Default::default(),
+        });
+        assert!(matches!(
+            verify_snapshot(&snapshot),
+            Err(SignatureError::Forged)
+        ));
+    }
+
+    #[tokio::test]
+    async fn pinned_key_is_saved_in_the_ledger() {
+        let node = crate::node::Node::in_memory();
+        node.run(async {
+            check_site_key(&node.store, "B", &[2; 32]).await.unwrap();
+            check_site_key(&node.store, "B", &[2; 32]).await.unwrap();
+            assert!(matches!(
+                check_site_key(&node.store, "B", &[3; 32]).await,
+                Err(SignatureError::KeyMismatch { .. })
+            ));
+        })
+        .await;
+
+        // A restarted site reloads the key it pinned
+        let pinned = node.store.site_public_keys().unwrap();
+        assert_eq!(pinned.len(), 1);
+        let mut state = crate::state::AppState::new(
+            "A".to_string(),
+            Vec::new(),
+            "127.0.0.1:8080".parse().unwrap(),
+        );
+        state.init_site_keys(pinned);
+        assert!(state.check_site_key("B", &[3; 32]).is_err());
+    }
+
+    #[test]
+    fn identity_is_reloaded_from_pkcs8() {
+        let identity = SiteIdentity::generate().unwrap();
+        let reloaded = SiteIdentity::from_pkcs8(identity.to_pkcs8()).unwrap();
+        assert_eq!(identity.public_key(), reloaded.public_key());
+        assert!(SiteIdentity::from_pkcs8(b"not a key").is_err());
+    }
+}
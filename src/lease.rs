@@ -0,0 +1,548 @@
+//! Leases of the requests for the critical section
+//!
+//! With the `fifo` mutex, a request stays in the FIFO of every site until its site releases
+//! it. A site dying in the critical section, or while waiting for it, would block every
+//! request behind its own. Only its neighbours would forget it, when they lose the
+//! connection. Each request is therefore granted for a lease, which its site renews by a
+//! wave while the request is pending. A site seeing a lease expire evicts the request on
+//! every site by a wave. The evictions are logged and shown on the Info page.
+//!
+//! A site stops running its commands once its own lease expired and asks again for the
+//! critical section. The command running at that moment is finished, so the lease must be
+//! longer than a command.
+//!
+//! With the `token` mutex, a site whose request waited for a whole lease asks again, so
+//! that a token lost with its holder is created again, see `crate::mutex`.
+
+/// Default duration of the lease of a mutex request, in milliseconds
+pub const DEFAULT_LEASE_MS: u64 = 5000;
+
+#[cfg(feature = "server")]
+/// Number of evictions kept for the Info page
+const MAX_EVICTIONS: usize = 20;
+
+/// Eviction of a mutex request whose lease expired, as shown on the Info page
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct MutexEviction {
+    /// ID of the evicted site
+    pub site_id: String,
+    /// ID of the site which saw the lease expire
+    pub evicted_by: String,
+    /// Local time of the eviction
+    pub at: String,
+}
+
+#[cfg(feature = "server")]
+/// Leases of the mutex requests known by the site
+pub struct Leases {
+    /// Duration of a lease
+    duration: std::time::Duration,
+    /// Expiry of the lease of the requests of the other sites, by site and Lamport date
+    deadlines: std::collections::HashMap<(String, i64), tokio::time::Instant>,
+    /// Expiry of the lease of our pending request, as of its last renewal echoed by
+    /// every site
+    own_expiry: Option<tokio::time::Instant>,
+    /// Last evictions, oldest first
+    evictions: std::collections::VecDeque<MutexEviction>,
+}
+
+#[cfg(feature = "server")]
+impl Default for Leases {
+    fn default() -> Self {
+        Self::new(std::time::Duration::from_millis(DEFAULT_LEASE_MS))
+    }
+}
+
+#[cfg(feature = "server")]
+impl Leases {
+    /// Creates the leases of a site, each one granted for `duration`
+    pub fn new(duration: std::time::Duration) -> Self {
+        Self {
+            duration,
+            deadlines: std::collections::HashMap::new(),
+            own_expiry: None,
+            evictions: std::collections::VecDeque::new(),
+        }
+    }
+
+    /// Returns the duration of a lease
+    pub fn get_duration(&self) -> std::time::Duration {
+        self.duration
+    }
+
+    /// Renews the lease of the request of `site_id` made at Lamport date `date`
+    pub fn renew(&mut self, site_id: &str, date: i64, now: tokio::time::Instant) {
+        self.deadlines
+            .insert((site_id.to_string(), date), now + self.duration);
+    }
+
+    /// Returns the requests whose lease expired at `now` among the pending `requests`
+    ///
+    /// A request seen for the first time gets a lease, the leases of the requests that
+    /// are not pending anymore are dropped
+    pub fn take_expired(
+        &mut self,
+        requests: Vec<(String, i64)>,
+        now: tokio::time::Instant,
+    ) -> Vec<(String, i64)> {
+        let mut deadlines = std::collections::HashMap::new();
+        let mut expired = Vec::new();
+        for request in requests {
+            let deadline = self
+                .deadlines
+                .get(&request)
+                .copied()
+                .unwrap_or(now + self.duration);
+            if deadline <= now {
+                expired.push(request);
+            } else {
+                deadlines.insert(request, deadline);
+            }
+        }
+        self.deadlines = deadlines;
+        expired.sort();
+        expired
+    }
+
+    /// Starts the lease of our request, made at `now`
+    pub fn start(&mut self, now: tokio::time::Instant) {
+        self.own_expiry = Some(now + self.duration);
+    }
+
+    /// Extends the lease of our request after a renewal started at `sent` reached every
+    /// site
+    pub fn renewed(&mut self, sent: tokio::time::Instant) {
+        if let Some(expiry) = &mut self.own_expiry {
+            *expiry = (*expiry).max(sent + self.duration);
+        }
+    }
+
+    /// Ends the lease of our request, released or evicted
+    pub fn stop(&mut self) {
+        self.own_expiry = None;
+    }
+
+    /// Ends the lease of our request at `now`, after another site evicted it
+    pub fn lose(&mut self, now: tokio::time::Instant) {
+        if self.own_expiry.is_some() {
+            self.own_expiry = Some(now);
+        }
+    }
+
+    /// Returns true if the lease of our request expired at `now`
+    pub fn lease_lost(&self, now: tokio::time::Instant) -> bool {
+        self.own_expiry.is_some_and(|expiry| expiry <= now)
+    }
+
+    /// Returns true if a third of the lease of our request went by since its last renewal
+    pub fn needs_renewal(&self, now: tokio::time::Instant) -> bool {
+        self.own_expiry
+            .is_some_and(|expiry| now < expiry && expiry - now <= self.duration * 2 / 3)
+    }
+
+    /// Records the eviction of the request of `site_id`, seen by `evicted_by`
+    pub fn record_eviction(&mut self, site_id: &str, evicted_by: &str) {
+        if self.evictions.len() == MAX_EVICTIONS {
+            self.evictions.pop_front();
+        }
+        self.evictions.push_back(MutexEviction {
+            site_id: site_id.to_string(),
+            evicted_by: evicted_by.to_string(),
+            at: chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+        });
+    }
+
+    /// Returns the last evictions, oldest first
+    pub fn get_evictions(&self) -> Vec<MutexEviction> {
+        self.evictions.iter().cloned().collect()
+    }
+}
+
+#[cfg(feature = "server")]
+/// Spawns the task renewing our lease and evicting the requests whose lease expired
+pub fn spawn_lease_task() {
+    use crate::state::LOCAL_APP_STATE;
+
+    crate::node::spawn(async move {
+        let duration = {
+            let state = LOCAL_APP_STATE.lock().await;
+            state.leases.get_duration()
+        };
+        let mut interval = tokio::time::interval(duration / 4);
+        loop {
+            interval.tick().await;
+            let mut state = LOCAL_APP_STATE.lock().await;
+            let now = tokio::time::Instant::now();
+
+            let site_id = state.get_site_id();
+            let requests = state
+                .global_mutex_fifo
+                .iter()
+                .filter(|(id, stamp)| {
+                    **id != site_id && stamp.tag == crate::state::MutexTag::Request
+                })
+                .map(|(id, stamp)| (id.clone(), stamp.date))
+                .collect();
+            for (evicted, date) in state.leases.take_expired(requests, now) {
+                log::warn!(
+                    "Le bail de la demande de section critique de {} a expiré, elle est évincée",
+                    evicted
+                );
+                if let Err(e) = evict(&mut state, evicted, date).await {
+                    log::error!("Unable to diffuse an eviction: {}", e);
+                }
+            }
+
+            if state.waiting_sc
+                && state
+                    .token
+                    .should_ask_again(now, state.leases.get_duration())
+            {
+                log::warn!("Le jeton n'est pas arrivé avant la fin du bail, nouvelle demande");
+                let scope = state.sc_scope.clone();
+                if let Err(e) = state.acquire_mutex(scope).await {
+                    log::error!("Erreur lors de l'acquisition du mutex : {}", e);
+                }
+            } else if state.waiting_sc && state.leases.lease_lost(now) {
+                log::warn!("Le bail de notre demande a expiré, nouvelle demande");
+                let scope = state.sc_scope.clone();
+                if let Err(e) = state.acquire_mutex(scope).await {
+                    log::error!("Erreur lors de l'acquisition du mutex : {}", e);
+                }
+            } else if state.leases.needs_renewal(now)
+                && let Some(stamp) = state.global_mutex_fifo.get(&site_id)
+            {
+                let date = stamp.date;
+                if let Err(e) = renew(&mut state, date).await {
+                    log::error!("Unable to renew the lease: {}", e);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(feature = "server")]
+/// Builds a wave message of this site
+fn wave_message(
+    state: &mut crate::state::AppState,
+    code: crate::message::NetworkMessageCode,
+    info: crate::message::MessageInfo,
+) -> crate::message::Message {
+    crate::message::Message {
+        sender_id: state.get_site_id(),
+        sender_addr: state.get_site_addr(),
+        message_initiator_id: state.get_site_id(),
+        message_initiator_addr: state.get_site_addr(),
+        clock: state.get_clock(),
+        command: None,
+        info,
+        code,
+        signature: None,
+        wave_id: Some(state.next_wave_id()),
+        causal_deps: None,
+    }
+}
+
+#[cfg(feature = "server")]
+/// Renews the lease of our request made at Lamport date `date` on every site
+async fn renew(
+    state: &mut crate::state::AppState,
+    date: i64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use crate::message::{MessageInfo, NetworkMessageCode, RenewLeasePayload};
+
+    let sent = tokio::time::Instant::now();
+    if state.get_nb_connected_neighbours() == 0 {
+        state.leases.renewed(sent);
+        return Ok(());
+    }
+    state.update_clock(None).await;
+    let msg = wave_message(
+        state,
+        NetworkMessageCode::RenewLease,
+        MessageInfo::RenewLease(RenewLeasePayload { date }),
+    );
+    let done = crate::network::wave::start_without_lock(state, msg).await?;
+    crate::node::spawn(async move {
+        // Every site renewed the lease
+        if done.await.is_ok() {
+            let mut state = crate::state::LOCAL_APP_STATE.lock().await;
+            state.leases.renewed(sent);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(feature = "server")]
+/// Evicts the request of `site_id` made at Lamport date `date` from every site
+async fn evict(
+    state: &mut crate::state::AppState,
+    site_id: String,
+    date: i64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use crate::message::{EvictRequestPayload, MessageInfo, NetworkMessageCode};
+
+    let own_id = state.get_site_id();
+    remove_request(state, &site_id, date, &own_id);
+    if state.get_nb_connected_neighbours() > 0 {
+        state.update_clock(None).await;
+        let msg = wave_message(
+            state,
+            NetworkMessageCode::EvictRequest,
+            MessageInfo::EvictRequest(EvictRequestPayload { site_id, date }),
+        );
+        // Nothing to wait for, each site forgets the request when the wave reaches it
+        crate::network::wave::start_without_lock(state, msg).await?;
+    }
+    if state.sc_request_recorded {
+        state.try_enter_sc();
+    }
+    Ok(())
+}
+
+#[cfg(feature = "server")]
+/// Removes the request of `site_id` made at Lamport date `date` from the FIFO
+///
+/// Returns false if the request is not pending anymore
+fn remove_request(
+    state: &mut crate::state::AppState,
+    site_id: &str,
+    date: i64,
+    evicted_by: &str,
+) -> bool {
+    let pending = state
+        .global_mutex_fifo
+        .get(site_id)
+        .is_some_and(|stamp| stamp.tag == crate::state::MutexTag::Request && stamp.date == date);
+    if pending {
+        state.global_mutex_fifo.remove(site_id);
+        state.leases.record_eviction(site_id, evicted_by);
+    }
+    pending
+}
+
+#[cfg(feature = "server")]
+/// Wave renewing the lease of a mutex request
+pub struct RenewLeaseWave;
+
+#[cfg(feature = "server")]
+impl crate::network::wave::WaveProtocol for RenewLeaseWave {
+    fn code(&self) -> crate::message::NetworkMessageCode {
+        crate::message::NetworkMessageCode::RenewLease
+    }
+
+    fn echo_code(&self) -> crate::message::NetworkMessageCode {
+        crate::message::NetworkMessageCode::AckRenewLease
+    }
+
+    fn visit(
+        &self,
+        _store: std::sync::Arc<dyn crate::store::LedgerStore>,
+        message: crate::message::Message,
+    ) -> crate::network::wave::VisitFuture {
+        use crate::message::MessageInfo;
+
+        Box::pin(async move {
+            let MessageInfo::RenewLease(payload) = message.info else {
+                return Err("RenewLease message without a request".into());
+            };
+            let mut state = crate::state::LOCAL_APP_STATE.lock().await;
+            let requester = message.message_initiator_id;
+            // An evicted request is not brought back
+            if state
+                .global_mutex_fifo
+                .get(&requester)
+                .is_some_and(|stamp| stamp.date == payload.date)
+            {
+                state
+                    .leases
+                    .renew(&requester, payload.date, tokio::time::Instant::now());
+            }
+            Ok(MessageInfo::None)
+        })
+    }
+}
+
+#[cfg(feature = "server")]
+/// Wave evicting a mutex request whose lease expired
+pub struct EvictRequestWave;
+
+#[cfg(feature = "server")]
+impl crate::network::wave::WaveProtocol for EvictRequestWave {
+    fn code(&self) -> crate::message::NetworkMessageCode {
+        crate::message::NetworkMessageCode::EvictRequest
+    }
+
+    fn echo_code(&self) -> crate::message::NetworkMessageCode {
+        crate::message::NetworkMessageCode::AckEvictRequest
+    }
+
+    /// A site finding its own request evicted stops its critical section, or asks again
+    /// if it was waiting
+    fn visit(
+        &self,
+        _store: std::sync::Arc<dyn crate::store::LedgerStore>,
+        message: crate::message::Message,
+    ) -> crate::network::wave::VisitFuture {
+        use crate::message::MessageInfo;
+
+        Box::pin(async move {
+            let MessageInfo::EvictRequest(payload) = message.info else {
+                return Err("EvictRequest message without a request".into());
+            };
+            let evicted_by = message.message_initiator_id;
+            let mut state = crate::state::LOCAL_APP_STATE.lock().await;
+
+            if !remove_request(&mut state, &payload.site_id, payload.date, &evicted_by) {
+                return Ok(MessageInfo::None);
+            }
+            if payload.site_id != state.get_site_id() {
+                log::warn!(
+                    "La demande de section critique de {} est évincée par {}",
+                    payload.site_id,
+                    evicted_by
+                );
+                if state.sc_request_recorded {
+                    state.try_enter_sc();
+                }
+                return Ok(MessageInfo::None);
+            }
+
+            log::warn!(
+                "Notre demande de section critique est évincée par {}",
+                evicted_by
+            );
+            state.leases.lose(tokio::time::Instant::now());
+            if state.waiting_sc {
+                let scope = state.sc_scope.clone();
+                state
+                    .acquire_mutex(scope)
+                    .await
+                    .map_err(|e| e.to_string())?;
+            }
+            Ok(MessageInfo::None)
+        })
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "server")]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    fn request(site_id: &str, date: i64) -> (String, i64) {
+        (site_id.to_string(), date)
+    }
+
+    #[test]
+    fn silent_requests_expire_unless_renewed() {
+        let mut leases = Leases::new(Duration::from_secs(5));
+        let start = Instant::now();
+        let pending = || vec![request("B", 3), request("C", 4)];
+
+        assert!(leases.take_expired(pending(), start).is_empty());
+        leases.renew("C", 4, start + Duration::from_secs(3));
+        assert_eq!(
+            leases.take_expired(pending(), start + Duration::from_secs(5)),
+            vec![request("B", 3)]
+        );
+        // A newer request of the same site gets its own lease
+        assert!(
+            leases
+                .take_expired(vec![request("C", 9)], start + Duration::from_secs(8))
+                .is_empty()
+        );
+        assert_eq!(
+            leases.take_expired(vec![request("C", 9)], start + Duration::from_secs(13)),
+            vec![request("C", 9)]
+        );
+    }
+
+    #[test]
+    fn own_lease_is_renewed_until_lost() {
+        let mut leases = Leases::new(Duration::from_secs(6));
+        let start = Instant::now();
+        assert!(!leases.needs_renewal(start));
+        assert!(!leases.lease_lost(start + Duration::from_secs(60)));
+
+        leases.start(start);
+        assert!(!leases.needs_renewal(start + Duration::from_secs(1)));
+        assert!(leases.needs_renewal(start + Duration::from_secs(2)));
+        leases.renewed(start + Duration::from_secs(2));
+        assert!(!leases.lease_lost(start + Duration::from_secs(7)));
+        assert!(leases.lease_lost(start + Duration::from_secs(8)));
+        assert!(!leases.needs_renewal(start + Duration::from_secs(8)));
+
+        leases.stop();
+        assert!(!leases.lease_lost(start + Duration::from_secs(8)));
+        leases.start(start);
+        leases.lose(start + Duration::from_secs(1));
+        assert!(leases.lease_lost(start + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn evictions_are_kept_for_the_info_page() {
+        let mut leases = Leases::default();
+        for i in 0..MAX_EVICTIONS + 1 {
+            leases.record_eviction(&format!("S{}", i), "A");
+        }
+        let evictions = leases.get_evictions();
+        assert_eq!(evictions.len(), MAX_EVICTIONS);
+        assert_eq!(evictions[0].site_id, "S1");
+        assert_eq!(evictions[0].evicted_by, "A");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_lease_evicts_a_dead_request() {
+        use crate::network::sim::{SimConfig, SimNetwork};
+        use crate::node::testing::{create_user, start_triangle, wait_until};
+        use crate::state::{MutexStamp, MutexTag};
+
+        let sim = SimNetwork::new(SimConfig {
+            seed: 15,
+            ..SimConfig::default()
+        });
+        let nodes = start_triangle(&sim, crate::mutex::MutexAlgorithm::Fifo).await;
+
+        // Z died after every site recorded its request for every account
+        for node in &nodes {
+            node.run(async {
+                let mut state = crate::state::LOCAL_APP_STATE.lock().await;
+                state.global_mutex_fifo.insert(
+                    "Z".to_string(),
+                    MutexStamp {
+                        tag: MutexTag::Request,
+                        date: 1,
+                        scope: crate::mutex::LockScope::All,
+                    },
+                );
+            })
+            .await;
+        }
+        create_user(&nodes, &nodes[0], "alice").await;
+        wait_until(&nodes, "Z to be evicted", || {
+            crate::state::LOCAL_APP_STATE
+                .get()
+                .try_lock()
+                .is_ok_and(|s| !s.global_mutex_fifo.contains_key("Z"))
+        })
+        .await;
+
+        for node in &nodes {
+            let evicted = node
+                .run(async {
+                    let state = crate::state::LOCAL_APP_STATE.lock().await;
+                    state
+                        .leases
+                        .get_evictions()
+                        .into_iter()
+                        .map(|eviction| eviction.site_id)
+                        .collect::<Vec<_>>()
+                })
+                .await;
+            assert_eq!(evicted, ["Z"]);
+        }
+    }
+}
@@ -6,13 +6,28 @@
 
 #![allow(non_snake_case)]
 
+mod beacon;
+mod causal;
 mod clock;
+mod codec;
 mod control;
 mod db;
+mod error;
+mod failure_detector;
+mod identity;
+mod lease;
+mod membership;
 mod message;
+mod migration;
+mod money;
+mod mutex;
 mod network;
+mod node;
+mod reconnect;
 mod snapshot;
 mod state;
+mod store;
+mod tls;
 mod utils;
 
 /// Command-line arguments for configuring the Peillute application
@@ -38,32 +53,75 @@ struct Args {
     /// ID for the batabase path
     #[arg(long, default_value_t = 0)]
     cli_db_id: u16,
+
+    /// Maximum size in bytes of a message frame exchanged between sites
+    #[arg(long, default_value_t = codec::DEFAULT_MAX_FRAME_SIZE)]
+    cli_max_frame_size: usize,
+
+    /// Multicast group and port used to find peers on the LAN when no peer is given
+    #[arg(long, default_value_t = String::from(beacon::DEFAULT_DISCOVERY_GROUP))]
+    cli_discovery_group: String,
+
+    /// Interval in milliseconds between two heartbeats sent to the neighbours
+    #[arg(long, default_value_t = failure_detector::DEFAULT_HEARTBEAT_INTERVAL_MS)]
+    cli_heartbeat_interval_ms: u64,
+
+    /// Suspicion level (phi) above which a silent neighbour is considered failed
+    #[arg(long, default_value_t = failure_detector::DEFAULT_PHI_THRESHOLD)]
+    cli_phi_threshold: f64,
+
+    /// PEM certificate of this site, signed by the deployment CA (enables mutual TLS)
+    #[arg(long)]
+    cli_tls_cert: Option<String>,
+
+    /// PEM private key matching the site certificate
+    #[arg(long)]
+    cli_tls_key: Option<String>,
+
+    /// PEM certificate of the deployment CA, used to authenticate other sites
+    #[arg(long)]
+    cli_tls_ca: Option<String>,
+
+    /// Mutual exclusion algorithm, the same on every site of the network
+    #[arg(long, value_enum, default_value_t = mutex::MutexAlgorithm::Fifo)]
+    cli_mutex: mutex::MutexAlgorithm,
+
+    /// Duration in milliseconds of the lease of a mutex request, renewed while it is pending
+    #[arg(long, default_value_t = lease::DEFAULT_LEASE_MS)]
+    cli_mutex_lease_ms: u64,
+
+    /// List the schema migrations the database is missing without applying them, then exit
+    #[arg(long)]
+    cli_migrate_dry_run: bool,
 }
 
 #[cfg(feature = "server")]
 #[tokio::main]
 async fn main() -> rusqlite::Result<(), Box<dyn std::error::Error>> {
-    use crate::state::LOCAL_APP_STATE;
     use clap::Parser;
     use std::io::{self as std_io, Write};
     use std::net::SocketAddr;
     use tokio::io::{self as tokio_io, AsyncBufReadExt, BufReader};
-    use tokio::net::TcpListener;
 
     const LOW_PORT: u16 = 10000;
     const HIGH_PORT: u16 = 11000;
     const PORT_OFFSET: u16 = HIGH_PORT - LOW_PORT + 1;
 
-    if !db::is_database_initialized()? {
-        let _ = db::init_db();
-    }
-
-    control::control_worker();
     // Init the logger
     env_logger::init();
 
     let args = Args::parse();
 
+    let db_path = format!("peillute_{}.db", args.cli_db_id);
+    if args.cli_migrate_dry_run {
+        migration::dry_run(&db_path)?;
+        return Ok(());
+    }
+
+    // The CLI and the web server run on the node of this process
+    let node = node::Node::open(&db_path)?;
+    node::set_default(node.clone());
+
     let port_range = LOW_PORT..=HIGH_PORT;
     let selected_port = if args.cli_port == 0 {
         port_range
@@ -78,13 +136,16 @@ async fn main() -> rusqlite::Result<(), Box<dyn std::error::Error>> {
     let client_server_interaction_addr: SocketAddr =
         format!("{}:{}", &args.cli_ip, selected_port + PORT_OFFSET).parse()?;
 
+    let discovery_group: std::net::SocketAddrV4 = args.cli_discovery_group.parse()?;
+
     let final_cli_peers_addrs: Vec<SocketAddr> = args
         .cli_peers
         .into_iter()
         .filter_map(|peer| peer.parse::<SocketAddr>().ok())
         .collect();
 
-    let (final_site_id, final_clock, needs_sync) = match utils::reload_existing_site().await {
+    let reloaded = utils::reload_existing_site(&node.store).await;
+    let (final_site_id, final_clock, needs_sync) = match reloaded {
         Ok((site_id_from_db, clock_from_db)) => (site_id_from_db, clock_from_db, true),
         Err(_) => {
             let generated_site_id = if args.cli_site_id.is_empty() {
@@ -96,20 +157,39 @@ async fn main() -> rusqlite::Result<(), Box<dyn std::error::Error>> {
         }
     };
 
-    {
-        let mut state = LOCAL_APP_STATE.lock().await;
-        state.init_site_id(final_site_id.clone());
-        state.init_site_addr(final_site_addr);
-        state.init_clock(final_clock);
-        state.init_parent_addr_for_transaction_wave();
-        state.init_cli_peer_addrs(final_cli_peers_addrs);
-        state.init_sync(needs_sync);
-    }
+    let identity = utils::load_or_create_identity(node.store.as_ref(), &final_site_id)?;
+
+    let mut config = node::NodeConfig::new(final_site_id, final_site_addr, final_cli_peers_addrs);
+    config.clock = final_clock;
+    config.needs_sync = needs_sync;
+    config.identity = Some(identity);
+    config.site_keys = node.store.site_public_keys()?;
+    config.failure_detector = failure_detector::FailureDetector::new(
+        std::time::Duration::from_millis(args.cli_heartbeat_interval_ms),
+        args.cli_phi_threshold,
+    );
+    config.codec = codec::FrameCodec::new(args.cli_max_frame_size);
+    config.discovery_group = Some(discovery_group);
+    config.mutex = args.cli_mutex;
+    config.mutex_lease = std::time::Duration::from_millis(args.cli_mutex_lease_ms);
+    config.tls = match (&args.cli_tls_cert, &args.cli_tls_key, &args.cli_tls_ca) {
+        (Some(cert), Some(key), Some(ca)) => {
+            log::info!("Mutual TLS enabled on the peer port");
+            Some(tls::TlsConfig::from_files(cert, key, ca)?)
+        }
+        (None, None, None) => {
+            log::warn!("TLS is disabled, peer connections are not authenticated");
+            None
+        }
+        _ => {
+            return Err(
+                "--cli-tls-cert, --cli-tls-key and --cli-tls-ca must be given together".into(),
+            );
+        }
+    };
 
-    // Create the network listener
-    let network_listener_local_addr = final_site_addr.clone();
-    let listener: TcpListener = TcpListener::bind(network_listener_local_addr).await?;
-    log::debug!("Listening on: {}", network_listener_local_addr);
+    // Listen for the peers and announce our presence to the network
+    node.start(config).await?;
 
     // Create the web app listener
     let router = axum::Router::new().serve_dioxus_application(ServeConfigBuilder::default(), App);
@@ -123,9 +203,6 @@ async fn main() -> rusqlite::Result<(), Box<dyn std::error::Error>> {
     let reader: BufReader<tokio_io::Stdin> = BufReader::new(stdin);
     let mut lines: tokio_io::Lines<_> = reader.lines();
 
-    // Announce our presence to the network
-    network::announce(&args.cli_ip, LOW_PORT, HIGH_PORT, selected_port).await;
-
     println!(
         "\n\
         ===================================================\n\
@@ -141,14 +218,12 @@ async fn main() -> rusqlite::Result<(), Box<dyn std::error::Error>> {
     print!("> ");
     std_io::stdout().flush().unwrap();
 
-    let main_loop_app_state = LOCAL_APP_STATE.clone();
-
     // Spawn the web server
     let server_task = tokio::spawn(async move {
         axum::serve(backend_listener, router).await.unwrap();
     });
 
-    main_loop(main_loop_app_state, &mut lines, listener).await;
+    main_loop(&node.store, &mut lines).await;
 
     // Ensure the server task finishes cleanly if ever reached
     server_task.await?;
@@ -158,9 +233,8 @@ async fn main() -> rusqlite::Result<(), Box<dyn std::error::Error>> {
 
 #[cfg(feature = "server")]
 async fn main_loop(
-    _state: std::sync::Arc<tokio::sync::Mutex<crate::state::AppState>>,
+    store: &std::sync::Arc<dyn store::LedgerStore>,
     lines: &mut tokio::io::Lines<tokio::io::BufReader<tokio::io::Stdin>>,
-    listener: tokio::net::TcpListener,
 ) {
     use crate::control::{parse_command, process_cli_command};
     use std::io::{self as std_io, Write};
@@ -170,15 +244,12 @@ async fn main_loop(
         select! {
             line = lines.next_line() => {
                 let command = parse_command(line);
-                if let Err(e) = process_cli_command(command).await{
+                if let Err(e) = process_cli_command(store, command).await{
                     log::error!("Error handling a cli command:\n{}", e);
                 }
                 print!("> ");
                 std_io::stdout().flush().unwrap();
             }
-            Ok((stream, addr)) = listener.accept() => {
-                let _ = crate::network::start_listening(stream, addr).await;
-            }
             _ = tokio::signal::ctrl_c() => {
                 disconnect().await;
                 std::process::exit(0);
@@ -203,6 +274,9 @@ async fn disconnect() {
     };
 
     info!("Shutting down site {}.", site_id);
+    if let Err(e) = LOCAL_APP_STATE.lock().await.leave_mutex().await {
+        error!("Unable to hand over the mutex: {}", e);
+    }
     for peer_addr in connected_nei_addr {
         // increment the clock for every deconnection
         let clock = {
@@ -220,6 +294,7 @@ async fn disconnect() {
             &site_id,
             &site_id,
             local_addr,
+            None,
             clock.clone(),
         )
         .await
@@ -236,8 +311,8 @@ fn main() {
     dioxus::launch(App);
 }
 
-mod views;
 mod hooks;
+mod views;
 use views::*;
 
 const FAVICON: Asset = asset!("/assets/icon.png");
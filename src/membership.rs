@@ -0,0 +1,301 @@
+//! Retirement of the departed sites from the vector clocks
+//!
+//! Without it the vector clocks keep an entry for every site ever seen, and every
+//! transaction stores one row per entry. When a site leaves the network properly, each
+//! neighbour getting its disconnection runs two waves. The first one asks every live site
+//! to acknowledge the departure, a site still connected to the departed one refuses. Once
+//! every site acknowledged, the second one makes each site drop the entry of the departed
+//! site. The acknowledgments carry the last value of the entry each site knows, so that
+//! the clocks sent before the departure do not bring the entry back. A retired site that
+//! comes back gets its entry again with its first newer clock.
+//!
+//! Sites lost without a disconnection are not retired, they may still be running on the
+//! other side of a partition.
+
+#[cfg(feature = "server")]
+/// Wave retiring a departed site, see [`crate::message::RetirePhase`]
+pub struct RetireSiteWave;
+
+#[cfg(feature = "server")]
+impl crate::network::wave::WaveProtocol for RetireSiteWave {
+    fn code(&self) -> crate::message::NetworkMessageCode {
+        crate::message::NetworkMessageCode::RetireSite
+    }
+
+    fn echo_code(&self) -> crate::message::NetworkMessageCode {
+        crate::message::NetworkMessageCode::AckRetireSite
+    }
+
+    fn visit(
+        &self,
+        _store: std::sync::Arc<dyn crate::store::LedgerStore>,
+        message: crate::message::Message,
+    ) -> crate::network::wave::VisitFuture {
+        use crate::message::{MessageInfo, RetirePhase};
+
+        Box::pin(async move {
+            let MessageInfo::RetireSite(payload) = message.info else {
+                return Err("RetireSite message without a site to retire".into());
+            };
+            let mut state = crate::state::LOCAL_APP_STATE.lock().await;
+            match payload.phase {
+                RetirePhase::Acknowledge => Ok(acknowledge(&state, &payload.site_id)),
+                RetirePhase::Retire => {
+                    state.retire_site(&payload.site_id, payload.last).await;
+                    Ok(MessageInfo::None)
+                }
+            }
+        })
+    }
+
+    /// A refusal wins over the acknowledgments, which keep the largest value of the entry
+    fn reduce(
+        &self,
+        acc: crate::message::MessageInfo,
+        child: crate::message::MessageInfo,
+    ) -> crate::message::MessageInfo {
+        use crate::message::MessageInfo;
+
+        match (acc, child) {
+            (MessageInfo::RetireSite(mut acc), MessageInfo::RetireSite(child)) => {
+                acc.last = acc.last.max(child.last);
+                MessageInfo::RetireSite(acc)
+            }
+            (refused @ MessageInfo::Error(_), _) | (_, refused @ MessageInfo::Error(_)) => refused,
+            (acc, child) => {
+                log::error!("Unexpected retirement echo: {:?}", child);
+                acc
+            }
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+/// Acknowledges the departure of `site_id` with the last value of its entry
+///
+/// Answers `MessageInfo::Error` instead if this site is still connected to it
+fn acknowledge(state: &crate::state::AppState, site_id: &str) -> crate::message::MessageInfo {
+    use crate::message::{ErrorPayload, MessageInfo, RetirePhase, RetireSitePayload};
+
+    if site_id == state.get_site_id() || state.is_connected_to(site_id) {
+        MessageInfo::Error(ErrorPayload {
+            reason: format!(
+                "site {} is still connected to site {}",
+                site_id,
+                state.get_site_id()
+            ),
+        })
+    } else {
+        MessageInfo::RetireSite(RetireSitePayload {
+            site_id: site_id.to_string(),
+            phase: RetirePhase::Acknowledge,
+            last: state.get_clock().get_entry(site_id),
+        })
+    }
+}
+
+#[cfg(feature = "server")]
+/// Retires a site which left the network from the vector clocks of every live site
+///
+/// Nothing is retired if a site is still connected to it
+pub async fn retire(site_id: String) {
+    use crate::message::{MessageInfo, RetirePhase};
+
+    let last = match run_phase(&site_id, RetirePhase::Acknowledge, 0).await {
+        Ok(MessageInfo::RetireSite(acknowledged)) => acknowledged.last,
+        Ok(MessageInfo::Error(refused)) => {
+            log::info!("Site {} is not retired: {}", site_id, refused.reason);
+            return;
+        }
+        Ok(echo) => {
+            log::error!("Unexpected retirement echo: {:?}", echo);
+            return;
+        }
+        Err(e) => {
+            log::error!("Unable to retire site {}: {}", site_id, e);
+            return;
+        }
+    };
+
+    match run_phase(&site_id, RetirePhase::Retire, last).await {
+        Ok(_) => log::info!("Site {} retired from the vector clocks", site_id),
+        Err(e) => log::error!("Unable to retire site {}: {}", site_id, e),
+    }
+}
+
+#[cfg(feature = "server")]
+/// Runs a phase of the retirement over the network
+///
+/// Returns the value of this site reduced with the value of the wave
+async fn run_phase(
+    site_id: &str,
+    phase: crate::message::RetirePhase,
+    last: i64,
+) -> Result<crate::message::MessageInfo, Box<dyn std::error::Error>> {
+    use crate::message::{
+        Message, MessageInfo, NetworkMessageCode, RetirePhase, RetireSitePayload,
+    };
+    use crate::network::wave::WaveProtocol;
+
+    let (own, done) = {
+        let mut state = crate::state::LOCAL_APP_STATE.lock().await;
+
+        // The initiator is not visited by its own wave
+        let own = match phase {
+            RetirePhase::Acknowledge => acknowledge(&state, site_id),
+            RetirePhase::Retire => {
+                state.retire_site(site_id, last).await;
+                MessageInfo::None
+            }
+        };
+        if let MessageInfo::Error(_) = own {
+            return Ok(own);
+        }
+
+        state.update_clock(None).await;
+        let site_addr = state.get_site_addr();
+        let msg = Message {
+            command: None,
+            code: NetworkMessageCode::RetireSite,
+            info: MessageInfo::RetireSite(RetireSitePayload {
+                site_id: site_id.to_string(),
+                phase,
+                last,
+            }),
+            clock: state.get_clock(),
+            sender_addr: site_addr,
+            sender_id: state.get_site_id(),
+            message_initiator_id: state.get_site_id(),
+            message_initiator_addr: site_addr,
+            signature: None,
+            wave_id: Some(state.next_wave_id()),
+            causal_deps: None,
+        };
+        (
+            own,
+            crate::network::wave::start_without_lock(&mut state, msg).await?,
+        )
+    };
+    Ok(match done.await? {
+        MessageInfo::None => own,
+        echo => RetireSiteWave.reduce(own, echo),
+    })
+}
+
+#[cfg(test)]
+#[cfg(feature = "server")]
+mod tests {
+    use super::*;
+    use crate::message::{ErrorPayload, MessageInfo, RetirePhase, RetireSitePayload};
+    use crate::network::wave::WaveProtocol;
+
+    fn acknowledged(last: i64) -> MessageInfo {
+        MessageInfo::RetireSite(RetireSitePayload {
+            site_id: "C".to_string(),
+            phase: RetirePhase::Acknowledge,
+            last,
+        })
+    }
+
+    #[test]
+    fn connected_sites_refuse_the_departure() {
+        let mut state = crate::state::AppState::new(
+            "A".to_string(),
+            Vec::new(),
+            "127.0.0.1:8080".parse().unwrap(),
+        );
+        let b_addr = "127.0.0.1:8081".parse().unwrap();
+        state.add_site_id("B".to_string(), b_addr);
+        let b_clock = crate::clock::Clock::new_with_values(0, [("B".to_string(), 1)].into());
+        state.add_incomming_peer(b_addr, b_addr, b_clock);
+        // Known through relayed messages only
+        state.add_site_id("C".to_string(), "127.0.0.1:8082".parse().unwrap());
+
+        assert!(matches!(acknowledge(&state, "B"), MessageInfo::Error(_)));
+        assert!(matches!(acknowledge(&state, "A"), MessageInfo::Error(_)));
+        assert!(matches!(
+            acknowledge(&state, "C"),
+            MessageInfo::RetireSite(RetireSitePayload { last: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn acknowledgments_keep_the_last_entry_unless_refused() {
+        let wave = RetireSiteWave;
+        assert!(matches!(
+            wave.reduce(acknowledged(3), acknowledged(5)),
+            MessageInfo::RetireSite(RetireSitePayload { last: 5, .. })
+        ));
+
+        let refused = MessageInfo::Error(ErrorPayload {
+            reason: "still connected".to_string(),
+        });
+        assert!(matches!(
+            wave.reduce(acknowledged(3), refused.clone()),
+            MessageInfo::Error(_)
+        ));
+        assert!(matches!(
+            wave.reduce(refused, acknowledged(3)),
+            MessageInfo::Error(_)
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn departed_site_is_retired_from_every_clock() {
+        use crate::message::NetworkMessageCode;
+        use crate::network::sim::{SimConfig, SimNetwork};
+        use crate::node::testing::{create_user, sim_addr, start_sites, wait_until};
+
+        let sim = SimNetwork::new(SimConfig {
+            seed: 11,
+            ..SimConfig::default()
+        });
+        // A - B - C, A only hears of C through B
+        let nodes = start_sites(Some(&sim), &[("A", &[]), ("B", &[0]), ("C", &[1])], |_| {}).await;
+        let entry_of_c = || {
+            crate::state::LOCAL_APP_STATE
+                .get()
+                .try_lock()
+                .ok()
+                .map(|s| s.get_clock().get_entry("C"))
+        };
+
+        create_user(&nodes, &nodes[2], "carol").await;
+        let live = [nodes[0].clone(), nodes[1].clone()];
+        wait_until(&live, "C to be in every clock", || {
+            entry_of_c().is_some_and(|value| value > 0)
+        })
+        .await;
+
+        nodes[2]
+            .run(async {
+                let state = crate::state::LOCAL_APP_STATE.lock().await;
+                crate::network::send_message(
+                    sim_addr(1),
+                    MessageInfo::None,
+                    None,
+                    NetworkMessageCode::Disconnect,
+                    sim_addr(2),
+                    "C",
+                    "C",
+                    sim_addr(2),
+                    None,
+                    state.get_clock(),
+                )
+                .await
+                .unwrap();
+            })
+            .await;
+
+        wait_until(&live, "C to be retired", || {
+            crate::state::LOCAL_APP_STATE
+                .get()
+                .try_lock()
+                .is_ok_and(|s| s.get_clock().get_retired() == vec!["C".to_string()])
+        })
+        .await;
+        for node in &live {
+            assert_eq!(node.run(async { entry_of_c() }).await, Some(0));
+        }
+    }
+}
@@ -6,6 +6,7 @@
 
 #[cfg(feature = "server")]
 /// Represents a financial transaction in the system
+#[allow(dead_code)]
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
 pub struct Transaction {
     /// Unique identifier for the transaction
@@ -13,7 +14,7 @@ pub struct Transaction {
     /// ID of the user performing the transaction
     pub user_id: String,
     /// Transaction amount
-    pub amount: f64,
+    pub amount: crate::money::Money,
     /// Description of the transaction
     pub description: String,
 }
@@ -46,6 +47,51 @@ pub enum NetworkMessageCode {
     AckGlobalMutex,
     /// Acknowledgment of the global mutex acquisition
     AckReleaseGlobalMutex,
+    /// Periodic sign of life sent to the neighbours
+    Heartbeat,
+    /// Retirement of a departed site from the vector clocks
+    RetireSite,
+    /// Answer of a site to a retirement
+    AckRetireSite,
+    /// Request for the mutex token
+    TokenRequest,
+    /// Answer of a site to a request for the mutex token
+    AckTokenRequest,
+    /// Mutex token handed over to a site
+    TokenTransfer,
+    /// Answer of a site to a token transfer
+    AckTokenTransfer,
+    /// Renewal of the lease of a mutex request
+    RenewLease,
+    /// Answer of a site to a lease renewal
+    AckRenewLease,
+    /// Eviction of a mutex request whose lease expired
+    EvictRequest,
+    /// Answer of a site to an eviction
+    AckEvictRequest,
+}
+
+#[cfg(feature = "server")]
+impl NetworkMessageCode {
+    /// Whether the messages with this code must be signed by their initiator
+    ///
+    /// These are the messages changing the state of the sites they reach
+    pub fn is_signed(&self) -> bool {
+        matches!(
+            self,
+            NetworkMessageCode::Transaction
+                | NetworkMessageCode::Acknowledgment
+                | NetworkMessageCode::Disconnect
+                | NetworkMessageCode::SnapshotRequest
+                | NetworkMessageCode::AcquireMutex
+                | NetworkMessageCode::ReleaseGlobalMutex
+                | NetworkMessageCode::RetireSite
+                | NetworkMessageCode::TokenRequest
+                | NetworkMessageCode::TokenTransfer
+                | NetworkMessageCode::RenewLease
+                | NetworkMessageCode::EvictRequest
+        )
+    }
 }
 
 #[cfg(feature = "server")]
@@ -68,6 +114,44 @@ pub struct Message {
     pub info: MessageInfo,
     /// Type of the message
     pub code: NetworkMessageCode,
+    /// Signature of the initiator, required when [`NetworkMessageCode::is_signed`]
+    pub signature: Option<MessageSignature>,
+    /// Wave the message belongs to, None for messages exchanged between neighbours only
+    pub wave_id: Option<WaveId>,
+    /// Number of transactions of each site applied by the initiator, this one included
+    ///
+    /// Set on transaction messages only, see `crate::causal`
+    pub causal_deps: Option<std::collections::HashMap<String, i64>>,
+}
+
+#[cfg(feature = "server")]
+/// Identifier of a wave, unique among the waves of every initiator
+///
+/// Each site numbers the waves it starts, so that the waves started back to back by
+/// the same site (mutex acquisition, transaction, release) get separate bookkeeping
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct WaveId {
+    /// ID of the site that started the wave
+    pub initiator: String,
+    /// Sequence number of the wave among the waves of the initiator
+    pub seq: u64,
+}
+
+#[cfg(feature = "server")]
+impl std::fmt::Display for WaveId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}#{}", self.initiator, self.seq)
+    }
+}
+
+#[cfg(feature = "server")]
+/// Signature of a message by the site that initiated it
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct MessageSignature {
+    /// Ed25519 public key of the initiator
+    pub public_key: Vec<u8>,
+    /// Ed25519 signature of the initiator fields, the clock and the payload
+    pub signature: Vec<u8>,
 }
 
 #[cfg(feature = "server")]
@@ -88,14 +172,30 @@ pub enum MessageInfo {
     Pay(Pay),
     /// Process a refund
     Refund(Refund),
-    /// Response to a snapshot request
-    SnapshotResponse(SnapshotResponse),
+    /// Local snapshots of the sites reached through a neighbour, in response to a snapshot request
+    SnapshotResponse(Vec<SnapshotResponse>),
     /// Initiate a critical section
     AcquireMutex(AcquireMutexPayload),
     /// Release a critical section
     ReleaseMutex(ReleaseMutexPayload),
     /// Acknowledge a critical section
     AckMutex(AckMutexPayload),
+    /// Reason of a rejected message
+    Error(ErrorPayload),
+    /// Site to retire from the vector clocks
+    RetireSite(RetireSitePayload),
+    /// Request for the mutex token
+    TokenRequest(TokenRequestPayload),
+    /// What a site knows of the mutex token, in answer to a request
+    TokenStatus(TokenStatusPayload),
+    /// Mutex token handed over to a site
+    Token(TokenPayload),
+    /// Mutex request whose lease is renewed
+    RenewLease(RenewLeasePayload),
+    /// Mutex request evicted after its lease expired
+    EvictRequest(EvictRequestPayload),
+    /// Transactions made in one critical section, applied together
+    Batch(Vec<BatchedTransaction>),
     /// No payload
     None,
 }
@@ -105,13 +205,20 @@ pub enum MessageInfo {
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub struct AcknowledgePayload {
     /// Logical clock state of the acknowledging node
-    pub global_fifo: std::collections::HashMap<String, crate::state::MutexStamp>,
+    ///
+    /// The maps are ordered, the signed bytes of the message do not change on the way
+    pub global_fifo: std::collections::BTreeMap<String, crate::state::MutexStamp>,
+    /// Number of transactions of each site applied by the acknowledging node
+    pub delivered: std::collections::BTreeMap<String, i64>,
 }
 
 #[cfg(feature = "server")]
 /// Payload for the AcquireMutex message
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
-pub struct AcquireMutexPayload;
+pub struct AcquireMutexPayload {
+    /// Accounts the critical section is asked for
+    pub scope: crate::mutex::LockScope,
+}
 
 #[cfg(feature = "server")]
 /// Payload for the ReleaseMutex message
@@ -127,6 +234,117 @@ pub struct AckMutexPayload {
     pub clock: i64,
 }
 
+#[cfg(feature = "server")]
+/// Payload for the Error message
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct ErrorPayload {
+    /// Why the message was rejected
+    pub reason: String,
+}
+
+#[cfg(feature = "server")]
+/// Payload for the RenewLease message
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct RenewLeasePayload {
+    /// Lamport date of the renewed request
+    pub date: i64,
+}
+
+#[cfg(feature = "server")]
+/// Payload for the EvictRequest message
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct EvictRequestPayload {
+    /// ID of the site whose request is evicted
+    pub site_id: String,
+    /// Lamport date of the evicted request, a newer request of the site is kept
+    pub date: i64,
+}
+
+#[cfg(feature = "server")]
+/// Transaction of a batch, with the clock of the site that made it
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct BatchedTransaction {
+    /// Operation of the transaction
+    pub info: MessageInfo,
+    /// Clock of the initiator when it made the transaction
+    pub clock: crate::clock::Clock,
+}
+
+#[cfg(feature = "server")]
+/// Step of the retirement of a departed site
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RetirePhase {
+    /// Every site acknowledges the departure, unless it is still connected to the site
+    ///
+    /// The acknowledgments carry the last value of the clock entry of the site
+    Acknowledge,
+    /// Every site drops the entry of the site
+    Retire,
+}
+
+#[cfg(feature = "server")]
+/// Payload for the RetireSite message
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct RetireSitePayload {
+    /// ID of the departed site
+    pub site_id: String,
+    /// Step of the retirement
+    pub phase: RetirePhase,
+    /// Largest value of the clock entry of the site known by the sender
+    ///
+    /// Reduced by maximum over the acknowledgments, then sent along with the retirement
+    pub last: i64,
+}
+
+#[cfg(feature = "server")]
+/// Payload for the TokenRequest message
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct TokenRequestPayload {
+    /// Number of the request among the requests of the initiator
+    pub seq: u64,
+}
+
+#[cfg(feature = "server")]
+/// Payload for the AckTokenRequest message, reduced over the network
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+pub struct TokenStatusPayload {
+    /// Whether a site saw the token
+    pub token_known: bool,
+    /// Lamport date and ID of the sites waiting for the token
+    pub requesters: Vec<(i64, String)>,
+    /// Last site the token was handed to as far as the sites know, with the generation
+    /// of the token
+    pub holder: Option<(u64, String)>,
+    /// ID of the sites which answered the request
+    pub reached: Vec<String>,
+}
+
+#[cfg(feature = "server")]
+/// Token of the mutual exclusion, see `crate::mutex`
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Token {
+    /// Number of the last request of each site that was served
+    ///
+    /// Sorted, so that every site computes the same bytes when checking the signature of
+    /// the transfer
+    pub last_served: std::collections::BTreeMap<String, u64>,
+    /// Sites waiting for the token, in the order they get it
+    pub queue: std::collections::VecDeque<String>,
+    /// Number of times the token was handed over or created again, the live token has
+    /// the largest
+    pub generation: u64,
+}
+
+#[cfg(feature = "server")]
+/// Payload for the TokenTransfer message
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct TokenPayload {
+    /// ID of the site getting the token
+    pub to: String,
+    /// The token
+    pub token: Token,
+}
+
 #[cfg(feature = "server")]
 /// Response to a state snapshot request
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
@@ -137,6 +355,8 @@ pub struct SnapshotResponse {
     pub clock: crate::clock::Clock,
     /// Transaction log summary
     pub tx_log: Vec<crate::snapshot::TxSummary>,
+    /// Signature of the responding node, relays cannot change its transactions
+    pub signature: Option<MessageSignature>,
 }
 
 #[cfg(feature = "server")]
@@ -162,13 +382,13 @@ pub struct Deposit {
     /// Name of the account
     pub name: String,
     /// Amount to deposit
-    pub amount: f64,
+    pub amount: crate::money::Money,
 }
 
 #[cfg(feature = "server")]
 impl Deposit {
     /// Creates a new Deposit request
-    pub fn new(name: String, amount: f64) -> Self {
+    pub fn new(name: String, amount: crate::money::Money) -> Self {
         Self { name, amount }
     }
 }
@@ -180,13 +400,13 @@ pub struct Withdraw {
     /// Name of the account
     pub name: String,
     /// Amount to withdraw
-    pub amount: f64,
+    pub amount: crate::money::Money,
 }
 
 #[cfg(feature = "server")]
 impl Withdraw {
     /// Creates a new Withdraw request
-    pub fn new(name: String, amount: f64) -> Self {
+    pub fn new(name: String, amount: crate::money::Money) -> Self {
         Self { name, amount }
     }
 }
@@ -200,13 +420,13 @@ pub struct Transfer {
     /// Name of the destination account
     pub beneficiary: String,
     /// Amount to transfer
-    pub amount: f64,
+    pub amount: crate::money::Money,
 }
 
 #[cfg(feature = "server")]
 impl Transfer {
     /// Creates a new Transfer request
-    pub fn new(name: String, beneficiary: String, amount: f64) -> Self {
+    pub fn new(name: String, beneficiary: String, amount: crate::money::Money) -> Self {
         Self {
             name,
             beneficiary,
@@ -222,13 +442,13 @@ pub struct Pay {
     /// Name of the account
     pub name: String,
     /// Amount to pay
-    pub amount: f64,
+    pub amount: crate::money::Money,
 }
 
 #[cfg(feature = "server")]
 impl Pay {
     /// Creates a new Pay request
-    pub fn new(name: String, amount: f64) -> Self {
+    pub fn new(name: String, amount: crate::money::Money) -> Self {
         Self { name, amount }
     }
 }
@@ -267,12 +487,12 @@ mod tests {
         let transaction = Transaction {
             id: 1,
             user_id: "test_user".to_string(),
-            amount: 100.0,
+            amount: crate::money::Money::from_euros(100),
             description: "Test transaction".to_string(),
         };
         assert_eq!(
             format!("{:?}", transaction),
-            "Transaction { id: 1, user_id: \"test_user\", amount: 100.0, description: \"Test transaction\" }"
+            "Transaction { id: 1, user_id: \"test_user\", amount: Money(10000), description: \"Test transaction\" }"
         );
     }
 
@@ -285,10 +505,13 @@ mod tests {
             sender_addr: "127.0.0.1:8080".parse().unwrap(),
             message_initiator_id: "A".to_string(),
             message_initiator_addr: "127.0.0.1:8080".parse().unwrap(),
-            clock: clock,
+            clock,
             command: None,
             info: MessageInfo::None,
             code: NetworkMessageCode::Transaction,
+            signature: None,
+            wave_id: None,
+            causal_deps: None,
         };
         assert!(format!("{:?}", message).contains("Message { sender_id: \"A\""));
     }
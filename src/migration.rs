@@ -0,0 +1,388 @@
+//! Versioned migrations of the database schema
+//!
+//! Every change of the schema is a migration step, numbered from 1 and applied in order
+//! when a node opens its database. The `schema_version` table records the steps already
+//! applied, so that an existing ledger is upgraded in place instead of deleted. Each step
+//! also checks the schema it changes, databases created before the steps were versioned
+//! start at version 0 with some of them already done.
+//!
+//! A node refuses to open a database with a version newer than the last step it knows,
+//! written by a newer build, as it could not tell what the unknown steps changed.
+
+#[cfg(feature = "server")]
+/// Step of the schema, applied once in its own SQLite transaction
+pub struct Migration {
+    /// Schema version once the step is applied
+    pub version: i64,
+    /// What the step changes, shown by the dry run
+    pub description: &'static str,
+    apply: fn(&rusqlite::Connection) -> rusqlite::Result<()>,
+}
+
+#[cfg(feature = "server")]
+/// Every step, in the order they are applied
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create the ledger tables",
+        apply: create_ledger_tables,
+    },
+    Migration {
+        version: 2,
+        description: "store the signing key of the site",
+        apply: create_site_identity_table,
+    },
+    Migration {
+        version: 3,
+        description: "date transactions and the local state with a hybrid logical clock",
+        apply: add_hybrid_clock_columns,
+    },
+    Migration {
+        version: 4,
+        description: "count balances and amounts in integer cents",
+        apply: convert_amounts_to_cents,
+    },
+    Migration {
+        version: 5,
+        description: "store the public keys pinned for the other sites",
+        apply: create_site_public_key_table,
+    },
+];
+
+#[cfg(feature = "server")]
+/// Version of the schema once every known step is applied
+pub const SCHEMA_VERSION: i64 = MIGRATIONS.len() as i64;
+
+#[cfg(feature = "server")]
+/// Applies the pending steps to the database of `conn`
+pub fn migrate(conn: &rusqlite::Connection) -> Result<(), crate::error::PeillutError> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            description TEXT NOT NULL,
+            applied_at INTEGER NOT NULL
+        );",
+        [],
+    )?;
+
+    for migration in pending(conn)? {
+        log::info!(
+            "Migrating the database to version {}: {}",
+            migration.version,
+            migration.description
+        );
+        let tx = conn.unchecked_transaction()?;
+        (migration.apply)(&tx)?;
+        tx.execute(
+            "INSERT INTO schema_version (version, description, applied_at) VALUES (?1, ?2, ?3)",
+            rusqlite::params![
+                migration.version,
+                migration.description,
+                chrono::Utc::now().timestamp()
+            ],
+        )?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "server")]
+/// Steps not yet applied to the database of `conn`, which is not modified
+///
+/// Fails if the database was migrated by a newer build
+pub fn pending(
+    conn: &rusqlite::Connection,
+) -> Result<&'static [Migration], crate::error::PeillutError> {
+    let version = schema_version(conn)?;
+    if version > SCHEMA_VERSION {
+        return Err(crate::error::PeillutError::UnsupportedSchema {
+            version,
+            supported: SCHEMA_VERSION,
+        });
+    }
+    Ok(&MIGRATIONS[version as usize..])
+}
+
+#[cfg(feature = "server")]
+/// Version of the schema of the database, 0 if no step was recorded
+pub fn schema_version(conn: &rusqlite::Connection) -> rusqlite::Result<i64> {
+    let versioned: bool = conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'schema_version')",
+        [],
+        |row| row.get(0),
+    )?;
+    if !versioned {
+        return Ok(0);
+    }
+    conn.query_row(
+        "SELECT IFNULL(MAX(version), 0) FROM schema_version",
+        [],
+        |row| row.get(0),
+    )
+}
+
+#[cfg(feature = "server")]
+/// Prints the steps pending on the database at `db_path`, without modifying it
+pub fn dry_run(db_path: &str) -> Result<(), crate::error::PeillutError> {
+    let conn = if std::path::Path::new(db_path).exists() {
+        rusqlite::Connection::open_with_flags(db_path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)?
+    } else {
+        // Opening a missing database would create its file
+        rusqlite::Connection::open_in_memory()?
+    };
+
+    let version = schema_version(&conn)?;
+    let steps = pending(&conn)?;
+    if steps.is_empty() {
+        println!("✅ {} is up to date, schema version {}", db_path, version);
+        return Ok(());
+    }
+    println!(
+        "📋 {} is at schema version {}, {} step(s) would bring it to version {}:",
+        db_path,
+        version,
+        steps.len(),
+        SCHEMA_VERSION
+    );
+    for step in steps {
+        println!("  {:>3}  {}", step.version, step.description);
+    }
+    Ok(())
+}
+
+#[cfg(feature = "server")]
+/// Version 1, the schema of the first release
+fn create_ledger_tables(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS VectorClock (
+            id INTEGER PRIMARY KEY AUTOINCREMENT
+        );
+        CREATE TABLE IF NOT EXISTS VectorClockEntry (
+            vector_clock_id INTEGER,
+            site_id TEXT,
+            value INTEGER NOT NULL,
+            PRIMARY KEY(vector_clock_id, site_id),
+            FOREIGN KEY(vector_clock_id) REFERENCES VectorClock(id) ON DELETE CASCADE
+        );
+        CREATE TABLE IF NOT EXISTS User (
+            unique_name TEXT PRIMARY KEY,
+            solde FLOAT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS Transactions (
+            from_user TEXT,
+            to_user TEXT NOT NULL,
+            amount FLOAT NOT NULL,
+            lamport_time INTEGER NOT NULL,
+            vector_clock_id INTEGER NOT NULL,
+            source_node TEXT NOT NULL,
+            optional_msg TEXT,
+            FOREIGN KEY(from_user) REFERENCES User(unique_name),
+            FOREIGN KEY(to_user) REFERENCES User(unique_name),
+            FOREIGN KEY(vector_clock_id) REFERENCES VectorClock(id),
+            PRIMARY KEY(lamport_time, source_node)
+        );
+        CREATE TABLE IF NOT EXISTS LocalState (
+            site_id TEXT PRIMARY KEY,
+            lamport_time INTEGER NOT NULL,
+            vector_clock_id INTEGER NOT NULL,
+            FOREIGN KEY(vector_clock_id) REFERENCES VectorClock(id)
+        );",
+    )
+}
+
+#[cfg(feature = "server")]
+/// Version 2, see [`crate::identity`]
+fn create_site_identity_table(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS SiteIdentity (
+            site_id TEXT PRIMARY KEY,
+            signing_key BLOB NOT NULL
+        );",
+    )
+}
+
+#[cfg(feature = "server")]
+/// Version 3, see [`crate::clock::HybridTimestamp`]
+fn add_hybrid_clock_columns(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+    for table in ["Transactions", "LocalState"] {
+        add_missing_column(conn, table, "hlc_wall", "INTEGER NOT NULL DEFAULT 0")?;
+        add_missing_column(conn, table, "hlc_logical", "INTEGER NOT NULL DEFAULT 0")?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "server")]
+/// Version 4, see [`crate::money::Money`]
+fn convert_amounts_to_cents(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+    convert_to_cents(
+        conn,
+        "User",
+        "unique_name TEXT PRIMARY KEY,
+        solde INTEGER NOT NULL",
+        "solde",
+    )?;
+    convert_to_cents(
+        conn,
+        "Transactions",
+        "from_user TEXT,
+        to_user TEXT NOT NULL,
+        amount INTEGER NOT NULL,
+        lamport_time INTEGER NOT NULL,
+        vector_clock_id INTEGER NOT NULL,
+        source_node TEXT NOT NULL,
+        optional_msg TEXT,
+        hlc_wall INTEGER NOT NULL DEFAULT 0,
+        hlc_logical INTEGER NOT NULL DEFAULT 0,
+        FOREIGN KEY(from_user) REFERENCES User(unique_name),
+        FOREIGN KEY(to_user) REFERENCES User(unique_name),
+        FOREIGN KEY(vector_clock_id) REFERENCES VectorClock(id),
+        PRIMARY KEY(lamport_time, source_node)",
+        "amount",
+    )
+}
+
+#[cfg(feature = "server")]
+/// Version 5, see [`crate::identity`]
+fn create_site_public_key_table(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS SitePublicKey (
+            site_id TEXT PRIMARY KEY,
+            public_key BLOB NOT NULL
+        );",
+    )
+}
+
+#[cfg(feature = "server")]
+/// Adds a column to a table, if it is missing
+fn add_missing_column(
+    conn: &rusqlite::Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let columns = stmt.query_map([], |row| row.get::<_, String>(1))?;
+    for name in columns {
+        if name? == column {
+            return Ok(());
+        }
+    }
+    conn.execute(
+        &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+        [],
+    )?;
+    Ok(())
+}
+
+#[cfg(feature = "server")]
+/// Converts a FLOAT column of euros to an INTEGER column of cents, if it is not yet
+///
+/// Amounts are rounded to the nearest cent. SQLite cannot change the type of a column,
+/// so the table is rebuilt with `columns` and its rows copied.
+fn convert_to_cents(
+    conn: &rusqlite::Connection,
+    table: &str,
+    columns: &str,
+    column: &str,
+) -> rusqlite::Result<()> {
+    let mut names = Vec::new();
+    let mut is_float = false;
+    {
+        let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let name: String = row.get(1)?;
+            let declared: String = row.get(2)?;
+            if name == column {
+                is_float = declared.eq_ignore_ascii_case("FLOAT");
+            }
+            names.push(name);
+        }
+    }
+    if !is_float {
+        return Ok(());
+    }
+
+    let selected: Vec<String> = names
+        .iter()
+        .map(|name| match name == column {
+            true => format!("CAST(ROUND({} * 100) AS INTEGER)", name),
+            false => name.clone(),
+        })
+        .collect();
+    conn.execute_batch(&format!(
+        "CREATE TABLE {table}_cents ({columns});
+        INSERT INTO {table}_cents ({names}) SELECT {selected} FROM {table};
+        DROP TABLE {table};
+        ALTER TABLE {table}_cents RENAME TO {table};",
+        names = names.join(", "),
+        selected = selected.join(", "),
+    ))
+}
+
+#[cfg(test)]
+#[cfg(feature = "server")]
+mod tests {
+    use super::*;
+
+    fn version(conn: &rusqlite::Connection) -> i64 {
+        schema_version(conn).unwrap()
+    }
+
+    #[test]
+    fn steps_are_numbered_in_order() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, i as i64 + 1);
+        }
+    }
+
+    #[test]
+    fn unversioned_databases_are_upgraded_in_place() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        // Written by a build before the versions, with the hybrid clock columns
+        create_ledger_tables(&conn).unwrap();
+        add_hybrid_clock_columns(&conn).unwrap();
+        conn.execute_batch(
+            "INSERT INTO User VALUES ('alice', 0.29);
+            INSERT INTO Transactions (from_user, to_user, amount, lamport_time, vector_clock_id, source_node)
+            VALUES ('NULL', 'alice', 0.29, 1, 0, 'A');",
+        )
+        .unwrap();
+        assert_eq!(version(&conn), 0);
+        assert_eq!(pending(&conn).unwrap().len(), MIGRATIONS.len());
+
+        migrate(&conn).unwrap();
+        assert_eq!(version(&conn), SCHEMA_VERSION);
+        assert!(pending(&conn).unwrap().is_empty());
+        let (amount, solde): (i64, i64) = conn
+            .query_row(
+                "SELECT amount, solde FROM Transactions JOIN User ON to_user = unique_name",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        // Rounded, 0.29 is slightly less than 29 cents as a float
+        assert_eq!((amount, solde), (29, 29));
+
+        // Nothing left to apply on the next start
+        migrate(&conn).unwrap();
+        assert_eq!(version(&conn), SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn newer_databases_are_refused() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        migrate(&conn).unwrap();
+        conn.execute(
+            "INSERT INTO schema_version VALUES (?1, 'from the future', 0)",
+            [SCHEMA_VERSION + 1],
+        )
+        .unwrap();
+
+        assert!(matches!(
+            migrate(&conn),
+            Err(crate::error::PeillutError::UnsupportedSchema { version, supported })
+                if version == SCHEMA_VERSION + 1 && supported == SCHEMA_VERSION
+        ));
+    }
+}
@@ -0,0 +1,208 @@
+//! Amounts of money
+//!
+//! Amounts are counted in integer cents everywhere: in the database, in the messages sent
+//! to the other sites and in the web interface. A float cannot hold most decimal amounts,
+//! 0.29 is 0.28999999999999998 and became 28 cents once truncated, so balances computed
+//! on two sites could differ.
+
+/// Amount of money, in cents
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(transparent)]
+pub struct Money(i64);
+
+impl Money {
+    /// No money at all
+    pub const ZERO: Money = Money(0);
+
+    /// Largest amount read from a user, a billion euros
+    pub const MAX_AMOUNT: Money = Money::from_euros(1_000_000_000);
+
+    /// Amount of `cents` cents
+    pub const fn from_cents(cents: i64) -> Self {
+        Money(cents)
+    }
+
+    /// Amount of `euros` euros, without cents
+    pub const fn from_euros(euros: i64) -> Self {
+        Money(euros * 100)
+    }
+
+    /// Number of cents of the amount
+    pub const fn cents(self) -> i64 {
+        self.0
+    }
+}
+
+/// Written as euros with two decimals, e.g. `12.05`
+impl std::fmt::Display for Money {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let cents = self.0.unsigned_abs();
+        f.pad(&format!("{}{}.{:02}", sign, cents / 100, cents % 100))
+    }
+}
+
+/// Error of an amount which is not euros with at most two decimals
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseMoneyError(String);
+
+impl std::fmt::Display for ParseMoneyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "invalid amount '{}', expected euros with at most two decimals",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for ParseMoneyError {}
+
+/// Reads euros with at most two decimals, e.g. `12`, `12.5` or `-0.29`, without rounding
+///
+/// Amounts above [`Money::MAX_AMOUNT`] are refused
+impl std::str::FromStr for Money {
+    type Err = ParseMoneyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseMoneyError(s.to_string());
+
+        let trimmed = s.trim();
+        let (negative, digits) = match trimmed.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, trimmed),
+        };
+        let (euros, decimals) = digits.split_once('.').unwrap_or((digits, ""));
+        if (euros.is_empty() && decimals.is_empty())
+            || decimals.len() > 2
+            || !euros
+                .chars()
+                .chain(decimals.chars())
+                .all(|c| c.is_ascii_digit())
+        {
+            return Err(invalid());
+        }
+
+        let euros: i64 = if euros.is_empty() {
+            0
+        } else {
+            euros.parse().map_err(|_| invalid())?
+        };
+        let cents: i64 = format!("{:0<2}", decimals).parse().map_err(|_| invalid())?;
+        let amount = euros
+            .checked_mul(100)
+            .and_then(|amount| amount.checked_add(cents))
+            .filter(|amount| *amount <= Money::MAX_AMOUNT.0)
+            .ok_or_else(invalid)?;
+        Ok(Money(if negative { -amount } else { amount }))
+    }
+}
+
+#[cfg(feature = "server")]
+impl Money {
+    /// Sum of both amounts, an error if it does not fit
+    pub fn checked_add(self, other: Money) -> Result<Money, crate::error::PeillutError> {
+        self.0
+            .checked_add(other.0)
+            .map(Money)
+            .ok_or(crate::error::PeillutError::AmountOverflow)
+    }
+
+    /// Difference of both amounts, an error if it does not fit
+    pub fn checked_sub(self, other: Money) -> Result<Money, crate::error::PeillutError> {
+        self.0
+            .checked_sub(other.0)
+            .map(Money)
+            .ok_or(crate::error::PeillutError::AmountOverflow)
+    }
+}
+
+#[cfg(feature = "server")]
+/// Stored as an INTEGER number of cents
+impl rusqlite::ToSql for Money {
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
+        Ok(rusqlite::types::ToSqlOutput::from(self.0))
+    }
+}
+
+#[cfg(feature = "server")]
+impl rusqlite::types::FromSql for Money {
+    fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
+        i64::column_result(value).map(Money)
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "server")]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn amounts_are_read_without_rounding() {
+        assert_eq!("0.29".parse(), Ok(Money::from_cents(29)));
+        assert_eq!("12".parse(), Ok(Money::from_euros(12)));
+        assert_eq!("12.5".parse(), Ok(Money::from_cents(1250)));
+        assert_eq!(" .05 ".parse(), Ok(Money::from_cents(5)));
+        assert_eq!("-3.10".parse(), Ok(Money::from_cents(-310)));
+        assert_eq!("1000000000".parse(), Ok(Money::MAX_AMOUNT));
+
+        for invalid in [
+            "",
+            "-",
+            ".",
+            "1.234",
+            "1,5",
+            "abc",
+            "1e3",
+            "99999999999999999999",
+            "1000000000.01",
+            "-92233720368547758.07",
+        ] {
+            assert!(invalid.parse::<Money>().is_err(), "{invalid} was read");
+        }
+    }
+
+    #[test]
+    fn amounts_are_written_with_two_decimals() {
+        assert_eq!(Money::from_cents(29).to_string(), "0.29");
+        assert_eq!(Money::from_cents(1205).to_string(), "12.05");
+        assert_eq!(Money::from_cents(-310).to_string(), "-3.10");
+        assert_eq!(format!("{:>6}", Money::from_euros(1)), "  1.00");
+        assert_eq!(
+            Money::from_cents(1205).to_string().parse(),
+            Ok(Money::from_cents(1205))
+        );
+    }
+
+    #[test]
+    fn arithmetic_overflow_is_an_error() {
+        use crate::error::PeillutError;
+
+        assert_eq!(
+            Money::from_euros(1)
+                .checked_add(Money::from_cents(5))
+                .unwrap(),
+            Money::from_cents(105)
+        );
+        assert!(matches!(
+            Money::from_cents(i64::MAX).checked_add(Money::from_cents(1)),
+            Err(PeillutError::AmountOverflow)
+        ));
+        assert!(matches!(
+            Money::from_cents(i64::MIN).checked_sub(Money::from_cents(1)),
+            Err(PeillutError::AmountOverflow)
+        ));
+    }
+}
@@ -0,0 +1,1135 @@
+//! Mutual exclusion between the sites
+//!
+//! The critical commands of a site run in a critical section shared by the whole network.
+//! The algorithm is chosen at launch with `--cli-mutex`, every site of a network must use
+//! the same one:
+//!
+//! - `fifo`: every request and every release is diffused by a wave. A site enters once every
+//!   site recorded its request and no older request is pending, which costs two waves per
+//!   critical section.
+//! - `token`: Suzuki–Kasami. The site holding the token enters at once, as many times as it
+//!   wants without any message. Another site diffuses a numbered request, the holder hands
+//!   the token over when it leaves the critical section, along with the queue of the sites
+//!   still waiting for it.
+//!
+//! A critical section is granted for the accounts its commands touch, see [`LockScope`].
+//! With `fifo`, sites asking for disjoint accounts are in their critical sections at the
+//! same time. The token covers every account, `token` serves one site at a time.
+//!
+//! With `fifo`, a request is granted for a lease, so that a site dying with a pending
+//! request does not block the others, see `crate::lease`.
+//!
+//! No site holds the token when a network starts. The echo of a request tells whether a
+//! site saw the token and which sites are waiting for it; when no site saw it, the oldest
+//! request creates it.
+//!
+//! A site asks again for the token when its request waited for a whole lease. The echo
+//! also tells the last site the token was handed to, and whether it answered. When it did
+//! not, the token is deemed lost with its holder and the oldest request creates it again,
+//! with a larger generation. A token handed over is long arrived by then, but a holder cut
+//! from the others by a partition keeps its token: two networks holding a token each must
+//! not be joined. A site receiving a token older than the last one it saw drops it.
+
+#[cfg(feature = "server")]
+/// Future returned by the operations of a mutual exclusion algorithm
+pub type MutexFuture<'a> = std::pin::Pin<
+    Box<dyn std::future::Future<Output = Result<(), Box<dyn std::error::Error>>> + Send + 'a>,
+>;
+
+#[cfg(feature = "server")]
+/// Algorithm giving the critical section to one site of the network at a time
+pub trait MutualExclusion: Send + Sync {
+    /// Asks for the critical section
+    ///
+    /// Once it is granted, `state.in_sc` is set and `state.notify_sc` is notified
+    fn acquire<'a>(&'a self, state: &'a mut crate::state::AppState) -> MutexFuture<'a>;
+
+    /// Leaves the critical section
+    fn release<'a>(&'a self, state: &'a mut crate::state::AppState) -> MutexFuture<'a>;
+
+    /// Hands over what the other sites need before this site leaves the network
+    fn leave<'a>(&'a self, _state: &'a mut crate::state::AppState) -> MutexFuture<'a> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Mutual exclusion algorithms a site can run
+///
+/// Parsed from the command line, which the web build also compiles
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MutexAlgorithm {
+    /// Requests and releases diffused by waves
+    #[default]
+    Fifo,
+    /// Suzuki–Kasami token
+    Token,
+}
+
+#[cfg(feature = "server")]
+impl MutexAlgorithm {
+    /// Returns the implementation of the algorithm
+    pub fn implementation(self) -> &'static dyn MutualExclusion {
+        match self {
+            MutexAlgorithm::Fifo => &FifoMutex,
+            MutexAlgorithm::Token => &TokenMutex,
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+/// Accounts a critical section is asked for
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum LockScope {
+    /// The listed accounts only
+    Accounts(std::collections::BTreeSet<String>),
+    /// Every account, for the commands reading the whole ledger
+    All,
+}
+
+#[cfg(feature = "server")]
+impl Default for LockScope {
+    fn default() -> Self {
+        LockScope::Accounts(Default::default())
+    }
+}
+
+#[cfg(feature = "server")]
+impl LockScope {
+    /// Returns the scope of the given accounts
+    pub fn accounts<I, S>(names: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        LockScope::Accounts(names.into_iter().map(Into::into).collect())
+    }
+
+    /// Returns true if both scopes share an account
+    pub fn conflicts_with(&self, other: &LockScope) -> bool {
+        match (self, other) {
+            (LockScope::Accounts(a), LockScope::Accounts(b)) => !a.is_disjoint(b),
+            (LockScope::Accounts(a), LockScope::All) | (LockScope::All, LockScope::Accounts(a)) => {
+                !a.is_empty()
+            }
+            (LockScope::All, LockScope::All) => true,
+        }
+    }
+
+    /// Returns true if every account of `other` is in this scope
+    pub fn covers(&self, other: &LockScope) -> bool {
+        match (self, other) {
+            (LockScope::All, _) => true,
+            (LockScope::Accounts(_), LockScope::All) => false,
+            (LockScope::Accounts(a), LockScope::Accounts(b)) => b.is_subset(a),
+        }
+    }
+
+    /// Returns the scope of the accounts of both scopes
+    pub fn union(self, other: LockScope) -> LockScope {
+        match (self, other) {
+            (LockScope::Accounts(mut a), LockScope::Accounts(b)) => {
+                a.extend(b);
+                LockScope::Accounts(a)
+            }
+            _ => LockScope::All,
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+/// Mutual exclusion by waves, the requests are served in the order of their Lamport date
+///
+/// A request only waits for the older requests sharing one of its accounts
+pub struct FifoMutex;
+
+#[cfg(feature = "server")]
+impl MutualExclusion for FifoMutex {
+    fn acquire<'a>(&'a self, state: &'a mut crate::state::AppState) -> MutexFuture<'a> {
+        use crate::message::{Message, MessageInfo, NetworkMessageCode};
+        use crate::state::{LOCAL_APP_STATE, MutexStamp, MutexTag};
+
+        Box::pin(async move {
+            state.update_clock(None).await;
+            let wave_id = state.next_wave_id();
+            let clock = state.get_clock();
+
+            state.global_mutex_fifo.insert(
+                state.get_site_id(),
+                MutexStamp {
+                    tag: MutexTag::Request,
+                    date: *clock.get_lamport(),
+                    scope: state.sc_scope.clone(),
+                },
+            );
+            state.leases.start(tokio::time::Instant::now());
+
+            let msg = Message {
+                sender_id: state.get_site_id(),
+                sender_addr: state.get_site_addr(),
+                message_initiator_id: state.get_site_id(),
+                message_initiator_addr: state.get_site_addr(),
+                clock,
+                command: None,
+                info: MessageInfo::AcquireMutex(crate::message::AcquireMutexPayload {
+                    scope: state.sc_scope.clone(),
+                }),
+                code: NetworkMessageCode::AcquireMutex,
+                signature: None,
+                wave_id: Some(wave_id),
+                causal_deps: None,
+            };
+
+            if state.get_nb_connected_neighbours() > 0 {
+                state.notify_sc.notify_waiters();
+                state.in_sc = false;
+                state.waiting_sc = true;
+                state.sc_request_recorded = false;
+                log::info!("Début de la diffusion d'une acquisition de mutex");
+                let done = crate::network::wave::start_without_lock(state, msg).await?;
+                crate::node::spawn(async move {
+                    // Every site recorded our request, we may enter if we are the oldest one
+                    if done.await.is_ok() {
+                        let mut state = LOCAL_APP_STATE.lock().await;
+                        state.sc_request_recorded = true;
+                        state.try_enter_sc();
+                    }
+                });
+            } else {
+                log::info!("Il n'y a pas de voisins, on prends la section critique");
+                state.in_sc = true;
+                state.waiting_sc = false;
+                state.notify_sc.notify_one();
+            }
+
+            Ok(())
+        })
+    }
+
+    fn release<'a>(&'a self, state: &'a mut crate::state::AppState) -> MutexFuture<'a> {
+        use crate::message::{Message, MessageInfo, NetworkMessageCode};
+
+        Box::pin(async move {
+            state.update_clock(None).await;
+            let wave_id = state.next_wave_id();
+
+            let msg = Message {
+                sender_id: state.get_site_id(),
+                sender_addr: state.get_site_addr(),
+                message_initiator_id: state.get_site_id(),
+                message_initiator_addr: state.get_site_addr(),
+                clock: state.get_clock(),
+                command: None,
+                info: MessageInfo::ReleaseMutex(crate::message::ReleaseMutexPayload),
+                code: NetworkMessageCode::ReleaseGlobalMutex,
+                signature: None,
+                wave_id: Some(wave_id),
+                causal_deps: None,
+            };
+
+            state.global_mutex_fifo.remove(&state.get_site_id());
+            state.leases.stop();
+            state.in_sc = false;
+            state.waiting_sc = false;
+
+            if state.get_nb_connected_neighbours() > 0 {
+                log::info!("Début de la diffusion d'un relachement de mutex");
+                // Nothing to wait for, the other sites only have to forget our request
+                crate::network::wave::start_without_lock(state, msg).await?;
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(feature = "server")]
+/// Wave asking every site to record our request for the global mutex
+pub struct AcquireMutexWave;
+
+#[cfg(feature = "server")]
+impl crate::network::wave::WaveProtocol for AcquireMutexWave {
+    fn code(&self) -> crate::message::NetworkMessageCode {
+        crate::message::NetworkMessageCode::AcquireMutex
+    }
+
+    fn echo_code(&self) -> crate::message::NetworkMessageCode {
+        crate::message::NetworkMessageCode::AckGlobalMutex
+    }
+
+    fn visit(
+        &self,
+        _store: std::sync::Arc<dyn crate::store::LedgerStore>,
+        message: crate::message::Message,
+    ) -> crate::network::wave::VisitFuture {
+        use crate::message::MessageInfo;
+        use crate::state::{MutexStamp, MutexTag};
+
+        Box::pin(async move {
+            let MessageInfo::AcquireMutex(request) = message.info else {
+                return Err("AcquireMutex message without a scope".into());
+            };
+            let mut state = crate::state::LOCAL_APP_STATE.lock().await;
+            state.global_mutex_fifo.insert(
+                message.message_initiator_id,
+                MutexStamp {
+                    tag: MutexTag::Request,
+                    date: *message.clock.get_lamport(),
+                    scope: request.scope,
+                },
+            );
+            Ok(crate::message::MessageInfo::None)
+        })
+    }
+}
+
+#[cfg(feature = "server")]
+/// Wave telling every site that we left the critical section
+pub struct ReleaseMutexWave;
+
+#[cfg(feature = "server")]
+impl crate::network::wave::WaveProtocol for ReleaseMutexWave {
+    fn code(&self) -> crate::message::NetworkMessageCode {
+        crate::message::NetworkMessageCode::ReleaseGlobalMutex
+    }
+
+    fn echo_code(&self) -> crate::message::NetworkMessageCode {
+        crate::message::NetworkMessageCode::AckReleaseGlobalMutex
+    }
+
+    fn visit(
+        &self,
+        _store: std::sync::Arc<dyn crate::store::LedgerStore>,
+        message: crate::message::Message,
+    ) -> crate::network::wave::VisitFuture {
+        Box::pin(async move {
+            let mut state = crate::state::LOCAL_APP_STATE.lock().await;
+            state
+                .global_mutex_fifo
+                .remove(&message.message_initiator_id);
+            // The next site in the FIFO may be us, once every site recorded our request
+            if state.sc_request_recorded {
+                state.try_enter_sc();
+            }
+            Ok(crate::message::MessageInfo::None)
+        })
+    }
+}
+
+#[cfg(feature = "server")]
+/// What a site knows of the token
+#[derive(Debug, Default)]
+pub struct TokenState {
+    /// Number of the last request of each site
+    requests: std::collections::HashMap<String, u64>,
+    /// Lamport date of our pending request
+    requested_at: Option<i64>,
+    /// The token, while this site holds it
+    token: Option<crate::message::Token>,
+    /// Whether this site saw the token
+    token_known: bool,
+    /// Last site the token was handed to, with the generation of the token
+    holder: Option<(u64, String)>,
+    /// When our pending request was made, the requests made again keep it
+    requested_since: Option<tokio::time::Instant>,
+    /// When our pending request was last diffused
+    asked_at: Option<tokio::time::Instant>,
+}
+
+#[cfg(feature = "server")]
+impl TokenState {
+    /// Returns true if this site holds the token
+    pub fn holds_token(&self) -> bool {
+        self.token.is_some()
+    }
+
+    /// Numbers a new request of this site, made at Lamport date `date` and at `now`
+    fn request(&mut self, site_id: &str, date: i64, now: tokio::time::Instant) -> u64 {
+        self.requested_at = Some(date);
+        self.requested_since.get_or_insert(now);
+        self.asked_at = Some(now);
+        let seq = self.requests.entry(site_id.to_string()).or_insert(0);
+        *seq += 1;
+        *seq
+    }
+
+    /// Records a request of another site, outdated requests are ignored
+    fn record_request(&mut self, site_id: &str, seq: u64) {
+        let last = self.requests.entry(site_id.to_string()).or_insert(0);
+        *last = (*last).max(seq);
+    }
+
+    /// Returns what this site knows of the token, to answer a request
+    fn status(&self, site_id: &str) -> crate::message::TokenStatusPayload {
+        crate::message::TokenStatusPayload {
+            token_known: self.token_known,
+            requesters: self
+                .requested_at
+                .map(|date| (date, site_id.to_string()))
+                .into_iter()
+                .collect(),
+            holder: self.holder.clone(),
+            reached: vec![site_id.to_string()],
+        }
+    }
+
+    /// Returns true if our pending request was diffused a lease ago without the token
+    /// coming
+    pub fn should_ask_again(&self, now: tokio::time::Instant, lease: std::time::Duration) -> bool {
+        self.token.is_none() && self.asked_at.is_some_and(|at| at + lease <= now)
+    }
+
+    /// Records the hand-over of the token of `generation` to `to`
+    ///
+    /// Returns false if a newer token was seen, the token handed over is then lost
+    pub fn saw_transfer(&mut self, generation: u64, to: &str) -> bool {
+        self.token_known = true;
+        if self
+            .holder
+            .as_ref()
+            .is_some_and(|(known, _)| *known > generation)
+        {
+            return false;
+        }
+        self.holder = Some((generation, to.to_string()));
+        true
+    }
+
+    /// Returns the generation of the token to create after our request, None if the token
+    /// is alive or an older request creates it
+    ///
+    /// `status` is the echo of the request merged with the status of this site. The token
+    /// is created if no site saw it, or created again if its last holder did not answer
+    /// although our request waited for a whole lease.
+    fn to_create(
+        &self,
+        status: &crate::message::TokenStatusPayload,
+        site_id: &str,
+        now: tokio::time::Instant,
+        lease: std::time::Duration,
+    ) -> Option<u64> {
+        let lost = match &status.holder {
+            Some((_, holder)) => {
+                !status.reached.contains(holder)
+                    && self
+                        .requested_since
+                        .is_some_and(|since| since + lease <= now)
+            }
+            None => !status.token_known,
+        };
+        let oldest = status.requesters.iter().min();
+        if !lost || oldest.is_none_or(|(_, oldest)| oldest != site_id) {
+            return None;
+        }
+        Some(
+            status
+                .holder
+                .as_ref()
+                .map_or(0, |(generation, _)| generation + 1),
+        )
+    }
+
+    /// Returns true if a request of `site_id` has not been served yet
+    fn is_waiting(&self, token: &crate::message::Token, site_id: &str) -> bool {
+        let served = token.last_served.get(site_id).copied().unwrap_or(0);
+        self.requests.get(site_id).copied().unwrap_or(0) > served
+    }
+
+    /// Takes the token to hand it over to `site_id`, if this site holds it and the site is
+    /// waiting for it
+    fn grant(&mut self, site_id: &str) -> Option<crate::message::Token> {
+        match &self.token {
+            Some(token) if self.is_waiting(token, site_id) => self.token.take(),
+            _ => None,
+        }
+    }
+
+    /// Takes the token on leaving the critical section, if another site is waiting for it
+    ///
+    /// The sites waiting for the token are queued in the order of their IDs, the first one
+    /// gets it
+    fn pass_on(&mut self, site_id: &str) -> Option<(String, crate::message::Token)> {
+        let mut token = self.token.take()?;
+        let served = self.requests.get(site_id).copied().unwrap_or(0);
+        token.last_served.insert(site_id.to_string(), served);
+
+        let mut waiting: Vec<&String> = self
+            .requests
+            .keys()
+            .filter(|id| !token.queue.contains(*id) && self.is_waiting(&token, id))
+            .collect();
+        waiting.sort();
+        token.queue.extend(waiting.into_iter().cloned());
+
+        match token.queue.pop_front() {
+            Some(next) => Some((next, token)),
+            None => {
+                self.token = Some(token);
+                None
+            }
+        }
+    }
+
+    /// Keeps the token handed over to `site_id`, this site
+    fn receive(&mut self, token: crate::message::Token, site_id: &str) {
+        self.requested_at = None;
+        self.requested_since = None;
+        self.asked_at = None;
+        self.holder = Some((token.generation, site_id.to_string()));
+        self.token = Some(token);
+        self.token_known = true;
+    }
+}
+
+#[cfg(feature = "server")]
+/// Mutual exclusion by the Suzuki–Kasami token
+pub struct TokenMutex;
+
+#[cfg(feature = "server")]
+impl MutualExclusion for TokenMutex {
+    fn acquire<'a>(&'a self, state: &'a mut crate::state::AppState) -> MutexFuture<'a> {
+        use crate::message::{Message, MessageInfo, NetworkMessageCode, TokenRequestPayload};
+
+        Box::pin(async move {
+            if state.token.holds_token() {
+                enter(state);
+                return Ok(());
+            }
+
+            state.update_clock(None).await;
+            let clock = state.get_clock();
+            let seq = state.token.request(
+                &state.get_site_id(),
+                *clock.get_lamport(),
+                tokio::time::Instant::now(),
+            );
+            state.in_sc = false;
+            state.waiting_sc = true;
+
+            let msg = Message {
+                sender_id: state.get_site_id(),
+                sender_addr: state.get_site_addr(),
+                message_initiator_id: state.get_site_id(),
+                message_initiator_addr: state.get_site_addr(),
+                clock,
+                command: None,
+                info: MessageInfo::TokenRequest(TokenRequestPayload { seq }),
+                code: NetworkMessageCode::TokenRequest,
+                signature: None,
+                wave_id: Some(state.next_wave_id()),
+                causal_deps: None,
+            };
+            log::info!("Demande du jeton n°{}", seq);
+            let done = crate::network::wave::start_without_lock(state, msg).await?;
+            crate::node::spawn(async move {
+                // Every site answered, the token is created if none of them saw it
+                if let Ok(echo) = done.await {
+                    let mut state = crate::state::LOCAL_APP_STATE.lock().await;
+                    create_token(&mut state, echo);
+                }
+            });
+            Ok(())
+        })
+    }
+
+    fn release<'a>(&'a self, state: &'a mut crate::state::AppState) -> MutexFuture<'a> {
+        Box::pin(async move {
+            state.in_sc = false;
+            state.waiting_sc = false;
+            match state.token.pass_on(&state.get_site_id()) {
+                Some((next, token)) => send_token(state, next, token).await,
+                None => Ok(()),
+            }
+        })
+    }
+
+    /// The token is given to the next waiting site, or to a neighbour
+    fn leave<'a>(&'a self, state: &'a mut crate::state::AppState) -> MutexFuture<'a> {
+        Box::pin(async move {
+            if state.in_sc {
+                return Ok(());
+            }
+            if let Some((next, token)) = state.token.pass_on(&state.get_site_id()) {
+                return send_token(state, next, token).await;
+            }
+            let neighbour = state
+                .get_connected_nei_addr()
+                .iter()
+                .find_map(|addr| state.site_ids_to_adr.get(addr).cloned());
+            match (neighbour, state.token.token.take()) {
+                (Some(neighbour), Some(token)) => send_token(state, neighbour, token).await,
+                (None, token) => {
+                    state.token.token = token;
+                    Ok(())
+                }
+                (Some(_), None) => Ok(()),
+            }
+        })
+    }
+}
+
+#[cfg(feature = "server")]
+/// Enters the critical section with the token, which covers every account
+fn enter(state: &mut crate::state::AppState) {
+    state.sc_scope = LockScope::All;
+    state.waiting_sc = false;
+    state.in_sc = true;
+    // The permit is kept if the control worker is busy, it enters on its next wait
+    state.notify_sc.notify_one();
+}
+
+#[cfg(feature = "server")]
+/// Creates the token after our request if it was never created or was lost, and no
+/// older request is pending, see [`TokenState::to_create`]
+fn create_token(state: &mut crate::state::AppState, echo: crate::message::MessageInfo) {
+    use crate::message::MessageInfo;
+
+    let site_id = state.get_site_id();
+    if state.token.holds_token() || !state.waiting_sc {
+        return;
+    }
+    let mut status = state.token.status(&site_id);
+    // Nothing is merged if no other site answered
+    if let MessageInfo::TokenStatus(echoed) = echo {
+        merge_status(&mut status, echoed);
+    }
+    let lease = state.leases.get_duration();
+    let Some(generation) =
+        state
+            .token
+            .to_create(&status, &site_id, tokio::time::Instant::now(), lease)
+    else {
+        return;
+    };
+    match &status.holder {
+        Some((_, holder)) => {
+            log::warn!("Le jeton est perdu avec {}, il est créé à nouveau", holder)
+        }
+        None => log::info!("Aucun site n'a vu le jeton, il est créé"),
+    }
+    state.token.receive(
+        crate::message::Token {
+            generation,
+            ..Default::default()
+        },
+        &site_id,
+    );
+    enter(state);
+}
+
+#[cfg(feature = "server")]
+/// Merges the status echoed by a site into `acc`
+fn merge_status(
+    acc: &mut crate::message::TokenStatusPayload,
+    child: crate::message::TokenStatusPayload,
+) {
+    acc.token_known |= child.token_known;
+    acc.requesters.extend(child.requesters);
+    acc.holder = acc.holder.take().max(child.holder);
+    acc.reached.extend(child.reached);
+}
+
+#[cfg(feature = "server")]
+/// Hands the token over to `to` by a wave
+async fn send_token(
+    state: &mut crate::state::AppState,
+    to: String,
+    mut token: crate::message::Token,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use crate::message::{Message, MessageInfo, NetworkMessageCode, TokenPayload};
+
+    log::info!("Le jeton est transmis à {}", to);
+    // The initiator is not visited by its own wave
+    token.generation += 1;
+    state.token.saw_transfer(token.generation, &to);
+    state.update_clock(None).await;
+    let msg = Message {
+        sender_id: state.get_site_id(),
+        sender_addr: state.get_site_addr(),
+        message_initiator_id: state.get_site_id(),
+        message_initiator_addr: state.get_site_addr(),
+        clock: state.get_clock(),
+        command: None,
+        info: MessageInfo::Token(TokenPayload { to, token }),
+        code: NetworkMessageCode::TokenTransfer,
+        signature: None,
+        wave_id: Some(state.next_wave_id()),
+        causal_deps: None,
+    };
+    // Nothing to wait for, the token is taken by its site when the wave reaches it
+    crate::network::wave::start_without_lock(state, msg).await?;
+    Ok(())
+}
+
+#[cfg(feature = "server")]
+/// Wave diffusing a request for the token
+pub struct TokenRequestWave;
+
+#[cfg(feature = "server")]
+impl crate::network::wave::WaveProtocol for TokenRequestWave {
+    fn code(&self) -> crate::message::NetworkMessageCode {
+        crate::message::NetworkMessageCode::TokenRequest
+    }
+
+    fn echo_code(&self) -> crate::message::NetworkMessageCode {
+        crate::message::NetworkMessageCode::AckTokenRequest
+    }
+
+    /// The holder hands the token over if it is not in the critical section
+    fn visit(
+        &self,
+        _store: std::sync::Arc<dyn crate::store::LedgerStore>,
+        message: crate::message::Message,
+    ) -> crate::network::wave::VisitFuture {
+        use crate::message::MessageInfo;
+
+        Box::pin(async move {
+            let MessageInfo::TokenRequest(request) = message.info else {
+                return Err("TokenRequest message without a request".into());
+            };
+            let requester = message.message_initiator_id;
+            let mut state = crate::state::LOCAL_APP_STATE.lock().await;
+            // A request we make afterwards is dated after this one
+            state.update_clock(Some(&message.clock)).await;
+            state.token.record_request(&requester, request.seq);
+            let status = state.token.status(&state.get_site_id());
+
+            if !state.in_sc
+                && let Some(token) = state.token.grant(&requester)
+            {
+                send_token(&mut state, requester, token)
+                    .await
+                    .map_err(|e| e.to_string())?;
+            }
+            Ok(MessageInfo::TokenStatus(status))
+        })
+    }
+
+    /// The sites which saw the token and the requesters are gathered
+    fn reduce(
+        &self,
+        acc: crate::message::MessageInfo,
+        child: crate::message::MessageInfo,
+    ) -> crate::message::MessageInfo {
+        use crate::message::MessageInfo;
+
+        match (acc, child) {
+            (MessageInfo::TokenStatus(mut acc), MessageInfo::TokenStatus(child)) => {
+                merge_status(&mut acc, child);
+                MessageInfo::TokenStatus(acc)
+            }
+            (acc, child) => {
+                log::error!("Unexpected token request echo: {:?}", child);
+                acc
+            }
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+/// Wave carrying the token to the site it is handed over to
+pub struct TokenTransferWave;
+
+#[cfg(feature = "server")]
+impl crate::network::wave::WaveProtocol for TokenTransferWave {
+    fn code(&self) -> crate::message::NetworkMessageCode {
+        crate::message::NetworkMessageCode::TokenTransfer
+    }
+
+    fn echo_code(&self) -> crate::message::NetworkMessageCode {
+        crate::message::NetworkMessageCode::AckTokenTransfer
+    }
+
+    fn visit(
+        &self,
+        _store: std::sync::Arc<dyn crate::store::LedgerStore>,
+        message: crate::message::Message,
+    ) -> crate::network::wave::VisitFuture {
+        use crate::message::MessageInfo;
+
+        Box::pin(async move {
+            let MessageInfo::Token(payload) = message.info else {
+                return Err("TokenTransfer message without a token".into());
+            };
+            let mut state = crate::state::LOCAL_APP_STATE.lock().await;
+            let fresh = state
+                .token
+                .saw_transfer(payload.token.generation, &payload.to);
+            if payload.to != state.get_site_id() {
+                return Ok(MessageInfo::None);
+            }
+            if !fresh {
+                log::warn!(
+                    "Jeton périmé reçu de {}, il est abandonné",
+                    message.message_initiator_id
+                );
+                return Ok(MessageInfo::None);
+            }
+
+            log::info!("Jeton reçu de {}", message.message_initiator_id);
+            let site_id = state.get_site_id();
+            state.token.receive(payload.token, &site_id);
+            if state.pending_commands.is_empty() {
+                // Nothing to run anymore, the token goes on to the next waiting site
+                TokenMutex
+                    .release(&mut state)
+                    .await
+                    .map_err(|e| e.to_string())?;
+            } else {
+                enter(&mut state);
+            }
+            Ok(MessageInfo::None)
+        })
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "server")]
+mod tests {
+    use super::*;
+    use crate::control::CriticalCommands;
+    use crate::money::Money;
+    use crate::network::sim::{SimConfig, SimNetwork};
+    use crate::node::testing::{balance, create_user, enqueue, start_triangle, wait_until};
+    use std::time::Duration;
+
+    fn holder(requests: &[(&str, u64)], last_served: &[(&str, u64)]) -> TokenState {
+        let mut state = TokenState::default();
+        for (site_id, seq) in requests {
+            state.record_request(site_id, *seq);
+        }
+        state.receive(
+            crate::message::Token {
+                last_served: last_served
+                    .iter()
+                    .map(|(site_id, seq)| (site_id.to_string(), *seq))
+                    .collect(),
+                queue: Default::default(),
+                generation: 0,
+            },
+            "A",
+        );
+        state
+    }
+
+    #[test]
+    fn scopes_conflict_on_shared_accounts() {
+        let alice = LockScope::accounts(["alice"]);
+        let transfer = LockScope::accounts(["alice", "bob"]);
+        let carol = LockScope::accounts(["carol"]);
+
+        assert!(alice.conflicts_with(&transfer));
+        assert!(!alice.conflicts_with(&carol));
+        assert!(LockScope::All.conflicts_with(&carol));
+        assert!(!LockScope::default().conflicts_with(&alice));
+        assert!(!LockScope::default().conflicts_with(&LockScope::All));
+
+        assert!(transfer.covers(&alice));
+        assert!(!alice.covers(&transfer));
+        assert!(!transfer.covers(&LockScope::All));
+        assert!(LockScope::All.covers(&transfer));
+        assert_eq!(
+            alice.clone().union(carol),
+            LockScope::accounts(["alice", "carol"])
+        );
+        assert_eq!(alice.union(LockScope::All), LockScope::All);
+    }
+
+    #[test]
+    fn older_requests_on_other_accounts_do_not_block() {
+        use crate::state::{MutexStamp, MutexTag};
+
+        let mut state = crate::state::AppState::new(
+            "A".to_string(),
+            Vec::new(),
+            "127.0.0.1:8080".parse().unwrap(),
+        );
+        let request = |date, scope| MutexStamp {
+            tag: MutexTag::Request,
+            date,
+            scope,
+        };
+        state
+            .global_mutex_fifo
+            .insert("B".to_string(), request(1, LockScope::accounts(["alice"])));
+
+        state
+            .global_mutex_fifo
+            .insert("A".to_string(), request(2, LockScope::accounts(["bob"])));
+        state.try_enter_sc();
+        assert!(state.in_sc);
+
+        state.in_sc = false;
+        state.global_mutex_fifo.insert(
+            "A".to_string(),
+            request(3, LockScope::accounts(["alice", "bob"])),
+        );
+        state.try_enter_sc();
+        assert!(!state.in_sc);
+    }
+
+    #[test]
+    fn holder_keeps_the_token_while_nobody_waits() {
+        let mut state = holder(&[("A", 2), ("B", 1)], &[("B", 1)]);
+        state.request("A", 7, tokio::time::Instant::now());
+        assert!(state.pass_on("A").is_none());
+        assert!(state.holds_token());
+        assert!(state.grant("B").is_none());
+    }
+
+    #[test]
+    fn token_goes_to_the_waiting_sites_in_turn() {
+        let mut state = holder(&[("A", 1), ("B", 1), ("C", 2)], &[("C", 1)]);
+
+        let (next, token) = state.pass_on("A").expect("B and C are waiting");
+        assert_eq!(next, "B");
+        assert_eq!(token.queue, ["C".to_string()]);
+        assert_eq!(token.last_served.get("A"), Some(&1));
+        assert!(!state.holds_token());
+
+        // B records its own requests, then leaves the critical section
+        let mut b = TokenState::default();
+        b.record_request("C", 2);
+        b.request("B", 3, tokio::time::Instant::now());
+        b.receive(token, "B");
+        let (next, token) = b.pass_on("B").expect("C is queued");
+        assert_eq!(next, "C");
+        assert!(token.queue.is_empty());
+    }
+
+    #[test]
+    fn idle_holder_grants_pending_requests_only() {
+        let mut state = holder(&[("B", 1)], &[("B", 1)]);
+        assert!(state.grant("B").is_none());
+
+        state.record_request("B", 2);
+        assert!(state.grant("B").is_some());
+        assert!(!state.holds_token());
+    }
+
+    #[test]
+    fn status_lists_the_pending_request() {
+        let mut state = TokenState::default();
+        assert!(state.status("A").requesters.is_empty());
+
+        state.request("A", 4, tokio::time::Instant::now());
+        let status = state.status("A");
+        assert!(!status.token_known);
+        assert_eq!(status.requesters, [(4, "A".to_string())]);
+    }
+
+    #[test]
+    fn token_lost_with_its_holder_is_created_again_after_a_lease() {
+        let lease = std::time::Duration::from_secs(5);
+        let since = tokio::time::Instant::now();
+        let mut state = TokenState::default();
+        state.saw_transfer(3, "Z");
+        state.request("A", 4, since);
+
+        let mut status = state.status("A");
+        merge_status(
+            &mut status,
+            crate::message::TokenStatusPayload {
+                token_known: true,
+                holder: Some((2, "B".to_string())),
+                reached: vec!["B".to_string()],
+                ..Default::default()
+            },
+        );
+        assert_eq!(status.holder, Some((3, "Z".to_string())));
+
+        // Z may still be on its way, or the token on its way to Z
+        assert_eq!(state.to_create(&status, "A", since, lease), None);
+        assert!(!state.should_ask_again(since, lease));
+        assert!(state.should_ask_again(since + lease, lease));
+        assert_eq!(state.to_create(&status, "A", since + lease, lease), Some(4));
+
+        // An older request creates it
+        status.requesters.push((1, "B".to_string()));
+        assert_eq!(state.to_create(&status, "A", since + lease, lease), None);
+
+        // A holder which answered keeps it
+        status.requesters.pop();
+        status.reached.push("Z".to_string());
+        assert_eq!(state.to_create(&status, "A", since + lease, lease), None);
+    }
+
+    #[test]
+    fn token_older_than_the_last_one_seen_is_dropped() {
+        let mut state = TokenState::default();
+        assert!(state.saw_transfer(5, "B"));
+        assert!(!state.saw_transfer(4, "C"));
+        assert_eq!(state.holder, Some((5, "B".to_string())));
+        assert!(state.saw_transfer(6, "C"));
+    }
+
+    /// Runs three deposits on A, returns the number of messages they took
+    async fn messages_for_three_deposits(mutex: MutexAlgorithm) -> usize {
+        let sim = SimNetwork::new(SimConfig {
+            seed: 5,
+            ..SimConfig::default()
+        });
+        let nodes = start_triangle(&sim, mutex).await;
+        create_user(&nodes, &nodes[0], "alice").await;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let before = sim.trace().len();
+        for i in 1..=3 {
+            enqueue(
+                &nodes[0],
+                CriticalCommands::Deposit {
+                    name: "alice".to_string(),
+                    amount: Money::from_euros(10),
+                },
+            )
+            .await;
+            wait_until(&nodes, "the deposit", || {
+                balance("alice") == Some(Money::from_euros(10 * i))
+            })
+            .await;
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        sim.trace().len() - before
+    }
+
+    fn holds_token() -> bool {
+        crate::state::LOCAL_APP_STATE
+            .get()
+            .try_lock()
+            .is_ok_and(|s| s.token.holds_token())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn token_holder_enters_without_messages() {
+        let fifo = messages_for_three_deposits(MutexAlgorithm::Fifo).await;
+        let token = messages_for_three_deposits(MutexAlgorithm::Token).await;
+
+        // A transaction wave on the triangle: 2 sends, 2 relays, 2 echoes
+        assert_eq!(token, 3 * 6);
+        // The wave mutex adds a wave to acquire and another one to release
+        assert_eq!(fifo, 3 * token);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn token_is_handed_over_to_the_requesting_site() {
+        let sim = SimNetwork::new(SimConfig {
+            seed: 9,
+            ..SimConfig::default()
+        });
+        let nodes = start_triangle(&sim, MutexAlgorithm::Token).await;
+
+        create_user(&nodes, &nodes[0], "alice").await;
+        wait_until(&nodes[..1], "A to hold the token", holds_token).await;
+
+        for (node, amount) in [
+            (&nodes[1], Money::from_euros(10)),
+            (&nodes[2], Money::from_euros(20)),
+            (&nodes[0], Money::from_euros(30)),
+        ] {
+            enqueue(
+                node,
+                CriticalCommands::Deposit {
+                    name: "alice".to_string(),
+                    amount,
+                },
+            )
+            .await;
+        }
+        wait_until(&nodes, "the deposits", || {
+            balance("alice") == Some(Money::from_euros(60))
+        })
+        .await;
+
+        let mut holders = 0;
+        for node in &nodes {
+            holders += node.run(async { holds_token() }).await as usize;
+        }
+        assert_eq!(holders, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn token_lost_with_its_holder_is_created_again() {
+        let sim = SimNetwork::new(SimConfig {
+            seed: 17,
+            ..SimConfig::default()
+        });
+        let nodes = start_triangle(&sim, MutexAlgorithm::Token).await;
+
+        // Z died holding the token every site saw handed to it
+        for node in &nodes {
+            node.run(async {
+                let mut state = crate::state::LOCAL_APP_STATE.lock().await;
+                state.token.saw_transfer(5, "Z");
+            })
+            .await;
+        }
+        create_user(&nodes, &nodes[1], "alice").await;
+        wait_until(&nodes[1..2], "B to hold the token", holds_token).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_sections_keep_overdraft_protection() {
+        let sim = SimNetwork::new(SimConfig {
+            seed: 13,
+            ..SimConfig::default()
+        });
+        let nodes = start_triangle(&sim, MutexAlgorithm::Fifo).await;
+
+        for name in ["alice", "bob"] {
+            create_user(&nodes, &nodes[0], name).await;
+        }
+        enqueue(
+            &nodes[0],
+            CriticalCommands::Deposit {
+                name: "alice".to_string(),
+                amount: Money::from_euros(10),
+            },
+        )
+        .await;
+        wait_until(&nodes, "alice to be funded", || {
+            balance("alice") == Some(Money::from_euros(10))
+        })
+        .await;
+
+        // A and C spend the same money while B works on another account
+        enqueue(
+            &nodes[0],
+            CriticalCommands::Withdraw {
+                name: "alice".to_string(),
+                amount: Money::from_euros(10),
+            },
+        )
+        .await;
+        enqueue(
+            &nodes[1],
+            CriticalCommands::Deposit {
+                name: "bob".to_string(),
+                amount: Money::from_euros(5),
+            },
+        )
+        .await;
+        enqueue(
+            &nodes[2],
+            CriticalCommands::Pay {
+                name: "alice".to_string(),
+                amount: Money::from_euros(10),
+            },
+        )
+        .await;
+        wait_until(&nodes, "the sections to end", || {
+            crate::state::LOCAL_APP_STATE
+                .get()
+                .try_lock()
+                .is_ok_and(|s| !s.in_sc && !s.waiting_sc && s.pending_commands.is_empty())
+                && balance("bob") == Some(Money::from_euros(5))
+        })
+        .await;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        // Only one of the two payments went through
+        for node in &nodes {
+            assert_eq!(
+                node.run(async { balance("alice") }).await,
+                Some(Money::from_euros(0))
+            );
+        }
+    }
+}
@@ -3,6 +3,16 @@
 //! This module handles all network-related functionality, including peer discovery,
 //! message sending/receiving, and connection management in the distributed system.
 
+#[cfg(feature = "server")]
+pub mod wave;
+
+#[cfg(feature = "server")]
+pub mod transport;
+
+#[cfg(test)]
+#[cfg(feature = "server")]
+pub mod sim;
+
 #[cfg(feature = "server")]
 /// Represents a connection to a peer node
 pub struct PeerConnection {
@@ -17,6 +27,12 @@ pub struct NetworkManager {
     pub nb_active_connections: u16,
     /// Pool of active peer connections
     pub connection_pool: std::collections::HashMap<std::net::SocketAddr, PeerConnection>,
+    /// Codec used to frame messages on every connection
+    codec: crate::codec::FrameCodec,
+    /// TLS configuration, plain TCP is used when None
+    tls: Option<crate::tls::TlsConfig>,
+    /// Transport carrying the encoded messages to the peers
+    transport: std::sync::Arc<dyn transport::Transport>,
 }
 
 #[cfg(feature = "server")]
@@ -26,9 +42,42 @@ impl NetworkManager {
         Self {
             nb_active_connections: 0,
             connection_pool: std::collections::HashMap::new(),
+            codec: crate::codec::FrameCodec::default(),
+            tls: None,
+            transport: std::sync::Arc::new(transport::TcpTransport),
         }
     }
 
+    /// Sets the transport carrying the messages, at initialization
+    pub fn init_transport(&mut self, transport: std::sync::Arc<dyn transport::Transport>) {
+        self.transport = transport;
+    }
+
+    /// Returns the transport carrying the messages
+    pub fn get_transport(&self) -> std::sync::Arc<dyn transport::Transport> {
+        self.transport.clone()
+    }
+
+    /// Enables mutual TLS on every peer connection, at initialization
+    pub fn init_tls(&mut self, tls: crate::tls::TlsConfig) {
+        self.tls = Some(tls);
+    }
+
+    /// Returns the TLS configuration if TLS is enabled
+    pub fn get_tls(&self) -> Option<crate::tls::TlsConfig> {
+        self.tls.clone()
+    }
+
+    /// Sets the codec used to frame messages, at initialization
+    pub fn init_codec(&mut self, codec: crate::codec::FrameCodec) {
+        self.codec = codec;
+    }
+
+    /// Returns the codec used to frame messages
+    pub fn get_codec(&self) -> crate::codec::FrameCodec {
+        self.codec
+    }
+
     /// Adds a new peer connection to the connection pool
     fn add_connection(
         &mut self,
@@ -50,7 +99,13 @@ impl NetworkManager {
 
         let stream = TcpStream::connect(site_addr).await?;
         let (tx, rx) = mpsc::channel(256);
-        spawn_writer_task(stream, rx).await;
+        match &self.tls {
+            Some(tls) => {
+                let stream = tls.connect(site_addr, stream).await?;
+                spawn_writer_task(stream, rx, self.codec).await;
+            }
+            None => spawn_writer_task(stream, rx, self.codec).await,
+        }
         self.add_connection(site_addr, tx);
         Ok(())
     }
@@ -70,24 +125,26 @@ impl NetworkManager {
 }
 
 #[cfg(feature = "server")]
-lazy_static::lazy_static! {
-    pub static ref NETWORK_MANAGER: std::sync::Arc<tokio::sync::Mutex<NetworkManager>> =
-        std::sync::Arc::new(tokio::sync::Mutex::new(NetworkManager::new()));
-}
+/// Network manager of the node running the current task
+pub static NETWORK_MANAGER: crate::node::NodeLocal<tokio::sync::Mutex<NetworkManager>> =
+    crate::node::NodeLocal::new(|node| &node.network);
 
 #[cfg(feature = "server")]
 /// Spawns a task to handle writing messages to a peer connection
-pub async fn spawn_writer_task(
-    stream: tokio::net::TcpStream,
+///
+/// Each payload received on the channel is written as a single frame
+pub async fn spawn_writer_task<S>(
+    stream: S,
     mut rx: tokio::sync::mpsc::Receiver<Vec<u8>>,
-) {
-    use tokio::io::AsyncWriteExt;
-
+    codec: crate::codec::FrameCodec,
+) where
+    S: tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
     tokio::spawn(async move {
         let mut stream = stream;
         while let Some(data) = rx.recv().await {
-            if stream.write_all(&data).await.is_err() {
-                log::error!("Failed to send message");
+            if let Err(e) = codec.write_frame(&mut stream, &data).await {
+                log::error!("Failed to send message: {}", e);
                 break;
             }
         }
@@ -98,8 +155,9 @@ pub async fn spawn_writer_task(
 #[cfg(feature = "server")]
 /// Announces this node's presence to potential peers in the network.
 /// If the user gave peers in args, we will only connect to those peers.
-/// If not, we will scan the port range and try connecting to all sockets.
-pub async fn announce(ip: &str, start_port: u16, end_port: u16, selected_port: u16) {
+/// If not, we will send a beacon to the discovery group and connect to the sites that answer,
+/// unless LAN discovery is disabled.
+pub async fn announce(discovery_group: Option<std::net::SocketAddrV4>) {
     use crate::message::{MessageInfo, NetworkMessageCode};
     use crate::state::LOCAL_APP_STATE;
 
@@ -117,12 +175,25 @@ pub async fn announce(ip: &str, start_port: u16, end_port: u16, selected_port: u
     let peer_to_ping: Vec<std::net::SocketAddr> = if !cli_peers.is_empty() {
         log::debug!("Manually connecting to peers based on args");
         cli_peers
+    } else if let Some(discovery_group) = discovery_group {
+        log::debug!("Looking for peers on the LAN through {}", discovery_group);
+        let own = crate::beacon::Beacon::new(site_id.clone(), local_addr);
+        match crate::beacon::discover(
+            discovery_group,
+            crate::beacon::interface_for(local_addr.ip()),
+            &own,
+            crate::beacon::DISCOVERY_WINDOW,
+        )
+        .await
+        {
+            Ok(beacons) => beacons.into_iter().map(|b| b.peer_addr).collect(),
+            Err(e) => {
+                log::error!("Unable to look for peers on the LAN: {}", e);
+                Vec::new()
+            }
+        }
     } else {
-        log::debug!("Looking for all ports to find potential peers");
-        (start_port..=end_port)
-            .filter(|&port| port != selected_port)
-            .map(|port| format!("{}:{}", ip, port).parse().unwrap())
-            .collect()
+        Vec::new()
     };
 
     //If there are no peers, we don't need to do anything
@@ -143,10 +214,9 @@ pub async fn announce(ip: &str, start_port: u16, end_port: u16, selected_port: u
     for addr in peer_to_ping {
         let site_id = site_id.clone();
         let clocks = clocks.clone();
-        let local_addr = local_addr.clone();
         let success_count = Arc::clone(&success_count);
 
-        let handle = tokio::spawn(async move {
+        let handle = crate::node::spawn(async move {
             let result = send_message(
                 addr,
                 MessageInfo::None,
@@ -156,6 +226,7 @@ pub async fn announce(ip: &str, start_port: u16, end_port: u16, selected_port: u
                 &site_id,
                 &site_id,
                 local_addr,
+                None,
                 clocks,
             )
             .await;
@@ -179,13 +250,97 @@ pub async fn announce(ip: &str, start_port: u16, end_port: u16, selected_port: u
     }
 }
 
+#[cfg(feature = "server")]
+/// Spawns the task sending heartbeats to the neighbours
+///
+/// At each heartbeat, the neighbours suspected by the failure detector are removed
+/// from the network, so that waves stop waiting for them
+pub fn spawn_heartbeat_task() {
+    use crate::message::{MessageInfo, NetworkMessageCode};
+    use crate::state::LOCAL_APP_STATE;
+
+    crate::node::spawn(async move {
+        let heartbeat_interval = {
+            let state = LOCAL_APP_STATE.lock().await;
+            state.get_heartbeat_interval()
+        };
+        let mut interval = tokio::time::interval(heartbeat_interval);
+        loop {
+            interval.tick().await;
+
+            let (local_addr, site_id, clock, neighbours) = {
+                let mut state = LOCAL_APP_STATE.lock().await;
+                for addr in state.take_suspected_neighbours() {
+                    log::warn!(
+                        "Neighbour {} is suspected to have failed, removing it",
+                        addr
+                    );
+                    state.remove_peer(addr).await;
+                }
+                (
+                    state.get_site_addr(),
+                    state.get_site_id(),
+                    state.get_clock(),
+                    state.get_connected_nei_addr(),
+                )
+            };
+
+            for addr in neighbours {
+                let site_id = site_id.clone();
+                let clock = clock.clone();
+                // A frozen neighbour must not delay the heartbeats of the others
+                crate::node::spawn(async move {
+                    if let Err(e) = send_message(
+                        addr,
+                        MessageInfo::None,
+                        None,
+                        NetworkMessageCode::Heartbeat,
+                        local_addr,
+                        &site_id,
+                        &site_id,
+                        local_addr,
+                        None,
+                        clock,
+                    )
+                    .await
+                    {
+                        log::debug!("Unable to send a heartbeat to {}: {}", addr, e);
+                    }
+                });
+            }
+        }
+    });
+}
+
 #[cfg(feature = "server")]
 /// Starts listening for messages from a new peer
-pub async fn start_listening(stream: tokio::net::TcpStream, addr: std::net::SocketAddr) {
+///
+/// If TLS is enabled, the peer must complete the handshake with a certificate
+/// signed by the deployment CA, otherwise the connection is refused
+pub async fn start_listening(
+    store: std::sync::Arc<dyn crate::store::LedgerStore>,
+    stream: tokio::net::TcpStream,
+    addr: std::net::SocketAddr,
+) {
     log::debug!("Accepted connection from: {}", addr);
 
-    tokio::spawn(async move {
-        if let Err(e) = handle_network_message(stream, addr).await {
+    let tls = {
+        let manager = NETWORK_MANAGER.lock().await;
+        manager.get_tls()
+    };
+
+    crate::node::spawn(async move {
+        let result = match tls {
+            Some(tls) => match tls.accept(stream).await {
+                Ok(stream) => handle_network_message(store, stream, addr).await,
+                Err(e) => {
+                    log::warn!("Refusing connection from {}: {}", addr, e);
+                    return;
+                }
+            },
+            None => handle_network_message(store, stream, addr).await,
+        };
+        if let Err(e) = result {
             log::error!("Error handling connection from {}: {}", addr, e);
         }
     });
@@ -194,20 +349,43 @@ pub async fn start_listening(stream: tokio::net::TcpStream, addr: std::net::Sock
 #[cfg(feature = "server")]
 /// Handles incoming messages from a peer
 /// Implement our wave diffusion protocol
-pub async fn handle_network_message(
-    mut stream: tokio::net::TcpStream,
+///
+/// The messages are applied to `store`, the ledger of the site
+pub async fn handle_network_message<S>(
+    store: std::sync::Arc<dyn crate::store::LedgerStore>,
+    mut stream: S,
     socket_of_the_sender: std::net::SocketAddr,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    S: tokio::io::AsyncRead + Unpin,
+{
     use crate::message::{Message, MessageInfo, NetworkMessageCode};
     use crate::state::LOCAL_APP_STATE;
-    use rmp_serde::decode;
-    use tokio::io::AsyncReadExt;
 
-    let mut buf = vec![0; 1024];
+    let codec = {
+        let manager = NETWORK_MANAGER.lock().await;
+        manager.get_codec()
+    };
+
     loop {
-        let n = stream.read(&mut buf).await?;
+        let frame = match codec.read_frame(&mut stream).await {
+            Ok(frame) => frame,
+            Err(e) => {
+                // The stream can not be trusted anymore, the connection is dropped
+                log::error!(
+                    "Rejecting frame from {}, closing the connection: {}",
+                    socket_of_the_sender,
+                    e
+                );
+                let mut state = LOCAL_APP_STATE.lock().await;
+                state
+                    .remove_peer_from_socket_closed(socket_of_the_sender)
+                    .await;
+                return Err(e.into());
+            }
+        };
 
-        if n == 0 {
+        let Some(frame) = frame else {
             log::warn!("Connection closed by: {}", socket_of_the_sender);
             // Here we should remove the site from the network in the app state
             {
@@ -218,14 +396,24 @@ pub async fn handle_network_message(
                     .await;
             }
             return Ok(());
-        }
+        };
 
-        log::debug!("Received {} bytes from {}", n, socket_of_the_sender);
+        log::debug!(
+            "Received a frame of {} bytes from {}",
+            frame.len(),
+            socket_of_the_sender
+        );
 
-        let message: Message = match decode::from_slice(&buf[..n]) {
+        let message: Message = match codec.decode(&frame) {
             Ok(msg) => msg,
             Err(e) => {
-                log::error!("Error decoding message: {}", e);
+                // Framing is still aligned, only this message is rejected
+                log::error!(
+                    "Rejecting frame of {} bytes from {}: {}",
+                    frame.len(),
+                    socket_of_the_sender,
+                    e
+                );
                 continue;
             }
         };
@@ -236,348 +424,51 @@ pub async fn handle_network_message(
             message.clone()
         );
 
+        if message.code.is_signed()
+            && let Err(e) = verify_initiator(&store, &message).await
+        {
+            // Neither applied nor forwarded, the sender is told why
+            log::error!(
+                "Rejecting {:?} message from {} claiming to be initiated by {}: {}",
+                message.code,
+                message.sender_addr,
+                message.message_initiator_id,
+                e
+            );
+            if let Err(e) = reject_message(&message, e.to_string()).await {
+                log::error!(
+                    "Unable to notify {} of the rejection: {}",
+                    message.sender_addr,
+                    e
+                );
+            }
+            continue;
+        }
+
         {
             let mut state = LOCAL_APP_STATE.lock().await;
             state.add_site_id(
                 message.message_initiator_id.clone(),
-                message.message_initiator_addr.clone(),
+                message.message_initiator_addr,
             );
         }
 
-        match message.code {
-            NetworkMessageCode::AcquireMutex => {
-                // We store the request
-                {
-                    let mut st = LOCAL_APP_STATE.lock().await;
-                    st.global_mutex_fifo.insert(
-                        message.message_initiator_id.clone(),
-                        crate::state::MutexStamp {
-                            tag: crate::state::MutexTag::Request,
-                            date: message.clock.get_lamport().clone(),
-                        },
-                    );
-                }
-                // wave diffusion
-                let mut diffuse = false;
-                let (local_site_id, local_site_addr) = {
-                    let mut state = LOCAL_APP_STATE.lock().await;
-                    let parent_id = state
-                        .parent_addr_for_transaction_wave
-                        .get(&message.message_initiator_id)
-                        .unwrap_or(&"0.0.0.0:0".parse().unwrap())
-                        .to_string();
-                    if parent_id == "0.0.0.0:0" {
-                        state.set_parent_addr(
-                            message.message_initiator_id.clone(),
-                            message.sender_addr,
-                        );
-
-                        let nb_neighbours = state.get_nb_connected_neighbours();
-                        let current_value = state
-                            .attended_neighbours_nb_for_transaction_wave
-                            .get(&message.message_initiator_id)
-                            .copied()
-                            .unwrap_or(nb_neighbours);
-
-                        state
-                            .attended_neighbours_nb_for_transaction_wave
-                            .insert(message.message_initiator_id.clone(), current_value - 1);
-
-                        log::debug!("Nombre de voisin : {}", current_value - 1);
-
-                        diffuse = state
-                            .attended_neighbours_nb_for_transaction_wave
-                            .get(&message.message_initiator_id)
-                            .copied()
-                            .unwrap_or(0)
-                            > 0;
-                    }
-                    (state.get_site_id(), state.get_site_addr())
-                };
-
-                if diffuse {
-                    let mut snd_msg = message.clone();
-                    snd_msg.sender_id = local_site_id.to_string();
-                    snd_msg.sender_addr = local_site_addr;
-                    diffuse_message(&snd_msg).await?;
-                } else {
-                    let (parent_addr, local_addr, site_id) = {
-                        let state = LOCAL_APP_STATE.lock().await;
-                        (
-                            state.get_parent_addr_for_wave(message.message_initiator_id.clone()),
-                            &state.get_site_addr(),
-                            &state.get_site_id().to_string(),
-                        )
-                    };
-                    // Acquit message to parent
-                    log::debug!(
-                        "Réception d'un message de d'acquisition de mutex, on est sur une feuille, on acquite, envoie à {}",
-                        message.sender_addr.to_string().as_str()
-                    );
-                    send_message(
-                        message.sender_addr,
-                        MessageInfo::AckMutex(crate::message::AckMutexPayload {
-                            clock: message.clock.get_lamport().clone(),
-                        }),
-                        None,
-                        NetworkMessageCode::AckGlobalMutex,
-                        *local_addr,
-                        site_id,
-                        &message.message_initiator_id,
-                        message.message_initiator_addr,
-                        message.clock.clone(),
-                    )
-                    .await?;
-
-                    if message.sender_addr == parent_addr {
-                        // réinitialisation s'il s'agit de la remontée après réception des rouges de tous les fils
-                        let mut state = LOCAL_APP_STATE.lock().await;
-                        let peer_count = state.get_nb_connected_neighbours();
-                        state
-                            .attended_neighbours_nb_for_transaction_wave
-                            .insert(message.message_initiator_id.clone(), peer_count as i64);
-                        state.parent_addr_for_transaction_wave.insert(
-                            message.message_initiator_id.clone(),
-                            "0.0.0.0:0".parse().unwrap(),
-                        );
-                    }
-                }
-            }
-
-            NetworkMessageCode::AckGlobalMutex => {
-                // Message rouge
-                let mut state = LOCAL_APP_STATE.lock().await;
-
-                let nb_neighbours = state.get_nb_connected_neighbours();
-                let current_value = state
-                    .attended_neighbours_nb_for_transaction_wave
-                    .get(&message.message_initiator_id)
-                    .copied()
-                    .unwrap_or(nb_neighbours);
-                state
-                    .attended_neighbours_nb_for_transaction_wave
-                    .insert(message.message_initiator_id.clone(), current_value - 1);
-
-                if state
-                    .attended_neighbours_nb_for_transaction_wave
-                    .get(&message.message_initiator_id.clone())
-                    .copied()
-                    .unwrap_or(-1)
-                    == 0
-                {
-                    if state
-                        .parent_addr_for_transaction_wave
-                        .get(&message.message_initiator_id.clone())
-                        .copied()
-                        .unwrap_or("99.99.99.99:0".parse().unwrap())
-                        == state.get_site_addr()
-                    {
-                        // on est chez le parent
-                        // diffusion terminée
-                        // Réinitialisation
-
-                        println!("\x1b[1;31mDiffusion terminée et réussie !\x1b[0m");
-                        state.try_enter_sc();
-                    } else {
-                        log::debug!(
-                            "On est de le noeud {}. On a reçu un rouge de tous nos fils: on acquite au parent {}",
-                            state.get_site_addr(),
-                            state
-                                .get_parent_addr_for_wave(message.message_initiator_id.clone())
-                                .to_string()
-                                .as_str()
-                        );
-                        send_message(
-                            state.get_parent_addr_for_wave(message.message_initiator_id.clone()),
-                            MessageInfo::AckMutex(crate::message::AckMutexPayload {
-                                clock: message.clock.get_lamport().clone(),
-                            }),
-                            None,
-                            NetworkMessageCode::AckGlobalMutex,
-                            state.get_site_addr(),
-                            &state.get_site_id().to_string(),
-                            &message.message_initiator_id,
-                            message.message_initiator_addr,
-                            state.get_clock().clone(),
-                        )
-                        .await?;
-                    }
-
-                    let peer_count = state.get_nb_connected_neighbours();
-                    state
-                        .attended_neighbours_nb_for_transaction_wave
-                        .insert(message.message_initiator_id.clone(), peer_count as i64);
-                    state.parent_addr_for_transaction_wave.insert(
-                        message.message_initiator_id.clone(),
-                        "0.0.0.0:0".parse().unwrap(),
-                    );
-                }
-            }
-
-            NetworkMessageCode::AckReleaseGlobalMutex => {
-                // Message rouge
-                let mut state = LOCAL_APP_STATE.lock().await;
-
-                let nb_neighbours = state.get_nb_connected_neighbours();
-                let current_value = state
-                    .attended_neighbours_nb_for_transaction_wave
-                    .get(&message.message_initiator_id)
-                    .copied()
-                    .unwrap_or(nb_neighbours);
-                state
-                    .attended_neighbours_nb_for_transaction_wave
-                    .insert(message.message_initiator_id.clone(), current_value - 1);
-
-                if state
-                    .attended_neighbours_nb_for_transaction_wave
-                    .get(&message.message_initiator_id.clone())
-                    .copied()
-                    .unwrap_or(-1)
-                    == 0
-                {
-                    if state
-                        .parent_addr_for_transaction_wave
-                        .get(&message.message_initiator_id.clone())
-                        .copied()
-                        .unwrap_or("99.99.99.99:0".parse().unwrap())
-                        == state.get_site_addr()
-                    {
-                        // on est chez le parent
-                        // diffusion terminée
-                        // Réinitialisation
-
-                        println!("\x1b[1;31mDiffusion terminée et réussie !\x1b[0m");
-                        // On vient de release la section critique, on peut essayer d'y entrer à nouveau
-                        state.try_enter_sc();
-                    } else {
-                        log::debug!(
-                            "On est de le noeud {}. On a reçu un rouge de tous nos fils: on acquite au parent {}",
-                            state.get_site_addr(),
-                            state
-                                .get_parent_addr_for_wave(message.message_initiator_id.clone())
-                                .to_string()
-                                .as_str()
-                        );
-                        send_message(
-                            state.get_parent_addr_for_wave(message.message_initiator_id.clone()),
-                            MessageInfo::None,
-                            None,
-                            NetworkMessageCode::AckReleaseGlobalMutex,
-                            state.get_site_addr(),
-                            &state.get_site_id().to_string(),
-                            &message.message_initiator_id,
-                            message.message_initiator_addr,
-                            state.get_clock().clone(),
-                        )
-                        .await?;
-                    }
-
-                    let peer_count = state.get_nb_connected_neighbours();
-                    state
-                        .attended_neighbours_nb_for_transaction_wave
-                        .insert(message.message_initiator_id.clone(), peer_count as i64);
-                    state.parent_addr_for_transaction_wave.insert(
-                        message.message_initiator_id.clone(),
-                        "0.0.0.0:0".parse().unwrap(),
-                    );
-                }
-            }
-
-            NetworkMessageCode::ReleaseGlobalMutex => {
-                // A node is releasing the critical section
-                {
-                    let mut st = LOCAL_APP_STATE.lock().await;
-                    st.global_mutex_fifo.remove(&message.message_initiator_id);
-                    st.try_enter_sc();
-                }
-                // wave diffusion
-                let mut diffuse = false;
-                let (local_site_id, local_site_addr) = {
-                    let mut state = LOCAL_APP_STATE.lock().await;
-                    let parent_id = state
-                        .parent_addr_for_transaction_wave
-                        .get(&message.message_initiator_id)
-                        .unwrap_or(&"0.0.0.0:0".parse().unwrap())
-                        .to_string();
-                    if parent_id == "0.0.0.0:0" {
-                        state.set_parent_addr(
-                            message.message_initiator_id.clone(),
-                            message.sender_addr,
-                        );
-
-                        let nb_neighbours = state.get_nb_connected_neighbours();
-                        let current_value = state
-                            .attended_neighbours_nb_for_transaction_wave
-                            .get(&message.message_initiator_id)
-                            .copied()
-                            .unwrap_or(nb_neighbours);
-
-                        state
-                            .attended_neighbours_nb_for_transaction_wave
-                            .insert(message.message_initiator_id.clone(), current_value - 1);
-
-                        log::debug!("Nombre de voisin : {}", current_value - 1);
-
-                        diffuse = state
-                            .attended_neighbours_nb_for_transaction_wave
-                            .get(&message.message_initiator_id)
-                            .copied()
-                            .unwrap_or(0)
-                            > 0;
-                    }
-                    (state.get_site_id(), state.get_site_addr())
-                };
-
-                if diffuse {
-                    let mut snd_msg = message.clone();
-                    snd_msg.sender_id = local_site_id.to_string();
-                    snd_msg.sender_addr = local_site_addr;
-                    diffuse_message(&snd_msg).await?;
-                } else {
-                    let (parent_addr, local_addr, site_id) = {
-                        let state = LOCAL_APP_STATE.lock().await;
-                        (
-                            state.get_parent_addr_for_wave(message.message_initiator_id.clone()),
-                            &state.get_site_addr(),
-                            &state.get_site_id().to_string(),
-                        )
-                    };
-                    // Acquit message to parent
-                    log::debug!(
-                        "Réception d'un message de relachement de mutex global, on est sur une feuille, on acquite, envoie à {}",
-                        message.sender_addr.to_string().as_str()
-                    );
-                    send_message(
-                        message.sender_addr,
-                        MessageInfo::None,
-                        None,
-                        NetworkMessageCode::AckReleaseGlobalMutex,
-                        *local_addr,
-                        site_id,
-                        &message.message_initiator_id,
-                        message.message_initiator_addr,
-                        message.clock.clone(),
-                    )
-                    .await?;
+        {
+            let mut state = LOCAL_APP_STATE.lock().await;
+            state.record_heartbeat(message.sender_addr);
+        }
 
-                    if message.sender_addr == parent_addr {
-                        // réinitialisation s'il s'agit de la remontée après réception des rouges de tous les fils
-                        let mut state = LOCAL_APP_STATE.lock().await;
-                        let peer_count = state.get_nb_connected_neighbours();
-                        state
-                            .attended_neighbours_nb_for_transaction_wave
-                            .insert(message.message_initiator_id.clone(), peer_count as i64);
-                        state.parent_addr_for_transaction_wave.insert(
-                            message.message_initiator_id.clone(),
-                            "0.0.0.0:0".parse().unwrap(),
-                        );
-                    }
-                }
+        match message.code {
+            NetworkMessageCode::Heartbeat => {
+                // Only a sign of life, the logical clocks are left untouched
+                continue;
             }
-
             NetworkMessageCode::Discovery => {
                 let mut state = LOCAL_APP_STATE.lock().await;
 
+                // A retired site may be joining again
+                state.readmit_site(&message.message_initiator_id);
+
                 // Try to add this new site as a new peer
                 state.add_incomming_peer(
                     message.message_initiator_addr,
@@ -603,14 +494,26 @@ pub async fn handle_network_message(
                     send_message(
                         message.sender_addr,
                         MessageInfo::Acknowledge(crate::message::AcknowledgePayload {
-                            global_fifo: state.get_global_mutex_fifo().clone(),
+                            global_fifo: state
+                                .get_global_mutex_fifo()
+                                .iter()
+                                .map(|(site_id, stamp)| (site_id.clone(), stamp.clone()))
+                                .collect(),
+                            delivered: state
+                                .causal
+                                .get_delivered()
+                                .iter()
+                                .map(|(site_id, count)| (site_id.clone(), *count))
+                                .collect(),
                         }),
                         None,
                         NetworkMessageCode::Acknowledgment,
                         state.get_site_addr(),
                         state.get_site_id().as_str(),
-                        &message.message_initiator_id.clone(),
-                        message.message_initiator_addr,
+                        // Signed by this site, whose mutex and causal state it carries
+                        state.get_site_id().as_str(),
+                        state.get_site_addr(),
+                        None,
                         state.get_clock(),
                     )
                     .await?;
@@ -618,7 +521,7 @@ pub async fn handle_network_message(
             }
 
             NetworkMessageCode::Acknowledgment => {
-                let ready_to_sync = {
+                let (ready_to_sync, reconnected) = {
                     let mut state = LOCAL_APP_STATE.lock().await;
                     // If the site received an acknoledgement from a site,
                     // It can be a site that is not in the network anymore
@@ -627,482 +530,90 @@ pub async fn handle_network_message(
                         socket_of_the_sender,
                         message.clock.clone(),
                     );
-                    if message.message_initiator_addr == state.get_site_addr() {
-                        for (site_id, nb_a_i) in state.get_nb_nei_for_wave().iter() {
-                            state
-                                .attended_neighbours_nb_for_transaction_wave
-                                .insert(site_id.clone(), *nb_a_i + 1);
-                        }
-                    }
                     // If we are in sync mode, we can start the sync process
                     // And we have received all the responses from the first attended neighbours counter
                     // We can start the sync process by starting a snapshot with sync mode
-                    state.get_sync()
+                    let ready_to_sync = state.get_sync()
                         && state.get_nb_first_attended_neighbours()
-                            == state.get_nb_connected_neighbours()
+                            == state.get_nb_connected_neighbours();
+                    // A CLI peer reached again by the reconnection supervisor also needs a sync
+                    (
+                        ready_to_sync,
+                        state.take_reconnected_peer(message.sender_addr),
+                    )
                 };
 
                 // Récupérer le global_fifo envoyé dans l'acknowledgment
-                let global_fifo = match &message.info {
-                    MessageInfo::Acknowledge(payload) => Some(payload.global_fifo.clone()),
-                    _ => None,
-                };
-
-                if let Some(global_fifo) = global_fifo {
-                    let mut state = LOCAL_APP_STATE.lock().await;
-                    state.set_global_mutex_fifo(global_fifo);
+                if let MessageInfo::Acknowledge(payload) = &message.info {
+                    let adopt = {
+                        let mut state = LOCAL_APP_STATE.lock().await;
+                        state.set_global_mutex_fifo(
+                            payload.global_fifo.clone().into_iter().collect(),
+                        );
+                        state.causal.is_fresh() || ready_to_sync || reconnected
+                    };
+                    // A site without history, or about to synchronize, starts counting the
+                    // transactions where its neighbour is, the past ones come with the sync
+                    if adopt {
+                        crate::causal::deliver(&store, |causal| {
+                            causal.adopt(&payload.delivered.clone().into_iter().collect())
+                        })
+                        .await;
+                    }
                 }
 
-                if ready_to_sync {
+                if reconnected {
+                    log::info!(
+                        "Reconnected to {}, starting synchronization",
+                        message.sender_addr
+                    );
+                } else if ready_to_sync {
                     log::info!("All neighbours have responded, starting synchronization");
+                }
+                if ready_to_sync || reconnected {
                     crate::control::enqueue_critical(
+                        &store,
                         crate::control::CriticalCommands::SyncSnapshot,
                     )
                     .await?;
                 }
             }
 
-            NetworkMessageCode::Transaction => {
-                // messages bleus
-                if message.command.is_some() {
-                    if let Err(e) = crate::control::process_network_command(
-                        message.info.clone(),
-                        message.clock.clone(),
-                        message.message_initiator_id.as_str(),
-                    )
-                    .await
-                    {
-                        log::error!("Error handling command:\n{}", e);
-                    }
-                    // wave diffusion
-                    let mut diffuse = false;
-                    let (local_site_id, local_site_addr) = {
-                        let mut state = LOCAL_APP_STATE.lock().await;
-                        let parent_id = state
-                            .parent_addr_for_transaction_wave
-                            .get(&message.message_initiator_id.clone())
-                            .unwrap_or(&"0.0.0.0:0".parse().unwrap())
-                            .to_string();
-                        if parent_id == "0.0.0.0:0" {
-                            state.set_parent_addr(
-                                message.message_initiator_id.clone(),
-                                message.sender_addr,
-                            );
-
-                            let nb_neighbours = state.get_nb_connected_neighbours();
-                            let current_value = state
-                                .attended_neighbours_nb_for_transaction_wave
-                                .get(&message.message_initiator_id)
-                                .copied()
-                                .unwrap_or(nb_neighbours);
-
-                            state
-                                .attended_neighbours_nb_for_transaction_wave
-                                .insert(message.message_initiator_id.clone(), current_value - 1);
-
-                            log::debug!("Nombre de voisin : {}", current_value - 1);
-
-                            diffuse = state
-                                .attended_neighbours_nb_for_transaction_wave
-                                .get(&message.message_initiator_id.clone())
-                                .copied()
-                                .unwrap_or(0)
-                                > 0;
-                        }
-                        (state.get_site_id(), state.get_site_addr())
-                    };
-
-                    if diffuse {
-                        let mut snd_msg = message.clone();
-                        snd_msg.sender_id = local_site_id.to_string();
-                        snd_msg.sender_addr = local_site_addr;
-                        diffuse_message(&snd_msg).await?;
-                    } else {
-                        let (parent_addr, local_addr, site_id) = {
-                            let state = LOCAL_APP_STATE.lock().await;
-                            (
-                                state
-                                    .get_parent_addr_for_wave(message.message_initiator_id.clone()),
-                                state.get_site_addr(),
-                                state.get_site_id().to_string(),
-                            )
-                        };
-                        // Acquit message to parent
-                        log::debug!(
-                            "Réception d'un message de transaction, on est sur une feuille, on acquite, envoie à {}",
-                            message.sender_addr.to_string().as_str()
-                        );
-                        send_message(
-                            message.sender_addr,
-                            MessageInfo::None,
-                            None,
-                            NetworkMessageCode::TransactionAcknowledgement,
-                            local_addr,
-                            site_id.as_str(),
-                            &message.message_initiator_id.clone(),
-                            message.message_initiator_addr,
-                            message.clock.clone(),
-                        )
-                        .await?;
-
-                        if message.sender_addr == parent_addr {
-                            // réinitialisation s'il s'agit de la remontée après réception des rouges de tous les fils
-                            let mut state = LOCAL_APP_STATE.lock().await;
-                            let peer_count = state.get_nb_connected_neighbours();
-                            state
-                                .attended_neighbours_nb_for_transaction_wave
-                                .insert(message.message_initiator_id.clone(), peer_count as i64);
-                            state
-                                .parent_addr_for_transaction_wave
-                                .insert(message.message_initiator_id, "0.0.0.0:0".parse().unwrap());
-                        }
-                    }
-                } else {
-                    log::error!("Command is None for Transaction message");
-                }
-            }
-            NetworkMessageCode::TransactionAcknowledgement => {
-                let mut should_reset = false;
-
-                // Message rouge
-                let mut state = LOCAL_APP_STATE.lock().await;
-
-                let nb_neighbours = state.get_nb_connected_neighbours();
-                let current_value = state
-                    .attended_neighbours_nb_for_transaction_wave
-                    .get(&message.message_initiator_id)
-                    .copied()
-                    .unwrap_or(nb_neighbours);
-                state
-                    .attended_neighbours_nb_for_transaction_wave
-                    .insert(message.message_initiator_id.clone(), current_value - 1);
-
-                if state
-                    .attended_neighbours_nb_for_transaction_wave
-                    .get(&message.message_initiator_id)
-                    .copied()
-                    .unwrap_or(-1)
-                    == 0
-                {
-                    if state
-                        .parent_addr_for_transaction_wave
-                        .get(&message.message_initiator_id)
-                        .copied()
-                        .unwrap_or("99.99.99.99:0".parse().unwrap())
-                        == state.get_site_addr()
-                    {
-                        // on est chez le parent
-                        // diffusion terminée
-                        // Réinitialisation
-
-                        println!("\x1b[1;31mDiffusion terminée et réussie !\x1b[0m");
-                        should_reset = true;
-                    } else {
-                        log::debug!(
-                            "On est dans le noeud {}. On a reçu un rouge de tous nos fils: on acquite au parent {}",
-                            state.get_site_addr().to_string().as_str(),
-                            state
-                                .get_parent_addr_for_wave(message.message_initiator_id.clone())
-                                .to_string()
-                                .as_str()
-                        );
-                        send_message(
-                            state.get_parent_addr_for_wave(message.message_initiator_id.clone()),
-                            MessageInfo::None,
-                            None,
-                            NetworkMessageCode::TransactionAcknowledgement,
-                            state.get_site_addr(),
-                            &state.get_site_id().to_string(),
-                            &message.message_initiator_id,
-                            message.message_initiator_addr,
-                            state.get_clock(),
-                        )
-                        .await?;
-                    }
-
-                    let peer_count = state.get_nb_connected_neighbours();
-                    state
-                        .attended_neighbours_nb_for_transaction_wave
-                        .insert(message.message_initiator_id.clone(), peer_count as i64);
-                    state
-                        .parent_addr_for_transaction_wave
-                        .insert(message.message_initiator_id, "0.0.0.0:0".parse().unwrap());
-
-                    if should_reset && state.pending_commands.len() == 0 {
-                        // fin de la section critique on peut notifier les pairs
-                        state.release_mutex().await?;
-                    };
+            NetworkMessageCode::Error => match &message.info {
+                MessageInfo::Error(payload) => {
+                    log::error!(
+                        "Site {} rejected our message: {}",
+                        message.sender_id,
+                        payload.reason
+                    );
                 }
-            }
-
-            NetworkMessageCode::Error => {
-                log::debug!("Error message received: {:?}", message);
-            }
+                _ => log::debug!("Error message received: {:?}", message),
+            },
             NetworkMessageCode::Disconnect => {
                 {
                     let mut state = LOCAL_APP_STATE.lock().await;
                     state.remove_peer(message.message_initiator_addr).await;
+                    // The last clock of the site is merged before acknowledging its departure
+                    state.update_clock(Some(&message.clock)).await;
                 }
+                crate::node::spawn(crate::membership::retire(
+                    message.message_initiator_id.clone(),
+                ));
                 println!(
                     "\x1b[1;31mSITE {} DISCONNECTED !\x1b[0m",
                     message.message_initiator_id
                 );
+                continue;
             }
-            NetworkMessageCode::SnapshotRequest => {
-                // messages bleus
-                // wave diffusion
-                let mut diffuse = false;
-                let (local_site_id, local_site_addr) = {
-                    let mut state = LOCAL_APP_STATE.lock().await;
-                    let parent_id = state
-                        .parent_addr_for_transaction_wave
-                        .get(&message.message_initiator_id.clone())
-                        .unwrap_or(&"0.0.0.0:0".parse().unwrap())
-                        .to_string();
-                    if parent_id == "0.0.0.0:0" {
-                        state.set_parent_addr(
-                            message.message_initiator_id.clone(),
-                            message.sender_addr,
-                        );
-
-                        let nb_neighbours = state.get_nb_connected_neighbours();
-                        let current_value = state
-                            .attended_neighbours_nb_for_transaction_wave
-                            .get(&message.message_initiator_id)
-                            .copied()
-                            .unwrap_or(nb_neighbours);
-
-                        state
-                            .attended_neighbours_nb_for_transaction_wave
-                            .insert(message.message_initiator_id.clone(), current_value - 1);
-
-                        log::debug!("Nombre de voisin : {}", current_value - 1);
-
-                        diffuse = state
-                            .attended_neighbours_nb_for_transaction_wave
-                            .get(&message.message_initiator_id.clone())
-                            .copied()
-                            .unwrap_or(0)
-                            > 0;
-                    }
-                    (state.get_site_id(), state.get_site_addr())
-                };
-
-                if diffuse {
-                    let mut snd_msg = message.clone();
-                    snd_msg.sender_id = local_site_id.to_string();
-                    snd_msg.sender_addr = local_site_addr;
-                    // Here we should diffuse the message because we are not on a leaf
-                    // We should also start a snapshot in network mode
-                    // When this snapshot is done, we should send the global snapshot to the parent
-                    log::debug!(
-                        "We are not on a leaf, we start our own global snapshot construction and diffuse the request to other nodes"
-                    );
-                    crate::snapshot::start_snapshot(crate::snapshot::SnapshotMode::NetworkMode)
-                        .await?;
-                    // When can then diffuse the request to other nodes
-                    diffuse_message(&snd_msg).await?;
-                } else {
-                    let parent_addr = {
-                        let state = LOCAL_APP_STATE.lock().await;
-                        state.get_parent_addr_for_wave(message.message_initiator_id.clone())
-                    };
-                    // Acquit message to parent
-                    log::debug!(
-                        "Réception d'une demande de snapshot, on est sur une feuille, on crée un snapshot local, on envoie à {}",
-                        message.sender_addr.to_string().as_str()
-                    );
-                    // Here we are on a leaf, we can crate a local snapshot and send it to the parent
-                    let txs = crate::db::get_local_transaction_log()?;
-                    let summaries: Vec<_> = txs.iter().map(|t| t.into()).collect();
-
-                    let (site_id, clock, local_addr) = {
-                        let st = LOCAL_APP_STATE.lock().await;
-                        (st.get_site_id(), st.get_clock(), st.get_site_addr())
-                    };
-
-                    send_message(
-                        message.sender_addr,
-                        MessageInfo::SnapshotResponse(crate::message::SnapshotResponse {
-                            site_id: site_id.clone(),
-                            clock: clock.clone(),
-                            tx_log: summaries,
-                        }),
-                        None,
-                        NetworkMessageCode::SnapshotResponse,
-                        local_addr,
-                        &site_id,
-                        &message.message_initiator_id,
-                        message.message_initiator_addr,
-                        clock.clone(),
-                    )
-                    .await?;
-
-                    if message.sender_addr == parent_addr {
-                        // réinitialisation s'il s'agit de la remontée après réception des rouges de tous les fils
-                        let mut state = LOCAL_APP_STATE.lock().await;
-                        let peer_count = state.get_nb_connected_neighbours();
-                        state
-                            .attended_neighbours_nb_for_transaction_wave
-                            .insert(message.message_initiator_id.clone(), peer_count as i64);
-                        state
-                            .parent_addr_for_transaction_wave
-                            .insert(message.message_initiator_id, "0.0.0.0:0".parse().unwrap());
-                    }
-                }
-            }
-            NetworkMessageCode::SnapshotResponse => {
-                // Message rouge
-                let mut should_reset = false;
-                let mut state = LOCAL_APP_STATE.lock().await;
-
-                let nb_neighbours = state.get_nb_connected_neighbours();
-                let current_value = state
-                    .attended_neighbours_nb_for_transaction_wave
-                    .get(&message.message_initiator_id)
-                    .copied()
-                    .unwrap_or(nb_neighbours);
-                state
-                    .attended_neighbours_nb_for_transaction_wave
-                    .insert(message.message_initiator_id.clone(), current_value - 1);
-
-                if state
-                    .attended_neighbours_nb_for_transaction_wave
-                    .get(&message.message_initiator_id)
-                    .copied()
-                    .unwrap_or(-1)
-                    == 0
-                {
-                    if state
-                        .parent_addr_for_transaction_wave
-                        .get(&message.message_initiator_id)
-                        .copied()
-                        .unwrap_or("99.99.99.99:0".parse().unwrap())
-                        == state.get_site_addr()
-                    {
-                        // on est chez le parent
-                        // diffusion terminée
-                        // Réinitialisation
-                        log::debug!(
-                            "L'initiateur à reçu toutes ses snapshots, il devrait créer sa snapshot globale"
-                        );
-                        if let MessageInfo::SnapshotResponse(resp) = message.info {
-                            let mut mgr = crate::snapshot::LOCAL_SNAPSHOT_MANAGER.lock().await;
-                            if mgr.mode == crate::snapshot::SnapshotMode::FileMode {
-                                log::debug!("La snapshot devrait être sauvegardée");
-                                if let Some(gs) = mgr.push(resp) {
-                                    log::info!(
-                                        "Global snapshot ready to save, hold per site : {:#?}",
-                                        gs.missing
-                                    );
-                                    mgr.path = crate::snapshot::persist(&gs, state.get_site_id())
-                                        .await
-                                        .unwrap()
-                                        .parse()
-                                        .ok();
-                                }
-                            } else if mgr.mode == crate::snapshot::SnapshotMode::SyncMode {
-                                log::debug!(
-                                    "La snapshot devrait être utilisée pour la synchronisation"
-                                );
-                                if let Some(gs) = mgr.push(resp) {
-                                    log::info!(
-                                        "Global snapshot ready to be synced, hold per site : {:#?}",
-                                        gs.missing
-                                    );
-                                    crate::db::update_db_with_snapshot(
-                                        &gs,
-                                        state.get_clock().get_vector_clock_map(),
-                                    );
-                                }
-                            }
-                        }
-
-                        println!("\x1b[1;31mDiffusion terminée et réussie !\x1b[0m");
-                        should_reset = true;
-                    } else {
-                        log::debug!(
-                            "On est dans le noeud {}. On a reçu un rouge de tous nos fils: on acquite au parent {}",
-                            state.get_site_addr().to_string().as_str(),
-                            state
-                                .get_parent_addr_for_wave(message.message_initiator_id.clone())
-                                .to_string()
-                                .as_str()
-                        );
-                        log::debug!(
-                            "On devrait pouvoir construire une snapshot globale avec tous nos voisins et l'envoyer à l'adresse de notre parent {}",
-                            state
-                                .get_parent_addr_for_wave(message.message_initiator_id.clone())
-                                .to_string()
-                                .as_str()
-                        );
-                        if let MessageInfo::SnapshotResponse(resp) = message.info {
-                            let mut mgr = crate::snapshot::LOCAL_SNAPSHOT_MANAGER.lock().await;
-                            if mgr.mode == crate::snapshot::SnapshotMode::NetworkMode {
-                                log::debug!("La snapshot devrait être envoyés au père");
-                                if let Some(gs) = mgr.push(resp) {
-                                    log::info!(
-                                        "Global snapshot ready to be send to parent, hold per site : {:#?}",
-                                        gs.missing
-                                    );
-                                    send_message(
-                                        state.get_parent_addr_for_wave(
-                                            message.message_initiator_id.clone(),
-                                        ),
-                                        MessageInfo::SnapshotResponse(
-                                            crate::message::SnapshotResponse {
-                                                site_id: state.get_site_id().to_string(),
-                                                clock: state.get_clock(),
-                                                tx_log: gs.all_transactions.into_iter().collect(),
-                                            },
-                                        ),
-                                        None,
-                                        NetworkMessageCode::SnapshotResponse,
-                                        state.get_site_addr(),
-                                        &state.get_site_id().to_string(),
-                                        &message.message_initiator_id,
-                                        message.message_initiator_addr,
-                                        state.get_clock(),
-                                    )
-                                    .await?;
-                                } else {
-                                    log::error!("Le site aurait du récupérer toutes ses snapshots");
-                                }
-                            }
-                        } else {
-                            log::error!("Message de type SnapshotResponse attendu, mais pas reçu");
-                        }
-                    }
-
-                    let peer_count = state.get_nb_connected_neighbours();
-                    state
-                        .attended_neighbours_nb_for_transaction_wave
-                        .insert(message.message_initiator_id.clone(), peer_count as i64);
-                    state
-                        .parent_addr_for_transaction_wave
-                        .insert(message.message_initiator_id, "0.0.0.0:0".parse().unwrap());
-                    if should_reset && state.pending_commands.len() == 0 {
-                        // fin de la section critique on peut notifier les pairs
-                        state.release_mutex().await?;
-                    };
-                } else {
-                    log::debug!(
-                        "On a reçu un message rouge d'un des fils mais la vague n'est pas encore terminée"
-                    );
-                    // We should add the Snapshot to our manager
-                    if let MessageInfo::SnapshotResponse(resp) = message.info {
-                        let mut mgr = crate::snapshot::LOCAL_SNAPSHOT_MANAGER.lock().await;
-                        log::debug!("La snapshot devrait être ajoutés à l'état du manager");
-                        if let Some(_) = mgr.push(resp) {
-                            log::error!(
-                                "On ne devrait pas encore pouvoir construire une snapshot globale vu que la vague n'est pas terminée"
-                            );
-                        }
-                    } else {
-                        log::error!("Message de type SnapshotResponse attendu, mais pas reçu");
-                    }
-                }
-            }
+            // Messages of the waves, handled by the protocol they belong to
+            _ => match wave::protocol_for(&message.code) {
+                Some(protocol) => wave::receive(&store, protocol, &message).await?,
+                None => log::warn!(
+                    "Ignoring {:?} message from {}",
+                    message.code,
+                    message.sender_addr
+                ),
+            },
         }
 
         let mut state = LOCAL_APP_STATE.lock().await;
@@ -1110,8 +621,50 @@ pub async fn handle_network_message(
     }
 }
 
+#[cfg(feature = "server")]
+/// Checks that a message was signed by the site it claims to be initiated by
+async fn verify_initiator(
+    store: &std::sync::Arc<dyn crate::store::LedgerStore>,
+    message: &crate::message::Message,
+) -> Result<(), crate::identity::SignatureError> {
+    let public_key = crate::identity::verify(message)?;
+    crate::identity::check_site_key(store, &message.message_initiator_id, public_key).await
+}
+
+#[cfg(feature = "server")]
+/// Replies to the sender of a rejected message with an Error message
+async fn reject_message(
+    message: &crate::message::Message,
+    reason: String,
+) -> Result<(), crate::error::PeillutError> {
+    use crate::message::{ErrorPayload, MessageInfo, NetworkMessageCode};
+
+    let (local_addr, site_id, clock) = {
+        let state = crate::state::LOCAL_APP_STATE.lock().await;
+        (
+            state.get_site_addr(),
+            state.get_site_id(),
+            state.get_clock(),
+        )
+    };
+    send_message(
+        message.sender_addr,
+        MessageInfo::Error(ErrorPayload { reason }),
+        None,
+        NetworkMessageCode::Error,
+        local_addr,
+        &site_id,
+        &site_id,
+        local_addr,
+        None,
+        clock,
+    )
+    .await
+}
+
 #[cfg(feature = "server")]
 /// Send a message to a specific peer
+#[allow(clippy::too_many_arguments)]
 pub async fn send_message(
     recipient_address: std::net::SocketAddr,
     info: crate::message::MessageInfo,
@@ -1121,17 +674,19 @@ pub async fn send_message(
     local_site: &str,
     initiator_id: &str,
     initiator_addr: std::net::SocketAddr,
+    wave_id: Option<crate::message::WaveId>,
     sender_clock: crate::clock::Clock,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<(), crate::error::PeillutError> {
     use crate::message::Message;
-    use rmp_serde::encode;
 
     if code == crate::message::NetworkMessageCode::Transaction && command.is_none() {
         log::error!("Command is None for Transaction message");
-        return Err("Command is None for Transaction message".into());
+        return Err(crate::error::PeillutError::Network(
+            "Command is None for Transaction message".to_string(),
+        ));
     }
 
-    let msg = Message {
+    let mut msg = Message {
         sender_id: local_site.to_string(),
         sender_addr: local_addr,
         message_initiator_id: initiator_id.to_string(),
@@ -1140,86 +695,50 @@ pub async fn send_message(
         info,
         code,
         message_initiator_addr: initiator_addr,
+        signature: None,
+        wave_id,
+        causal_deps: None,
     };
-
-    if recipient_address.ip().is_unspecified() || recipient_address.port() == 0 {
-        log::warn!("Skipping invalid peer address {}", recipient_address);
-        return Ok(());
+    if msg.code.is_signed() {
+        crate::identity::site_identity()
+            .and_then(|identity| identity.sign(&mut msg))
+            .map_err(|e| crate::error::PeillutError::Network(e.to_string()))?;
     }
 
-    let buf = encode::to_vec(&msg)?;
-
-    let mut manager = NETWORK_MANAGER.lock().await;
-
-    let sender = match manager.get_sender(&recipient_address) {
-        Some(s) => s,
-        None => {
-            if let Err(e) = manager.create_connection(recipient_address).await {
-                return Err(format!(
-                    "error with connection to {}: {}",
-                    recipient_address.to_string(),
-                    e
-                )
-                .into());
-            }
-            match manager.get_sender(&recipient_address) {
-                Some(s) => s,
-                None => {
-                    let err_msg =
-                        format!("Sender not found after connecting to {}", recipient_address);
-                    log::error!("{}", err_msg);
-                    return Err(err_msg.into());
-                }
-            }
-        }
-    };
-
-    match sender.send(buf).await {
-        Ok(s) => s,
-        Err(e) => {
-            let err_msg = format!(
-                "Impossible to send msg to {} due to error : {}",
-                recipient_address, e
-            );
-            log::error!("{}", err_msg);
-            return Err(err_msg.into());
-        }
-    };
-    log::debug!("Sent message {:?} to {}", &msg, recipient_address);
-    Ok(())
+    deliver_message(recipient_address, &msg).await
 }
 
 #[cfg(feature = "server")]
-/// Implement our wave diffusion protocol
+/// Send an already built message to a specific peer
 ///
-/// Lock the app state and diffuse a message
-pub async fn diffuse_message(
-    message: &crate::message::Message,
-) -> Result<(), Box<dyn std::error::Error>> {
-    use crate::state::LOCAL_APP_STATE;
+/// Used to forward signed messages, which must reach the peer unchanged
+pub async fn deliver_message(
+    recipient_address: std::net::SocketAddr,
+    msg: &crate::message::Message,
+) -> Result<(), crate::error::PeillutError> {
+    use crate::error::PeillutError;
 
-    log::debug!(
-        "Début de la diffusion d'un message de type {:?}",
-        message.code
-    );
+    if recipient_address.ip().is_unspecified() || recipient_address.port() == 0 {
+        log::warn!("Skipping invalid peer address {}", recipient_address);
+        return Ok(());
+    }
 
-    let (local_addr, site_id, connected_nei_addr, parent_address) = {
-        let state = LOCAL_APP_STATE.lock().await;
-        (
-            state.get_site_addr(),
-            state.get_site_id(),
-            state.get_connected_nei_addr(),
-            state.get_parent_addr_for_wave(message.message_initiator_id.clone()),
-        )
+    let (buf, transport) = {
+        let manager = NETWORK_MANAGER.lock().await;
+        let buf = manager
+            .get_codec()
+            .encode(msg)
+            .map_err(|e| PeillutError::Network(e.to_string()))?;
+        (buf, manager.get_transport())
     };
-    diffuse_message_without_lock(
-        message,
-        local_addr,
-        &site_id,
-        connected_nei_addr,
-        parent_address,
-    )
-    .await?;
+
+    transport
+        .send(msg.sender_addr, recipient_address, buf)
+        .await
+        .map_err(|e| {
+            PeillutError::Network(format!("unable to reach {}: {}", recipient_address, e))
+        })?;
+    log::debug!("Sent message {:?} to {}", msg, recipient_address);
     Ok(())
 }
 
@@ -1233,25 +752,18 @@ pub async fn diffuse_message_without_lock(
     site_id: &str,
     connected_nei_addr: Vec<std::net::SocketAddr>,
     parent_address: std::net::SocketAddr,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<(), crate::error::PeillutError> {
+    // Only the sender fields are rewritten, the signature of the initiator stays valid
+    let mut message = message.clone();
+    message.sender_id = site_id.to_string();
+    message.sender_addr = local_addr;
+
     for connected_nei in connected_nei_addr {
         let peer_addr_str = connected_nei.to_string();
         if connected_nei != parent_address {
             log::debug!("Sending message to: {}", peer_addr_str);
 
-            if let Err(e) = send_message(
-                connected_nei,
-                message.info.clone(),
-                message.command.clone(),
-                message.code.clone(),
-                local_addr,
-                &site_id,
-                &message.message_initiator_id,
-                message.message_initiator_addr,
-                message.clock.clone(),
-            )
-            .await
-            {
+            if let Err(e) = deliver_message(connected_nei, &message).await {
                 log::error!("❌ Impossible d’envoyer à {} : {}", peer_addr_str, e);
             }
         }
@@ -1267,31 +779,37 @@ mod tests {
 
     #[tokio::test]
     async fn test_send_message() -> Result<(), Box<dyn std::error::Error>> {
-        use crate::clock::Clock;
-        use crate::message::{MessageInfo, NetworkMessageCode};
-
-        let address: std::net::SocketAddr = "127.0.0.1:8081".parse().unwrap();
-        let local_addr: std::net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
-        let local_site = "A";
-        let clock = Clock::new();
-
-        let _listener = TcpListener::bind(address).await?;
-
-        let code = NetworkMessageCode::Discovery;
-
-        let send_result = send_message(
-            address,
-            MessageInfo::None,
-            None,
-            code,
-            local_addr,
-            local_site,
-            local_site,
-            local_addr,
-            clock,
-        )
-        .await;
-        assert!(send_result.is_ok());
-        Ok(())
+        // The message goes through the network of a node
+        crate::node::Node::in_memory()
+            .run(async {
+                use crate::clock::Clock;
+                use crate::message::{MessageInfo, NetworkMessageCode};
+
+                let address: std::net::SocketAddr = "127.0.0.1:8081".parse().unwrap();
+                let local_addr: std::net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+                let local_site = "A";
+                let clock = Clock::new();
+
+                let _listener = TcpListener::bind(address).await?;
+
+                let code = NetworkMessageCode::Discovery;
+
+                let send_result = send_message(
+                    address,
+                    MessageInfo::None,
+                    None,
+                    code,
+                    local_addr,
+                    local_site,
+                    local_site,
+                    local_addr,
+                    None,
+                    clock,
+                )
+                .await;
+                assert!(send_result.is_ok());
+                Ok(())
+            })
+            .await
     }
 }
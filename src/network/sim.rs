@@ -0,0 +1,499 @@
+//! Simulated network for the tests
+//!
+//! Sites registered on a `SimNetwork` exchange their messages in memory. Every message is
+//! delayed, and may be reordered, duplicated or dropped, as decided by a random generator
+//! seeded from the configuration. Each link delivers its messages from a single schedule
+//! ordered by delivery time then rank, so that a failing run replays exactly from its seed.
+//! Links between sites can also be cut to partition the network.
+
+#[cfg(feature = "server")]
+/// Faults injected by the simulated network
+#[derive(Debug, Clone, Copy)]
+pub struct SimConfig {
+    /// Seed of the random generator, the same seed replays the same faults
+    pub seed: u64,
+    /// Minimum delay of a message
+    pub min_delay: std::time::Duration,
+    /// Maximum delay of a message
+    pub max_delay: std::time::Duration,
+    /// Probability that a message overtakes the messages sent before it on its link
+    pub reorder_rate: f64,
+    /// Probability that a message is delivered twice
+    pub duplicate_rate: f64,
+    /// Probability that a message is lost
+    pub drop_rate: f64,
+}
+
+#[cfg(feature = "server")]
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            min_delay: std::time::Duration::from_millis(1),
+            max_delay: std::time::Duration::from_millis(10),
+            reorder_rate: 0.0,
+            duplicate_rate: 0.0,
+            drop_rate: 0.0,
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+/// What happens to a message on its link
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fate {
+    /// The message is lost
+    Dropped,
+    /// A copy of the message is delivered after each delay
+    Delivered {
+        delays: Vec<std::time::Duration>,
+        /// Whether the copies may overtake the messages sent before them
+        overtakes: bool,
+    },
+}
+
+#[cfg(feature = "server")]
+/// Message sent on the simulated network
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimEvent {
+    pub from: std::net::SocketAddr,
+    pub to: std::net::SocketAddr,
+    /// Rank of the message on its link
+    pub rank: u64,
+    pub fate: Fate,
+}
+
+#[cfg(feature = "server")]
+/// SplitMix64 generator, small and good enough to draw the faults
+struct SplitMix64(u64);
+
+#[cfg(feature = "server")]
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Draws a float in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(feature = "server")]
+/// FNV-1a hash of a link, stable across runs unlike the std hashers
+fn link_hash(from: std::net::SocketAddr, to: std::net::SocketAddr) -> u64 {
+    format!("{}>{}", from, to)
+        .bytes()
+        .fold(0xCBF2_9CE4_8422_2325, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01B3)
+        })
+}
+
+#[cfg(feature = "server")]
+/// Frames waiting on a link, by delivery time, rank and copy
+type Schedule = std::collections::BTreeMap<(tokio::time::Instant, u64, usize), Vec<u8>>;
+
+#[cfg(feature = "server")]
+/// Directed link between two sites
+struct Link {
+    /// Number of messages sent on the link
+    rank: u64,
+    /// Delivery time of the last in order message
+    last_delivery: tokio::time::Instant,
+    /// Frames delivered by the task of the link
+    schedule: std::sync::Arc<std::sync::Mutex<Schedule>>,
+    /// Wakes the task of the link up when a frame is scheduled
+    scheduled: std::sync::Arc<tokio::sync::Notify>,
+}
+
+#[cfg(feature = "server")]
+#[derive(Default)]
+struct SimInner {
+    nodes: std::collections::HashMap<std::net::SocketAddr, std::sync::Arc<crate::node::Node>>,
+    links: std::collections::HashMap<(std::net::SocketAddr, std::net::SocketAddr), Link>,
+    /// Links cut by a partition
+    cut: std::collections::HashSet<(std::net::SocketAddr, std::net::SocketAddr)>,
+    trace: Vec<SimEvent>,
+}
+
+#[cfg(feature = "server")]
+/// In-memory network between the nodes of a test
+pub struct SimNetwork {
+    config: SimConfig,
+    inner: std::sync::Mutex<SimInner>,
+}
+
+#[cfg(feature = "server")]
+impl SimNetwork {
+    pub fn new(config: SimConfig) -> std::sync::Arc<Self> {
+        std::sync::Arc::new(Self {
+            config,
+            inner: std::sync::Mutex::new(SimInner::default()),
+        })
+    }
+
+    /// Delivers the messages sent to `addr` to `node`
+    pub fn register(&self, addr: std::net::SocketAddr, node: std::sync::Arc<crate::node::Node>) {
+        self.inner.lock().unwrap().nodes.insert(addr, node);
+    }
+
+    /// Cuts the links between `a` and `b`, their messages are dropped until `heal`
+    pub fn partition(&self, a: std::net::SocketAddr, b: std::net::SocketAddr) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.cut.insert((a, b));
+        inner.cut.insert((b, a));
+    }
+
+    /// Restores every cut link
+    pub fn heal(&self) {
+        self.inner.lock().unwrap().cut.clear();
+    }
+
+    /// Returns the messages sent so far, with their fate
+    pub fn trace(&self) -> Vec<SimEvent> {
+        self.inner.lock().unwrap().trace.clone()
+    }
+
+    /// Draws the fate of the message of rank `rank` on a link, from the seed only
+    pub fn fate(&self, from: std::net::SocketAddr, to: std::net::SocketAddr, rank: u64) -> Fate {
+        let config = &self.config;
+        let mut rng = SplitMix64(
+            config.seed ^ link_hash(from, to) ^ rank.wrapping_mul(0xD6E8_FEB8_6659_FD93),
+        );
+
+        if rng.next_f64() < config.drop_rate {
+            return Fate::Dropped;
+        }
+        let copies = if rng.next_f64() < config.duplicate_rate {
+            2
+        } else {
+            1
+        };
+        let spread = config.max_delay.saturating_sub(config.min_delay);
+        let delays = (0..copies)
+            .map(|_| config.min_delay + spread.mul_f64(rng.next_f64()))
+            .collect();
+        Fate::Delivered {
+            delays,
+            overtakes: rng.next_f64() < config.reorder_rate,
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+/// Opens the link from `from` to the node `to`, the node reads it like a TCP connection
+fn open_link(from: std::net::SocketAddr, to: std::sync::Arc<crate::node::Node>) -> Link {
+    let (writer, reader) = tokio::io::duplex(64 * 1024);
+    let writer = std::sync::Arc::new(tokio::sync::Mutex::new(writer));
+
+    tokio::spawn(async move {
+        let handled = to.run(crate::network::handle_network_message(
+            to.store.clone(),
+            reader,
+            from,
+        ));
+        if let Err(e) = handled.await {
+            log::error!("Simulated link from {} closed: {}", from, e);
+        }
+    });
+
+    let schedule = std::sync::Arc::new(std::sync::Mutex::new(Schedule::new()));
+    let scheduled = std::sync::Arc::new(tokio::sync::Notify::new());
+    let (pending, wake) = (schedule.clone(), scheduled.clone());
+    tokio::spawn(async move {
+        loop {
+            let next = pending.lock().unwrap().keys().next().map(|(at, ..)| *at);
+            match next {
+                None => wake.notified().await,
+                Some(at) if at <= tokio::time::Instant::now() => {
+                    let (_, frame) = pending.lock().unwrap().pop_first().unwrap();
+                    write_frame(&writer, &frame).await;
+                }
+                // A frame scheduled meanwhile may overtake the next one
+                Some(at) => {
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(at) => {}
+                        _ = wake.notified() => {}
+                    }
+                }
+            }
+        }
+    });
+
+    Link {
+        rank: 0,
+        last_delivery: tokio::time::Instant::now(),
+        schedule,
+        scheduled,
+    }
+}
+
+#[cfg(feature = "server")]
+async fn write_frame(writer: &tokio::sync::Mutex<tokio::io::DuplexStream>, frame: &[u8]) {
+    let mut writer = writer.lock().await;
+    if let Err(e) = crate::codec::FrameCodec::default()
+        .write_frame(&mut *writer, frame)
+        .await
+    {
+        log::error!("Unable to deliver a simulated frame: {}", e);
+    }
+}
+
+#[cfg(feature = "server")]
+impl super::transport::Transport for SimNetwork {
+    fn send(
+        &self,
+        from: std::net::SocketAddr,
+        to: std::net::SocketAddr,
+        frame: Vec<u8>,
+    ) -> super::transport::TransportFuture<'_> {
+        let result = self.schedule(from, to, frame).map_err(|e| e.to_string());
+        Box::pin(async move { Ok(result?) })
+    }
+}
+
+#[cfg(feature = "server")]
+impl SimNetwork {
+    /// Schedules the deliveries of a frame according to its fate
+    fn schedule(
+        &self,
+        from: std::net::SocketAddr,
+        to: std::net::SocketAddr,
+        frame: Vec<u8>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+
+        let node = match inner.nodes.get(&to) {
+            Some(node) => node.clone(),
+            None => return Err(format!("No simulated site at {}", to).into()),
+        };
+        let link = inner
+            .links
+            .entry((from, to))
+            .or_insert_with(|| open_link(from, node));
+        let rank = link.rank;
+        link.rank += 1;
+
+        let fate = if inner.cut.contains(&(from, to)) {
+            Fate::Dropped
+        } else {
+            self.fate(from, to, rank)
+        };
+
+        if let Fate::Delivered { delays, overtakes } = &fate {
+            let now = tokio::time::Instant::now();
+            let mut schedule = link.schedule.lock().unwrap();
+            for (copy, delay) in delays.iter().enumerate() {
+                let mut at = now + *delay;
+                if !*overtakes {
+                    link.last_delivery = link.last_delivery.max(at);
+                    at = link.last_delivery;
+                }
+                schedule.insert((at, rank, copy), frame.clone());
+            }
+            link.scheduled.notify_one();
+        }
+
+        log::debug!(
+            "Simulated message {} from {} to {}: {:?}",
+            rank,
+            from,
+            to,
+            fate
+        );
+        inner.trace.push(SimEvent {
+            from,
+            to,
+            rank,
+            fate,
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "server")]
+mod tests {
+    use super::*;
+    use crate::control::CriticalCommands;
+    use crate::money::Money;
+    use crate::mutex::MutexAlgorithm;
+    use crate::node::Node;
+    use crate::node::testing::{
+        TRIANGLE, balance, create_user, enqueue, sim_addr, start_sites, start_triangle, wait_until,
+    };
+
+    #[test]
+    fn fates_replay_from_the_seed() {
+        let config = SimConfig {
+            seed: 42,
+            reorder_rate: 0.3,
+            duplicate_rate: 0.3,
+            drop_rate: 0.3,
+            ..SimConfig::default()
+        };
+        let draw = |config: SimConfig| {
+            let sim = SimNetwork::new(config);
+            (0..64)
+                .map(|rank| sim.fate(sim_addr(0), sim_addr(1), rank))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(draw(config), draw(config));
+        assert_ne!(draw(config), draw(SimConfig { seed: 43, ..config }));
+    }
+
+    #[test]
+    fn fates_follow_the_rates() {
+        let dropping = SimNetwork::new(SimConfig {
+            drop_rate: 1.0,
+            ..SimConfig::default()
+        });
+        assert!((0..32).all(|rank| dropping.fate(sim_addr(0), sim_addr(1), rank) == Fate::Dropped));
+
+        let config = SimConfig {
+            duplicate_rate: 1.0,
+            ..SimConfig::default()
+        };
+        let duplicating = SimNetwork::new(config);
+        for rank in 0..32 {
+            let Fate::Delivered { delays, overtakes } =
+                duplicating.fate(sim_addr(0), sim_addr(1), rank)
+            else {
+                panic!("message {} dropped", rank);
+            };
+            assert_eq!(delays.len(), 2);
+            assert!(!overtakes);
+            assert!(
+                delays
+                    .iter()
+                    .all(|d| *d >= config.min_delay && *d <= config.max_delay)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn partitioned_links_drop_messages_until_healed() {
+        let sim = SimNetwork::new(SimConfig::default());
+        sim.register(sim_addr(1), Node::in_memory());
+        sim.register(sim_addr(0), Node::in_memory());
+
+        sim.partition(sim_addr(0), sim_addr(1));
+        sim.schedule(sim_addr(0), sim_addr(1), Vec::new()).unwrap();
+        sim.schedule(sim_addr(1), sim_addr(0), Vec::new()).unwrap();
+        sim.heal();
+        sim.schedule(sim_addr(0), sim_addr(1), Vec::new()).unwrap();
+
+        let fates: Vec<_> = sim.trace().into_iter().map(|e| (e.rank, e.fate)).collect();
+        assert_eq!(fates[0], (0, Fate::Dropped));
+        assert_eq!(fates[1], (0, Fate::Dropped));
+        assert!(matches!(fates[2], (1, Fate::Delivered { .. })));
+        assert!(sim.schedule(sim_addr(0), sim_addr(2), Vec::new()).is_err());
+    }
+
+    // The paused clock only moves when every task waits, so a run replays from its seed
+    #[tokio::test(start_paused = true)]
+    async fn replicas_converge_over_a_delayed_network() {
+        let sim = SimNetwork::new(SimConfig {
+            seed: 7,
+            ..SimConfig::default()
+        });
+        let nodes = start_sites(Some(&sim), TRIANGLE, |_| {}).await;
+        let (a, b, c) = (&nodes[0], &nodes[1], &nodes[2]);
+
+        create_user(&nodes, a, "alice").await;
+
+        enqueue(
+            b,
+            CriticalCommands::Deposit {
+                name: "alice".to_string(),
+                amount: Money::from_euros(100),
+            },
+        )
+        .await;
+        wait_until(&nodes, "the deposit", || {
+            balance("alice") == Some(Money::from_euros(100))
+        })
+        .await;
+
+        enqueue(
+            c,
+            CriticalCommands::CreateUser {
+                name: "bob".to_string(),
+            },
+        )
+        .await;
+        enqueue(
+            c,
+            CriticalCommands::Transfer {
+                from: "alice".to_string(),
+                to: "bob".to_string(),
+                amount: Money::from_euros(30),
+            },
+        )
+        .await;
+        enqueue(
+            c,
+            CriticalCommands::Withdraw {
+                name: "bob".to_string(),
+                amount: Money::from_euros(10),
+            },
+        )
+        .await;
+        wait_until(&nodes, "identical balances", || {
+            balance("alice") == Some(Money::from_euros(70))
+                && balance("bob") == Some(Money::from_euros(20))
+        })
+        .await;
+        assert!(sim.trace().iter().all(|e| e.fate != Fate::Dropped));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replicas_converge_over_a_faulty_network() {
+        let sim = SimNetwork::new(SimConfig {
+            seed: 21,
+            reorder_rate: 0.3,
+            duplicate_rate: 0.2,
+            ..SimConfig::default()
+        });
+        let nodes = start_triangle(&sim, MutexAlgorithm::Fifo).await;
+
+        create_user(&nodes, &nodes[0], "alice").await;
+        for (node, amount) in [
+            (&nodes[1], Money::from_euros(10)),
+            (&nodes[2], Money::from_euros(20)),
+            (&nodes[0], Money::from_euros(30)),
+        ] {
+            enqueue(
+                node,
+                CriticalCommands::Deposit {
+                    name: "alice".to_string(),
+                    amount,
+                },
+            )
+            .await;
+        }
+        wait_until(&nodes, "the deposits", || {
+            balance("alice") == Some(Money::from_euros(60))
+        })
+        .await;
+
+        let trace = sim.trace();
+        assert!(trace.iter().any(|e| matches!(
+            &e.fate,
+            Fate::Delivered {
+                overtakes: true,
+                ..
+            }
+        )));
+        assert!(trace.iter().any(|e| matches!(
+            &e.fate,
+            Fate::Delivered { delays, .. } if delays.len() == 2
+        )));
+    }
+}
@@ -0,0 +1,68 @@
+//! Transports carrying the messages between sites
+//!
+//! `send_message` encodes the messages and hands the frames to the transport of the node.
+//! Sites talk over TCP, optionally with mutual TLS; tests can plug in a simulated network
+//! instead.
+
+#[cfg(feature = "server")]
+/// Future returned when a frame is handed to a transport
+pub type TransportFuture<'a> = std::pin::Pin<
+    Box<dyn std::future::Future<Output = Result<(), Box<dyn std::error::Error>>> + Send + 'a>,
+>;
+
+#[cfg(feature = "server")]
+/// Carries encoded messages from a site to its peers
+pub trait Transport: Send + Sync {
+    /// Sends a frame from the site at `from` to the peer at `to`
+    fn send(
+        &self,
+        from: std::net::SocketAddr,
+        to: std::net::SocketAddr,
+        frame: Vec<u8>,
+    ) -> TransportFuture<'_>;
+}
+
+#[cfg(feature = "server")]
+/// Transport over the TCP connections of the network manager
+///
+/// A connection is opened to a peer the first time a frame is sent to it
+pub struct TcpTransport;
+
+#[cfg(feature = "server")]
+impl Transport for TcpTransport {
+    fn send(
+        &self,
+        _from: std::net::SocketAddr,
+        to: std::net::SocketAddr,
+        frame: Vec<u8>,
+    ) -> TransportFuture<'_> {
+        Box::pin(async move {
+            let mut manager = super::NETWORK_MANAGER.lock().await;
+
+            let sender = match manager.get_sender(&to) {
+                // The writer task of a closed connection is gone, a new connection is opened
+                Some(s) if !s.is_closed() => s,
+                _ => {
+                    if let Err(e) = manager.create_connection(to).await {
+                        return Err(format!("error with connection to {}: {}", to, e).into());
+                    }
+                    match manager.get_sender(&to) {
+                        Some(s) => s,
+                        None => {
+                            let err_msg = format!("Sender not found after connecting to {}", to);
+                            log::error!("{}", err_msg);
+                            return Err(err_msg.into());
+                        }
+                    }
+                }
+            };
+
+            if let Err(e) = sender.send(frame).await {
+                let err_msg = format!("Impossible to send msg to {} due to error : {}", to, e);
+                log::error!("{}", err_msg);
+                return Err(err_msg.into());
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,822 @@
+//! Echo waves over the graph of neighbours
+//!
+//! A wave is started by an initiator, which sends a message to every neighbour. A site
+//! reached for the first time takes the sender as its parent, visits the message and
+//! forwards it to its other neighbours. A message of the wave received once the site was
+//! reached stands for the answer of its sender, which then is not a child. Once a site
+//! got an answer from every neighbour but its parent, it reduces the values echoed by its
+//! children with its own value and echoes the result to its parent. When the initiator
+//! got all its echoes, the future returned at the start of the wave resolves with the
+//! value reduced over the whole network.
+//!
+//! A protocol carried by a wave only gives its two message codes, the visit run on each
+//! site and the reduce function, see [`WaveProtocol`]. It is registered in [`PROTOCOLS`]
+//! so that `handle_network_message` routes its messages here.
+
+#[cfg(feature = "server")]
+/// Future returned by the visit of a wave message on a site
+pub type VisitFuture = std::pin::Pin<
+    Box<
+        dyn std::future::Future<
+                Output = Result<crate::message::MessageInfo, Box<dyn std::error::Error>>,
+            > + Send,
+    >,
+>;
+
+#[cfg(feature = "server")]
+/// Protocol carried by an echo wave
+pub trait WaveProtocol: Send + Sync {
+    /// Code of the messages exploring the network
+    fn code(&self) -> crate::message::NetworkMessageCode;
+
+    /// Code of the echoes sent back to the parents
+    fn echo_code(&self) -> crate::message::NetworkMessageCode;
+
+    /// Runs once on each site reached by the wave, the initiator excepted
+    ///
+    /// Returns the value of the site, `MessageInfo::None` if there is nothing to send back
+    fn visit(
+        &self,
+        store: std::sync::Arc<dyn crate::store::LedgerStore>,
+        message: crate::message::Message,
+    ) -> VisitFuture;
+
+    /// Merges the value echoed by a child into the value of the site
+    ///
+    /// Only called when both values are set, by default the value of the child is dropped
+    fn reduce(
+        &self,
+        acc: crate::message::MessageInfo,
+        _child: crate::message::MessageInfo,
+    ) -> crate::message::MessageInfo {
+        acc
+    }
+}
+
+#[cfg(feature = "server")]
+/// Protocols carried by waves
+pub static PROTOCOLS: &[&dyn WaveProtocol] = &[
+    &crate::mutex::AcquireMutexWave,
+    &crate::mutex::ReleaseMutexWave,
+    &crate::mutex::TokenRequestWave,
+    &crate::mutex::TokenTransferWave,
+    &crate::control::TransactionWave,
+    &crate::snapshot::SnapshotWave,
+    &crate::membership::RetireSiteWave,
+    &crate::lease::RenewLeaseWave,
+    &crate::lease::EvictRequestWave,
+];
+
+#[cfg(feature = "server")]
+/// Returns the protocol a message belongs to, if it is part of a wave
+pub fn protocol_for(
+    code: &crate::message::NetworkMessageCode,
+) -> Option<&'static dyn WaveProtocol> {
+    PROTOCOLS
+        .iter()
+        .copied()
+        .find(|protocol| protocol.code() == *code || protocol.echo_code() == *code)
+}
+
+#[cfg(feature = "server")]
+/// Merges two values, `MessageInfo::None` being the neutral value
+fn fold(
+    protocol: &dyn WaveProtocol,
+    acc: crate::message::MessageInfo,
+    child: crate::message::MessageInfo,
+) -> crate::message::MessageInfo {
+    use crate::message::MessageInfo;
+
+    match (acc, child) {
+        (acc, MessageInfo::None) => acc,
+        (MessageInfo::None, child) => child,
+        (acc, child) => protocol.reduce(acc, child),
+    }
+}
+
+#[cfg(feature = "server")]
+/// Progress of a wave on this site
+#[derive(Debug)]
+struct WaveState {
+    /// Neighbour the wave came from, the site itself for the initiator
+    parent: std::net::SocketAddr,
+    /// Neighbours whose answer is still expected
+    waiting: std::collections::HashSet<std::net::SocketAddr>,
+    /// Whether the site visited the message, it cannot echo before
+    visited: bool,
+    /// Value of the site reduced with the values echoed so far
+    value: crate::message::MessageInfo,
+    /// Message received from the parent, None for the initiator
+    message: Option<crate::message::Message>,
+    /// Resolves the future of the initiator
+    done: Option<tokio::sync::oneshot::Sender<crate::message::MessageInfo>>,
+}
+
+#[cfg(feature = "server")]
+/// What a site has to send next for a wave
+#[derive(Debug)]
+pub enum WaveStep {
+    /// Forward the wave message to every neighbour but `parent`, then send `echo` to it
+    /// if the site got every answer already
+    Forward {
+        parent: std::net::SocketAddr,
+        echo: Option<crate::message::MessageInfo>,
+    },
+    /// Send an echo carrying `value` to `to`
+    Echo {
+        to: std::net::SocketAddr,
+        value: crate::message::MessageInfo,
+    },
+    /// Nothing to send
+    Wait,
+}
+
+#[cfg(feature = "server")]
+/// Number of completed waves remembered by a site
+const COMPLETED_WAVES: usize = 4096;
+
+#[cfg(feature = "server")]
+/// Waves going through this site
+///
+/// A wave is dropped as soon as this site completed its part of the echo. Its id is kept
+/// among the last completed ones, so that a late message of the wave is not taken for a
+/// new wave and visited again.
+#[derive(Debug, Default)]
+pub struct WaveTable {
+    waves: std::collections::HashMap<crate::message::WaveId, WaveState>,
+    /// Ids of the last completed waves
+    completed: std::collections::HashSet<crate::message::WaveId>,
+    /// Same ids, the oldest first
+    completed_order: std::collections::VecDeque<crate::message::WaveId>,
+}
+
+#[cfg(feature = "server")]
+impl WaveTable {
+    /// Registers a wave started by this site, which has `neighbours`
+    ///
+    /// Returns the future of the wave and whether the message has to be diffused
+    pub fn start(
+        &mut self,
+        wave_id: crate::message::WaveId,
+        site_addr: std::net::SocketAddr,
+        neighbours: &[std::net::SocketAddr],
+    ) -> (
+        tokio::sync::oneshot::Receiver<crate::message::MessageInfo>,
+        WaveStep,
+    ) {
+        use crate::message::MessageInfo;
+
+        let (done, completion) = tokio::sync::oneshot::channel();
+        if neighbours.is_empty() {
+            // Nobody to wait for, the wave is already complete
+            let _ = done.send(MessageInfo::None);
+            return (completion, WaveStep::Wait);
+        }
+        self.waves.insert(
+            wave_id,
+            WaveState {
+                parent: site_addr,
+                waiting: neighbours.iter().copied().collect(),
+                visited: true,
+                value: MessageInfo::None,
+                message: None,
+                done: Some(done),
+            },
+        );
+        (
+            completion,
+            WaveStep::Forward {
+                parent: site_addr,
+                echo: None,
+            },
+        )
+    }
+
+    /// Registers a wave message received from one of `neighbours`
+    ///
+    /// Returns false if the wave already reached this site, the message is then the
+    /// answer of its sender and is recorded with [`WaveTable::echoed`]
+    pub fn explore(
+        &mut self,
+        wave_id: crate::message::WaveId,
+        message: &crate::message::Message,
+        neighbours: &[std::net::SocketAddr],
+    ) -> bool {
+        if self.waves.contains_key(&wave_id) || self.completed.contains(&wave_id) {
+            return false;
+        }
+        let parent = message.sender_addr;
+        self.waves.insert(
+            wave_id,
+            WaveState {
+                parent,
+                waiting: neighbours
+                    .iter()
+                    .copied()
+                    .filter(|addr| *addr != parent)
+                    .collect(),
+                visited: false,
+                value: crate::message::MessageInfo::None,
+                message: Some(message.clone()),
+                done: None,
+            },
+        );
+        true
+    }
+
+    /// Records the value of this site once it visited the wave message
+    pub fn visited(
+        &mut self,
+        protocol: &dyn WaveProtocol,
+        wave_id: &crate::message::WaveId,
+        value: crate::message::MessageInfo,
+    ) -> WaveStep {
+        let Some(wave) = self.waves.get_mut(wave_id) else {
+            log::warn!("Wave {} ended before its visit", wave_id);
+            return WaveStep::Wait;
+        };
+        let acc = std::mem::replace(&mut wave.value, crate::message::MessageInfo::None);
+        wave.value = fold(protocol, acc, value);
+        wave.visited = true;
+        let parent = wave.parent;
+        let echo = if !wave.waiting.is_empty() {
+            None
+        } else {
+            // Every other neighbour answered during the visit, or there is none
+            match self.complete(wave_id) {
+                WaveStep::Echo { value, .. } => Some(value),
+                _ => None,
+            }
+        };
+        WaveStep::Forward { parent, echo }
+    }
+
+    /// Records the answer of the neighbour `from`, carrying `value` if it is a child
+    pub fn echoed(
+        &mut self,
+        protocol: &dyn WaveProtocol,
+        wave_id: &crate::message::WaveId,
+        from: std::net::SocketAddr,
+        value: crate::message::MessageInfo,
+    ) -> WaveStep {
+        let Some(wave) = self.waves.get_mut(wave_id) else {
+            if self.completed.contains(wave_id) {
+                log::debug!("Ignoring a late message of the completed wave {}", wave_id);
+            } else {
+                log::warn!("Ignoring an echo of the unknown wave {}", wave_id);
+            }
+            return WaveStep::Wait;
+        };
+        if !wave.waiting.remove(&from) {
+            log::debug!(
+                "Ignoring an unexpected answer of {} to wave {}",
+                from,
+                wave_id
+            );
+            return WaveStep::Wait;
+        }
+        let acc = std::mem::replace(&mut wave.value, crate::message::MessageInfo::None);
+        wave.value = fold(protocol, acc, value);
+        if !wave.waiting.is_empty() || !wave.visited {
+            WaveStep::Wait
+        } else {
+            self.complete(wave_id)
+        }
+    }
+
+    /// Stops waiting for a neighbour that left the network
+    ///
+    /// The waves it was the parent of are abandoned, the others do not wait for its
+    /// answer anymore. Returns the message and the echo of each wave this completes.
+    pub fn forget_neighbour(
+        &mut self,
+        addr: std::net::SocketAddr,
+    ) -> Vec<(crate::message::Message, WaveStep)> {
+        let abandoned: Vec<_> = self
+            .waves
+            .iter()
+            .filter(|(_, wave)| wave.parent == addr && wave.done.is_none())
+            .map(|(wave_id, _)| wave_id.clone())
+            .collect();
+        for wave_id in abandoned {
+            log::warn!("Abandoning wave {}, its parent {} left", wave_id, addr);
+            self.waves.remove(&wave_id);
+            self.remember(wave_id);
+        }
+
+        let completed: Vec<_> = self
+            .waves
+            .iter_mut()
+            .filter_map(|(wave_id, wave)| {
+                let answered = wave.waiting.remove(&addr);
+                (answered && wave.waiting.is_empty() && wave.visited)
+                    .then(|| (wave_id.clone(), wave.message.clone()))
+            })
+            .collect();
+        let mut echoes = Vec::new();
+        for (wave_id, message) in completed {
+            let step = self.complete(&wave_id);
+            if let Some(message) = message {
+                echoes.push((message, step));
+            }
+        }
+        echoes
+    }
+
+    /// Ends a wave on this site, its value goes to the parent or to the initiator future
+    fn complete(&mut self, wave_id: &crate::message::WaveId) -> WaveStep {
+        let Some(wave) = self.waves.remove(wave_id) else {
+            return WaveStep::Wait;
+        };
+        self.remember(wave_id.clone());
+        match wave.done {
+            Some(done) => {
+                // The initiator may have stopped waiting for the result
+                let _ = done.send(wave.value);
+                WaveStep::Wait
+            }
+            None => WaveStep::Echo {
+                to: wave.parent,
+                value: wave.value,
+            },
+        }
+    }
+
+    /// Keeps the id of a wave this site is done with
+    fn remember(&mut self, wave_id: crate::message::WaveId) {
+        if self.completed_order.len() == COMPLETED_WAVES
+            && let Some(oldest) = self.completed_order.pop_front()
+        {
+            self.completed.remove(&oldest);
+        }
+        self.completed.insert(wave_id.clone());
+        self.completed_order.push_back(wave_id);
+    }
+
+    /// Returns the parent of every ongoing wave
+    pub fn parents(
+        &self,
+    ) -> std::collections::HashMap<crate::message::WaveId, std::net::SocketAddr> {
+        self.waves
+            .iter()
+            .map(|(wave_id, wave)| (wave_id.clone(), wave.parent))
+            .collect()
+    }
+
+    /// Returns the number of answers expected for every ongoing wave
+    pub fn remaining(&self) -> std::collections::HashMap<crate::message::WaveId, i64> {
+        self.waves
+            .iter()
+            .map(|(wave_id, wave)| (wave_id.clone(), wave.waiting.len() as i64))
+            .collect()
+    }
+}
+
+#[cfg(feature = "server")]
+/// Starts a wave carrying `message`, which must have a wave id
+///
+/// Returns a future resolving with the value reduced over the network once every site echoed
+pub async fn start(
+    message: crate::message::Message,
+) -> Result<tokio::sync::oneshot::Receiver<crate::message::MessageInfo>, crate::error::PeillutError>
+{
+    let mut state = crate::state::LOCAL_APP_STATE.lock().await;
+    start_without_lock(&mut state, message).await
+}
+
+#[cfg(feature = "server")]
+/// Starts a wave without locking the app state
+///
+/// The message is signed by the site, the relays check it before visiting it
+pub async fn start_without_lock(
+    state: &mut crate::state::AppState,
+    mut message: crate::message::Message,
+) -> Result<tokio::sync::oneshot::Receiver<crate::message::MessageInfo>, crate::error::PeillutError>
+{
+    crate::identity::site_identity()
+        .and_then(|identity| identity.sign(&mut message))
+        .map_err(|e| crate::error::PeillutError::Network(e.to_string()))?;
+    let wave_id = message.wave_id.clone().ok_or_else(|| {
+        crate::error::PeillutError::Network(
+            "Only the messages of a wave can be diffused".to_string(),
+        )
+    })?;
+    let site_addr = state.get_site_addr();
+    let neighbours = state.get_connected_nei_addr();
+
+    let (completion, step) = state.waves.start(wave_id.clone(), site_addr, &neighbours);
+    if let WaveStep::Forward { .. } = step {
+        log::debug!("Début de la vague {} de type {:?}", wave_id, message.code);
+        super::diffuse_message_without_lock(
+            &message,
+            site_addr,
+            &state.get_site_id(),
+            neighbours,
+            site_addr,
+        )
+        .await?;
+    }
+    Ok(completion)
+}
+
+#[cfg(feature = "server")]
+/// Handles a message of a wave received from a neighbour, the visit works on `store`
+pub async fn receive(
+    store: &std::sync::Arc<dyn crate::store::LedgerStore>,
+    protocol: &'static dyn WaveProtocol,
+    message: &crate::message::Message,
+) -> Result<(), crate::error::PeillutError> {
+    use crate::message::MessageInfo;
+    use crate::state::LOCAL_APP_STATE;
+
+    let Some(wave_id) = message.wave_id.clone() else {
+        log::error!(
+            "Ignoring {:?} message without wave id from {}",
+            message.code,
+            message.sender_addr
+        );
+        return Ok(());
+    };
+
+    let step = if message.code == protocol.code() {
+        // messages bleus
+        let first_visit = {
+            let mut state = LOCAL_APP_STATE.lock().await;
+            let neighbours = state.get_connected_nei_addr();
+            state.waves.explore(wave_id.clone(), message, &neighbours)
+        };
+
+        if first_visit {
+            // The visit may need the app state, it runs without the lock
+            let value = match protocol.visit(store.clone(), message.clone()).await {
+                Ok(value) => value,
+                Err(e) => {
+                    log::error!(
+                        "Error visiting {:?} of wave {}:\n{}",
+                        message.code,
+                        wave_id,
+                        e
+                    );
+                    MessageInfo::None
+                }
+            };
+            let mut state = LOCAL_APP_STATE.lock().await;
+            state.waves.visited(protocol, &wave_id, value)
+        } else {
+            // The sender was reached by another neighbour, its message is its answer
+            let mut state = LOCAL_APP_STATE.lock().await;
+            state
+                .waves
+                .echoed(protocol, &wave_id, message.sender_addr, MessageInfo::None)
+        }
+    } else {
+        // messages rouges
+        let mut state = LOCAL_APP_STATE.lock().await;
+        state.waves.echoed(
+            protocol,
+            &wave_id,
+            message.sender_addr,
+            message.info.clone(),
+        )
+    };
+
+    match step {
+        WaveStep::Forward { parent, echo } => {
+            let (local_addr, site_id, neighbours) = {
+                let state = LOCAL_APP_STATE.lock().await;
+                (
+                    state.get_site_addr(),
+                    state.get_site_id(),
+                    state.get_connected_nei_addr(),
+                )
+            };
+            super::diffuse_message_without_lock(message, local_addr, &site_id, neighbours, parent)
+                .await?;
+            match echo {
+                Some(value) => send_echo(protocol, message, wave_id, parent, value).await,
+                None => Ok(()),
+            }
+        }
+        WaveStep::Echo { to, value } => send_echo(protocol, message, wave_id, to, value).await,
+        WaveStep::Wait => Ok(()),
+    }
+}
+
+#[cfg(feature = "server")]
+/// Sends the echo of a wave carrying `value` to `to`
+async fn send_echo(
+    protocol: &dyn WaveProtocol,
+    message: &crate::message::Message,
+    wave_id: crate::message::WaveId,
+    to: std::net::SocketAddr,
+    value: crate::message::MessageInfo,
+) -> Result<(), crate::error::PeillutError> {
+    let (local_addr, site_id, clock) = {
+        let state = crate::state::LOCAL_APP_STATE.lock().await;
+        (
+            state.get_site_addr(),
+            state.get_site_id(),
+            state.get_clock(),
+        )
+    };
+    log::debug!("Écho de la vague {} envoyé à {}", wave_id, to);
+    super::send_message(
+        to,
+        value,
+        None,
+        protocol.echo_code(),
+        local_addr,
+        &site_id,
+        &message.message_initiator_id,
+        message.message_initiator_addr,
+        Some(wave_id),
+        clock,
+    )
+    .await
+}
+
+#[cfg(feature = "server")]
+/// Sends the echoes of the waves completed when a neighbour left, see
+/// [`WaveTable::forget_neighbour`]
+pub async fn send_echoes(echoes: Vec<(crate::message::Message, WaveStep)>) {
+    for (message, step) in echoes {
+        let (Some(protocol), Some(wave_id), WaveStep::Echo { to, value }) =
+            (protocol_for(&message.code), message.wave_id.clone(), step)
+        else {
+            continue;
+        };
+        if let Err(e) = send_echo(protocol, &message, wave_id, to, value).await {
+            log::error!(
+                "Unable to send the echo of {:?} to {}: {}",
+                message.code,
+                to,
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "server")]
+mod tests {
+    use super::*;
+    use crate::message::{Message, MessageInfo, SnapshotResponse, WaveId};
+    use std::collections::VecDeque;
+    use std::net::SocketAddr;
+
+    fn addr(site: usize) -> SocketAddr {
+        format!("127.0.0.1:{}", 9000 + site).parse().unwrap()
+    }
+
+    fn addrs(sites: &[usize]) -> Vec<SocketAddr> {
+        sites.iter().map(|&site| addr(site)).collect()
+    }
+
+    fn wave(initiator: &str, seq: u64) -> WaveId {
+        WaveId {
+            initiator: initiator.to_string(),
+            seq,
+        }
+    }
+
+    /// Message of `wave_id` sent by `from`
+    fn explorer(wave_id: &WaveId, from: usize) -> Message {
+        Message {
+            sender_id: from.to_string(),
+            sender_addr: addr(from),
+            message_initiator_id: wave_id.initiator.clone(),
+            clock: crate::clock::Clock::new(),
+            command: None,
+            info: MessageInfo::None,
+            code: crate::snapshot::SnapshotWave.code(),
+            message_initiator_addr: addr(0),
+            signature: None,
+            wave_id: Some(wave_id.clone()),
+            causal_deps: None,
+        }
+    }
+
+    fn local_snapshot(site: usize) -> MessageInfo {
+        MessageInfo::SnapshotResponse(vec![SnapshotResponse {
+            site_id: site.to_string(),
+            clock: crate::clock::Clock::new(),
+            tx_log: Vec::new(),
+            signature: None,
+        }])
+    }
+
+    /// Runs a snapshot wave started by site 0 over `edges`, delivering the messages in order
+    fn run_wave(nb_sites: usize, edges: &[(usize, usize)]) -> (MessageInfo, Vec<WaveTable>) {
+        let protocol = &crate::snapshot::SnapshotWave;
+        let neighbours = |site: usize| -> Vec<usize> {
+            edges
+                .iter()
+                .filter_map(|&(a, b)| match site {
+                    s if s == a => Some(b),
+                    s if s == b => Some(a),
+                    _ => None,
+                })
+                .collect()
+        };
+        let site_of = |a: SocketAddr| (0..nb_sites).find(|&s| addr(s) == a).unwrap();
+        let wave_id = wave("0", 1);
+        let mut tables: Vec<WaveTable> = (0..nb_sites).map(|_| WaveTable::default()).collect();
+        // (from, to, None for an explorer or the echoed value)
+        let mut queue: VecDeque<(usize, usize, Option<MessageInfo>)> = VecDeque::new();
+
+        let (mut completion, step) =
+            tables[0].start(wave_id.clone(), addr(0), &addrs(&neighbours(0)));
+        assert!(matches!(step, WaveStep::Forward { .. }));
+        for n in neighbours(0) {
+            queue.push_back((0, n, None));
+        }
+
+        while let Some((from, to, echo)) = queue.pop_front() {
+            let step = match echo {
+                None => {
+                    let nb = addrs(&neighbours(to));
+                    if tables[to].explore(wave_id.clone(), &explorer(&wave_id, from), &nb) {
+                        tables[to].visited(protocol, &wave_id, local_snapshot(to))
+                    } else {
+                        tables[to].echoed(protocol, &wave_id, addr(from), MessageInfo::None)
+                    }
+                }
+                Some(value) => tables[to].echoed(protocol, &wave_id, addr(from), value),
+            };
+            match step {
+                WaveStep::Forward { parent, echo } => {
+                    let parent = site_of(parent);
+                    for n in neighbours(to).into_iter().filter(|&n| n != parent) {
+                        queue.push_back((to, n, None));
+                    }
+                    if let Some(value) = echo {
+                        queue.push_back((to, parent, Some(value)));
+                    }
+                }
+                WaveStep::Echo { to: dest, value } => {
+                    queue.push_back((to, site_of(dest), Some(value)))
+                }
+                WaveStep::Wait => {}
+            }
+        }
+
+        (completion.try_recv().unwrap(), tables)
+    }
+
+    #[test]
+    fn echo_reduces_the_value_of_every_site_once() {
+        // Triangle 0-1-2 with a leaf 3 behind 2
+        let (result, tables) = run_wave(4, &[(0, 1), (0, 2), (1, 2), (2, 3)]);
+
+        let MessageInfo::SnapshotResponse(snapshots) = result else {
+            panic!("Expected the local snapshots of the other sites");
+        };
+        let mut sites: Vec<_> = snapshots.into_iter().map(|s| s.site_id).collect();
+        sites.sort();
+        assert_eq!(sites, vec!["1", "2", "3"]);
+
+        // Every site forgot the wave once its part was done
+        assert!(tables.iter().all(|table| table.parents().is_empty()));
+    }
+
+    #[test]
+    fn lone_initiator_completes_at_once() {
+        let mut table = WaveTable::default();
+        let (mut completion, step) = table.start(wave("A", 1), addr(0), &[]);
+        assert!(matches!(step, WaveStep::Wait));
+        assert!(matches!(completion.try_recv(), Ok(MessageInfo::None)));
+        assert!(table.parents().is_empty());
+    }
+
+    #[test]
+    fn concurrent_waves_of_one_initiator_are_kept_apart() {
+        let protocol = &crate::control::TransactionWave;
+        let mut table = WaveTable::default();
+        let acquire = wave("A", 1);
+        let transaction = wave("A", 2);
+        let neighbours = addrs(&[1, 2]);
+
+        // Relay of both waves, reached through two different neighbours
+        assert!(table.explore(acquire.clone(), &explorer(&acquire, 1), &neighbours));
+        assert!(table.explore(transaction.clone(), &explorer(&transaction, 2), &neighbours));
+        assert!(!table.explore(acquire.clone(), &explorer(&acquire, 2), &neighbours));
+        assert!(matches!(
+            table.visited(protocol, &acquire, MessageInfo::None),
+            WaveStep::Forward { echo: None, .. }
+        ));
+        assert!(matches!(
+            table.visited(protocol, &transaction, MessageInfo::None),
+            WaveStep::Forward { echo: None, .. }
+        ));
+
+        // The answer of 2 to the acquisition only completes the acquisition
+        match table.echoed(protocol, &acquire, addr(2), MessageInfo::None) {
+            WaveStep::Echo { to, .. } => assert_eq!(to, addr(1)),
+            step => panic!("Expected an echo to the parent, got {:?}", step),
+        }
+        assert_eq!(
+            table.remaining(),
+            std::collections::HashMap::from([(transaction.clone(), 1)])
+        );
+        assert_eq!(table.parents()[&transaction], addr(2));
+    }
+
+    #[test]
+    fn a_forward_received_after_completion_is_not_visited_again() {
+        let protocol = &crate::snapshot::SnapshotWave;
+        let wave_id = wave("0", 1);
+        let neighbours = addrs(&[0, 2]);
+        let mut table = WaveTable::default();
+
+        // Reached by 0, with 2 as other neighbour, which was reached by 0 as well
+        assert!(table.explore(wave_id.clone(), &explorer(&wave_id, 0), &neighbours));
+        assert!(matches!(
+            table.visited(protocol, &wave_id, local_snapshot(1)),
+            WaveStep::Forward { echo: None, .. }
+        ));
+
+        // The forward of 2 is its answer
+        assert!(!table.explore(wave_id.clone(), &explorer(&wave_id, 2), &neighbours));
+        match table.echoed(protocol, &wave_id, addr(2), MessageInfo::None) {
+            WaveStep::Echo { to, .. } => assert_eq!(to, addr(0)),
+            step => panic!("Expected an echo to the parent, got {:?}", step),
+        }
+
+        // A copy arriving once the wave completed is neither visited nor counted
+        assert!(!table.explore(wave_id.clone(), &explorer(&wave_id, 2), &neighbours));
+        assert!(matches!(
+            table.echoed(protocol, &wave_id, addr(2), MessageInfo::None),
+            WaveStep::Wait
+        ));
+        assert!(table.parents().is_empty());
+    }
+
+    #[test]
+    fn answers_received_during_the_visit_wait_for_it() {
+        let protocol = &crate::snapshot::SnapshotWave;
+        let wave_id = wave("0", 1);
+        let neighbours = addrs(&[0, 2]);
+        let mut table = WaveTable::default();
+
+        assert!(table.explore(wave_id.clone(), &explorer(&wave_id, 0), &neighbours));
+        assert!(!table.explore(wave_id.clone(), &explorer(&wave_id, 2), &neighbours));
+        assert!(matches!(
+            table.echoed(protocol, &wave_id, addr(2), MessageInfo::None),
+            WaveStep::Wait
+        ));
+
+        // The site still forwards, 2 waits for its answer, then echoes its own value
+        match table.visited(protocol, &wave_id, local_snapshot(1)) {
+            WaveStep::Forward {
+                parent,
+                echo: Some(MessageInfo::SnapshotResponse(snapshots)),
+            } => {
+                assert_eq!(parent, addr(0));
+                assert_eq!(snapshots[0].site_id, "1");
+            }
+            step => panic!("Expected a forward then an echo, got {:?}", step),
+        }
+    }
+
+    #[test]
+    fn a_neighbour_killed_mid_wave_is_not_waited_for() {
+        let protocol = &crate::snapshot::SnapshotWave;
+        let relayed = wave("0", 1);
+        let from_dead = wave("3", 1);
+        let own = wave("1", 1);
+        let mut table = WaveTable::default();
+
+        // Site 1 relays a wave of 0 and a wave of 3, and started one, with 2 and 3 alive
+        let neighbours = addrs(&[0, 2, 3]);
+        assert!(table.explore(relayed.clone(), &explorer(&relayed, 0), &neighbours));
+        table.visited(protocol, &relayed, local_snapshot(1));
+        table.echoed(protocol, &relayed, addr(2), MessageInfo::None);
+        assert!(table.explore(from_dead.clone(), &explorer(&from_dead, 3), &neighbours));
+        table.visited(protocol, &from_dead, local_snapshot(1));
+        let (mut completion, _) = table.start(own.clone(), addr(1), &neighbours);
+        table.echoed(protocol, &own, addr(0), MessageInfo::None);
+        table.echoed(protocol, &own, addr(2), local_snapshot(2));
+
+        // 3 dies: the relayed wave echoes to 0, the wave it was the parent of is
+        // abandoned and the wave of the site completes
+        let echoes = table.forget_neighbour(addr(3));
+        assert_eq!(echoes.len(), 1);
+        let (message, step) = &echoes[0];
+        assert_eq!(message.wave_id, Some(relayed.clone()));
+        match step {
+            WaveStep::Echo { to, .. } => assert_eq!(*to, addr(0)),
+            step => panic!("Expected an echo to the parent, got {:?}", step),
+        }
+        assert!(matches!(
+            completion.try_recv(),
+            Ok(MessageInfo::SnapshotResponse(_))
+        ));
+        assert!(table.parents().is_empty());
+
+        // The late echoes of the children of the abandoned wave are dropped
+        assert!(matches!(
+            table.echoed(protocol, &from_dead, addr(2), MessageInfo::None),
+            WaveStep::Wait
+        ));
+    }
+}
@@ -0,0 +1,401 @@
+//! Node running a Peillute site
+//!
+//! A node owns everything a site needs: its app state, its network manager, its snapshot
+//! manager and its ledger store. The handles used across the code base (`LOCAL_APP_STATE`,
+//! `NETWORK_MANAGER`, `LOCAL_SNAPSHOT_MANAGER`, `DELIVERY`) resolve to the node running the
+//! current task, so that several nodes can live in the same process, for instance in tests. Tasks
+//! started with [`spawn`] keep running on the node that started them. The store is not one
+//! of these handles: the node gives it to its control worker and to the network code, which
+//! pass it on. Only the web server, which runs outside of the nodes, takes it from [`current`].
+
+#[cfg(test)]
+#[cfg(feature = "server")]
+pub mod testing;
+
+#[cfg(feature = "server")]
+/// Everything owned by a site
+pub struct Node {
+    /// State of the site
+    pub state: std::sync::Arc<tokio::sync::Mutex<crate::state::AppState>>,
+    /// Connections to the neighbours
+    pub network: std::sync::Arc<tokio::sync::Mutex<crate::network::NetworkManager>>,
+    /// Snapshots being collected
+    pub snapshots: std::sync::Arc<tokio::sync::Mutex<crate::snapshot::SnapshotManager>>,
+    /// Ledger of the site
+    pub store: std::sync::Arc<dyn crate::store::LedgerStore>,
+    /// Taken while the transactions released by the causal buffer are applied
+    pub delivery: std::sync::Arc<tokio::sync::Mutex<()>>,
+    /// Signing key of the site, set when it starts
+    pub identity: std::sync::OnceLock<std::sync::Arc<crate::identity::SiteIdentity>>,
+}
+
+#[cfg(feature = "server")]
+/// Configuration of a node, given when it starts
+pub struct NodeConfig {
+    /// Unique identifier of the site
+    pub site_id: String,
+    /// Address to listen on for the peers, port 0 picks a free port
+    pub site_addr: std::net::SocketAddr,
+    /// Peers to connect to
+    pub peers: Vec<std::net::SocketAddr>,
+    /// Clock of the site, reloaded from the database for an existing site
+    pub clock: crate::clock::Clock,
+    /// Whether the site has to synchronize with the network
+    pub needs_sync: bool,
+    /// Signing key of the site, a new one is generated if None
+    pub identity: Option<crate::identity::SiteIdentity>,
+    /// Public keys of the other sites, pinned by the site before it restarted
+    pub site_keys: std::collections::HashMap<String, Vec<u8>>,
+    /// Failure detector fed by the heartbeats of the neighbours
+    pub failure_detector: crate::failure_detector::FailureDetector,
+    /// Codec used to frame messages
+    pub codec: crate::codec::FrameCodec,
+    /// Mutual TLS configuration, plain TCP is used when None
+    pub tls: Option<crate::tls::TlsConfig>,
+    /// Multicast group used to find peers on the LAN, LAN discovery is disabled when None
+    pub discovery_group: Option<std::net::SocketAddrV4>,
+    /// Transport carrying the messages, the site listens on TCP when None
+    pub transport: Option<std::sync::Arc<dyn crate::network::transport::Transport>>,
+    /// Mutual exclusion algorithm of the site
+    pub mutex: crate::mutex::MutexAlgorithm,
+    /// Duration of the lease of a mutex request
+    pub mutex_lease: std::time::Duration,
+}
+
+#[cfg(feature = "server")]
+impl NodeConfig {
+    /// Creates the configuration of a new site with the default settings
+    pub fn new(
+        site_id: String,
+        site_addr: std::net::SocketAddr,
+        peers: Vec<std::net::SocketAddr>,
+    ) -> Self {
+        Self {
+            site_id,
+            site_addr,
+            peers,
+            clock: crate::clock::Clock::new(),
+            needs_sync: false,
+            identity: None,
+            site_keys: std::collections::HashMap::new(),
+            failure_detector: crate::failure_detector::FailureDetector::default(),
+            codec: crate::codec::FrameCodec::default(),
+            tls: None,
+            discovery_group: None,
+            transport: None,
+            mutex: crate::mutex::MutexAlgorithm::default(),
+            mutex_lease: std::time::Duration::from_millis(crate::lease::DEFAULT_LEASE_MS),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+tokio::task_local! {
+    /// Node running the current task
+    static CURRENT_NODE: std::sync::Arc<Node>;
+}
+
+#[cfg(feature = "server")]
+/// Node of the tasks that do not run on a node
+static DEFAULT_NODE: std::sync::OnceLock<std::sync::Arc<Node>> = std::sync::OnceLock::new();
+
+#[cfg(feature = "server")]
+impl Node {
+    /// Creates a node with its database at `db_path`, an existing database gets the
+    /// migrations introduced since it was created
+    pub fn open(db_path: &str) -> Result<std::sync::Arc<Self>, crate::error::PeillutError> {
+        Ok(Self::with_store(std::sync::Arc::new(
+            crate::db::SqliteStore::open(db_path)?,
+        )))
+    }
+
+    /// Creates a node with its ledger in memory
+    #[cfg(test)]
+    pub fn in_memory() -> std::sync::Arc<Self> {
+        Self::with_store(std::sync::Arc::new(crate::store::MemoryStore::default()))
+    }
+
+    /// Creates a node keeping its ledger in `store`
+    pub fn with_store(
+        store: std::sync::Arc<dyn crate::store::LedgerStore>,
+    ) -> std::sync::Arc<Self> {
+        use std::sync::Arc;
+
+        let state = crate::state::AppState::new(
+            "".to_string(), // empty site id at start
+            Vec::new(),
+            "0.0.0.0:0".parse().unwrap(),
+        );
+        Arc::new(Self {
+            state: Arc::new(tokio::sync::Mutex::new(state)),
+            network: Arc::new(tokio::sync::Mutex::new(
+                crate::network::NetworkManager::new(),
+            )),
+            snapshots: Arc::new(tokio::sync::Mutex::new(
+                crate::snapshot::SnapshotManager::new(0),
+            )),
+            store,
+            delivery: Arc::new(tokio::sync::Mutex::new(())),
+            identity: std::sync::OnceLock::new(),
+        })
+    }
+
+    /// Runs `future` on this node
+    pub async fn run<F: std::future::Future>(self: &std::sync::Arc<Self>, future: F) -> F::Output {
+        CURRENT_NODE.scope(self.clone(), future).await
+    }
+
+    /// Starts the site: listens for the peers, announces it and spawns its background tasks
+    ///
+    /// Returns the address the site listens on
+    pub async fn start(
+        self: &std::sync::Arc<Self>,
+        config: NodeConfig,
+    ) -> Result<std::net::SocketAddr, Box<dyn std::error::Error>> {
+        self.run(async move {
+            // A site on a custom transport receives its messages from the transport
+            let listener = match config.transport {
+                Some(_) => None,
+                None => Some(tokio::net::TcpListener::bind(config.site_addr).await?),
+            };
+            let site_addr = match &listener {
+                Some(listener) => listener.local_addr()?,
+                None => config.site_addr,
+            };
+            log::debug!("Listening on: {}", site_addr);
+
+            let identity = match config.identity {
+                Some(identity) => identity,
+                None => crate::identity::SiteIdentity::generate()?,
+            };
+            let mut site_keys = config.site_keys;
+            site_keys.insert(config.site_id.clone(), identity.public_key());
+            if self.identity.set(std::sync::Arc::new(identity)).is_err() {
+                return Err("The node is started already".into());
+            }
+            {
+                let mut state = self.state.lock().await;
+                state.init_site_id(config.site_id.clone());
+                state.init_site_keys(site_keys);
+                state.init_failure_detector(config.failure_detector);
+                state.init_site_addr(site_addr);
+                state.init_clock(config.clock);
+                state.init_cli_peer_addrs(config.peers);
+                state.init_sync(config.needs_sync);
+                state.init_mutex(config.mutex);
+                state.init_mutex_lease(config.mutex_lease);
+            }
+            {
+                let mut network = self.network.lock().await;
+                network.init_codec(config.codec);
+                if let Some(tls) = config.tls {
+                    network.init_tls(tls);
+                }
+                if let Some(transport) = config.transport {
+                    network.init_transport(transport);
+                }
+            }
+
+            spawn(save_clocks(
+                self.store.clone(),
+                config.site_id.clone(),
+                self.state.lock().await.watch_clock(),
+            ));
+            crate::control::control_worker(self.store.clone());
+            if let Some(listener) = listener {
+                spawn(accept_peers(self.store.clone(), listener));
+            }
+
+            // Answer the beacons of the sites starting on the LAN
+            if let Some(group) = config.discovery_group {
+                crate::beacon::spawn_beacon_listener(
+                    group,
+                    crate::beacon::interface_for(site_addr.ip()),
+                    crate::beacon::Beacon::new(config.site_id, site_addr),
+                );
+            }
+
+            // Announce our presence to the network
+            crate::network::announce(config.discovery_group).await;
+            crate::network::spawn_heartbeat_task();
+            crate::lease::spawn_lease_task();
+            crate::reconnect::spawn_reconnection_supervisor();
+
+            Ok(site_addr)
+        })
+        .await
+    }
+}
+
+#[cfg(feature = "server")]
+/// Saves the clock of the site in its ledger each time it is updated
+async fn save_clocks(
+    store: std::sync::Arc<dyn crate::store::LedgerStore>,
+    site_id: String,
+    mut clocks: tokio::sync::watch::Receiver<crate::clock::Clock>,
+) {
+    while clocks.changed().await.is_ok() {
+        let clock = clocks.borrow_and_update().clone();
+        let site_id = site_id.clone();
+        if let Err(e) = crate::store::blocking(&store, move |store| {
+            store.save_local_state(&site_id, &clock)
+        })
+        .await
+        {
+            log::error!("Unable to save the clock: {}", e);
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+/// Accepts the connections of the peers, whose messages are applied to `store`
+async fn accept_peers(
+    store: std::sync::Arc<dyn crate::store::LedgerStore>,
+    listener: tokio::net::TcpListener,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                crate::network::start_listening(store.clone(), stream, addr).await
+            }
+            Err(e) => log::error!("Unable to accept a peer connection: {}", e),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+/// Makes `node` the node of the tasks that do not run on a node, such as the web server
+///
+/// Only the first call has an effect
+pub fn set_default(node: std::sync::Arc<Node>) {
+    if DEFAULT_NODE.set(node).is_err() {
+        log::warn!("The default node is already set");
+    }
+}
+
+#[cfg(feature = "server")]
+/// Returns the node running the current task
+///
+/// Outside of any node, the default node is used
+///
+/// # Panics
+///
+/// Panics outside of any node if the default node was never set
+pub fn current() -> std::sync::Arc<Node> {
+    CURRENT_NODE
+        .try_with(|node| node.clone())
+        .unwrap_or_else(|_| {
+            DEFAULT_NODE
+                .get()
+                .cloned()
+                .expect("no node runs the current task and no default node is set")
+        })
+}
+
+#[cfg(feature = "server")]
+/// Spawns a task running on the current node
+pub fn spawn<F>(future: F) -> tokio::task::JoinHandle<F::Output>
+where
+    F: std::future::Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(CURRENT_NODE.scope(current(), future))
+}
+
+#[cfg(feature = "server")]
+/// Handle to a resource of the node running the current task
+pub struct NodeLocal<T: 'static> {
+    resource: fn(&Node) -> &std::sync::Arc<T>,
+}
+
+#[cfg(feature = "server")]
+impl<T> NodeLocal<T> {
+    /// Creates a handle to the resource returned by `resource`
+    pub const fn new(resource: fn(&Node) -> &std::sync::Arc<T>) -> Self {
+        Self { resource }
+    }
+
+    /// Returns the resource of the current node
+    pub fn get(&self) -> std::sync::Arc<T> {
+        (self.resource)(&current()).clone()
+    }
+}
+
+#[cfg(feature = "server")]
+impl<T> NodeLocal<tokio::sync::Mutex<T>> {
+    /// Locks the resource of the current node
+    pub async fn lock(&self) -> tokio::sync::OwnedMutexGuard<T> {
+        self.get().lock_owned().await
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "server")]
+mod tests {
+    use super::testing::{balance, create_user, enqueue, start_sites, wait_until};
+    use super::*;
+    use crate::money::Money;
+
+    #[test]
+    fn nodes_have_their_own_database() {
+        let a = Node::in_memory();
+        let b = Node::in_memory();
+        a.store.create_user("alice").unwrap();
+        assert!(a.store.user_exists("alice").unwrap());
+        assert!(!b.store.user_exists("alice").unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn replicas_end_with_identical_balances() {
+        use crate::control::CriticalCommands;
+
+        // Line topology A - B - C, over TCP with the SQLite backend
+        let nodes = start_sites(None, &[("A", &[]), ("B", &[0]), ("C", &[1])], |_| {}).await;
+        let (a, b, c) = (&nodes[0], &nodes[1], &nodes[2]);
+        create_user(&nodes, a, "alice").await;
+
+        enqueue(
+            b,
+            CriticalCommands::Deposit {
+                name: "alice".to_string(),
+                amount: Money::from_euros(100),
+            },
+        )
+        .await;
+        wait_until(&nodes, "the deposit", || {
+            balance("alice") == Some(Money::from_euros(100))
+        })
+        .await;
+
+        create_user(&nodes, c, "bob").await;
+
+        enqueue(
+            c,
+            CriticalCommands::Transfer {
+                from: "alice".to_string(),
+                to: "bob".to_string(),
+                amount: Money::from_euros(30),
+            },
+        )
+        .await;
+        wait_until(&nodes, "the transfer", || {
+            balance("alice") == Some(Money::from_euros(70))
+                && balance("bob") == Some(Money::from_euros(30))
+        })
+        .await;
+
+        enqueue(
+            a,
+            CriticalCommands::Withdraw {
+                name: "bob".to_string(),
+                amount: Money::from_euros(10),
+            },
+        )
+        .await;
+        wait_until(&nodes, "identical balances", || {
+            balance("alice") == Some(Money::from_euros(70))
+                && balance("bob") == Some(Money::from_euros(20))
+        })
+        .await;
+    }
+}
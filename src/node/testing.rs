@@ -0,0 +1,147 @@
+//! Helpers for the tests running several nodes
+
+use super::{Node, NodeConfig};
+use crate::money::Money;
+use crate::mutex::MutexAlgorithm;
+use crate::network::sim::SimNetwork;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Waits until `check` holds on every node
+pub async fn wait_until<F>(nodes: &[Arc<Node>], what: &str, check: F)
+where
+    F: Fn() -> bool,
+{
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(20);
+    loop {
+        let mut ok = true;
+        for node in nodes {
+            ok &= node.run(async { check() }).await;
+        }
+        if ok {
+            return;
+        }
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "Timed out waiting for {}",
+            what
+        );
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+/// Returns the balance of `name` on the node running the current task, None if the user
+/// does not exist there yet
+pub fn balance(name: &str) -> Option<Money> {
+    let store = super::current().store.clone();
+    match store.user_exists(name) {
+        Ok(true) => store.balance(name).ok(),
+        _ => None,
+    }
+}
+
+/// Queues `cmd` on `node`
+pub async fn enqueue(node: &Arc<Node>, cmd: crate::control::CriticalCommands) {
+    node.run(crate::control::enqueue_critical(&node.store, cmd))
+        .await
+        .unwrap();
+}
+
+/// Creates `name` on `node`, waits until every node has the account
+pub async fn create_user(nodes: &[Arc<Node>], node: &Arc<Node>, name: &str) {
+    enqueue(
+        node,
+        crate::control::CriticalCommands::CreateUser {
+            name: name.to_string(),
+        },
+    )
+    .await;
+    wait_until(nodes, &format!("{} to exist", name), || {
+        balance(name) == Some(Money::from_euros(0))
+    })
+    .await;
+}
+
+/// Address of the `i`-th site of a simulated network
+pub fn sim_addr(i: usize) -> SocketAddr {
+    SocketAddr::from(([10, 0, 0, i as u8 + 1], 10000))
+}
+
+/// Sites named with the indexes of the sites started before them they connect to
+pub type Topology<'a> = &'a [(&'a str, &'a [usize])];
+
+/// A, B and C fully connected
+pub const TRIANGLE: Topology = &[("A", &[]), ("B", &[0]), ("C", &[0, 1])];
+
+/// Starts one node per site of `topology` with `configure` applied to its configuration,
+/// waits until every site is connected to its neighbours
+///
+/// The sites exchange their messages on `sim`, or over TCP with their ledger in SQLite
+/// when `sim` is None.
+pub async fn start_sites<F>(
+    sim: Option<&Arc<SimNetwork>>,
+    topology: Topology<'_>,
+    configure: F,
+) -> Vec<Arc<Node>>
+where
+    F: Fn(&mut NodeConfig),
+{
+    let mut nodes = Vec::new();
+    let mut addrs = Vec::new();
+    for (i, (site_id, peers)) in topology.iter().enumerate() {
+        let peers = peers.iter().map(|&peer| addrs[peer]).collect();
+        let (node, mut config) = match sim {
+            Some(sim) => {
+                let node = Node::in_memory();
+                sim.register(sim_addr(i), node.clone());
+                let mut config = NodeConfig::new(site_id.to_string(), sim_addr(i), peers);
+                config.transport = Some(sim.clone());
+                (node, config)
+            }
+            None => {
+                let store = crate::db::SqliteStore::in_memory().unwrap();
+                let config =
+                    NodeConfig::new(site_id.to_string(), "127.0.0.1:0".parse().unwrap(), peers);
+                (Node::with_store(Arc::new(store)), config)
+            }
+        };
+        configure(&mut config);
+        addrs.push(node.start(config).await.unwrap());
+        nodes.push(node);
+    }
+
+    for (i, node) in nodes.iter().enumerate() {
+        let neighbours = topology[i].1.len()
+            + topology
+                .iter()
+                .filter(|(_, peers)| peers.contains(&i))
+                .count();
+        wait_until(
+            std::slice::from_ref(node),
+            "the neighbours to connect",
+            || {
+                crate::state::LOCAL_APP_STATE
+                    .get()
+                    .try_lock()
+                    .is_ok_and(|s| s.get_nb_connected_neighbours() == neighbours as i64)
+            },
+        )
+        .await;
+    }
+    nodes
+}
+
+/// Starts A, B and C fully connected on `sim`, running `mutex`
+///
+/// The heartbeats are spaced out so that only the messages of the commands are sent
+pub async fn start_triangle(sim: &Arc<SimNetwork>, mutex: MutexAlgorithm) -> Vec<Arc<Node>> {
+    start_sites(Some(sim), TRIANGLE, |config| {
+        config.mutex = mutex;
+        config.failure_detector = crate::failure_detector::FailureDetector::new(
+            Duration::from_secs(3600),
+            crate::failure_detector::DEFAULT_PHI_THRESHOLD,
+        );
+    })
+    .await
+}
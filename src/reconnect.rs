@@ -0,0 +1,200 @@
+//! Reconnection to the peers given on the command line
+//!
+//! `announce` contacts the peers given with `--cli-peers` only once, at startup. The
+//! supervisor keeps watching them afterwards: whenever one of them is not a connected
+//! neighbour anymore, for instance after a reboot, the Discovery handshake is replayed
+//! with an exponential backoff and jitter until it succeeds. Once the peer acknowledged,
+//! the site synchronizes with the network through a snapshot.
+
+#[cfg(feature = "server")]
+/// Delay before the first reconnection attempt, in milliseconds
+pub const INITIAL_BACKOFF_MS: u64 = 500;
+
+#[cfg(feature = "server")]
+/// Maximum delay between two reconnection attempts, in milliseconds
+pub const MAX_BACKOFF_MS: u64 = 30_000;
+
+#[cfg(feature = "server")]
+/// Interval at which a connected peer is checked, in milliseconds
+const WATCH_INTERVAL_MS: u64 = 1000;
+
+#[cfg(feature = "server")]
+/// Exponential backoff with jitter between reconnection attempts
+pub struct Backoff {
+    /// Delay before the first attempt
+    initial: std::time::Duration,
+    /// Maximum delay between two attempts
+    max: std::time::Duration,
+    /// Number of attempts since the last reset
+    attempt: u32,
+}
+
+#[cfg(feature = "server")]
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(
+            std::time::Duration::from_millis(INITIAL_BACKOFF_MS),
+            std::time::Duration::from_millis(MAX_BACKOFF_MS),
+        )
+    }
+}
+
+#[cfg(feature = "server")]
+impl Backoff {
+    /// Creates a backoff starting at `initial` and doubling up to `max`
+    pub fn new(initial: std::time::Duration, max: std::time::Duration) -> Self {
+        Self {
+            initial,
+            max,
+            attempt: 0,
+        }
+    }
+
+    /// Starts again from the initial delay
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    /// Returns the delay before the next attempt
+    ///
+    /// The delay is drawn between half and all of the current ceiling, `jitter` being
+    /// a random value in `[0, 1)`, so that sites do not retry in lockstep
+    pub fn next_delay(&mut self, jitter: f64) -> std::time::Duration {
+        let ceiling = self
+            .initial
+            .saturating_mul(2u32.saturating_pow(self.attempt))
+            .min(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+        ceiling.mul_f64(0.5 + jitter.clamp(0.0, 1.0) / 2.0)
+    }
+}
+
+#[cfg(feature = "server")]
+/// Returns a random value in `[0, 1)`
+fn random_jitter() -> f64 {
+    use ring::rand::SecureRandom;
+
+    let mut bytes = [0u8; 8];
+    if ring::rand::SystemRandom::new().fill(&mut bytes).is_err() {
+        return 0.5;
+    }
+    (u64::from_be_bytes(bytes) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(feature = "server")]
+/// Spawns a task watching each peer given on the command line
+pub fn spawn_reconnection_supervisor() {
+    use crate::state::LOCAL_APP_STATE;
+
+    crate::node::spawn(async {
+        let cli_peers = {
+            let state = LOCAL_APP_STATE.lock().await;
+            state.get_cli_peers_addrs()
+        };
+        for addr in cli_peers {
+            crate::node::spawn(supervise_peer(addr));
+        }
+    });
+}
+
+#[cfg(feature = "server")]
+/// Replays the Discovery handshake with `addr` whenever it is not a connected neighbour
+async fn supervise_peer(addr: std::net::SocketAddr) {
+    use crate::state::LOCAL_APP_STATE;
+    use tokio::time::{Duration, sleep};
+
+    let is_connected = || async {
+        let state = LOCAL_APP_STATE.lock().await;
+        state.get_connected_nei_addr().contains(&addr)
+    };
+
+    let mut backoff = Backoff::default();
+    loop {
+        // Also leaves time to the handshake started by `announce` or by the last attempt
+        sleep(Duration::from_millis(WATCH_INTERVAL_MS)).await;
+        if is_connected().await {
+            backoff.reset();
+            continue;
+        }
+
+        let delay = backoff.next_delay(random_jitter());
+        log::debug!("Peer {} is unreachable, next attempt in {:?}", addr, delay);
+        sleep(delay).await;
+        if is_connected().await {
+            continue;
+        }
+
+        match send_discovery(addr).await {
+            Ok(()) => log::info!(
+                "Peer {} reached again, waiting for its acknowledgment",
+                addr
+            ),
+            Err(e) => log::debug!("Unable to reach peer {}: {}", addr, e),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+/// Sends a Discovery message to a peer, its acknowledgment will trigger a synchronization
+async fn send_discovery(addr: std::net::SocketAddr) -> Result<(), crate::error::PeillutError> {
+    use crate::message::{MessageInfo, NetworkMessageCode};
+    use crate::state::LOCAL_APP_STATE;
+
+    let (local_addr, site_id, clock) = {
+        let mut state = LOCAL_APP_STATE.lock().await;
+        state.add_reconnecting_peer(addr);
+        (
+            state.get_site_addr(),
+            state.get_site_id(),
+            state.get_clock(),
+        )
+    };
+
+    crate::network::send_message(
+        addr,
+        MessageInfo::None,
+        None,
+        NetworkMessageCode::Discovery,
+        local_addr,
+        &site_id,
+        &site_id,
+        local_addr,
+        None,
+        clock,
+    )
+    .await
+}
+
+#[cfg(test)]
+#[cfg(feature = "server")]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_millis(1000));
+        let delays: Vec<_> = (0..6).map(|_| backoff.next_delay(1.0)).collect();
+        assert_eq!(
+            delays,
+            [100, 200, 400, 800, 1000, 1000].map(Duration::from_millis)
+        );
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay(1.0), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn backoff_jitter_stays_within_the_ceiling() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_millis(1000));
+        assert_eq!(backoff.next_delay(0.0), Duration::from_millis(50));
+        assert_eq!(backoff.next_delay(0.5), Duration::from_millis(150));
+
+        for _ in 0..100 {
+            let jitter = random_jitter();
+            assert!((0.0..1.0).contains(&jitter));
+            let delay = backoff.next_delay(jitter);
+            assert!(delay >= Duration::from_millis(200) && delay <= Duration::from_millis(1000));
+        }
+    }
+}
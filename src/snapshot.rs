@@ -17,7 +17,9 @@ pub struct TxSummary {
     /// Destination user of the transaction
     pub to_user: String,
     /// Transaction amount
-    pub amount_in_cent: i64,
+    pub amount: crate::money::Money,
+    /// Hybrid logical clock timestamp of the transaction
+    pub hlc: crate::clock::HybridTimestamp,
 }
 
 #[cfg(feature = "server")]
@@ -26,8 +28,6 @@ pub struct TxSummary {
 pub enum SnapshotMode {
     /// When all snapshots are received, we can create a global snapshot and save the file
     FileMode,
-    /// When all snapshot are received, we can create a global snapshot and send it to the network
-    NetworkMode,
     /// When all snapshot are received, we can create a global snapshot and apply it to the local state
     SyncMode,
 }
@@ -40,7 +40,8 @@ impl From<&crate::db::Transaction> for TxSummary {
             source_node: tx.source_node.clone(),
             from_user: tx.from_user.clone(),
             to_user: tx.to_user.clone(),
-            amount_in_cent: (tx.amount * 100.0) as i64,
+            amount: tx.amount,
+            hlc: tx.hlc,
         }
     }
 }
@@ -80,10 +81,9 @@ impl GlobalSnapshot {
                 if let (Some(&cij), Some(&cjj)) = (
                     si.vector_clock.get(&sj.site_id),
                     sj.vector_clock.get(&sj.site_id),
-                ) {
-                    if cij > cjj {
-                        return false;
-                    }
+                ) && cij > cjj
+                {
+                    return false;
                 }
             }
         }
@@ -138,15 +138,10 @@ impl SnapshotManager {
 
         log::debug!("All local snapshots received, processing snapshot.");
 
-        // In Sync and Network modes we simply aggregate all received
-        // transactions without enforcing snapshot consistency. This prevents
-        // dropping valid transactions when a node joins and requests a global
-        // snapshot or when an intermediate node aggregates snapshots from its
-        // children
-        if matches!(
-            self.mode,
-            SnapshotMode::SyncMode | SnapshotMode::NetworkMode
-        ) {
+        // In Sync mode we simply aggregate all received transactions without
+        // enforcing snapshot consistency. This prevents dropping valid
+        // transactions when a node joins and requests a global snapshot
+        if self.mode == SnapshotMode::SyncMode {
             return Some(self.build_snapshot(&self.received));
         }
 
@@ -159,9 +154,16 @@ impl SnapshotManager {
         // This ensures that we only consider transactions that are consistent across all snapshots.
 
         // Compute the minimum vector clock (vmin) across all received snapshots.
+        // Only the sites taking part in the snapshot are bounded: the entries of departed
+        // sites are missing from the clocks that retired them, see `crate::membership`.
+        let participants: std::collections::HashSet<&String> =
+            self.received.iter().map(|snap| &snap.site_id).collect();
         let mut vmin: std::collections::HashMap<String, i64> = std::collections::HashMap::new();
         for snap in &self.received {
             for (site, &val) in &snap.vector_clock {
+                if !participants.contains(site) {
+                    continue;
+                }
                 // Update vmin for each site to the minimum value observed across all snapshots.
                 vmin.entry(site.clone())
                     .and_modify(|m| *m = (*m).min(val))
@@ -177,11 +179,15 @@ impl SnapshotManager {
             s.vector_clock.insert(s.site_id.clone(), lim);
 
             // Filter the transaction log to only include transactions that are consistent
-            // with the minimum vector clock for their source node.
+            // with the minimum vector clock for their source node. The transactions of a
+            // departed site are all kept, it cannot send any newer one.
             let tx_keep: std::collections::HashSet<_> = s
                 .tx_log
                 .into_iter()
-                .filter(|t| t.lamport_time <= *vmin.get(&t.source_node).unwrap_or(&0))
+                .filter(|t| {
+                    !participants.contains(&t.source_node)
+                        || t.lamport_time <= *vmin.get(&t.source_node).unwrap_or(&0)
+                })
                 .collect();
             s.tx_log = tx_keep;
 
@@ -222,63 +228,138 @@ impl SnapshotManager {
 }
 
 #[cfg(feature = "server")]
-/// Initiates a new snapshot process
-///
-/// Collects the local transaction log and sends snapshot requests to all peers.
-pub async fn start_snapshot(mode: SnapshotMode) -> Result<(), Box<dyn std::error::Error>> {
-    let local_txs = crate::db::get_local_transaction_log()?;
+/// Takes the local snapshot of this site
+pub async fn local_snapshot(
+    store: &std::sync::Arc<dyn crate::store::LedgerStore>,
+) -> Result<crate::message::SnapshotResponse, Box<dyn std::error::Error>> {
+    let local_txs = crate::store::blocking(store, |store| store.transactions()).await?;
     let summaries: Vec<TxSummary> = local_txs.iter().map(|t| t.into()).collect();
 
-    let (site_id, clock, expected) = {
+    let (site_id, clock) = {
         let st = crate::state::LOCAL_APP_STATE.lock().await;
-        // We expect a snapshot from all connected peers
-        // + 1 for self
-        let expected_peers = match mode {
-            SnapshotMode::NetworkMode => {
-                // In NetworkMode, we expect a snapshot from all connected peers except our parent
-                st.get_connected_nei_addr().len()
-            }
-            _ => {
-                // Default case: expect snapshots from all connected peers including ourselves
-                st.get_connected_nei_addr().len() + 1
-            }
-        };
-        (st.get_site_id(), st.get_clock(), expected_peers)
+        (st.get_site_id(), st.get_clock())
     };
 
-    {
-        let mut mgr = LOCAL_SNAPSHOT_MANAGER.lock().await;
-        mgr.expected = expected;
-        mgr.received.clear();
-        mgr.mode = mode.clone();
-        if let Some(gs) = mgr.push(crate::message::SnapshotResponse {
-            site_id: site_id.clone(),
-            clock: clock.clone(),
-            tx_log: summaries.clone(),
-        }) {
-            if mode.clone() == SnapshotMode::FileMode {
-                log::info!(
-                    "Global snapshot ready to be saved at start, hold per site : {:#?}",
-                    gs.missing
-                );
-                mgr.path = crate::snapshot::persist(&gs, site_id.clone())
-                    .await
-                    .unwrap()
-                    .parse()
-                    .ok();
-            } else if mode.clone() == SnapshotMode::SyncMode {
-                log::info!("No other site, synchronization done");
-            } else {
-                log::error!(
-                    "Start snapshot is not supposed to be called when there is no neighbours with network mode"
+    let mut snapshot = crate::message::SnapshotResponse {
+        site_id,
+        clock,
+        tx_log: summaries,
+        signature: None,
+    };
+    // Relays gather it in their echo, the initiator checks that it is ours
+    crate::identity::site_identity()?.sign_snapshot(&mut snapshot)?;
+    Ok(snapshot)
+}
+
+#[cfg(feature = "server")]
+/// Builds the global snapshot from the local snapshots of every site
+///
+/// In FileMode the global snapshot is saved, in SyncMode it is applied to the ledger
+pub async fn complete_snapshot(
+    store: &std::sync::Arc<dyn crate::store::LedgerStore>,
+    mode: SnapshotMode,
+    snapshots: Vec<crate::message::SnapshotResponse>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for snapshot in &snapshots {
+        let public_key = crate::identity::verify_snapshot(snapshot)
+            .map_err(|e| format!("Invalid snapshot of site {}: {}", snapshot.site_id, e))?;
+        crate::identity::check_site_key(store, &snapshot.site_id, public_key)
+            .await
+            .map_err(|e| format!("Invalid snapshot of site {}: {}", snapshot.site_id, e))?;
+    }
+
+    let (site_id, clock) = {
+        let st = crate::state::LOCAL_APP_STATE.lock().await;
+        (st.get_site_id(), st.get_clock())
+    };
+
+    let mut mgr = LOCAL_SNAPSHOT_MANAGER.lock().await;
+    mgr.expected = snapshots.len();
+    mgr.received.clear();
+    mgr.mode = mode.clone();
+
+    let mut global = None;
+    for snapshot in snapshots {
+        global = mgr.push(snapshot);
+    }
+    let gs = global.ok_or("No local snapshot to build the global snapshot from")?;
+
+    match mode {
+        SnapshotMode::FileMode => {
+            log::info!(
+                "Global snapshot ready to save, hold per site : {:#?}",
+                gs.missing
+            );
+            mgr.path = persist(&gs, site_id).await?.parse().ok();
+        }
+        SnapshotMode::SyncMode => {
+            log::info!(
+                "Global snapshot ready to be synced, hold per site : {:#?}",
+                gs.missing
+            );
+            let vector_clock = clock.get_vector_clock_map().clone();
+            let skipped = crate::store::blocking(store, move |store| {
+                store.apply_snapshot(&gs, &vector_clock)
+            })
+            .await?;
+            if !skipped.is_empty() {
+                log::warn!(
+                    "{} transactions of the global snapshot were not recorded",
+                    skipped.len()
                 );
             }
         }
     }
-
     Ok(())
 }
 
+#[cfg(feature = "server")]
+/// Wave collecting the local snapshot of every site
+pub struct SnapshotWave;
+
+#[cfg(feature = "server")]
+impl crate::network::wave::WaveProtocol for SnapshotWave {
+    fn code(&self) -> crate::message::NetworkMessageCode {
+        crate::message::NetworkMessageCode::SnapshotRequest
+    }
+
+    fn echo_code(&self) -> crate::message::NetworkMessageCode {
+        crate::message::NetworkMessageCode::SnapshotResponse
+    }
+
+    fn visit(
+        &self,
+        store: std::sync::Arc<dyn crate::store::LedgerStore>,
+        _message: crate::message::Message,
+    ) -> crate::network::wave::VisitFuture {
+        Box::pin(async move {
+            Ok(crate::message::MessageInfo::SnapshotResponse(vec![
+                local_snapshot(&store).await?,
+            ]))
+        })
+    }
+
+    /// The local snapshots of the subtrees are gathered, the initiator builds the global snapshot
+    fn reduce(
+        &self,
+        acc: crate::message::MessageInfo,
+        child: crate::message::MessageInfo,
+    ) -> crate::message::MessageInfo {
+        use crate::message::MessageInfo;
+
+        match (acc, child) {
+            (MessageInfo::SnapshotResponse(mut acc), MessageInfo::SnapshotResponse(child)) => {
+                acc.extend(child);
+                MessageInfo::SnapshotResponse(acc)
+            }
+            (acc, child) => {
+                log::error!("Unexpected snapshot echo: {:?}", child);
+                acc
+            }
+        }
+    }
+}
+
 #[cfg(feature = "server")]
 /// Persists a global snapshot to disk
 ///
@@ -298,10 +379,9 @@ pub async fn persist(snapshot: &GlobalSnapshot, site_id: String) -> std::io::Res
 }
 
 #[cfg(feature = "server")]
-lazy_static::lazy_static! {
-    pub static ref LOCAL_SNAPSHOT_MANAGER: tokio::sync::Mutex<SnapshotManager> =
-        tokio::sync::Mutex::new(SnapshotManager::new(0));
-}
+/// Snapshot manager of the node running the current task
+pub static LOCAL_SNAPSHOT_MANAGER: crate::node::NodeLocal<tokio::sync::Mutex<SnapshotManager>> =
+    crate::node::NodeLocal::new(|node| &node.snapshots);
 
 #[cfg(test)]
 #[cfg(feature = "server")]
@@ -313,8 +393,8 @@ mod tests {
         for (id, v) in pairs {
             m.insert((*id).to_string(), *v);
         }
-        let c = crate::clock::Clock::new_with_values(0, m);
-        c
+
+        crate::clock::Clock::new_with_values(0, m)
     }
 
     fn resp(site: &str, vc: &[(&str, i64)], txs: &[TxSummary]) -> crate::message::SnapshotResponse {
@@ -322,6 +402,7 @@ mod tests {
             site_id: site.to_string(),
             clock: mk_clock(vc),
             tx_log: txs.to_vec(),
+            signature: None,
         }
     }
 
@@ -363,9 +444,10 @@ mod tests {
             source_node: "A".into(),
             from_user: "user1".into(),
             to_user: "user2".into(),
-            amount_in_cent: 100,
+            amount: crate::money::Money::from_cents(100),
+            hlc: Default::default(),
         };
-        let r1 = resp("A", &[("A", 1)], &[tx.clone()]);
+        let r1 = resp("A", &[("A", 1)], std::slice::from_ref(&tx));
         assert!(mgr.push(r1).is_none());
         assert_eq!(mgr.received.len(), 1);
     }
@@ -393,17 +475,19 @@ mod tests {
             source_node: "A".into(),
             from_user: "user1".into(),
             to_user: "user2".into(),
-            amount_in_cent: 100,
+            amount: crate::money::Money::from_cents(100),
+            hlc: Default::default(),
         };
         let t2 = TxSummary {
             lamport_time: 11,
             source_node: "B".into(),
             from_user: "user3".into(),
             to_user: "user4".into(),
-            amount_in_cent: 200,
+            amount: crate::money::Money::from_cents(200),
+            hlc: Default::default(),
         };
 
-        let r1 = resp("A", &[("A", 1)], &[t1.clone()]);
+        let r1 = resp("A", &[("A", 1)], std::slice::from_ref(&t1));
         let r2 = resp("B", &[("B", 1)], &[t1.clone(), t2.clone()]);
 
         let _ = mgr.push(r1);
@@ -441,21 +525,24 @@ mod tests {
             source_node: "A".into(),
             from_user: "user1".into(),
             to_user: "user2".into(),
-            amount_in_cent: 100,
+            amount: crate::money::Money::from_cents(100),
+            hlc: Default::default(),
         };
         let t3 = TxSummary {
             lamport_time: 3,
             source_node: "A".into(),
             from_user: "user1".into(),
             to_user: "user2".into(),
-            amount_in_cent: 300,
+            amount: crate::money::Money::from_cents(300),
+            hlc: Default::default(),
         };
         let t5 = TxSummary {
             lamport_time: 5,
             source_node: "A".into(),
             from_user: "user1".into(),
             to_user: "user2".into(),
-            amount_in_cent: 500,
+            amount: crate::money::Money::from_cents(500),
+            hlc: Default::default(),
         };
 
         let r_a = resp(
@@ -473,6 +560,30 @@ mod tests {
         assert!(!snap.all_transactions.contains(&t5));
     }
 
+    #[test]
+    fn backtrack_keeps_transactions_of_retired_sites() {
+        let mut mgr = SnapshotManager::new(2);
+
+        // C left the network, A and B retired its entry
+        let from_c = TxSummary {
+            lamport_time: 4,
+            source_node: "C".into(),
+            from_user: "user1".into(),
+            to_user: "user2".into(),
+            amount: crate::money::Money::from_cents(400),
+            hlc: Default::default(),
+        };
+
+        let r_a = resp("A", &[("A", 5), ("B", 2)], std::slice::from_ref(&from_c));
+        let r_b = resp("B", &[("A", 2), ("B", 1)], std::slice::from_ref(&from_c));
+
+        let _ = mgr.push(r_a);
+        let snap = mgr.push(r_b).expect("snapshot after back-track");
+
+        assert!(snap.all_transactions.contains(&from_c));
+        assert!(snap.missing.is_empty());
+    }
+
     #[test]
     fn union_is_deduplicated() {
         let mut mgr = SnapshotManager::new(2);
@@ -481,11 +592,12 @@ mod tests {
             source_node: "A".into(),
             from_user: "user1".into(),
             to_user: "user2".into(),
-            amount_in_cent: 700,
+            amount: crate::money::Money::from_cents(700),
+            hlc: Default::default(),
         };
 
-        let r1 = resp("A", &[("A", 1)], &[tx.clone()]);
-        let r2 = resp("B", &[("B", 1)], &[tx.clone()]);
+        let r1 = resp("A", &[("A", 1)], std::slice::from_ref(&tx));
+        let r2 = resp("B", &[("B", 1)], std::slice::from_ref(&tx));
 
         let _ = mgr.push(r1);
         let gs = mgr.push(r2).expect("snapshot ready");
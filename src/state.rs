@@ -13,10 +13,12 @@ pub enum MutexTag {
 }
 
 #[cfg(feature = "server")]
-#[derive(serde::Serialize, serde::Deserialize, Copy, Clone, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub struct MutexStamp {
     pub tag: MutexTag,
     pub date: i64,
+    /// Accounts of the request
+    pub scope: crate::mutex::LockScope,
 }
 
 #[cfg(feature = "server")]
@@ -37,25 +39,47 @@ pub struct AppState {
     sync_needed: bool,
     /// Number of attended neighbours at launch, for the discovery phase
     nb_first_attended_neighbours: i64,
+    /// Failure detector fed by the messages received from the neighbours
+    failure_detector: crate::failure_detector::FailureDetector,
+    /// CLI peers contacted again by the reconnection supervisor, waiting for their acknowledgment
+    reconnecting_peers: std::collections::HashSet<std::net::SocketAddr>,
 
     pub site_ids_to_adr: std::collections::HashMap<std::net::SocketAddr, String>,
 
+    // --- Identity ---
+    /// Public key of each site, pinned the first time a signed message is seen from it
+    site_keys: std::collections::HashMap<String, Vec<u8>>,
+
     // --- Message Diffusion Info for Transaction ---
-    /// Adress of the parent (deg(1) neighbour for this site) for a specific wave from initiator id
-    pub parent_addr_for_transaction_wave: std::collections::HashMap<String, std::net::SocketAddr>,
-    /// Number of response expected from our direct neighbours (deg(1) neighbours for this site) = nb of connected neighbours - 1 (parent) for a specific wave initiator id
-    pub attended_neighbours_nb_for_transaction_wave: std::collections::HashMap<String, i64>,
+    /// Sequence number of the next wave started by this site
+    wave_seq: u64,
+    /// Parent and expected echoes of each wave going through this site
+    pub waves: crate::network::wave::WaveTable,
+    /// Transactions received before their causal predecessors
+    pub causal: crate::causal::CausalBuffer,
 
     // --- Logical Clocks ---
     /// Logical clock implementation for distributed synchronization
     clocks: crate::clock::Clock,
+    /// Publishes the clock each time it is updated, the node saves it in its ledger
+    clock_saves: tokio::sync::watch::Sender<crate::clock::Clock>,
 
     // GLobal mutex
+    /// Mutual exclusion algorithm of the site
+    mutex: &'static dyn crate::mutex::MutualExclusion,
+    /// Token of the token based mutual exclusion, and the requests for it
+    pub token: crate::mutex::TokenState,
     pub global_mutex_fifo: std::collections::HashMap<String, MutexStamp>,
     pub waiting_sc: bool,
     pub in_sc: bool,
+    /// Accounts of the critical section asked for or granted
+    pub sc_scope: crate::mutex::LockScope,
+    /// Whether every site recorded our pending request, with the `fifo` algorithm
+    pub sc_request_recorded: bool,
+    /// Leases of the requests in the FIFO, see `crate::lease`
+    pub leases: crate::lease::Leases,
     pub notify_sc: std::sync::Arc<tokio::sync::Notify>,
-    pub pending_commands: std::collections::VecDeque<crate::control::CriticalCommands>,
+    pub pending_commands: std::collections::VecDeque<crate::control::PendingCommand>,
 }
 
 #[cfg(feature = "server")]
@@ -67,8 +91,6 @@ impl AppState {
         local_addr: std::net::SocketAddr,
     ) -> Self {
         let clocks = crate::clock::Clock::new();
-        let parent_addr = std::collections::HashMap::new();
-        let nb_of_attended_neighbors = std::collections::HashMap::new();
         let in_use_neighbors = Vec::new();
         let sockets_for_connected_peers = std::collections::HashMap::new();
         let gm = std::collections::HashMap::new();
@@ -80,18 +102,33 @@ impl AppState {
             cli_peer_addrs: peer_addrs,
             neighbours_socket: sockets_for_connected_peers,
             site_addr: local_addr,
-            parent_addr_for_transaction_wave: parent_addr,
-            attended_neighbours_nb_for_transaction_wave: nb_of_attended_neighbors,
+            // Starting from the current time, a restarted site does not reuse the ids
+            // of the waves it started before
+            wave_seq: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_micros() as u64)
+                .unwrap_or(0),
+            waves: crate::network::wave::WaveTable::default(),
+            causal: crate::causal::CausalBuffer::default(),
             connected_neighbours_addrs: in_use_neighbors,
+            clock_saves: tokio::sync::watch::Sender::new(clocks.clone()),
             clocks,
             sync_needed: false,
             nb_first_attended_neighbours: 0,
+            failure_detector: crate::failure_detector::FailureDetector::default(),
+            reconnecting_peers: std::collections::HashSet::new(),
+            mutex: crate::mutex::MutexAlgorithm::default().implementation(),
+            token: crate::mutex::TokenState::default(),
             global_mutex_fifo: gm,
             waiting_sc,
             in_sc,
+            sc_scope: crate::mutex::LockScope::default(),
+            sc_request_recorded: false,
+            leases: crate::lease::Leases::default(),
             notify_sc: std::sync::Arc::new(tokio::sync::Notify::new()),
             pending_commands: std::collections::VecDeque::new(),
             site_ids_to_adr: std::collections::HashMap::new(),
+            site_keys: std::collections::HashMap::new(),
         }
     }
 
@@ -110,9 +147,22 @@ impl AppState {
     }
 
     pub fn add_site_id(&mut self, site_id: String, addr: std::net::SocketAddr) {
-        if !self.site_ids_to_adr.contains_key(&addr) {
-            self.site_ids_to_adr.insert(addr, site_id);
-        }
+        self.site_ids_to_adr.entry(addr).or_insert(site_id);
+    }
+
+    /// Sets the mutual exclusion algorithm at initialization
+    pub fn init_mutex(&mut self, algorithm: crate::mutex::MutexAlgorithm) {
+        self.mutex = algorithm.implementation();
+    }
+
+    /// Sets the duration of the leases of the mutex requests at initialization
+    pub fn init_mutex_lease(&mut self, duration: std::time::Duration) {
+        self.leases = crate::lease::Leases::new(duration);
+    }
+
+    /// Returns the clocks published each time the clock is updated
+    pub fn watch_clock(&self) -> tokio::sync::watch::Receiver<crate::clock::Clock> {
+        self.clock_saves.subscribe()
     }
 
     /// Sets the site ID at initialization
@@ -120,6 +170,36 @@ impl AppState {
         self.site_id = site_id;
     }
 
+    /// Sets the public keys pinned by the site at initialization, its own included
+    pub fn init_site_keys(&mut self, site_keys: std::collections::HashMap<String, Vec<u8>>) {
+        self.site_keys = site_keys;
+    }
+
+    /// Checks that `public_key` belongs to `site_id`
+    ///
+    /// The first key seen for a site is pinned, any other key is refused afterwards.
+    /// Returns whether the key was pinned by this call, see `crate::identity::check_site_key`
+    pub fn check_site_key(
+        &mut self,
+        site_id: &str,
+        public_key: &[u8],
+    ) -> Result<bool, crate::identity::SignatureError> {
+        match self.site_keys.get(site_id) {
+            Some(known) if known.as_slice() != public_key => {
+                Err(crate::identity::SignatureError::KeyMismatch {
+                    site_id: site_id.to_string(),
+                })
+            }
+            Some(_) => Ok(false),
+            None => {
+                log::info!("Pinning the public key of site {}", site_id);
+                self.site_keys
+                    .insert(site_id.to_string(), public_key.to_vec());
+                Ok(true)
+            }
+        }
+    }
+
     /// Sets the site address at initialization
     pub fn init_site_addr(&mut self, site_addr: std::net::SocketAddr) {
         self.site_addr = site_addr;
@@ -159,10 +239,49 @@ impl AppState {
         self.nb_first_attended_neighbours
     }
 
-    /// Initialize the parent of the current site as self for the wave protocol
-    pub fn init_parent_addr_for_transaction_wave(&mut self) {
-        self.parent_addr_for_transaction_wave
-            .insert(self.site_id.clone(), self.site_addr.clone());
+    /// Sets the failure detector at initialization
+    pub fn init_failure_detector(&mut self, detector: crate::failure_detector::FailureDetector) {
+        self.failure_detector = detector;
+    }
+
+    /// Returns the interval between two heartbeats sent to the neighbours
+    pub fn get_heartbeat_interval(&self) -> std::time::Duration {
+        self.failure_detector.get_heartbeat_interval()
+    }
+
+    /// Records a sign of life from a neighbour
+    pub fn record_heartbeat(&mut self, addr: std::net::SocketAddr) {
+        if !self.connected_neighbours_addrs.contains(&addr) {
+            return;
+        }
+        if self
+            .failure_detector
+            .heartbeat(addr, tokio::time::Instant::now())
+        {
+            log::info!("Neighbour {} is not suspected anymore", addr);
+        }
+    }
+
+    /// Returns the neighbours newly suspected to have failed
+    pub fn take_suspected_neighbours(&mut self) -> Vec<std::net::SocketAddr> {
+        self.failure_detector
+            .take_suspects(tokio::time::Instant::now())
+    }
+
+    /// Returns the suspicion state of the neighbours
+    pub fn get_neighbours_health(&self) -> Vec<crate::failure_detector::PeerHealth> {
+        self.failure_detector
+            .get_health(tokio::time::Instant::now())
+    }
+
+    /// Marks a CLI peer as contacted again by the reconnection supervisor
+    pub fn add_reconnecting_peer(&mut self, addr: std::net::SocketAddr) {
+        self.reconnecting_peers.insert(addr);
+    }
+
+    /// Returns true, once, if `addr` acknowledged after a reconnection attempt
+    pub fn take_reconnected_peer(&mut self, addr: std::net::SocketAddr) -> bool {
+        self.reconnecting_peers.remove(&addr)
     }
 
     /// Adds a new peer to the network and updates the logical clock
@@ -196,25 +315,7 @@ impl AppState {
             let mut net_manager = crate::network::NETWORK_MANAGER.lock().await;
             net_manager.remove_connection(&addr_to_remove);
         }
-
-        if let Some(pos) = self
-            .connected_neighbours_addrs
-            .iter()
-            .position(|x| *x == addr_to_remove)
-        {
-            self.connected_neighbours_addrs.remove(pos);
-            let site_id = self.site_ids_to_adr.get(&addr_to_remove);
-            if let Some(site_id) = site_id {
-                self.global_mutex_fifo.remove(site_id);
-                self.attended_neighbours_nb_for_transaction_wave
-                    .remove(site_id);
-                self.parent_addr_for_transaction_wave.remove(site_id);
-                self.site_ids_to_adr.remove(&addr_to_remove);
-            }
-
-            // We can keep the clock value for the site we want to remove
-            // if the site re-appears, it will be updated with the new clock value
-        }
+        self.forget_neighbour(addr_to_remove);
     }
 
     /// Removes a peer from the network with only an address
@@ -226,131 +327,82 @@ impl AppState {
     /// If a site is closed properly, it will send a disconnect message to all its neighbours
     pub async fn remove_peer_from_socket_closed(&mut self, socket_to_remove: std::net::SocketAddr) {
         // Find the site adress based on the socket
-        let Some(addr_to_remove) = self.neighbours_socket.get(&socket_to_remove) else {
+        let Some(addr_to_remove) = self.neighbours_socket.get(&socket_to_remove).copied() else {
             log::debug!("Site not found in the neighbours socket");
             return;
         };
+        self.forget_neighbour(addr_to_remove);
+    }
+
+    /// Drops a neighbour that left from the neighbours, the mutex and the waves
+    fn forget_neighbour(&mut self, addr_to_remove: std::net::SocketAddr) {
+        self.failure_detector.forget(&addr_to_remove);
 
         if let Some(pos) = self
             .connected_neighbours_addrs
             .iter()
-            .position(|x| *x == *addr_to_remove)
+            .position(|x| *x == addr_to_remove)
         {
             self.connected_neighbours_addrs.remove(pos);
-            let site_id = self.site_ids_to_adr.get(&addr_to_remove);
-            if let Some(site_id) = site_id {
-                self.global_mutex_fifo.remove(site_id);
-                self.attended_neighbours_nb_for_transaction_wave
-                    .remove(site_id);
-                self.parent_addr_for_transaction_wave.remove(site_id);
-                self.site_ids_to_adr.remove(&addr_to_remove);
+            if let Some(site_id) = self.site_ids_to_adr.remove(&addr_to_remove) {
+                self.global_mutex_fifo.remove(&site_id);
+            }
+
+            // The waves stop waiting for its answer, those it completes are echoed
+            let echoes = self.waves.forget_neighbour(addr_to_remove);
+            if !echoes.is_empty() {
+                crate::node::spawn(crate::network::wave::send_echoes(echoes));
             }
 
-            // We can keep the clock value for the site we want to remove
-            // if the site re-appears, it will be updated with the new clock value
+            // The clock entry is kept until every live site acknowledged the departure,
+            // see `crate::membership`
         }
     }
 
-    /// Returns the local address as a string
-    pub fn get_site_addr(&self) -> std::net::SocketAddr {
-        self.site_addr.clone()
+    /// Returns true if `site_id` is one of the connected deg(1) neighbours
+    pub fn is_connected_to(&self, site_id: &str) -> bool {
+        self.site_ids_to_adr
+            .iter()
+            .any(|(addr, id)| id == site_id && self.connected_neighbours_addrs.contains(addr))
     }
 
-    pub async fn acquire_mutex(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        use crate::message::{Message, MessageInfo, NetworkMessageCode};
-        use crate::network::diffuse_message_without_lock;
-
-        self.update_clock(None).await;
-
-        self.global_mutex_fifo.insert(
-            self.site_id.clone(),
-            MutexStamp {
-                tag: MutexTag::Request,
-                date: self.clocks.get_lamport().clone(),
-            },
-        );
-
-        let msg = Message {
-            sender_id: self.site_id.clone(),
-            sender_addr: self.site_addr,
-            message_initiator_id: self.site_id.clone(),
-            message_initiator_addr: self.site_addr,
-            clock: self.clocks.clone(),
-            command: None,
-            info: MessageInfo::AcquireMutex(crate::message::AcquireMutexPayload),
-            code: NetworkMessageCode::AcquireMutex,
-        };
+    /// Drops the clock entry of a site every live site knows has left
+    pub async fn retire_site(&mut self, site_id: &str, last: i64) {
+        self.clocks.retire(site_id, last);
+        self.site_ids_to_adr.retain(|_, id| id != site_id);
+        self.save_local_state().await;
+    }
 
-        let should_diffuse = {
-            // initialisation des paramètres avant la diffusion d'un message
-            self.set_parent_addr(self.site_id.to_string(), self.site_addr);
-            self.set_nb_nei_for_wave(self.site_id.to_string(), self.get_nb_connected_neighbours());
-            self.get_nb_connected_neighbours() > 0
-        };
+    /// Takes back the clock entry of a retired site which joined again
+    pub fn readmit_site(&mut self, site_id: &str) {
+        self.clocks.readmit(site_id);
+    }
 
-        if should_diffuse {
-            self.notify_sc.notify_waiters();
-            self.in_sc = false;
-            self.waiting_sc = true;
-            log::info!("Début de la diffusion d'une acquisition de mutex");
-            diffuse_message_without_lock(
-                &msg,
-                self.get_site_addr(),
-                self.get_site_id().as_str(),
-                self.get_connected_nei_addr(),
-                self.get_parent_addr_for_wave(msg.message_initiator_id.clone()),
-            )
-            .await?;
-        } else {
-            log::info!("Il n'y a pas de voisins, on prends la section critique");
-            self.in_sc = true;
-            self.waiting_sc = false;
-            self.notify_sc.notify_waiters();
-        }
+    /// Returns the local address as a string
+    pub fn get_site_addr(&self) -> std::net::SocketAddr {
+        self.site_addr
+    }
 
-        Ok(())
+    /// Asks for the global mutex on the accounts of `scope`, see `crate::mutex`
+    pub async fn acquire_mutex(
+        &mut self,
+        scope: crate::mutex::LockScope,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.sc_scope = scope;
+        let mutex = self.mutex;
+        mutex.acquire(self).await
     }
 
+    /// Leaves the global mutex
     pub async fn release_mutex(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        use crate::message::{Message, MessageInfo, NetworkMessageCode};
-        use crate::network::diffuse_message_without_lock;
-
-        self.update_clock(None).await;
-
-        let msg = Message {
-            sender_id: self.site_id.clone(),
-            sender_addr: self.site_addr,
-            message_initiator_id: self.site_id.clone(),
-            message_initiator_addr: self.site_addr,
-            clock: self.clocks.clone(),
-            command: None,
-            info: MessageInfo::ReleaseMutex(crate::message::ReleaseMutexPayload),
-            code: NetworkMessageCode::ReleaseGlobalMutex,
-        };
-
-        self.global_mutex_fifo.remove(&self.site_id);
-        self.in_sc = false;
-        self.waiting_sc = false;
-
-        let should_diffuse = {
-            // initialisation des paramètres avant la diffusion d'un message
-            self.set_parent_addr(self.site_id.to_string(), self.site_addr);
-            self.set_nb_nei_for_wave(self.site_id.to_string(), self.get_nb_connected_neighbours());
-            self.get_nb_connected_neighbours() > 0
-        };
+        let mutex = self.mutex;
+        mutex.release(self).await
+    }
 
-        if should_diffuse {
-            log::info!("Début de la diffusion d'un relachement de mutex");
-            diffuse_message_without_lock(
-                &msg,
-                self.get_site_addr(),
-                self.get_site_id().as_str(),
-                self.get_connected_nei_addr(),
-                self.get_parent_addr_for_wave(msg.message_initiator_id.clone()),
-            )
-            .await?;
-        }
-        Ok(())
+    /// Hands over what the other sites need for the global mutex before leaving the network
+    pub async fn leave_mutex(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let mutex = self.mutex;
+        mutex.leave(self).await
     }
 
     pub fn try_enter_sc(&mut self) {
@@ -363,19 +415,26 @@ impl AppState {
         // c'est à dire que tout le monde ait répondu ACK pour appeller cette fonction
         // sinon on va entrer en section critique à un moment sans qu'un des peers ait noté notre demande
         let my_stamp = match self.global_mutex_fifo.get(&self.site_id) {
-            Some(s) => *s,
+            Some(s) => s.clone(),
             None => return, // No local request found
         };
+        // The other sites may have evicted our request already
+        if self.leases.lease_lost(tokio::time::Instant::now()) {
+            return;
+        }
         let me = (my_stamp.date, self.site_id.clone());
 
         // ici on compara les stamps des autres demandes, est-ce qu'on est le suivant dans la FIFO ?
         // si oui on peut entrer en section critique
+        // Only the requests sharing one of our accounts are in our way
         let ok = self.global_mutex_fifo.iter().all(|(id, stamp)| {
             if id == &self.site_id {
                 true
             } else {
                 match stamp.tag {
-                    MutexTag::Request => me <= (stamp.date, id.clone()),
+                    MutexTag::Request if stamp.scope.conflicts_with(&my_stamp.scope) => {
+                        me <= (stamp.date, id.clone())
+                    }
                     _ => true,
                 }
             }
@@ -385,7 +444,8 @@ impl AppState {
             self.waiting_sc = false;
             self.in_sc = true;
             // All other sites are notified that we are in critical section
-            self.notify_sc.notify_waiters(); // notifies worker to execute pending commands
+            // notifies worker to execute pending commands, the permit is kept if it is busy
+            self.notify_sc.notify_one();
             // We remove obsolete Releases
             self.global_mutex_fifo
                 .retain(|_, s| s.tag != MutexTag::Release);
@@ -444,36 +504,25 @@ impl AppState {
         self.clocks.clone()
     }
 
-    /// Set the number of attended neighbors for the wave from initiator_id
-    pub fn set_nb_nei_for_wave(&mut self, initiator_id: String, n: i64) {
-        self.attended_neighbours_nb_for_transaction_wave
-            .insert(initiator_id, n);
+    /// Returns the id of a new wave started by this site
+    pub fn next_wave_id(&mut self) -> crate::message::WaveId {
+        self.wave_seq += 1;
+        crate::message::WaveId {
+            initiator: self.site_id.clone(),
+            seq: self.wave_seq,
+        }
     }
 
-    /// Get the list of attended neighbors for the wave from initiator_id
+    /// Get the parent address of every ongoing wave
     pub fn get_parent_for_wave_map(
         &self,
-    ) -> std::collections::HashMap<String, std::net::SocketAddr> {
-        self.parent_addr_for_transaction_wave.clone()
+    ) -> std::collections::HashMap<crate::message::WaveId, std::net::SocketAddr> {
+        self.waves.parents()
     }
 
-    /// Get the list of attended neighbors for the wave from initiator_id
-    pub fn get_nb_nei_for_wave(&self) -> std::collections::HashMap<String, i64> {
-        self.attended_neighbours_nb_for_transaction_wave.clone()
-    }
-
-    /// Get the parent (neighbour deg(1)) address for the wave from initiator_id
-    pub fn get_parent_addr_for_wave(&self, initiator_id: String) -> std::net::SocketAddr {
-        self.parent_addr_for_transaction_wave
-            .get(&initiator_id)
-            .copied()
-            .unwrap_or("0.0.0.0:0".parse().unwrap())
-    }
-
-    /// Set the parent (neighbour deg(1)) address for the wave from initiator_id
-    pub fn set_parent_addr(&mut self, initiator_id: String, peer_adr: std::net::SocketAddr) {
-        self.parent_addr_for_transaction_wave
-            .insert(initiator_id, peer_adr);
+    /// Get the number of attended neighbors of every ongoing wave
+    pub fn get_nb_nei_for_wave(&self) -> std::collections::HashMap<crate::message::WaveId, i64> {
+        self.waves.remaining()
     }
 
     /// Returns the number of deg(1) neighbors connected
@@ -490,9 +539,10 @@ impl AppState {
         self.save_local_state().await;
     }
 
+    /// Publishes the clock to be saved, see [`AppState::watch_clock`]
     pub async fn save_local_state(&self) {
         // this is likely to be called whenever the clocks are updated
-        let _ = crate::db::update_local_state(&self.site_id, self.clocks.clone());
+        self.clock_saves.send_replace(self.clocks.clone());
     }
 
     /// For tokyo test, set manually the number of connected neighbours
@@ -507,16 +557,10 @@ impl AppState {
     }
 }
 
-// Singleton
 #[cfg(feature = "server")]
-lazy_static::lazy_static! {
-    pub static ref LOCAL_APP_STATE: std::sync::Arc<tokio::sync::Mutex<AppState>> =
-        std::sync::Arc::new(tokio::sync::Mutex::new(AppState::new(
-            "".to_string(), // empty site id at start
-            Vec::new(),
-            "0.0.0.0:0".parse().unwrap(),
-        )));
-}
+/// State of the node running the current task
+pub static LOCAL_APP_STATE: crate::node::NodeLocal<tokio::sync::Mutex<AppState>> =
+    crate::node::NodeLocal::new(|node| &node.state);
 
 #[cfg(test)]
 #[cfg(feature = "server")]
@@ -539,4 +583,74 @@ mod tests {
         assert_eq!(shared_state.cli_peer_addrs, peer_addrs);
         assert_eq!(shared_state.clocks.get_vector_clock_map().len(), 0); // Initially empty
     }
+
+    #[test]
+    fn test_site_key_is_pinned() {
+        let identity = crate::identity::SiteIdentity::generate().unwrap();
+        let own_key = identity.public_key();
+        let mut state = AppState::new(
+            "A".to_string(),
+            Vec::new(),
+            "127.0.0.1:8080".parse().unwrap(),
+        );
+        state.init_site_keys([("A".to_string(), own_key.clone())].into());
+
+        // Nobody else can speak for our own site
+        assert!(!state.check_site_key("A", &own_key).unwrap());
+        assert!(state.check_site_key("A", &[1; 32]).is_err());
+
+        // The first key seen for a remote site is kept
+        assert!(state.check_site_key("B", &[2; 32]).unwrap());
+        assert!(!state.check_site_key("B", &[2; 32]).unwrap());
+        assert!(state.check_site_key("B", &[3; 32]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_waves_stop_waiting_for_a_departed_site() {
+        // The departed site is dropped from the network of a node
+        crate::node::Node::in_memory()
+            .run(async {
+                let mut state = AppState::new(
+                    "A".to_string(),
+                    Vec::new(),
+                    "127.0.0.1:8080".parse().unwrap(),
+                );
+                let b_addr: std::net::SocketAddr = "127.0.0.1:8081".parse().unwrap();
+                state.add_connected_neighbour(b_addr);
+                state.add_site_id("B".to_string(), b_addr);
+
+                let own = state.next_wave_id();
+                assert_eq!(own.initiator, "A");
+                assert_ne!(own, state.next_wave_id());
+                let (mut done, _) =
+                    state
+                        .waves
+                        .start(own.clone(), state.get_site_addr(), &[b_addr]);
+                let from_b = crate::message::WaveId {
+                    initiator: "B".to_string(),
+                    seq: 1,
+                };
+                let message = crate::message::Message {
+                    sender_id: "B".to_string(),
+                    sender_addr: b_addr,
+                    message_initiator_id: "B".to_string(),
+                    clock: crate::clock::Clock::new(),
+                    command: None,
+                    info: crate::message::MessageInfo::None,
+                    code: crate::message::NetworkMessageCode::SnapshotRequest,
+                    message_initiator_addr: b_addr,
+                    signature: None,
+                    wave_id: Some(from_b.clone()),
+                    causal_deps: None,
+                };
+                assert!(state.waves.explore(from_b.clone(), &message, &[b_addr]));
+                assert_eq!(state.get_parent_for_wave_map()[&from_b], b_addr);
+
+                // B dies in the middle of both waves
+                state.remove_peer(b_addr).await;
+                assert!(state.get_nb_nei_for_wave().is_empty());
+                assert!(done.try_recv().is_ok());
+            })
+            .await
+    }
 }
@@ -0,0 +1,1049 @@
+//! Storage of the ledger
+//!
+//! A [`LedgerStore`] keeps the accounts, the transactions and the local state of a site.
+//! Backends only store and read, the rules of the ledger (a payment needs the funds, a
+//! transaction is refunded once...) are the provided methods of the trait, so that every
+//! backend enforces them the same way. Each of these methods writes as one group: the
+//! other threads never see it half done. The store belongs to the [`crate::node::Node`] and
+//! is given to the code using it.
+//!
+//! [`crate::db::SqliteStore`] keeps the ledger in a SQLite database, [`MemoryStore`] keeps
+//! it in memory for the tests.
+//!
+//! The methods of a store block the calling thread. The async code calls them through
+//! [`blocking`], off the runtime threads.
+
+#[cfg(feature = "server")]
+/// Storage of the ledger of a site
+pub trait LedgerStore: Send + Sync {
+    /// Where the ledger is kept, shown to the user
+    fn location(&self) -> String;
+
+    /// Starts a group of writes kept or undone together, see `atomically`
+    ///
+    /// Groups can be nested, each `begin` is closed by a `commit` or a `rollback`
+    fn begin(&self) -> Result<(), crate::error::PeillutError>;
+
+    /// Keeps the writes of the innermost group
+    fn commit(&self) -> Result<(), crate::error::PeillutError>;
+
+    /// Undoes the writes of the innermost group
+    fn rollback(&self) -> Result<(), crate::error::PeillutError>;
+
+    /// Whether an account has this name
+    fn user_exists(&self, name: &str) -> Result<bool, crate::error::PeillutError>;
+
+    /// Adds an account without money, the name is not checked
+    fn insert_user(&self, name: &str) -> Result<(), crate::error::PeillutError>;
+
+    /// Removes an account, its transactions are kept
+    fn remove_user(&self, name: &str) -> Result<(), crate::error::PeillutError>;
+
+    /// Names of the accounts
+    fn users(&self) -> Result<Vec<String>, crate::error::PeillutError>;
+
+    /// Money received by an account minus the money it gave
+    fn balance(&self, name: &str) -> Result<crate::money::Money, crate::error::PeillutError>;
+
+    /// Records a transaction, the rules of the ledger are not checked
+    fn insert_transaction(
+        &self,
+        tx: &crate::db::Transaction,
+    ) -> Result<(), crate::error::PeillutError>;
+
+    /// Transaction made by `source_node` at `lamport_time`
+    fn transaction(
+        &self,
+        lamport_time: i64,
+        source_node: &str,
+    ) -> Result<Option<crate::db::Transaction>, crate::error::PeillutError>;
+
+    /// Every transaction, ordered by hybrid timestamp
+    fn transactions(&self) -> Result<Vec<crate::db::Transaction>, crate::error::PeillutError>;
+
+    /// Transactions from or to an account, ordered by hybrid timestamp
+    fn transactions_for_user(
+        &self,
+        name: &str,
+    ) -> Result<Vec<crate::db::Transaction>, crate::error::PeillutError>;
+
+    /// Whether a refund of the transaction made by `source_node` at `lamport_time` exists
+    fn is_refunded(
+        &self,
+        lamport_time: i64,
+        source_node: &str,
+    ) -> Result<bool, crate::error::PeillutError>;
+
+    /// Site ID and clock saved by the site, None for a new site
+    fn local_state(
+        &self,
+    ) -> Result<Option<(String, crate::clock::Clock)>, crate::error::PeillutError>;
+
+    /// Saves the site ID and the clock of the site
+    fn save_local_state(
+        &self,
+        site_id: &str,
+        clock: &crate::clock::Clock,
+    ) -> Result<(), crate::error::PeillutError>;
+
+    /// Signing key of a site, as a PKCS#8 document
+    fn site_key(&self, site_id: &str) -> Result<Option<Vec<u8>>, crate::error::PeillutError>;
+
+    /// Saves the signing key of a site, as a PKCS#8 document
+    fn save_site_key(
+        &self,
+        site_id: &str,
+        signing_key: &[u8],
+    ) -> Result<(), crate::error::PeillutError>;
+
+    /// Public keys pinned for the other sites, see [`crate::identity`]
+    fn site_public_keys(
+        &self,
+    ) -> Result<std::collections::HashMap<String, Vec<u8>>, crate::error::PeillutError>;
+
+    /// Saves the public key pinned for a site
+    fn save_site_public_key(
+        &self,
+        site_id: &str,
+        public_key: &[u8],
+    ) -> Result<(), crate::error::PeillutError>;
+
+    /// Whether the transaction made by `source_node` at `lamport_time` is recorded
+    fn transaction_exists(
+        &self,
+        lamport_time: i64,
+        source_node: &str,
+    ) -> Result<bool, crate::error::PeillutError> {
+        Ok(self.transaction(lamport_time, source_node)?.is_some())
+    }
+
+    /// Creates an account without money, nothing is done if it exists
+    fn create_user(&self, name: &str) -> Result<(), crate::error::PeillutError> {
+        atomically(self, || {
+            if self.user_exists(name)? {
+                log::warn!("User '{}' already exists.", name);
+                return Ok(());
+            }
+            log::debug!("Ajout de l'utilisateur {}", name);
+            self.insert_user(name)
+        })
+    }
+
+    /// Deletes an account
+    fn delete_user(&self, name: &str) -> Result<(), crate::error::PeillutError> {
+        atomically(self, || {
+            if !self.user_exists(name)? {
+                return Err(crate::error::PeillutError::UnknownUser {
+                    user: name.to_string(),
+                });
+            }
+            self.remove_user(name)
+        })
+    }
+
+    /// Moves `amount` from `from_user` to `to_user`, [`crate::db::NULL`] stands for outside
+    /// of the ledger
+    ///
+    /// The accounts are created if missing
+    #[allow(clippy::too_many_arguments)]
+    fn create_transaction(
+        &self,
+        from_user: &str,
+        to_user: &str,
+        amount: crate::money::Money,
+        lamport_time: &i64,
+        hlc: &crate::clock::HybridTimestamp,
+        source_node: &str,
+        optional_msg: &str,
+        vector_clock: &std::collections::HashMap<String, i64>,
+    ) -> Result<(), crate::error::PeillutError> {
+        use crate::db::NULL;
+        use crate::error::PeillutError;
+
+        atomically(self, || {
+            // A replayed transaction is reported as such, whatever the balance is now
+            if self.transaction_exists(*lamport_time, source_node)? {
+                return Err(PeillutError::DuplicateTransaction {
+                    transaction: crate::db::TransactionKey {
+                        lamport_time: *lamport_time,
+                        source_node: source_node.to_string(),
+                    },
+                });
+            }
+            if amount <= crate::money::Money::ZERO {
+                return Err(PeillutError::InvalidAmount { amount });
+            }
+            if from_user != NULL {
+                let balance = self.balance(from_user)?;
+                if balance.checked_sub(amount)? < crate::money::Money::ZERO {
+                    return Err(PeillutError::InsufficientFunds {
+                        user: from_user.to_string(),
+                        balance,
+                        amount,
+                    });
+                }
+            }
+            // Refused before the balance no longer fits
+            if to_user != NULL && self.user_exists(to_user)? {
+                self.balance(to_user)?.checked_add(amount)?;
+            }
+
+            for user in [from_user, to_user] {
+                if user != NULL && !self.user_exists(user)? {
+                    self.insert_user(user)?;
+                }
+            }
+
+            log::debug!(
+                "Creating transaction from {} to {} with amount {}",
+                from_user,
+                to_user,
+                amount
+            );
+            self.insert_transaction(&crate::db::Transaction {
+                from_user: from_user.to_string(),
+                to_user: to_user.to_string(),
+                amount,
+                lamport_time: *lamport_time,
+                source_node: source_node.to_string(),
+                optional_msg: Some(optional_msg.to_string()),
+                vector_clock: vector_clock.clone(),
+                hlc: *hlc,
+            })
+        })
+    }
+
+    /// Adds money to an existing account
+    fn deposit(
+        &self,
+        user: &str,
+        amount: crate::money::Money,
+        lamport_time: &i64,
+        hlc: &crate::clock::HybridTimestamp,
+        source_node: &str,
+        vector_clock: &std::collections::HashMap<String, i64>,
+    ) -> Result<(), crate::error::PeillutError> {
+        use crate::error::PeillutError;
+
+        atomically(self, || {
+            if !self.user_exists(user)? {
+                return Err(PeillutError::UnknownUser {
+                    user: user.to_string(),
+                });
+            }
+            if amount <= crate::money::Money::ZERO {
+                return Err(PeillutError::InvalidAmount { amount });
+            }
+
+            log::debug!("Depositing {} to {}", amount, user);
+            self.create_transaction(
+                crate::db::NULL,
+                user,
+                amount,
+                lamport_time,
+                hlc,
+                source_node,
+                "Deposit",
+                vector_clock,
+            )
+        })
+    }
+
+    /// Takes money out of an existing account
+    fn withdraw(
+        &self,
+        user: &str,
+        amount: crate::money::Money,
+        lamport_time: &i64,
+        hlc: &crate::clock::HybridTimestamp,
+        source_node: &str,
+        vector_clock: &std::collections::HashMap<String, i64>,
+    ) -> Result<(), crate::error::PeillutError> {
+        use crate::error::PeillutError;
+
+        atomically(self, || {
+            if amount <= crate::money::Money::ZERO {
+                return Err(PeillutError::InvalidAmount { amount });
+            }
+            if !self.user_exists(user)? {
+                return Err(PeillutError::UnknownUser {
+                    user: user.to_string(),
+                });
+            }
+            let balance = self.balance(user)?;
+            if balance < amount {
+                return Err(PeillutError::InsufficientFunds {
+                    user: user.to_string(),
+                    balance,
+                    amount,
+                });
+            }
+
+            log::debug!("Withdrawing {} from {}", amount, user);
+            self.create_transaction(
+                user,
+                crate::db::NULL,
+                amount,
+                lamport_time,
+                hlc,
+                source_node,
+                "Withdraw",
+                vector_clock,
+            )
+        })
+    }
+
+    /// Gives back the money of the transaction made by `node` at `transac_time`
+    ///
+    /// A transaction is refunded once, and a refund cannot be refunded
+    fn refund_transaction(
+        &self,
+        transac_time: i64,
+        node: &str,
+        lamport_time: &i64,
+        hlc: &crate::clock::HybridTimestamp,
+        source_node: &str,
+        vector_clock: &std::collections::HashMap<String, i64>,
+    ) -> Result<(), crate::error::PeillutError> {
+        use crate::error::PeillutError;
+
+        atomically(self, || {
+            let key = crate::db::TransactionKey {
+                lamport_time: transac_time,
+                source_node: node.to_string(),
+            };
+            let Some(tx) = self.transaction(transac_time, node)? else {
+                return Err(PeillutError::UnknownTransaction { transaction: key });
+            };
+
+            let balance = self.balance(&tx.to_user)?;
+            if balance < tx.amount {
+                return Err(PeillutError::InsufficientFunds {
+                    user: tx.to_user,
+                    balance,
+                    amount: tx.amount,
+                });
+            }
+
+            if tx
+                .optional_msg
+                .as_ref()
+                .is_some_and(|msg| msg.starts_with("Refund transaction"))
+            {
+                return Err(PeillutError::NotRefundable { transaction: key });
+            }
+
+            if self.is_refunded(transac_time, node)? {
+                return Err(PeillutError::AlreadyRefunded { transaction: key });
+            }
+
+            self.create_transaction(
+                &tx.to_user,
+                &tx.from_user,
+                tx.amount,
+                lamport_time,
+                hlc,
+                source_node,
+                &format!("Refund transaction {}-{}", node, transac_time),
+                vector_clock,
+            )
+        })
+    }
+
+    /// Records the transactions this site misses according to a global snapshot
+    ///
+    /// A transaction that cannot be recorded is skipped, the others are kept. Returns the
+    /// skipped transactions with the reason.
+    #[allow(clippy::type_complexity)]
+    fn apply_snapshot(
+        &self,
+        snapshot: &crate::snapshot::GlobalSnapshot,
+        vector_clock: &std::collections::HashMap<String, i64>,
+    ) -> Result<
+        Vec<(crate::db::TransactionKey, crate::error::PeillutError)>,
+        crate::error::PeillutError,
+    > {
+        log::info!("Applying snapshot to database");
+
+        if snapshot.missing.is_empty() {
+            log::info!("No missing transactions, nothing to do");
+            return Ok(Vec::new());
+        }
+
+        // sort tsx actions by lamport time
+        let mut sorted_txs: Vec<_> = snapshot
+            .missing
+            .values()
+            .flat_map(|txs| txs.iter())
+            .collect();
+        sorted_txs.sort_by_key(|tx| tx.lamport_time);
+
+        atomically(self, || {
+            let mut skipped = Vec::new();
+            for tx in sorted_txs {
+                let optional_msg = "";
+
+                if let Err(e) = self.create_transaction(
+                    &tx.from_user,
+                    &tx.to_user,
+                    tx.amount,
+                    &tx.lamport_time,
+                    &tx.hlc,
+                    &tx.source_node,
+                    optional_msg,
+                    vector_clock,
+                ) {
+                    let key = crate::db::TransactionKey {
+                        lamport_time: tx.lamport_time,
+                        source_node: tx.source_node.clone(),
+                    };
+                    log::warn!(
+                        "Transaction {}-{} of the snapshot skipped: {}",
+                        key.source_node,
+                        key.lamport_time,
+                        e
+                    );
+                    skipped.push((key, e));
+                }
+            }
+            Ok(skipped)
+        })
+    }
+}
+
+#[cfg(feature = "server")]
+impl dyn LedgerStore + '_ {
+    /// Runs `f` as one group of writes, nothing it wrote is kept if it fails
+    ///
+    /// The other threads wait for the group to be closed before writing, and read the
+    /// ledger as it was before the group until it is committed.
+    pub fn atomically<T, E: From<crate::error::PeillutError>>(
+        &self,
+        f: impl FnOnce() -> Result<T, E>,
+    ) -> Result<T, E> {
+        atomically(self, f)
+    }
+}
+
+#[cfg(feature = "server")]
+/// Runs `f` as one group of writes of `store`, see `LedgerStore::atomically`
+fn atomically<S, T, E>(store: &S, f: impl FnOnce() -> Result<T, E>) -> Result<T, E>
+where
+    S: LedgerStore + ?Sized,
+    E: From<crate::error::PeillutError>,
+{
+    store.begin()?;
+    let mut open = OpenGroup { store, open: true };
+    let result = f();
+    open.open = false;
+    match result {
+        Ok(value) => {
+            store.commit()?;
+            Ok(value)
+        }
+        Err(e) => {
+            // The failure of `f` is the one the caller has to know about
+            if let Err(rollback) = store.rollback() {
+                log::error!("Unable to roll back a failed group of writes: {}", rollback);
+            }
+            Err(e)
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+/// Group opened by `atomically`, rolled back if `f` panics
+///
+/// The gate is closed by the rollback, so that the other threads do not wait forever for
+/// a group nobody will close.
+struct OpenGroup<'a, S: LedgerStore + ?Sized> {
+    store: &'a S,
+    open: bool,
+}
+
+#[cfg(feature = "server")]
+impl<S: LedgerStore + ?Sized> Drop for OpenGroup<'_, S> {
+    fn drop(&mut self) {
+        if self.open
+            && let Err(e) = self.store.rollback()
+        {
+            log::error!("Unable to roll back a group of writes after a panic: {}", e);
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+/// Gives the groups of writes of a store to one thread at a time
+///
+/// Groups nest on the thread which opened the first one. The other threads wait for it
+/// to close them before writing, so that their writes do not land in its groups.
+#[derive(Default)]
+pub struct WriteGate {
+    /// Thread writing groups, with the number of groups it opened
+    owner: std::sync::Mutex<Option<(std::thread::ThreadId, usize)>>,
+    /// Signalled when the last group is closed
+    closed: std::sync::Condvar,
+}
+
+#[cfg(feature = "server")]
+impl WriteGate {
+    /// Waits until the groups of the other threads are closed
+    ///
+    /// The other threads cannot open a group while the returned guard is held
+    pub fn wait(&self) -> std::sync::MutexGuard<'_, Option<(std::thread::ThreadId, usize)>> {
+        let me = std::thread::current().id();
+        let owner = self.owner.lock().unwrap();
+        self.closed
+            .wait_while(owner, |owner| owner.is_some_and(|(thread, _)| thread != me))
+            .unwrap()
+    }
+
+    /// Opens a group on the current thread, `start` starts it in the backend
+    pub fn open(
+        &self,
+        start: impl FnOnce() -> Result<(), crate::error::PeillutError>,
+    ) -> Result<(), crate::error::PeillutError> {
+        let mut owner = self.wait();
+        start()?;
+        let depth = owner.map_or(0, |(_, depth)| depth);
+        *owner = Some((std::thread::current().id(), depth + 1));
+        Ok(())
+    }
+
+    /// Closes the innermost group of the current thread, `end` ends it in the backend
+    ///
+    /// The group is closed even if `end` fails, so that the other threads do not wait
+    /// forever
+    pub fn close(
+        &self,
+        end: impl FnOnce() -> Result<(), crate::error::PeillutError>,
+    ) -> Result<(), crate::error::PeillutError> {
+        let mut owner = self.owner.lock().unwrap();
+        let ended = end();
+        match *owner {
+            Some((thread, depth)) if depth > 1 => *owner = Some((thread, depth - 1)),
+            _ => {
+                *owner = None;
+                self.closed.notify_all();
+            }
+        }
+        ended
+    }
+
+    /// Whether the current thread has a group open
+    pub fn is_mine(&self) -> bool {
+        let me = std::thread::current().id();
+        self.owner
+            .lock()
+            .unwrap()
+            .is_some_and(|(thread, _)| thread == me)
+    }
+}
+
+#[cfg(feature = "server")]
+/// Runs `f` with the store on the blocking thread pool of the runtime
+///
+/// A slow query would otherwise stall a runtime thread, and the network tasks waiting on
+/// it. The calls making up one operation are grouped in a single `f`.
+pub async fn blocking<T, F>(store: &std::sync::Arc<dyn LedgerStore>, f: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce(&dyn LedgerStore) -> T + Send + 'static,
+{
+    let store = store.clone();
+    match tokio::task::spawn_blocking(move || f(store.as_ref())).await {
+        Ok(value) => value,
+        Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+        // The runtime shuts down
+        Err(e) => panic!("The ledger task was cancelled: {}", e),
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "server")]
+/// Content of a [`MemoryStore`]
+#[derive(Clone, Default)]
+struct Ledger {
+    users: std::collections::BTreeSet<String>,
+    transactions: Vec<crate::db::Transaction>,
+    local_state: Option<(String, crate::clock::Clock)>,
+    site_keys: std::collections::HashMap<String, Vec<u8>>,
+    site_public_keys: std::collections::HashMap<String, Vec<u8>>,
+}
+
+#[cfg(test)]
+#[cfg(feature = "server")]
+/// Ledger kept in memory, lost when the store is dropped
+#[derive(Default)]
+pub struct MemoryStore {
+    /// The ledger, then the ledger as it was when each open group began
+    ledgers: std::sync::Mutex<(Ledger, Vec<Ledger>)>,
+    /// Thread writing the open groups
+    gate: WriteGate,
+}
+
+#[cfg(test)]
+#[cfg(feature = "server")]
+impl MemoryStore {
+    /// Reads the ledger, the other threads read it as it was before the open groups
+    fn read<T>(&self, f: impl FnOnce(&Ledger) -> T) -> Result<T, crate::error::PeillutError> {
+        let mine = self.gate.is_mine();
+        let ledgers = self.ledgers.lock().unwrap();
+        let (current, saved) = &*ledgers;
+        match saved.first() {
+            Some(committed) if !mine => Ok(f(committed)),
+            _ => Ok(f(current)),
+        }
+    }
+
+    fn write(&self, f: impl FnOnce(&mut Ledger)) -> Result<(), crate::error::PeillutError> {
+        let _gate = self.gate.wait();
+        f(&mut self.ledgers.lock().unwrap().0);
+        Ok(())
+    }
+
+    fn ordered(mut txs: Vec<crate::db::Transaction>) -> Vec<crate::db::Transaction> {
+        txs.sort_by_key(|tx| tx.hlc);
+        txs
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "server")]
+impl LedgerStore for MemoryStore {
+    fn location(&self) -> String {
+        "in memory".to_string()
+    }
+
+    fn begin(&self) -> Result<(), crate::error::PeillutError> {
+        self.gate.open(|| {
+            let mut ledgers = self.ledgers.lock().unwrap();
+            let saved = ledgers.0.clone();
+            ledgers.1.push(saved);
+            Ok(())
+        })
+    }
+
+    fn commit(&self) -> Result<(), crate::error::PeillutError> {
+        self.gate.close(|| {
+            self.ledgers.lock().unwrap().1.pop();
+            Ok(())
+        })
+    }
+
+    fn rollback(&self) -> Result<(), crate::error::PeillutError> {
+        self.gate.close(|| {
+            let mut ledgers = self.ledgers.lock().unwrap();
+            if let Some(saved) = ledgers.1.pop() {
+                ledgers.0 = saved;
+            }
+            Ok(())
+        })
+    }
+
+    fn user_exists(&self, name: &str) -> Result<bool, crate::error::PeillutError> {
+        self.read(|ledger| ledger.users.contains(name))
+    }
+
+    fn insert_user(&self, name: &str) -> Result<(), crate::error::PeillutError> {
+        self.write(|ledger| {
+            ledger.users.insert(name.to_string());
+        })
+    }
+
+    fn remove_user(&self, name: &str) -> Result<(), crate::error::PeillutError> {
+        self.write(|ledger| {
+            ledger.users.remove(name);
+        })
+    }
+
+    fn users(&self) -> Result<Vec<String>, crate::error::PeillutError> {
+        self.read(|ledger| ledger.users.iter().cloned().collect())
+    }
+
+    fn balance(&self, name: &str) -> Result<crate::money::Money, crate::error::PeillutError> {
+        self.read(|ledger| {
+            ledger
+                .transactions
+                .iter()
+                .try_fold(crate::money::Money::ZERO, |mut balance, tx| {
+                    if tx.to_user == name {
+                        balance = balance.checked_add(tx.amount)?;
+                    }
+                    if tx.from_user == name {
+                        balance = balance.checked_sub(tx.amount)?;
+                    }
+                    Ok(balance)
+                })
+        })?
+    }
+
+    fn insert_transaction(
+        &self,
+        tx: &crate::db::Transaction,
+    ) -> Result<(), crate::error::PeillutError> {
+        self.write(|ledger| ledger.transactions.push(tx.clone()))
+    }
+
+    fn transaction(
+        &self,
+        lamport_time: i64,
+        source_node: &str,
+    ) -> Result<Option<crate::db::Transaction>, crate::error::PeillutError> {
+        self.read(|ledger| {
+            ledger
+                .transactions
+                .iter()
+                .find(|tx| tx.lamport_time == lamport_time && tx.source_node == source_node)
+                .cloned()
+        })
+    }
+
+    fn transactions(&self) -> Result<Vec<crate::db::Transaction>, crate::error::PeillutError> {
+        self.read(|ledger| Self::ordered(ledger.transactions.clone()))
+    }
+
+    fn transactions_for_user(
+        &self,
+        name: &str,
+    ) -> Result<Vec<crate::db::Transaction>, crate::error::PeillutError> {
+        self.read(|ledger| {
+            Self::ordered(
+                ledger
+                    .transactions
+                    .iter()
+                    .filter(|tx| tx.from_user == name || tx.to_user == name)
+                    .cloned()
+                    .collect(),
+            )
+        })
+    }
+
+    fn is_refunded(
+        &self,
+        lamport_time: i64,
+        source_node: &str,
+    ) -> Result<bool, crate::error::PeillutError> {
+        let refund = format!("Refund transaction {}-{}", source_node, lamport_time);
+        self.read(|ledger| {
+            ledger
+                .transactions
+                .iter()
+                .any(|tx| tx.optional_msg.as_deref() == Some(refund.as_str()))
+        })
+    }
+
+    fn local_state(
+        &self,
+    ) -> Result<Option<(String, crate::clock::Clock)>, crate::error::PeillutError> {
+        self.read(|ledger| ledger.local_state.clone())
+    }
+
+    fn save_local_state(
+        &self,
+        site_id: &str,
+        clock: &crate::clock::Clock,
+    ) -> Result<(), crate::error::PeillutError> {
+        // Saved like in a database, the retired sites are not kept
+        let saved = crate::clock::Clock::from_parts(
+            *clock.get_lamport(),
+            clock.get_vector_clock_map().clone(),
+            clock.get_hlc(),
+        );
+        self.write(|ledger| ledger.local_state = Some((site_id.to_string(), saved)))
+    }
+
+    fn site_key(&self, site_id: &str) -> Result<Option<Vec<u8>>, crate::error::PeillutError> {
+        self.read(|ledger| ledger.site_keys.get(site_id).cloned())
+    }
+
+    fn save_site_key(
+        &self,
+        site_id: &str,
+        signing_key: &[u8],
+    ) -> Result<(), crate::error::PeillutError> {
+        self.write(|ledger| {
+            ledger
+                .site_keys
+                .insert(site_id.to_string(), signing_key.to_vec());
+        })
+    }
+
+    fn site_public_keys(
+        &self,
+    ) -> Result<std::collections::HashMap<String, Vec<u8>>, crate::error::PeillutError> {
+        self.read(|ledger| ledger.site_public_keys.clone())
+    }
+
+    fn save_site_public_key(
+        &self,
+        site_id: &str,
+        public_key: &[u8],
+    ) -> Result<(), crate::error::PeillutError> {
+        self.write(|ledger| {
+            ledger
+                .site_public_keys
+                .insert(site_id.to_string(), public_key.to_vec());
+        })
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "server")]
+mod tests {
+    use super::*;
+    use crate::error::PeillutError;
+    use crate::money::Money;
+
+    /// Runs the same checks on every backend
+    fn backends() -> Vec<Box<dyn LedgerStore>> {
+        vec![
+            Box::new(MemoryStore::default()),
+            Box::new(crate::db::SqliteStore::in_memory().unwrap()),
+        ]
+    }
+
+    #[test]
+    fn failed_atomic_writes_are_rolled_back() {
+        let vc = std::collections::HashMap::new();
+        let hlc = crate::clock::HybridTimestamp::default();
+        for store in backends() {
+            store.create_user("alice").unwrap();
+
+            let failed: Result<(), PeillutError> = store.atomically(|| {
+                store.deposit("alice", Money::from_euros(10), &1, &hlc, "A", &vc)?;
+                store.withdraw("alice", Money::from_euros(50), &2, &hlc, "A", &vc)
+            });
+            assert!(failed.is_err());
+            assert!(!store.transaction_exists(1, "A").unwrap());
+            assert_eq!(store.balance("alice").unwrap(), Money::ZERO);
+
+            store
+                .atomically(|| {
+                    store.deposit("alice", Money::from_euros(10), &1, &hlc, "A", &vc)?;
+                    store.withdraw("alice", Money::from_euros(4), &2, &hlc, "A", &vc)
+                })
+                .unwrap();
+            assert_eq!(store.balance("alice").unwrap(), Money::from_euros(6));
+        }
+    }
+
+    #[test]
+    fn other_threads_wait_for_the_open_group() {
+        for store in backends() {
+            store.create_user("alice").unwrap();
+            store.begin().unwrap();
+            store.delete_user("alice").unwrap();
+            std::thread::scope(|scope| {
+                let writer = scope.spawn(|| store.create_user("carol").unwrap());
+                // The others neither see the group nor write into it
+                let reader = scope.spawn(|| store.user_exists("alice").unwrap());
+                std::thread::sleep(std::time::Duration::from_millis(50));
+                assert!(!store.user_exists("carol").unwrap());
+                store.rollback().unwrap();
+                writer.join().unwrap();
+                assert!(reader.join().unwrap());
+            });
+            assert!(store.user_exists("alice").unwrap());
+            assert!(store.user_exists("carol").unwrap());
+        }
+    }
+
+    #[test]
+    fn a_panic_in_a_group_rolls_it_back() {
+        for store in backends() {
+            let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                store.atomically(|| -> Result<(), PeillutError> {
+                    store.create_user("alice")?;
+                    panic!("the group panics");
+                })
+            }));
+            assert!(panicked.is_err());
+            assert!(!store.user_exists("alice").unwrap());
+
+            // The group is closed, the other threads write again
+            std::thread::scope(|scope| {
+                scope.spawn(|| store.create_user("carol").unwrap());
+            });
+            assert!(store.user_exists("carol").unwrap());
+        }
+    }
+
+    #[test]
+    fn skipped_snapshot_transactions_are_returned() {
+        let vc = std::collections::HashMap::new();
+        let hlc = crate::clock::HybridTimestamp::default();
+        let summary = |lamport_time, from_user: &str, amount| crate::snapshot::TxSummary {
+            lamport_time,
+            source_node: "B".to_string(),
+            from_user: from_user.to_string(),
+            to_user: "bob".to_string(),
+            amount: Money::from_euros(amount),
+            hlc,
+        };
+        let snapshot = crate::snapshot::GlobalSnapshot {
+            all_transactions: Default::default(),
+            missing: [(
+                "A".to_string(),
+                [summary(1, crate::db::NULL, 10), summary(2, "alice", 50)].into(),
+            )]
+            .into(),
+        };
+        for store in backends() {
+            store.create_user("alice").unwrap();
+
+            let skipped = store.apply_snapshot(&snapshot, &vc).unwrap();
+            assert_eq!(skipped.len(), 1);
+            assert_eq!(skipped[0].0.lamport_time, 2);
+            assert!(matches!(
+                skipped[0].1,
+                PeillutError::InsufficientFunds { .. }
+            ));
+            assert_eq!(store.balance("bob").unwrap(), Money::from_euros(10));
+        }
+    }
+
+    #[test]
+    fn ledger_failures_are_typed() {
+        let vc = std::collections::HashMap::new();
+        let hlc = crate::clock::HybridTimestamp::default();
+        for store in backends() {
+            store.create_user("alice").unwrap();
+
+            assert!(matches!(
+                store.deposit("bob", Money::from_euros(10), &1, &hlc, "A", &vc),
+                Err(PeillutError::UnknownUser { .. })
+            ));
+            store
+                .deposit("alice", Money::from_euros(10), &1, &hlc, "A", &vc)
+                .unwrap();
+            assert!(matches!(
+                store.deposit("alice", Money::from_euros(10), &1, &hlc, "A", &vc),
+                Err(PeillutError::DuplicateTransaction { .. })
+            ));
+            assert!(matches!(
+                store.withdraw("alice", Money::from_euros(50), &2, &hlc, "A", &vc),
+                Err(PeillutError::InsufficientFunds { balance, amount, .. })
+                    if balance == Money::from_euros(10) && amount == Money::from_euros(50)
+            ));
+            assert!(matches!(
+                store.create_transaction(
+                    "alice",
+                    "bob",
+                    Money::from_euros(50),
+                    &1,
+                    &hlc,
+                    "A",
+                    "",
+                    &vc
+                ),
+                Err(PeillutError::DuplicateTransaction { .. })
+            ));
+
+            store.create_user("bob").unwrap();
+            store
+                .create_transaction("alice", "bob", Money::from_euros(5), &2, &hlc, "A", "", &vc)
+                .unwrap();
+            store
+                .create_transaction("alice", "bob", Money::from_euros(5), &3, &hlc, "A", "", &vc)
+                .unwrap();
+            store
+                .refund_transaction(2, "A", &4, &hlc, "A", &vc)
+                .unwrap();
+            assert!(matches!(
+                store.refund_transaction(4, "A", &5, &hlc, "A", &vc),
+                Err(PeillutError::NotRefundable { .. })
+            ));
+            assert!(matches!(
+                store.refund_transaction(2, "A", &5, &hlc, "A", &vc),
+                Err(PeillutError::AlreadyRefunded { .. })
+            ));
+            assert!(matches!(
+                store.refund_transaction(9, "A", &5, &hlc, "A", &vc),
+                Err(PeillutError::UnknownTransaction { .. })
+            ));
+            assert_eq!(store.balance("alice").unwrap(), Money::from_euros(5));
+            assert_eq!(store.balance("bob").unwrap(), Money::from_euros(5));
+            assert_eq!(store.transactions_for_user("bob").unwrap().len(), 3);
+        }
+    }
+
+    #[test]
+    fn amounts_must_be_positive_and_fit_in_an_account() {
+        let vc = std::collections::HashMap::new();
+        let hlc = crate::clock::HybridTimestamp::default();
+        for store in backends() {
+            store.create_user("alice").unwrap();
+            store.create_user("bob").unwrap();
+
+            assert!(matches!(
+                store.deposit("alice", Money::ZERO, &1, &hlc, "A", &vc),
+                Err(PeillutError::InvalidAmount { .. })
+            ));
+            assert!(matches!(
+                store.withdraw("alice", Money::from_cents(-1), &1, &hlc, "A", &vc),
+                Err(PeillutError::InvalidAmount { .. })
+            ));
+            assert!(matches!(
+                store.create_transaction("alice", "bob", Money::ZERO, &1, &hlc, "A", "", &vc),
+                Err(PeillutError::InvalidAmount { .. })
+            ));
+
+            store
+                .deposit("alice", Money::from_cents(i64::MAX), &1, &hlc, "A", &vc)
+                .unwrap();
+            assert!(matches!(
+                store.deposit("alice", Money::from_cents(1), &2, &hlc, "A", &vc),
+                Err(PeillutError::AmountOverflow)
+            ));
+            assert_eq!(store.balance("alice").unwrap(), Money::from_cents(i64::MAX));
+        }
+    }
+
+    #[tokio::test]
+    async fn ledger_calls_run_off_the_runtime_threads() {
+        let store: std::sync::Arc<dyn LedgerStore> = std::sync::Arc::new(MemoryStore::default());
+        let runtime_thread = std::thread::current().id();
+        let thread = blocking(&store, |store| {
+            store.create_user("alice").unwrap();
+            std::thread::current().id()
+        })
+        .await;
+        assert_ne!(thread, runtime_thread);
+        assert!(
+            blocking(&store, |store| store.user_exists("alice"))
+                .await
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn local_state_is_saved() {
+        let clock = crate::clock::Clock::new_with_values(3, [("A".to_string(), 2)].into());
+        for store in backends() {
+            assert!(store.local_state().unwrap().is_none());
+            store.save_local_state("A", &clock).unwrap();
+            store.save_local_state("A", &clock).unwrap();
+
+            let (site_id, saved) = store.local_state().unwrap().unwrap();
+            assert_eq!(site_id, "A");
+            assert_eq!(saved.get_lamport(), &3);
+            assert_eq!(saved.get_vector_clock_map(), clock.get_vector_clock_map());
+        }
+    }
+
+    #[test]
+    fn pinned_public_keys_are_saved() {
+        for store in backends() {
+            assert!(store.site_public_keys().unwrap().is_empty());
+            store.save_site_public_key("B", &[2; 32]).unwrap();
+            store.save_site_public_key("C", &[3; 32]).unwrap();
+            store.save_site_public_key("B", &[2; 32]).unwrap();
+
+            let keys = store.site_public_keys().unwrap();
+            assert_eq!(keys.len(), 2);
+            assert_eq!(keys["B"], vec![2; 32]);
+            assert_eq!(keys["C"], vec![3; 32]);
+        }
+    }
+}
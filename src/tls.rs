@@ -0,0 +1,207 @@
+//! Mutual TLS between Peillute sites
+//!
+//! When enabled, every connection on the peer port is wrapped in TLS and both ends
+//! must present a certificate signed by the deployment CA. Sites are reached by IP
+//! address, so each site certificate must carry the IP it listens on as a subject
+//! alternative name.
+
+#[cfg(feature = "server")]
+/// TLS material shared by the listener and the outgoing connections of a site
+#[derive(Clone)]
+pub struct TlsConfig {
+    /// Used to accept connections from other sites
+    acceptor: tokio_rustls::TlsAcceptor,
+    /// Used to open connections to other sites
+    connector: tokio_rustls::TlsConnector,
+}
+
+#[cfg(feature = "server")]
+impl TlsConfig {
+    /// Loads the site certificate, its private key and the CA certificate from PEM files
+    pub fn from_files(
+        cert_path: &str,
+        key_path: &str,
+        ca_path: &str,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let read =
+            |path: &str| std::fs::read(path).map_err(|e| format!("Unable to read {}: {}", path, e));
+        Self::from_pem(&read(cert_path)?, &read(key_path)?, &read(ca_path)?)
+    }
+
+    /// Builds the TLS configuration from PEM encoded certificate chain, key and CA
+    pub fn from_pem(
+        cert_pem: &[u8],
+        key_pem: &[u8],
+        ca_pem: &[u8],
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        use rustls::server::WebPkiClientVerifier;
+        use rustls::{ClientConfig, RootCertStore, ServerConfig};
+        use rustls_pki_types::pem::PemObject;
+        use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+        use std::sync::Arc;
+
+        let certs = CertificateDer::pem_slice_iter(cert_pem).collect::<Result<Vec<_>, _>>()?;
+        if certs.is_empty() {
+            return Err("No certificate found for this site".into());
+        }
+        let key = PrivateKeyDer::from_pem_slice(key_pem)?;
+
+        let mut roots = RootCertStore::empty();
+        for ca in CertificateDer::pem_slice_iter(ca_pem) {
+            roots.add(ca?)?;
+        }
+        if roots.is_empty() {
+            return Err("No CA certificate found".into());
+        }
+        let roots = Arc::new(roots);
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+        // Every incoming site must present a certificate signed by the CA
+        let client_verifier =
+            WebPkiClientVerifier::builder_with_provider(roots.clone(), provider.clone()).build()?;
+        let server_config = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?
+            .with_client_cert_verifier(client_verifier)
+            .with_single_cert(certs.clone(), key.clone_key())?;
+
+        let client_config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_client_auth_cert(certs, key)?;
+
+        Ok(Self {
+            acceptor: tokio_rustls::TlsAcceptor::from(Arc::new(server_config)),
+            connector: tokio_rustls::TlsConnector::from(Arc::new(client_config)),
+        })
+    }
+
+    /// Runs the server side of the handshake on an accepted connection
+    ///
+    /// Fails if the remote site does not present a certificate signed by the CA
+    pub async fn accept(
+        &self,
+        stream: tokio::net::TcpStream,
+    ) -> std::io::Result<tokio_rustls::server::TlsStream<tokio::net::TcpStream>> {
+        self.acceptor.accept(stream).await
+    }
+
+    /// Runs the client side of the handshake on a connection to `site_addr`
+    ///
+    /// Fails if the remote certificate is not signed by the CA or is not valid for the site IP
+    pub async fn connect(
+        &self,
+        site_addr: std::net::SocketAddr,
+        stream: tokio::net::TcpStream,
+    ) -> std::io::Result<tokio_rustls::client::TlsStream<tokio::net::TcpStream>> {
+        let server_name = rustls_pki_types::ServerName::IpAddress(site_addr.ip().into());
+        self.connector.connect(server_name, stream).await
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "server")]
+mod tests {
+    use super::*;
+    use crate::codec::FrameCodec;
+    use tokio::net::{TcpListener, TcpStream};
+
+    struct Authority {
+        cert: rcgen::Certificate,
+        key: rcgen::KeyPair,
+    }
+
+    fn mk_authority() -> Authority {
+        let key = rcgen::KeyPair::generate().unwrap();
+        let mut params = rcgen::CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let cert = params.self_signed(&key).unwrap();
+        Authority { cert, key }
+    }
+
+    fn mk_site_config(ca: &Authority) -> TlsConfig {
+        let key = rcgen::KeyPair::generate().unwrap();
+        let mut params = rcgen::CertificateParams::new(vec!["127.0.0.1".to_string()]).unwrap();
+        params.extended_key_usages = vec![
+            rcgen::ExtendedKeyUsagePurpose::ServerAuth,
+            rcgen::ExtendedKeyUsagePurpose::ClientAuth,
+        ];
+        let cert = params.signed_by(&key, &ca.cert, &ca.key).unwrap();
+        TlsConfig::from_pem(
+            cert.pem().as_bytes(),
+            key.serialize_pem().as_bytes(),
+            ca.cert.pem().as_bytes(),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn sites_of_the_same_ca_exchange_frames() {
+        let ca = mk_authority();
+        let server = mk_site_config(&ca);
+        let client = mk_site_config(&ca);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let accept_task = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = server.accept(stream).await.unwrap();
+            FrameCodec::default().read_frame(&mut stream).await.unwrap()
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut stream = client.connect(addr, stream).await.unwrap();
+        FrameCodec::default()
+            .write_frame(&mut stream, b"peillute")
+            .await
+            .unwrap();
+
+        let frame = accept_task.await.unwrap();
+        assert_eq!(frame.as_deref(), Some(&b"peillute"[..]));
+    }
+
+    #[tokio::test]
+    async fn site_of_an_unknown_ca_is_refused() {
+        let server = mk_site_config(&mk_authority());
+        let intruder = mk_site_config(&mk_authority());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let accept_task = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            server.accept(stream).await.is_ok()
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        assert!(intruder.connect(addr, stream).await.is_err());
+        assert!(!accept_task.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn plaintext_peer_is_refused() {
+        use tokio::io::AsyncWriteExt;
+
+        let server = mk_site_config(&mk_authority());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let accept_task = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            server.accept(stream).await.is_ok()
+        });
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut wire = Vec::new();
+        FrameCodec::default()
+            .write_frame(&mut wire, b"deposit 1000")
+            .await
+            .unwrap();
+        stream.write_all(&wire).await.unwrap();
+        stream.shutdown().await.unwrap();
+
+        assert!(!accept_task.await.unwrap());
+    }
+}
@@ -4,24 +4,48 @@ pub fn get_mac_address() -> Option<String> {
     let interfaces = datalink::interfaces();
     for iface in interfaces {
         // Ignore loopback et interfaces sans MAC
-        if iface.is_up() && !iface.is_loopback() {
-            if let Some(mac) = iface.mac {
-                if mac.octets() != [0, 0, 0, 0, 0, 0] {
-                    return Some(mac.to_string().replace(":", ""));
-                }
-            }
+        if iface.is_up()
+            && !iface.is_loopback()
+            && let Some(mac) = iface.mac
+            && mac.octets() != [0, 0, 0, 0, 0, 0]
+        {
+            return Some(mac.to_string().replace(":", ""));
         }
     }
     None
 }
 #[cfg(feature = "server")]
-pub async fn reload_existing_site() -> Result<(String, crate::clock::Clock), String> {
+/// Reloads the signing key of the site from the database, or creates and saves a new one
+pub fn load_or_create_identity(
+    store: &dyn crate::store::LedgerStore,
+    site_id: &str,
+) -> Result<crate::identity::SiteIdentity, Box<dyn std::error::Error>> {
+    use crate::identity::SiteIdentity;
+    match store.site_key(site_id)? {
+        Some(pkcs8) => Ok(SiteIdentity::from_pkcs8(&pkcs8)?),
+        None => {
+            log::info!("No signing key found for site {}, creating one", site_id);
+            let identity = SiteIdentity::generate()?;
+            store.save_site_key(site_id, identity.to_pkcs8())?;
+            Ok(identity)
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+pub async fn reload_existing_site(
+    store: &std::sync::Arc<dyn crate::store::LedgerStore>,
+) -> Result<(String, crate::clock::Clock), String> {
     use log::info;
-    match crate::db::get_local_state() {
-        Ok((site_id, clock)) => {
+    match crate::store::blocking(store, |store| store.local_state()).await {
+        Ok(Some((site_id, clock))) => {
             info!("Existing site state reloaded");
             Ok((site_id, clock))
         }
+        Ok(None) => {
+            info!("No existing site state found, creating a new one.");
+            Err("Failed to reload existing site: no local state".to_string())
+        }
         Err(e) => {
             info!("No existing site state found, creating a new one.");
             Err(format!("Failed to reload existing site: {}", e))
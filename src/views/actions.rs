@@ -5,13 +5,14 @@
 //! refunds, and transfers between users.
 
 use crate::hooks::use_auto_refresh;
+use crate::money::Money;
 use dioxus::prelude::*;
 
 // show all transactions as vertical card list
 /// Transaction history component
 ///
 /// Displays a list of all transactions for a specific user, showing details such as
-/// the source and destination users, amount, and any associated messages.
+/// the source and destination users, amount, date, and any associated messages.
 /// Automatically refreshes transaction history every 3 seconds.
 #[component]
 pub fn History(name: String) -> Element {
@@ -20,18 +21,18 @@ pub fn History(name: String) -> Element {
     let refresh_trigger = use_signal(|| 0);
 
     let transactions_resource = use_resource(move || {
-        let trigger = *refresh_trigger.read();
+        let _trigger = *refresh_trigger.read();
         let name_clone = name_for_future.clone();
         async move { get_transactions_for_user_server(name_clone.to_string()).await }
     });
 
     // Auto-refresh every 3 seconds
     use_auto_refresh(3000, {
-        let refresh_trigger = refresh_trigger;
         move || {
-            let refresh_trigger = refresh_trigger;
+            let mut refresh_trigger = refresh_trigger;
             async move {
-                refresh_trigger.set(refresh_trigger.read() + 1);
+                let next = *refresh_trigger.read() + 1;
+                refresh_trigger.set(next);
             }
         }
     });
@@ -64,7 +65,11 @@ pub fn History(name: String) -> Element {
                                         }
                                         p {
                                             strong { "Amount:" }
-                                            " {transaction.amount:.2}"
+                                            " {transaction.amount}"
+                                        }
+                                        p {
+                                            strong { "Date:" }
+                                            " {transaction.hlc}"
                                         }
                                         if let Some(msg) = &transaction.optional_msg {
                                             if !msg.is_empty() {
@@ -95,7 +100,7 @@ pub fn History(name: String) -> Element {
 /// validation to ensure positive amounts and sufficient funds.
 #[component]
 pub fn Withdraw(name: String) -> Element {
-    let mut withdraw_amount = use_signal(|| 0f64);
+    let mut withdraw_amount = use_signal(|| Money::ZERO);
     let name = std::rc::Rc::new(name);
 
     let mut error_signal = use_signal(|| None::<String>);
@@ -113,7 +118,7 @@ pub fn Withdraw(name: String) -> Element {
                     step: 0.01,
                     value: "{withdraw_amount}",
                     oninput: move |event| {
-                        if let Ok(as_number) = event.value().parse::<f64>() {
+                        if let Ok(as_number) = event.value().parse::<Money>() {
                             withdraw_amount.set(as_number);
                         }
                     },
@@ -124,10 +129,13 @@ pub fn Withdraw(name: String) -> Element {
                         let name = name_for_future.clone();
                         let amount = *withdraw_amount.read();
                         async move {
-                            if amount >= 0.0 {
-                                if let Ok(_) = withdraw_for_user_server(name.to_string(), amount).await {
-                                    withdraw_amount.set(0.0);
-                                    error_signal.set(None);
+                            if amount >= Money::ZERO {
+                                match withdraw_for_user_server(name.to_string(), amount).await {
+                                    Ok(()) => {
+                                        withdraw_amount.set(Money::ZERO);
+                                        error_signal.set(None);
+                                    }
+                                    Err(e) => error_signal.set(Some(error_message(e))),
                                 }
                             } else {
                                 error_signal
@@ -154,13 +162,24 @@ const CHIPS_IMG: Asset = asset!("/assets/images/chips.png");
 const SANDWICH_IMG: Asset = asset!("/assets/images/sandwich.png");
 const COFFEE_IMG: Asset = asset!("/assets/images/coffee.png");
 
-const PRODUCTS: &[(&str, f64, Asset)] = &[
-    ("Coca", 1.50, COCA_IMG),
-    ("Chips", 2.00, CHIPS_IMG),
-    ("Sandwich", 4.50, SANDWICH_IMG),
-    ("Coffee", 1.20, COFFEE_IMG),
+const PRODUCTS: &[(&str, Money, Asset)] = &[
+    ("Coca", Money::from_cents(150), COCA_IMG),
+    ("Chips", Money::from_cents(200), CHIPS_IMG),
+    ("Sandwich", Money::from_cents(450), SANDWICH_IMG),
+    ("Coffee", Money::from_cents(120), COFFEE_IMG),
 ];
 
+/// Price of the products in `quantities`, None if it does not fit in an amount
+fn basket_total(quantities: &[u32]) -> Option<Money> {
+    PRODUCTS
+        .iter()
+        .zip(quantities)
+        .try_fold(0i64, |total, (&(_, price, _), &quantity)| {
+            total.checked_add(price.cents().checked_mul(quantity as i64)?)
+        })
+        .map(Money::from_cents)
+}
+
 // take the username and collect the an amount (float from form) to make a payment
 /// Payment component
 ///
@@ -173,27 +192,38 @@ pub fn Pay(name: String) -> Element {
     let name_for_payment = std::rc::Rc::new(name.clone());
 
     let mut error_signal = use_signal(|| None::<String>);
+    let mut receipt_signal = use_signal(|| None::<String>);
 
     let handle_pay = move |_| {
-        let current_quantities = product_quantities.read().clone();
+        let total = basket_total(&product_quantities.read());
         let name_clone = name_for_payment.clone();
 
-        let mut total_amount = 0.0;
-        for (i, &(_, price, _)) in PRODUCTS.iter().enumerate() {
-            if let Some(&quantity) = current_quantities.get(i) {
-                total_amount += price * quantity as f64;
-            }
-        }
-
         spawn(async move {
-            if total_amount > 0.0 {
-                if let Ok(_) = pay_for_user_server(name_clone.to_string(), total_amount).await {
-                    log::info!("Payment successful.");
-                    product_quantities.set(vec![0u32; PRODUCTS.len()]);
-                    error_signal.set(None);
+            let Some(total_amount) = total else {
+                receipt_signal.set(None);
+                error_signal.set(Some("The order is too large.".to_string()));
+                return;
+            };
+            if total_amount > Money::ZERO {
+                // Answered once the payment is recorded on every site, or refused
+                match pay_for_user_server(name_clone.to_string(), total_amount).await {
+                    Ok(key) => {
+                        log::info!("Payment successful.");
+                        product_quantities.set(vec![0u32; PRODUCTS.len()]);
+                        error_signal.set(None);
+                        receipt_signal.set(Some(format!(
+                            "Payment of €{total_amount} recorded as transaction {key}."
+                        )));
+                    }
+                    Err(e) => {
+                        log::warn!("Payment failed: {e}");
+                        receipt_signal.set(None);
+                        error_signal.set(Some(error_message(e)));
+                    }
                 }
             } else {
                 log::warn!("Attempted to pay with a total of 0.0. No action taken.");
+                receipt_signal.set(None);
                 error_signal.set(Some(
                     "Cannot pay €0. Please select at least one item.".to_string(),
                 ));
@@ -201,16 +231,8 @@ pub fn Pay(name: String) -> Element {
         });
     };
 
-    let current_total_display = use_memo(move || {
-        let mut total = 0.0;
-        let quantities_read = product_quantities.read();
-        for (i, &(_, price, _)) in PRODUCTS.iter().enumerate() {
-            if let Some(&quantity) = quantities_read.get(i) {
-                total += price * quantity as f64;
-            }
-        }
-        total
-    });
+    let current_total_display =
+        use_memo(move || basket_total(&product_quantities.read()).unwrap_or(Money::ZERO));
 
     rsx! {
         div { id: "pay-page",
@@ -220,7 +242,7 @@ pub fn Pay(name: String) -> Element {
                         img { src: "{image_path}", alt: "{product_name}" }
                         div { class: "product-info",
                             h3 { "{product_name}" }
-                            p { "€{price:.2}" }
+                            p { "€{price}" }
                             div {
                                 label { r#for: "qty-{index}", "Quantity:" }
                                 input {
@@ -251,17 +273,20 @@ pub fn Pay(name: String) -> Element {
 
             div { class: "cart-summary",
                 h2 { "Order Summary" }
-                h3 { "Total: €{current_total_display():.2}" }
+                h3 { "Total: €{current_total_display()}" }
                 form {
                     button {
                         r#type: "button",
-                        disabled: current_total_display() == 0.0,
+                        disabled: current_total_display() == Money::ZERO,
                         onclick: handle_pay,
                         "Pay Now"
                     }
                 }
             }
 
+            if let Some(receipt) = &*receipt_signal.read() {
+                p { class: "success-message", "{receipt}" }
+            }
             if let Some(error) = &*error_signal.read() {
                 p { class: "error-message", "{error}" }
             }
@@ -317,7 +342,11 @@ pub fn Refund(name: String) -> Element {
                                         }
                                         p {
                                             strong { "Amount:" }
-                                            " {transaction.amount:.2}"
+                                            " {transaction.amount}"
+                                        }
+                                        p {
+                                            strong { "Date:" }
+                                            " {transaction.hlc}"
                                         }
                                         if let Some(msg) = &transaction.optional_msg {
                                             if !msg.is_empty() {
@@ -330,7 +359,7 @@ pub fn Refund(name: String) -> Element {
                                         {
                                             let transaction_for_refund = transaction.clone();
                                             let name_for_refund = name.clone();
-                                            let mut resource_to_refresh = transactions_resource.clone();
+                                            let mut resource_to_refresh = transactions_resource;
                                             rsx! {
                                                 button {
                                                     r#type: "button",
@@ -338,21 +367,18 @@ pub fn Refund(name: String) -> Element {
                                                         let name_for_future = name_for_refund.clone();
                                                         let transaction_for_future = transaction_for_refund.clone();
                                                         async move {
-                                                            if let Ok(_) = refund_transaction_server(
+                                                            match refund_transaction_server(
                                                                     name_for_future.to_string(),
                                                                     transaction_for_future.lamport_time,
                                                                     transaction_for_future.source_node,
                                                                 )
                                                                 .await
                                                             {
-                                                                if let Ok(_) = get_transactions_for_user_server(
-                                                                        name_for_future.to_string(),
-                                                                    )
-                                                                    .await
-                                                                {
+                                                                Ok(()) => {
                                                                     error_signal.set(None);
                                                                     resource_to_refresh.restart();
                                                                 }
+                                                                Err(e) => error_signal.set(Some(error_message(e))),
                                                             }
                                                         }
                                                     },
@@ -389,7 +415,7 @@ pub fn Refund(name: String) -> Element {
 /// - Generating random messages for fun
 #[component]
 pub fn Transfer(name: String) -> Element {
-    let mut transfer_amount = use_signal(|| 0f64);
+    let mut transfer_amount = use_signal(|| Money::ZERO);
     let mut transfer_message = use_signal(String::new);
     let mut selected_user = use_signal(String::new);
     let name = std::rc::Rc::new(name);
@@ -441,7 +467,7 @@ pub fn Transfer(name: String) -> Element {
                             step: 0.01,
                             value: "{transfer_amount}",
                             oninput: move |evt| {
-                                if let Ok(val) = evt.value().parse::<f64>() {
+                                if let Ok(val) = evt.value().parse::<Money>() {
                                     transfer_amount.set(val);
                                 }
                             },
@@ -474,8 +500,8 @@ pub fn Transfer(name: String) -> Element {
                                 let message = transfer_message.read().clone();
                                 let from_user = name.clone();
                                 async move {
-                                    if !to_user.is_empty() && amount > 0.0 {
-                                        if let Ok(_) = transfer_from_user_to_user_server(
+                                    if !to_user.is_empty() && amount > Money::ZERO {
+                                        match transfer_from_user_to_user_server(
                                                 from_user.to_string(),
                                                 to_user,
                                                 amount,
@@ -483,10 +509,13 @@ pub fn Transfer(name: String) -> Element {
                                             )
                                             .await
                                         {
-                                            transfer_amount.set(0.0);
-                                            transfer_message.set(String::new());
-                                            selected_user.set(String::new());
-                                            error_signal.set(None);
+                                            Ok(()) => {
+                                                transfer_amount.set(Money::ZERO);
+                                                transfer_message.set(String::new());
+                                                selected_user.set(String::new());
+                                                error_signal.set(None);
+                                            }
+                                            Err(e) => error_signal.set(Some(error_message(e))),
                                         }
                                     } else {
                                         error_signal
@@ -518,7 +547,7 @@ pub fn Transfer(name: String) -> Element {
 /// validation to ensure positive amounts.
 #[component]
 pub fn Deposit(name: String) -> Element {
-    let mut deposit_amount = use_signal(|| 0f64);
+    let mut deposit_amount = use_signal(|| Money::ZERO);
     let name = std::rc::Rc::new(name);
 
     let mut error_signal = use_signal(|| None::<String>);
@@ -545,12 +574,12 @@ pub fn Deposit(name: String) -> Element {
                                 step: 0.01,
                                 min: "0.01",
                                 placeholder: "0.00",
-                                value: if *deposit_amount.read() > 0.0 { "{deposit_amount}" } else { "" },
+                                value: if *deposit_amount.read() > Money::ZERO { "{deposit_amount}" } else { "" },
                                 oninput: move |event| {
-                                    if let Ok(as_number) = event.value().parse::<f64>() {
+                                    if let Ok(as_number) = event.value().parse::<Money>() {
                                         deposit_amount.set(as_number);
                                     } else if event.value().is_empty() {
-                                        deposit_amount.set(0.0);
+                                        deposit_amount.set(Money::ZERO);
                                     }
                                 },
                             }
@@ -563,25 +592,25 @@ pub fn Deposit(name: String) -> Element {
                             button {
                                 r#type: "button",
                                 class: "quick-amount",
-                                onclick: move |_| deposit_amount.set(10.0),
+                                onclick: move |_| deposit_amount.set(Money::from_euros(10)),
                                 "€10"
                             }
                             button {
                                 r#type: "button",
                                 class: "quick-amount",
-                                onclick: move |_| deposit_amount.set(25.0),
+                                onclick: move |_| deposit_amount.set(Money::from_euros(25)),
                                 "€25"
                             }
                             button {
                                 r#type: "button",
                                 class: "quick-amount",
-                                onclick: move |_| deposit_amount.set(50.0),
+                                onclick: move |_| deposit_amount.set(Money::from_euros(50)),
                                 "€50"
                             }
                             button {
                                 r#type: "button",
                                 class: "quick-amount",
-                                onclick: move |_| deposit_amount.set(100.0),
+                                onclick: move |_| deposit_amount.set(Money::from_euros(100)),
                                 "€100"
                             }
                         }
@@ -590,15 +619,18 @@ pub fn Deposit(name: String) -> Element {
                     button {
                         r#type: "button",
                         class: "submit-button",
-                        disabled: *deposit_amount.read() <= 0.0,
+                        disabled: *deposit_amount.read() <= Money::ZERO,
                         onclick: move |_| {
                             let name = name_for_future.clone();
                             let amount = *deposit_amount.read();
                             async move {
-                                if amount > 0.0 {
-                                    if let Ok(_) = deposit_for_user_server(name.to_string(), amount).await {
-                                        deposit_amount.set(0.0);
-                                        error_signal.set(None);
+                                if amount > Money::ZERO {
+                                    match deposit_for_user_server(name.to_string(), amount).await {
+                                        Ok(()) => {
+                                            deposit_amount.set(Money::ZERO);
+                                            error_signal.set(None);
+                                        }
+                                        Err(e) => error_signal.set(Some(error_message(e))),
                                     }
                                 } else {
                                     error_signal
@@ -610,7 +642,7 @@ pub fn Deposit(name: String) -> Element {
                                 }
                             }
                         },
-                        "💰 Deposit €{deposit_amount:.2}"
+                        "💰 Deposit €{deposit_amount}"
                     }
                 }
                 
@@ -655,6 +687,14 @@ const RANDOM_MESSAGE: &[&str] = &[
     "Nan t'inquiete",
 ];
 
+/// Message shown for an action refused by the server, the ledger errors keep their own
+fn error_message(e: ServerFnError) -> String {
+    match e {
+        ServerFnError::ServerError(reason) => reason,
+        e => e.to_string(),
+    }
+}
+
 #[cfg(feature = "server")]
 fn get_seed() -> u64 {
     use std::time::{SystemTime, UNIX_EPOCH};
@@ -681,91 +721,78 @@ async fn get_random_message_server() -> Result<String, ServerFnError> {
 
 #[server]
 async fn get_users_server() -> Result<Vec<String>, ServerFnError> {
-    use crate::db;
-    let users = db::get_users()?;
+    let store = crate::node::current().store.clone();
+    let users = crate::store::blocking(&store, |store| store.users()).await?;
     Ok(users)
 }
 
 #[server]
-async fn deposit_for_user_server(user: String, amount: f64) -> Result<(), ServerFnError> {
-    if amount < 0.0 {
-        return Err(ServerFnError::new("Amount cannot be negative."));
+async fn deposit_for_user_server(user: String, amount: Money) -> Result<(), ServerFnError> {
+    if amount <= Money::ZERO {
+        return Err(ServerFnError::new("Amount must be positive."));
     }
 
-    if let Err(e) = crate::control::enqueue_critical(crate::control::CriticalCommands::Deposit {
-        name: user,
-        amount: amount,
-    })
-    .await
-    {
-        return Err(ServerFnError::new(format!(
-            "[SERVER] Failed to diffuse deposit : {e}"
-        )));
-    }
+    super::run_critical(
+        crate::control::CriticalCommands::Deposit { name: user, amount },
+        "[SERVER] Failed to diffuse deposit",
+    )
+    .await?;
 
     Ok(())
 }
 
 #[server]
-async fn withdraw_for_user_server(user: String, amount: f64) -> Result<(), ServerFnError> {
-    if amount < 0.0 {
-        return Err(ServerFnError::new("Amount cannot be negative."));
+async fn withdraw_for_user_server(user: String, amount: Money) -> Result<(), ServerFnError> {
+    if amount <= Money::ZERO {
+        return Err(ServerFnError::new("Amount must be positive."));
     }
 
-    if let Err(e) = crate::control::enqueue_critical(crate::control::CriticalCommands::Withdraw {
-        name: user,
-        amount: amount,
-    })
-    .await
-    {
-        return Err(ServerFnError::new(format!(
-            "[SERVER] Failed to withdraw : {e}"
-        )));
-    }
+    super::run_critical(
+        crate::control::CriticalCommands::Withdraw { name: user, amount },
+        "[SERVER] Failed to withdraw",
+    )
+    .await?;
 
     Ok(())
 }
 
 #[server]
-async fn pay_for_user_server(user: String, amount: f64) -> Result<(), ServerFnError> {
-    if amount < 0.0 {
-        return Err(ServerFnError::new("Amount cannot be negative."));
+async fn pay_for_user_server(
+    user: String,
+    amount: Money,
+) -> Result<crate::db::TransactionKey, ServerFnError> {
+    if amount <= Money::ZERO {
+        return Err(ServerFnError::new("Amount must be positive."));
     }
 
-    if let Err(e) = crate::control::enqueue_critical(crate::control::CriticalCommands::Pay {
-        name: user,
-        amount: amount,
-    })
-    .await
-    {
-        return Err(ServerFnError::new(format!("[SERVER] Failed to pay : {e}")));
-    }
-
-    Ok(())
+    super::run_critical(
+        crate::control::CriticalCommands::Pay { name: user, amount },
+        "[SERVER] Failed to pay",
+    )
+    .await?
+    .ok_or_else(|| ServerFnError::new("[SERVER] Payment recorded no transaction"))
 }
 
 #[server]
 async fn transfer_from_user_to_user_server(
     from_user: String,
     to_user: String,
-    amount: f64,
+    amount: Money,
     _optional_message: String,
 ) -> Result<(), ServerFnError> {
-    if amount < 0.0 {
-        return Err(ServerFnError::new("Amount cannot be negative."));
+    if amount <= Money::ZERO {
+        return Err(ServerFnError::new("Amount must be positive."));
     }
 
-    if let Err(e) = crate::control::enqueue_critical(crate::control::CriticalCommands::Transfer {
-        from: from_user,
-        to: to_user,
-        amount: amount,
-    })
-    .await
-    {
-        return Err(ServerFnError::new(format!(
-            "[SERVER] Failed to make the transfer: {e}"
-        )));
-    }
+    super::run_critical(
+        crate::control::CriticalCommands::Transfer {
+            from: from_user,
+            to: to_user,
+            amount,
+        },
+        "[SERVER] Failed to make the transfer",
+    )
+    .await?;
 
     Ok(())
 }
@@ -774,7 +801,10 @@ async fn transfer_from_user_to_user_server(
 async fn get_transactions_for_user_server(
     name: String,
 ) -> Result<Vec<crate::db::Transaction>, ServerFnError> {
-    if let Ok(data) = crate::db::get_transactions_for_user(&name) {
+    let store = crate::node::current().store.clone();
+    if let Ok(data) =
+        crate::store::blocking(&store, move |store| store.transactions_for_user(&name)).await
+    {
         Ok(data)
     } else {
         Err(ServerFnError::new("User not found."))
@@ -787,17 +817,15 @@ async fn refund_transaction_server(
     lamport_time: i64,
     transac_node: String,
 ) -> Result<(), ServerFnError> {
-    if let Err(e) = crate::control::enqueue_critical(crate::control::CriticalCommands::Refund {
-        name: name,
-        lamport: lamport_time,
-        node: transac_node,
-    })
-    .await
-    {
-        return Err(ServerFnError::new(format!(
-            "[SERVER] Failed to refund : {e}"
-        )));
-    }
+    super::run_critical(
+        crate::control::CriticalCommands::Refund {
+            name,
+            lamport: lamport_time,
+            node: transac_node,
+        },
+        "[SERVER] Failed to refund",
+    )
+    .await?;
 
     Ok(())
 }
@@ -17,7 +17,7 @@ use dioxus::prelude::*;
 #[component]
 pub fn Home() -> Element {
     let mut user_input = use_signal(|| "".to_string());
-    let mut users = use_signal(|| Vec::new());
+    let mut users = use_signal(Vec::new);
 
     // Initial load
     use_future(move || async move {
@@ -30,7 +30,7 @@ pub fn Home() -> Element {
     use_auto_refresh(2000, {
         let users = users;
         move || {
-            let users = users;
+            let mut users = users;
             async move {
                 if let Ok(data) = get_users().await {
                     users.set(data);
@@ -86,11 +86,10 @@ pub fn Home() -> Element {
                                                 onclick: move |_| {
                                                     let username = item_for_delete.clone();
                                                     spawn(async move {
-                                                        if let Ok(_) = delete_user(username).await {
-                                                            if let Ok(data) = get_users().await {
+                                                        if delete_user(username).await.is_ok()
+                                                            && let Ok(data) = get_users().await {
                                                                 users.set(data);
                                                             }
-                                                        }
                                                     });
                                                 },
                                                 "🗑️"
@@ -127,7 +126,7 @@ pub fn Home() -> Element {
                                 r#type: "button",
                                 disabled: user_input.read().trim().is_empty(),
                                 onclick: move |_| async move {
-                                    if let Ok(_) = add_user(user_input.to_string()).await {
+                                    if add_user(user_input.to_string()).await.is_ok() {
                                         user_input.set("".to_string());
                                     }
                                     if let Ok(data) = get_users().await {
@@ -147,8 +146,8 @@ pub fn Home() -> Element {
 /// Server function to retrieve the list of users
 #[server]
 async fn get_users() -> Result<Vec<String>, ServerFnError> {
-    use crate::db;
-    let users = db::get_users()?;
+    let store = crate::node::current().store.clone();
+    let users = crate::store::blocking(&store, |store| store.users()).await?;
     Ok(users)
 }
 
@@ -158,19 +157,15 @@ async fn get_users() -> Result<Vec<String>, ServerFnError> {
 /// to all nodes in the network.
 #[server]
 async fn add_user(name: String) -> Result<(), ServerFnError> {
-    if name == "" {
+    if name.is_empty() {
         return Err(ServerFnError::new("User name cannot be empty."));
     }
 
-    if let Err(e) = crate::control::enqueue_critical(crate::control::CriticalCommands::CreateUser {
-        name: name,
-    })
-    .await
-    {
-        return Err(ServerFnError::new(format!(
-            "Failed to diffuse the create user message: {e}"
-        )));
-    }
+    super::run_critical(
+        crate::control::CriticalCommands::CreateUser { name },
+        "Failed to diffuse the create user message",
+    )
+    .await?;
 
     Ok(())
 }
@@ -180,7 +175,7 @@ async fn add_user(name: String) -> Result<(), ServerFnError> {
 /// Removes a user from the local database.
 #[server]
 async fn delete_user(name: String) -> Result<(), ServerFnError> {
-    use crate::db;
-    db::delete_user(&name)?;
+    let store = crate::node::current().store.clone();
+    crate::store::blocking(&store, move |store| store.delete_user(&name)).await?;
     Ok(())
 }
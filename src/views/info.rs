@@ -43,22 +43,47 @@ async fn get_lamport() -> Result<i64, ServerFnError> {
 async fn get_vector_clock() -> Result<String, ServerFnError> {
     use crate::state::LOCAL_APP_STATE;
     let state = LOCAL_APP_STATE.lock().await;
-    let vector_clock = state.get_clock().get_vector_clock_values();
+    let vector_clock = state.get_clock().get_vector_clock_entries();
     let vector_clock_string = vector_clock
         .iter()
-        .map(|x| x.to_string())
+        .map(|(site_id, value)| format!("{}: {}", site_id, value))
         .collect::<Vec<String>>()
         .join(", ");
     Ok(vector_clock_string)
 }
 
+/// Server function to retrieve the transactions held back until their causal predecessors are applied
+#[server]
+async fn get_held_transactions() -> Result<Vec<String>, ServerFnError> {
+    use crate::state::LOCAL_APP_STATE;
+    let state = LOCAL_APP_STATE.lock().await;
+    let held = state
+        .causal
+        .get_held()
+        .iter()
+        .map(|msg| {
+            let missing = state
+                .causal
+                .waiting_for(msg)
+                .iter()
+                .map(|(site_id, count)| format!("{}: {}", site_id, count))
+                .collect::<Vec<String>>()
+                .join(", ");
+            format!(
+                "{:?} from {}, waiting for {}",
+                msg.info, msg.message_initiator_id, missing
+            )
+        })
+        .collect();
+    Ok(held)
+}
+
 /// Server function to retrieve the database path
 #[server]
 async fn get_db_path() -> Result<String, ServerFnError> {
-    let conn = crate::db::DB_CONN.lock().unwrap();
-    let path = conn.path().unwrap();
+    let path = crate::node::current().store.location();
     //keep only the name of the file (after the last "/")
-    Ok(path.to_string().split("/").last().unwrap().to_string())
+    Ok(path.split("/").last().unwrap().to_string())
 }
 
 /// Server function to retrieve the number of neighbours in the network
@@ -85,6 +110,23 @@ async fn get_connected_neighbours() -> Result<Vec<String>, ServerFnError> {
     Ok(state.get_connected_nei_addr_string())
 }
 
+/// Server function to retrieve the suspicion state of the neighbours
+#[server]
+async fn get_neighbours_health()
+-> Result<Vec<crate::failure_detector::PeerHealth>, ServerFnError> {
+    use crate::state::LOCAL_APP_STATE;
+    let state = LOCAL_APP_STATE.lock().await;
+    Ok(state.get_neighbours_health())
+}
+
+/// Server function to retrieve the last mutex requests evicted after their lease expired
+#[server]
+async fn get_mutex_evictions() -> Result<Vec<crate::lease::MutexEviction>, ServerFnError> {
+    use crate::state::LOCAL_APP_STATE;
+    let state = LOCAL_APP_STATE.lock().await;
+    Ok(state.leases.get_evictions())
+}
+
 /// Server function to retrieve the list of peer addresses
 #[server]
 async fn get_peer_addrs() -> Result<Vec<String>, ServerFnError> {
@@ -96,13 +138,11 @@ async fn get_peer_addrs() -> Result<Vec<String>, ServerFnError> {
 /// Ask for a snapshot
 #[server]
 async fn ask_for_snapshot() -> Result<(), ServerFnError> {
-    if let Err(e) =
-        crate::control::enqueue_critical(crate::control::CriticalCommands::FileSnapshot).await
-    {
-        return Err(ServerFnError::new(format!(
-            "[SERVER] Failed make the local snapshot: {e}"
-        )));
-    }
+    super::run_critical(
+        crate::control::CriticalCommands::FileSnapshot,
+        "[SERVER] Failed make the local snapshot",
+    )
+    .await?;
     Ok(())
 }
 
@@ -136,17 +176,23 @@ async fn get_snapshot_content() -> Result<Option<String>, ServerFnError> {
 /// - Site ID
 /// - Lamport timestamp
 /// - Vector clock state
+/// - Transactions held back by the causal delivery
 /// - Number of connected sites
 /// - List of connected peers
+/// - Suspicion state of the neighbours
+/// - Mutex requests evicted after their lease expired
 /// - Snapshot button
 #[component]
 pub fn Info() -> Element {
     let mut local_addr = use_signal(|| "".to_string());
     let mut site_id = use_signal(|| "".to_string());
-    let mut peers_addr = use_signal(|| Vec::new());
-    let mut connected_neighbours = use_signal(|| Vec::new());
+    let mut peers_addr = use_signal(Vec::new);
+    let mut connected_neighbours = use_signal(Vec::new);
+    let mut neighbours_health = use_signal(Vec::new);
+    let mut mutex_evictions = use_signal(Vec::new);
     let mut lamport = use_signal(|| 0i64);
     let mut vector_clock = use_signal(|| "".to_string());
+    let mut held_transactions = use_signal(Vec::new);
     let mut nb_neighbours = use_signal(|| 0i64);
     let mut nb_peers = use_signal(|| 0i64);
     let mut db_path = use_signal(|| "".to_string());
@@ -179,6 +225,16 @@ pub fn Info() -> Element {
             connected_neighbours.set(data);
         } // else: connected_neighbours remains empty or you could set an error state if needed
 
+        // Fetch the suspicion state of the neighbours
+        if let Ok(data) = get_neighbours_health().await {
+            neighbours_health.set(data);
+        } // else: neighbours_health remains empty
+
+        // Fetch the evicted mutex requests
+        if let Ok(data) = get_mutex_evictions().await {
+            mutex_evictions.set(data);
+        } // else: mutex_evictions remains empty
+
         // Fetch Lamport clock
         if let Ok(data) = get_lamport().await {
             lamport.set(data);
@@ -189,6 +245,11 @@ pub fn Info() -> Element {
             vector_clock.set(data);
         } // else: vector_clock remains 0 or handle error
 
+        // Fetch the transactions held back
+        if let Ok(data) = get_held_transactions().await {
+            held_transactions.set(data);
+        } // else: held_transactions remains empty
+
         // Fetch number of sites
         if let Ok(data) = get_nb_connected_neighbours().await {
             nb_neighbours.set(data);
@@ -216,21 +277,27 @@ pub fn Info() -> Element {
         let site_id = site_id;
         let peers_addr = peers_addr;
         let connected_neighbours = connected_neighbours;
+        let neighbours_health = neighbours_health;
+        let mutex_evictions = mutex_evictions;
         let lamport = lamport;
         let vector_clock = vector_clock;
+        let held_transactions = held_transactions;
         let nb_neighbours = nb_neighbours;
         let nb_peers = nb_peers;
         let db_path = db_path;
         move || {
-            let local_addr = local_addr;
-            let site_id = site_id;
-            let peers_addr = peers_addr;
-            let connected_neighbours = connected_neighbours;
-            let lamport = lamport;
-            let vector_clock = vector_clock;
-            let nb_neighbours = nb_neighbours;
-            let nb_peers = nb_peers;
-            let db_path = db_path;
+            let mut local_addr = local_addr;
+            let mut site_id = site_id;
+            let mut peers_addr = peers_addr;
+            let mut connected_neighbours = connected_neighbours;
+            let mut neighbours_health = neighbours_health;
+            let mut mutex_evictions = mutex_evictions;
+            let mut lamport = lamport;
+            let mut vector_clock = vector_clock;
+            let mut held_transactions = held_transactions;
+            let mut nb_neighbours = nb_neighbours;
+            let mut nb_peers = nb_peers;
+            let mut db_path = db_path;
             async move {
                 if let Ok(data) = get_local_addr().await {
                     local_addr.set(data);
@@ -244,12 +311,21 @@ pub fn Info() -> Element {
                 if let Ok(data) = get_connected_neighbours().await {
                     connected_neighbours.set(data);
                 }
+                if let Ok(data) = get_neighbours_health().await {
+                    neighbours_health.set(data);
+                }
+                if let Ok(data) = get_mutex_evictions().await {
+                    mutex_evictions.set(data);
+                }
                 if let Ok(data) = get_lamport().await {
                     lamport.set(data);
                 }
                 if let Ok(data) = get_vector_clock().await {
                     vector_clock.set(data);
                 }
+                if let Ok(data) = get_held_transactions().await {
+                    held_transactions.set(data);
+                }
                 if let Ok(data) = get_nb_connected_neighbours().await {
                     nb_neighbours.set(data);
                 }
@@ -288,6 +364,18 @@ pub fn Info() -> Element {
                 strong { "⏱️ Vector Clock : " }
                 span { "{vector_clock}" }
             }
+            div { class: "info-item",
+                strong { "⏳ Held Back Transactions: " }
+                if held_transactions.read().is_empty() {
+                    span { "No transaction waiting for its predecessors." }
+                } else {
+                    ul { class: "peer-list",
+                        for (i, tx) in held_transactions.read().iter().enumerate() {
+                            li { key: "{i}", "{tx}" }
+                        }
+                    }
+                }
+            }
             div { class: "info-item",
                 strong { "🌍 Number of connected neighbours: " }
                 span { "{nb_neighbours}" }
@@ -306,6 +394,44 @@ pub fn Info() -> Element {
                 }
             }
 
+            div { class: "info-item",
+                strong { "🩺 Neighbours Health (φ): " }
+                if neighbours_health.read().is_empty() {
+                    span { "No neighbour monitored." }
+                } else {
+                    ul { class: "peer-list",
+                        for peer in neighbours_health.read().iter() {
+                            li {
+                                key: "{peer.addr}",
+                                class: if peer.suspected { "suspected" } else { "" },
+                                if peer.suspected {
+                                    "{peer.addr} - suspected, φ = {peer.phi:.1}"
+                                } else {
+                                    "{peer.addr} - alive, φ = {peer.phi:.1}"
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+
+            div { class: "info-item",
+                strong { "⌛ Evicted Mutex Requests: " }
+                if mutex_evictions.read().is_empty() {
+                    span { "No lease expired." }
+                } else {
+                    ul { class: "peer-list",
+                        for (i, eviction) in mutex_evictions.read().iter().enumerate() {
+                            li {
+                                key: "{i}",
+                                class: "suspected",
+                                "{eviction.at} - request of {eviction.site_id} evicted by {eviction.evicted_by}"
+                            }
+                        }
+                    }
+                }
+            }
+
             div { class: "info-item",
                 strong { "🌍 Number of CLI peers: " }
                 span { "{nb_peers}" }
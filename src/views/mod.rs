@@ -22,3 +22,25 @@ pub use user::User;
 /// Transaction action components
 mod actions;
 pub use actions::{Deposit, History, Pay, Refund, Transfer, Withdraw};
+
+#[cfg(feature = "server")]
+/// Queues a critical command and waits until it is applied and diffused
+///
+/// Returns the key of the transaction it made, the errors start with `failure`
+async fn run_critical(
+    cmd: crate::control::CriticalCommands,
+    failure: &str,
+) -> Result<Option<crate::db::TransactionKey>, dioxus::prelude::ServerFnError> {
+    use dioxus::prelude::ServerFnError;
+
+    // The web server runs outside of the node, on the default one
+    let store = crate::node::current().store.clone();
+    let receipt = crate::control::enqueue_critical(&store, cmd)
+        .await
+        .map_err(|e| ServerFnError::new(format!("{failure} : {e}")))?;
+    receipt.outcome().await.map_err(|e| match e {
+        // Shown as is to the user, e.g. the funds missing for a payment
+        crate::control::CommandError::Rejected(reason) => ServerFnError::new(reason.to_string()),
+        e => ServerFnError::new(format!("{failure} : {e}")),
+    })
+}
@@ -21,7 +21,7 @@ use dioxus::prelude::*;
 /// - Automatic balance refresh every 2 seconds
 #[component]
 pub fn User(name: String) -> Element {
-    let mut solde = use_signal(|| 0f64);
+    let mut solde = use_signal(|| crate::money::Money::ZERO);
 
     let name = std::rc::Rc::new(name);
     let name_for_future = name.clone();
@@ -41,7 +41,7 @@ pub fn User(name: String) -> Element {
     // Auto-refresh balance every 2 seconds
     {
         use_auto_refresh(2000, {
-            let mut solde = solde;
+            let solde = solde;
             let name = name.clone();
             move || {
                 let mut solde = solde;
@@ -81,7 +81,7 @@ pub fn User(name: String) -> Element {
                     h1 { "Welcome back, {name}!" }
                     div { class: "balance-display",
                         span { class: "balance-label", "Current Balance" }
-                        h2 { class: "balance-amount", "€{solde():.2}" }
+                        h2 { class: "balance-amount", "€{solde()}" }
                     }
                 }
             }
@@ -151,8 +151,8 @@ pub fn User(name: String) -> Element {
 
 /// Server function to retrieve a user's current balance
 #[server]
-async fn get_solde(name: String) -> Result<f64, ServerFnError> {
-    use crate::db;
-    let solde = db::calculate_solde(&name)?;
+async fn get_solde(name: String) -> Result<crate::money::Money, ServerFnError> {
+    let store = crate::node::current().store.clone();
+    let solde = crate::store::blocking(&store, move |store| store.balance(&name)).await?;
     Ok(solde)
 }